@@ -0,0 +1,117 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use pprof::criterion::{Output, PProfProfiler};
+
+use ztunnel::identity::Identity;
+use ztunnel::rbac::{
+    Authorization, Connection, PolicySet, RbacAction, RbacMatch, RbacScope, StringMatch,
+};
+
+/// policies generates count policies. Most are ALLOW policies for a single port, as is common
+/// for policies written per service; every tenth is a DENY policy on a source IP range, which
+/// cannot be indexed and is checked for every connection.
+fn policies(count: usize) -> Vec<Authorization> {
+    (0..count)
+        .map(|i| {
+            let (action, m) = if i % 10 == 9 {
+                (
+                    RbacAction::Deny,
+                    RbacMatch {
+                        source_ips: vec![format!("10.{}.{}.0/24", i / 256 % 256, i % 256)
+                            .parse()
+                            .unwrap()],
+                        ..Default::default()
+                    },
+                )
+            } else {
+                (
+                    RbacAction::Allow,
+                    RbacMatch {
+                        destination_ports: vec![(i % 50_000) as u16 + 1],
+                        principals: vec![
+                            StringMatch::Exact(format!("cluster.local/ns/ns-{i}/sa/client")),
+                            StringMatch::Prefix(format!("cluster.local/ns/ns-{i}/")),
+                        ],
+                        not_namespaces: vec![StringMatch::Suffix("-blocked".to_string())],
+                        ..Default::default()
+                    },
+                )
+            };
+            Authorization {
+                name: format!("policy-{i}"),
+                namespace: "ns".to_string(),
+                scope: RbacScope::Namespace,
+                action,
+                rules: vec![vec![vec![m]]],
+                provider: None,
+            }
+        })
+        .collect()
+}
+
+fn connection(namespace: &str, port: u16) -> Connection {
+    Connection {
+        src_identity: Some(Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: namespace.to_string(),
+            service_account: "client".to_string(),
+        }),
+        src_ip: "127.0.0.1".parse().unwrap(),
+        dst_network: "".to_string(),
+        dst: format!("127.0.0.2:{port}").parse().unwrap(),
+        src_workload: None,
+        dst_service: None,
+    }
+}
+
+pub fn compile(c: &mut Criterion) {
+    let mut c = c.benchmark_group("rbac_compile");
+    for count in [10, 1_000, 10_000] {
+        let pols = policies(count);
+        c.throughput(Throughput::Elements(count as u64));
+        c.bench_with_input(BenchmarkId::from_parameter(count), &pols, |b, pols| {
+            b.iter(|| PolicySet::new(pols))
+        });
+    }
+}
+
+pub fn decide(c: &mut Criterion) {
+    let mut c = c.benchmark_group("rbac_decide");
+    for count in [10, 1_000, 10_000] {
+        let set = PolicySet::new(&policies(count));
+        // The last ALLOW policy matches, and no DENY policy does.
+        let last = count - 2;
+        let allowed = connection(&format!("ns-{last}"), (last % 50_000) as u16 + 1);
+        c.bench_with_input(BenchmarkId::new("allowed", count), &allowed, |b, conn| {
+            b.iter(|| assert!(set.decide(conn).is_allowed()))
+        });
+        let denied = connection("ns-blocked", 1);
+        c.bench_with_input(BenchmarkId::new("denied", count), &denied, |b, conn| {
+            b.iter(|| assert!(!set.decide(conn).is_allowed()))
+        });
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .with_profiler(PProfProfiler::new(100, Output::Protobuf))
+        .warm_up_time(Duration::from_millis(1));
+    targets = compile, decide
+}
+criterion_main!(benches);
@@ -22,6 +22,9 @@ fn main() -> Result<(), anyhow::Error> {
         "proto/workload.proto",
         "proto/authorization.proto",
         "proto/citadel.proto",
+        "proto/ext_authz.proto",
+        "proto/trust_bundle.proto",
+        "proto/workload_api.proto",
     ]
     .iter()
     .map(|name| std::env::current_dir().unwrap().join(name))
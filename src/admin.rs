@@ -13,25 +13,29 @@
 // limitations under the License.
 
 use crate::config::Config;
+use crate::fault::Fault;
+use crate::hot_restart::Listeners;
 use crate::hyper_util::{empty_response, plaintext_response, Server};
-use crate::identity::SecretManager;
+use crate::identity::{Identity, SecretManager};
+use crate::proxy::connection_manager::{ConnectionFilter, ConnectionManager};
 use crate::state::DemandProxyState;
 use crate::tls::asn1_time_to_system_time;
 use crate::version::BuildInfo;
 use crate::xds::LocalConfig;
-use crate::{signal, telemetry};
+use crate::{rbac, signal, telemetry};
 use boring::asn1::Asn1TimeRef;
 use boring::x509::X509;
 use bytes::Bytes;
 use drain::Watch;
-use http_body_util::Full;
+use http_body_util::{BodyExt, Full};
 use hyper::body::Incoming;
 use hyper::{header::HeaderValue, header::CONTENT_TYPE, Request, Response};
 use pprof::protos::Message;
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::{net::SocketAddr, time::Duration};
+use std::time::Duration;
 use tokio::time;
 use tracing::{error, info, warn};
 
@@ -49,6 +53,7 @@ struct State {
     config: Config,
     shutdown_trigger: signal::ShutdownTrigger,
     cert_manager: Arc<SecretManager>,
+    connection_manager: ConnectionManager,
 }
 
 pub struct Service {
@@ -87,18 +92,22 @@ impl Service {
         config: Config,
         proxy_state: DemandProxyState,
         shutdown_trigger: signal::ShutdownTrigger,
+        listeners: &Listeners,
         drain_rx: Watch,
         cert_manager: Arc<SecretManager>,
+        connection_manager: ConnectionManager,
     ) -> anyhow::Result<Self> {
         Server::<State>::bind(
             "admin",
             config.admin_addr,
+            listeners,
             drain_rx,
             State {
                 config,
                 proxy_state,
                 shutdown_trigger,
                 cert_manager,
+                connection_manager,
             },
         )
         .await
@@ -133,6 +142,11 @@ impl Service {
                 )
                 .await),
                 "/logging" => Ok(handle_logging(req).await),
+                "/debug/connections" => {
+                    Ok(handle_connections(&state.connection_manager, req).await)
+                }
+                "/debug/faults" => Ok(handle_faults(&state.proxy_state, req).await),
+                "/debug/authorization" => Ok(handle_authorization(&state.proxy_state, req).await),
                 "/" => Ok(handle_dashboard(req).await),
                 _ => Ok(empty_response(hyper::StatusCode::NOT_FOUND)),
             }
@@ -157,6 +171,18 @@ async fn handle_dashboard(_req: Request<Incoming>) -> Response<Full<Bytes>> {
         ("quitquitquit", "shut down the server"),
         ("config_dump", "dump the current Ztunnel configuration"),
         ("logging", "query/changing logging levels"),
+        (
+            "debug/connections",
+            "list active connections; POST to terminate matching connections",
+        ),
+        (
+            "debug/faults",
+            "list, add or remove injected faults for chaos testing",
+        ),
+        (
+            "debug/authorization",
+            "explain how authorization policies decide a connection",
+        ),
     ];
 
     let mut api_rows = String::new();
@@ -319,15 +345,7 @@ hint: mod_name:\tthe module name, i.e. ztunnel::proxy
 async fn handle_logging(req: Request<Incoming>) -> Response<Full<Bytes>> {
     match *req.method() {
         hyper::Method::POST => {
-            let qp = req
-                .uri()
-                .query()
-                .map(|v| {
-                    url::form_urlencoded::parse(v.as_bytes())
-                        .into_owned()
-                        .collect()
-                })
-                .unwrap_or_else(HashMap::new);
+            let qp = query_params(&req);
             let level = qp.get("level").cloned();
             let reset = qp.get("reset").cloned();
             if level.is_some() || reset.is_some() {
@@ -366,6 +384,229 @@ fn change_log_level(reset: bool, level: &str) -> Response<Full<Bytes>> {
     }
 }
 
+/// query_params returns the query parameters of the request. Only the last value of a repeated
+/// parameter is kept.
+fn query_params<B>(req: &Request<B>) -> HashMap<String, String> {
+    req.uri()
+        .query()
+        .map(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_else(HashMap::new)
+}
+
+static CONNECTIONS_HELP_STRING: &str = "
+usage: GET /debug/connections\t\t\t\t(To list all active connections)
+usage: GET /debug/connections?workload=<uid or name>\t(To list connections matching a filter)
+usage: POST /debug/connections?id=<id>\t\t\t(To terminate a specific connection)
+usage: POST /debug/connections?workload=<uid or name>\t(To terminate all connections matching a filter)
+
+hint: filters:\tid|workload|namespace|identity
+";
+async fn handle_connections<B>(
+    connection_manager: &ConnectionManager,
+    req: Request<B>,
+) -> Response<Full<Bytes>> {
+    let qp = query_params(&req);
+    let id = match qp.get("id").map(|id| id.parse::<u64>()).transpose() {
+        Ok(id) => id,
+        Err(e) => {
+            return plaintext_response(
+                hyper::StatusCode::BAD_REQUEST,
+                format!("invalid id: {e}\n{CONNECTIONS_HELP_STRING}"),
+            )
+        }
+    };
+    let filter = ConnectionFilter {
+        id,
+        workload: qp.get("workload").cloned(),
+        namespace: qp.get("namespace").cloned(),
+        identity: qp.get("identity").cloned(),
+    };
+    match *req.method() {
+        hyper::Method::GET => {
+            let vec = serde_json::to_vec(&connection_manager.connections(&filter)).unwrap();
+            Response::builder()
+                .status(hyper::StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(vec.into())
+                .unwrap()
+        }
+        hyper::Method::POST => {
+            if filter.is_empty() {
+                // Require an explicit filter, so a bare POST doesn't terminate every connection.
+                return plaintext_response(
+                    hyper::StatusCode::BAD_REQUEST,
+                    format!("a filter is required\n{CONNECTIONS_HELP_STRING}"),
+                );
+            }
+            let closed = connection_manager.close(&filter);
+            plaintext_response(
+                hyper::StatusCode::OK,
+                format!("terminated {closed} connections\n"),
+            )
+        }
+        _ => plaintext_response(
+            hyper::StatusCode::METHOD_NOT_ALLOWED,
+            format!("Invalid HTTP method\n {CONNECTIONS_HELP_STRING}"),
+        ),
+    }
+}
+
+static FAULTS_HELP_STRING: &str = "
+usage: GET /debug/faults\t\t\t(To list all configured faults)
+usage: POST /debug/faults\t\t\t(To add or replace a fault; the body is the fault as YAML or JSON)
+usage: DELETE /debug/faults?name=<name>\t(To remove a fault)
+
+hint: actions:\tDelay (delayMs)|Reject|Reset (resetAfterBytes, resetAfterMs)|Blackhole
+hint: matches:\tsourceWorkload|destinationWorkload|destinationVip|destinationPort|percentage
+";
+async fn handle_faults<B>(proxy_state: &DemandProxyState, req: Request<B>) -> Response<Full<Bytes>>
+where
+    B: hyper::body::Body,
+    B::Error: std::fmt::Display,
+{
+    match *req.method() {
+        hyper::Method::GET => {
+            let vec = serde_json::to_vec(&proxy_state.faults()).unwrap();
+            Response::builder()
+                .status(hyper::StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(vec.into())
+                .unwrap()
+        }
+        hyper::Method::POST => {
+            let body = match req.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => {
+                    return plaintext_response(
+                        hyper::StatusCode::BAD_REQUEST,
+                        format!("failed to read body: {e}\n"),
+                    )
+                }
+            };
+            let fault: Fault = match serde_yaml::from_slice(&body) {
+                Ok(fault) => fault,
+                Err(e) => {
+                    return plaintext_response(
+                        hyper::StatusCode::BAD_REQUEST,
+                        format!("invalid fault: {e}\n{FAULTS_HELP_STRING}"),
+                    )
+                }
+            };
+            let name = fault.name.clone();
+            match proxy_state.insert_fault(fault) {
+                Ok(()) => {
+                    info!(fault = name, "fault configured");
+                    plaintext_response(hyper::StatusCode::OK, format!("fault {name} configured\n"))
+                }
+                Err(e) => plaintext_response(
+                    hyper::StatusCode::BAD_REQUEST,
+                    format!("invalid fault: {e}\n{FAULTS_HELP_STRING}"),
+                ),
+            }
+        }
+        hyper::Method::DELETE => {
+            let qp = query_params(&req);
+            let Some(name) = qp.get("name") else {
+                return plaintext_response(
+                    hyper::StatusCode::BAD_REQUEST,
+                    format!("a fault name is required\n{FAULTS_HELP_STRING}"),
+                );
+            };
+            match proxy_state.remove_fault(name) {
+                Some(_) => {
+                    info!(fault = name, "fault removed");
+                    plaintext_response(hyper::StatusCode::OK, format!("fault {name} removed\n"))
+                }
+                None => plaintext_response(
+                    hyper::StatusCode::NOT_FOUND,
+                    format!("fault {name} not found\n"),
+                ),
+            }
+        }
+        _ => plaintext_response(
+            hyper::StatusCode::METHOD_NOT_ALLOWED,
+            format!("Invalid HTTP method\n {FAULTS_HELP_STRING}"),
+        ),
+    }
+}
+
+static AUTHORIZATION_HELP_STRING: &str = "
+usage: GET /debug/authorization?source_ip=<ip>&destination=<ip:port>\t(To explain the decision for a plaintext connection)
+usage: GET /debug/authorization?source_ip=<ip>&destination=<ip:port>&source_identity=<spiffe uri>\t(To explain the decision for an mTLS connection)
+
+hint: parameters:\tsource_ip|source_identity|destination|destination_service|network
+";
+async fn handle_authorization<B>(
+    proxy_state: &DemandProxyState,
+    req: Request<B>,
+) -> Response<Full<Bytes>> {
+    if *req.method() != hyper::Method::GET {
+        return plaintext_response(
+            hyper::StatusCode::METHOD_NOT_ALLOWED,
+            format!("Invalid HTTP method\n {AUTHORIZATION_HELP_STRING}"),
+        );
+    }
+    let conn = match authorization_connection(&query_params(&req)) {
+        Ok(conn) => conn,
+        Err(e) => {
+            return plaintext_response(
+                hyper::StatusCode::BAD_REQUEST,
+                format!("{e}\n{AUTHORIZATION_HELP_STRING}"),
+            )
+        }
+    };
+    let conn = rbac::Connection {
+        src_workload: proxy_state.source_workload(&conn, &conn.dst_network).await,
+        ..conn
+    };
+    match proxy_state.explain_rbac(&conn).await {
+        Some(explanation) => {
+            let vec = serde_json::to_vec(&explanation).unwrap();
+            Response::builder()
+                .status(hyper::StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(vec.into())
+                .unwrap()
+        }
+        None => plaintext_response(
+            hyper::StatusCode::NOT_FOUND,
+            format!("no workload found for {}\n", conn.dst.ip()),
+        ),
+    }
+}
+
+/// authorization_connection builds the hypothetical connection to explain from query parameters.
+fn authorization_connection(qp: &HashMap<String, String>) -> Result<rbac::Connection, String> {
+    let src_ip = qp
+        .get("source_ip")
+        .ok_or("source_ip is required")?
+        .parse::<IpAddr>()
+        .map_err(|e| format!("invalid source_ip: {e}"))?;
+    let dst = qp
+        .get("destination")
+        .ok_or("destination is required")?
+        .parse::<SocketAddr>()
+        .map_err(|e| format!("invalid destination: {e}"))?;
+    let src_identity = qp
+        .get("source_identity")
+        .map(|id| id.parse::<Identity>())
+        .transpose()
+        .map_err(|e| format!("invalid source_identity: {e}"))?;
+    Ok(rbac::Connection {
+        src_identity,
+        src_ip,
+        dst_network: qp.get("network").cloned().unwrap_or_default(),
+        dst,
+        // The source workload is looked up from source_ip.
+        src_workload: None,
+        dst_service: qp.get("destination_service").cloned(),
+    })
+}
+
 #[cfg(feature = "gperftools")]
 async fn handle_gprof(_req: Request<Incoming>) -> Response<Full<Bytes>> {
     const FILE_PATH: &str = "/tmp/profile.prof";
@@ -428,10 +669,15 @@ async fn handle_gprof_heap(_req: Request<Incoming>) -> Response<Full<Bytes>> {
 mod tests {
     use super::dump_certs;
     use super::handle_config_dump;
+    use super::handle_authorization;
+    use super::handle_connections;
+    use super::handle_faults;
     use super::ConfigDump;
     use crate::config::construct_config;
     use crate::config::ProxyConfig;
     use crate::identity;
+    use crate::metrics::traffic;
+    use crate::proxy::connection_manager::ConnectionManager;
     use crate::test_helpers::new_proxy_state;
     use crate::xds::istio::security::string_match::MatchType as XdsMatchType;
     use crate::xds::istio::security::Address as XdsAddress;
@@ -453,7 +699,46 @@ mod tests {
     use std::collections::HashMap;
     use std::time::Duration;
 
-    fn diff_json<'a>(a: &'a serde_json::Value, b: &'a serde_json::Value) -> String {
+    /// request builds an admin request for the path and query, with an empty body.
+    fn request(method: hyper::Method, uri: &str) -> hyper::Request<http_body_util::Empty<Bytes>> {
+        hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Default::default())
+            .unwrap()
+    }
+
+    async fn response_str(
+        resp: hyper::Response<http_body_util::Full<Bytes>>,
+    ) -> (hyper::StatusCode, String) {
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn faults(
+        state: &crate::state::DemandProxyState,
+        method: hyper::Method,
+        uri: &str,
+        body: &str,
+    ) -> (hyper::StatusCode, String) {
+        let req = hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(http_body_util::Full::new(Bytes::from(body.to_string())))
+            .unwrap();
+        response_str(handle_faults(state, req).await).await
+    }
+
+    async fn connections(
+        cm: &ConnectionManager,
+        method: hyper::Method,
+        uri: &str,
+    ) -> (hyper::StatusCode, String) {
+        response_str(handle_connections(cm, request(method, uri)).await).await
+    }
+
+        fn diff_json<'a>(a: &'a serde_json::Value, b: &'a serde_json::Value) -> String {
         let mut ret = String::new();
         let a = serde_json::to_string_pretty(a).unwrap();
         let b = serde_json::to_string_pretty(b).unwrap();
@@ -487,6 +772,7 @@ mod tests {
                     .unwrap()
                     .into(),
             ),
+            key_algorithm: None,
         });
         for i in 0..2 {
             manager
@@ -649,6 +935,7 @@ mod tests {
                     .unwrap()
                     .into(),
             ),
+            key_algorithm: None,
         });
 
         let wl = XdsWorkload {
@@ -683,6 +970,7 @@ mod tests {
             cluster_id: "Kubernetes".to_string(),
             authorization_policies: Vec::new(),
             native_tunnel: false,
+            mtls_mode: Default::default(),
             workload_type: XdsWorkloadType::Deployment.into(),
             virtual_ips: HashMap::from([(
                 "127.0.1.1".to_string(),
@@ -757,6 +1045,7 @@ mod tests {
                     }],
                 }],
             }],
+            provider: "".to_string(),
             // ..Default::default() // intentionally don't default. we want all fields populated
         };
 
@@ -800,4 +1089,182 @@ mod tests {
             resp_str.contains(r#"waypoint":{"destination":"defaultnw/127.0.0.10","port":15008}"#)
         );
     }
+
+    #[tokio::test]
+    async fn test_connections() {
+        use hyper::{Method, StatusCode};
+
+        let cm = ConnectionManager::default();
+        let workload = |uid: &str, name: &str| crate::state::workload::Workload {
+            uid: uid.to_string(),
+            name: name.to_string(),
+            namespace: "ns".to_string(),
+            ..crate::test_helpers::test_default_workload()
+        };
+        let track = |src, dst| {
+            cm.track(
+                &traffic::ConnectionOpen {
+                    reporter: traffic::Reporter::destination,
+                    source: Some(src),
+                    derived_source: None,
+                    destination: Some(dst),
+                    connection_security_policy: traffic::SecurityPolicy::mutual_tls,
+                    response_flags: traffic::ResponseFlags::none,
+                    destination_service: None,
+                    destination_service_namespace: None,
+                    destination_service_name: None,
+                },
+                None,
+                None,
+                "127.0.0.1".parse().unwrap(),
+                "127.0.0.2:80".parse().unwrap(),
+            )
+        };
+        let _ab = track(workload("uid-a", "a"), workload("uid-b", "b"));
+        let cd = track(workload("uid-c", "c"), workload("uid-d", "d"));
+
+        let (status, body) = connections(&cm, Method::GET, "/debug/connections").await;
+        assert_eq!(status, StatusCode::OK);
+        let all: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(all.len(), 2);
+        let uri = "/debug/connections?workload=uid-a";
+        let (status, body) = connections(&cm, Method::GET, uri).await;
+        assert_eq!(status, StatusCode::OK);
+        let filtered: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0]["source_workload"], "uid-a");
+
+        // Closing requires a valid filter.
+        let (status, _) = connections(&cm, Method::POST, "/debug/connections").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = connections(&cm, Method::POST, "/debug/connections?id=abc").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), cd.closed())
+                .await
+                .is_err(),
+            "no connection should be closed without a filter"
+        );
+
+        let uri = format!("/debug/connections?id={}", cd.id());
+        let (status, body) = connections(&cm, Method::POST, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "terminated 1 connections\n");
+        tokio::time::timeout(Duration::from_secs(1), cd.closed())
+            .await
+            .expect("connection should be closed");
+    }
+
+    #[tokio::test]
+    async fn test_faults() {
+        use hyper::{Method, StatusCode};
+
+        let state = crate::state::DemandProxyState::new(
+            std::sync::Arc::new(std::sync::RwLock::new(crate::state::ProxyState::default())),
+            None,
+        );
+        let list = |body: &str| -> Vec<String> {
+            serde_json::from_str::<Vec<serde_json::Value>>(body)
+                .unwrap()
+                .iter()
+                .map(|f| f["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let (status, body) = faults(&state, Method::GET, "/debug/faults", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(list(&body).is_empty());
+
+        let yaml = "name: slow\naction: Delay\ndelayMs: 100\ndestinationPort: 80\n";
+        let (status, body) = faults(&state, Method::POST, "/debug/faults", yaml).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let json = r#"{"name": "drop", "action": "Blackhole"}"#;
+        let (status, body) = faults(&state, Method::POST, "/debug/faults", json).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        // Invalid faults are rejected.
+        let invalid = "name: broken\naction: Delay\n";
+        let (status, _) = faults(&state, Method::POST, "/debug/faults", invalid).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = faults(&state, Method::POST, "/debug/faults", "[").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (_, body) = faults(&state, Method::GET, "/debug/faults", "").await;
+        assert_eq!(list(&body), vec!["drop", "slow"]);
+
+        let (status, _) = faults(&state, Method::DELETE, "/debug/faults", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = faults(&state, Method::DELETE, "/debug/faults?name=slow", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = faults(&state, Method::DELETE, "/debug/faults?name=slow", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, body) = faults(&state, Method::GET, "/debug/faults", "").await;
+        assert_eq!(list(&body), vec!["drop"]);
+    }
+
+    #[tokio::test]
+    async fn test_authorization() {
+        use hyper::{Method, StatusCode};
+
+        let wl = XdsWorkload {
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+            uid: "uid-dst".to_string(),
+            name: "dst".to_string(),
+            namespace: "ns".to_string(),
+            ..Default::default()
+        };
+        let auth = XdsAuthorization {
+            name: "allow".to_string(),
+            namespace: "ns".to_string(),
+            scope: 0,
+            action: 0,
+            rules: vec![XdsRule {
+                clauses: vec![XdsClause {
+                    matches: vec![XdsMatch {
+                        principals: vec![XdsStringMatch {
+                            match_type: Some(XdsMatchType::Exact(
+                                "spiffe://cluster.local/ns/ns/sa/allowed".to_string(),
+                            )),
+                        }],
+                        ..Default::default()
+                    }],
+                }],
+            }],
+            ..Default::default()
+        };
+        let proxy_state = new_proxy_state(vec![wl], vec![], vec![auth]).unwrap();
+        let state = &proxy_state;
+        let explain = |uri| async move {
+            response_str(handle_authorization(state, request(Method::GET, uri)).await).await
+        };
+        let explanation = |body: &str| serde_json::from_str::<serde_json::Value>(body).unwrap();
+
+        let (status, body) = explain(
+            "/debug/authorization?source_ip=127.0.0.3&destination=127.0.0.2:80\
+             &source_identity=spiffe://cluster.local/ns/ns/sa/allowed",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let allowed = explanation(&body);
+        assert_eq!(allowed["workload"], "ns/dst");
+        assert_eq!(allowed["allowed"], true);
+        assert_eq!(allowed["policy"], "ns/allow");
+        assert_eq!(allowed["policies"][0]["matched"], true);
+
+        let (status, body) = explain(
+            "/debug/authorization?source_ip=127.0.0.3&destination=127.0.0.2:80\
+             &source_identity=spiffe://cluster.local/ns/ns/sa/denied",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let denied = explanation(&body);
+        assert_eq!(denied["allowed"], false);
+        assert!(denied.get("policy").is_none());
+        assert_eq!(denied["policies"][0]["matched"], false);
+
+        let (status, _) = explain("/debug/authorization?destination=127.0.0.2:80").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) =
+            explain("/debug/authorization?source_ip=127.0.0.3&destination=127.0.0.9:80").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
 }
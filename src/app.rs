@@ -12,25 +12,38 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::hot_restart::Listeners;
 use crate::identity::SecretManager;
 use crate::metrics::Metrics;
+use crate::proxy::connection_manager::ConnectionManager;
 use crate::state::ProxyStateManager;
-use crate::{admin, config, identity, proxy, readiness, signal, stats};
+use crate::{admin, config, hot_restart, identity, proxy, readiness, signal, stats};
 use anyhow::Context;
 use prometheus_client::registry::Registry;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use tracing::Instrument;
+use tracing::{warn, Instrument};
 
 pub async fn build_with_cert(
     config: config::Config,
     cert_manager: Arc<SecretManager>,
+) -> anyhow::Result<Bound> {
+    build_with_cert_and_ca(config, cert_manager, None).await
+}
+
+/// build_with_cert_and_ca is build_with_cert, also serving the local CA to other nodes if it is
+/// configured to.
+async fn build_with_cert_and_ca(
+    config: config::Config,
+    cert_manager: Arc<SecretManager>,
+    local_ca: Option<Arc<identity::LocalCa>>,
 ) -> anyhow::Result<Bound> {
     let mut registry = Registry::default();
     let metrics = Arc::new(Metrics::from(&mut registry));
+    cert_manager.set_metrics(metrics.clone());
 
     let shutdown = signal::Shutdown::new();
     // Setup a drain channel. drain_tx is used to trigger a drain, which will complete
@@ -46,24 +59,44 @@ pub async fn build_with_cert(
         metrics.clone(),
         ready.register_task("workload manager"),
         cert_manager.clone(),
+        drain_rx.clone(),
     )
     .await?;
+    let connection_manager = ConnectionManager::default();
+
+    // If another ztunnel is running, take over its listeners rather than binding our own.
+    let (takeover, listeners) = match &config.hot_restart_socket {
+        Some(path) => match hot_restart::Takeover::connect(path)
+            .await
+            .context("hot restart takeover")?
+        {
+            Some((takeover, listeners)) => (Some(takeover), listeners),
+            None => (None, Listeners::default()),
+        },
+        None => (None, Listeners::default()),
+    };
+    // Sockets passed through systemd socket activation are used in place of binding, too.
+    #[cfg(unix)]
+    listeners.inherit(crate::socket::listen_fds());
 
     let admin_server = admin::Service::new(
         config.clone(),
         state_mgr.state.clone(),
         shutdown.trigger(),
+        &listeners,
         drain_rx.clone(),
         cert_manager.clone(),
+        connection_manager.clone(),
     )
     .await
     .context("admin server starts")?;
-    let stats_server = stats::Service::new(config.clone(), registry, drain_rx.clone())
+    let stats_server = stats::Service::new(config.clone(), registry, &listeners, drain_rx.clone())
         .await
         .context("stats server starts")?;
-    let readiness_server = readiness::Service::new(config.clone(), ready, drain_rx.clone())
-        .await
-        .context("readiness server starts")?;
+    let readiness_server =
+        readiness::Service::new(config.clone(), ready, &listeners, drain_rx.clone())
+            .await
+            .context("readiness server starts")?;
     let readiness_address = readiness_server.address();
     let admin_address = admin_server.address();
     let stats_address = stats_server.address();
@@ -73,17 +106,58 @@ pub async fn build_with_cert(
         state_mgr.state.clone(),
         cert_manager.clone(),
         metrics.clone(),
+        connection_manager.clone(),
+        &listeners,
+        &proxy_task,
         drain_rx.clone(),
     )
     .await?;
     drop(proxy_task);
 
+    let server = config.local_ca.as_ref().and_then(|cfg| cfg.server.clone());
+    if let (Some(ca), Some(server)) = (local_ca, server) {
+        ca.serve(
+            server,
+            state_mgr.state.clone(),
+            &listeners,
+            drain_rx.clone(),
+        )
+        .await
+        .context("local CA server starts")?;
+    }
+
+    // Everything is set up; the old ztunnel can stop accepting, and we take over.
+    listeners.close_unclaimed();
+    if let Some(takeover) = takeover {
+        takeover.complete().await.context("hot restart takeover")?;
+    }
+    let handed_off = Arc::new(AtomicBool::new(false));
+    let hot_restart_server = match &config.hot_restart_socket {
+        Some(path) => Some(hot_restart::Server::bind(
+            path,
+            listeners,
+            shutdown.trigger(),
+            handed_off.clone(),
+            drain_rx.clone(),
+        )?),
+        None => None,
+    };
+
     // spawn all tasks that should run in the main thread
     admin_server.spawn();
     stats_server.spawn();
+    if let Some(hot_restart_server) = hot_restart_server {
+        tokio::spawn(hot_restart_server.run());
+    }
+    tokio::spawn(connection_manager.enforce_policy_updates(
+        state_mgr.state.clone(),
+        state_mgr.policy_updates(),
+        metrics.clone(),
+    ));
     tokio::spawn(state_mgr.run());
 
     let proxy_addresses = proxy.addresses();
+    let drain_deadline = config.self_termination_deadline;
     let span = tracing::span::Span::current();
     thread::spawn(move || {
         let _span = span.enter();
@@ -113,14 +187,41 @@ pub async fn build_with_cert(
         admin_address,
         stats_address,
         proxy_addresses,
+        handed_off,
+        drain_deadline,
     })
 }
 
 pub async fn build(config: config::Config) -> anyhow::Result<Bound> {
     let cert_manager = if config.fake_ca {
-        identity::mock::new_secret_manager(Duration::from_secs(86400))
+        identity::mock::new_secret_manager_cfg(identity::mock::SecretManagerConfig {
+            cert_lifetime: Duration::from_secs(86400),
+            fetch_latency: Duration::ZERO,
+            epoch: None,
+            key_algorithm: Some(config.key_algorithm),
+        })
+    } else if let Some(socket) = &config.spiffe_endpoint_socket {
+        let client = identity::WorkloadApiClient::new(socket.clone());
+        let (trust_bundles, crls) = (client.trust_bundles().clone(), client.crls().clone());
+        Arc::new(SecretManager::new_with_client(client).with_trust(trust_bundles, crls))
+    } else if let Some(dir) = &config.workload_cert_dir {
+        let client = identity::FileCaClient::new(dir.clone()).await?;
+        let cert_manager = Arc::new(SecretManager::new_with_client(client.clone()));
+        client.watch(Arc::downgrade(&cert_manager));
+        cert_manager
+    } else if let Some(cfg) = &config.local_ca {
+        let ca = Arc::new(identity::LocalCa::load(
+            cfg,
+            config.trust_domain_aliases.clone(),
+            config.key_algorithm,
+        )?);
+        let cert_manager = Arc::new(SecretManager::new_with_client_and_refresh(
+            ca.clone(),
+            config.cert_refresh.clone(),
+        ));
+        return build_with_cert_and_ca(config, cert_manager, Some(ca)).await;
     } else {
-        Arc::new(SecretManager::new(config.clone())?)
+        Arc::new(SecretManager::new(config.clone()).await?)
     };
     build_with_cert(config, cert_manager).await
 }
@@ -133,6 +234,9 @@ pub struct Bound {
 
     pub shutdown: signal::Shutdown,
     drain_tx: drain::Signal,
+    /// Set once our listeners were handed over to a new process.
+    handed_off: Arc<AtomicBool>,
+    drain_deadline: Duration,
 }
 
 impl Bound {
@@ -142,7 +246,20 @@ impl Bound {
 
         // Start a drain; this will attempt to end all connections
         // or itself be interrupted by a stronger TERM signal, whichever comes first.
-        self.drain_tx.drain().await;
+        if self.handed_off.load(Ordering::SeqCst) {
+            // The new process is already serving; don't linger for long-lived connections.
+            if tokio::time::timeout(self.drain_deadline, self.drain_tx.drain())
+                .await
+                .is_err()
+            {
+                warn!(
+                    "Drain did not complete in {:?} after hot restart, terminating now",
+                    self.drain_deadline
+                );
+            }
+        } else {
+            self.drain_tx.drain().await;
+        }
 
         Ok(())
     }
@@ -0,0 +1,386 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The authorization log records each authorization decision as a line of JSON. It is written
+//! independently of the main log, so it is not affected by the log level.
+//!
+//! Records are written by a background thread, so a slow sink never blocks the connection that
+//! is being authorized. If the sink falls behind, records are dropped and counted instead.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use chrono::{SecondsFormat, Utc};
+use tracing::warn;
+
+use crate::metrics::rbac::AuthzLogDropped;
+use crate::metrics::{IncrementRecorder, Metrics};
+use crate::rbac::{self, Denial};
+use crate::state::workload::Workload;
+
+/// The number of records that may wait to be written before new ones are dropped.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// Config configures the authorization log.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// Where records are written.
+    pub sink: Sink,
+    /// If true, only denials are logged.
+    pub denials_only: bool,
+    /// The percentage of allowed connections that are logged. Denials are always logged.
+    pub allow_sample_percent: u8,
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum Sink {
+    Stdout,
+    /// Records are appended to the file.
+    File(PathBuf),
+}
+
+/// AuthzLog writes authorization records. The default AuthzLog is disabled.
+#[derive(Clone, Default)]
+pub struct AuthzLog {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    cfg: Config,
+    records: SyncSender<Vec<u8>>,
+    metrics: Arc<Metrics>,
+}
+
+impl fmt::Debug for AuthzLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthzLog")
+            .field("config", &self.inner.as_ref().map(|i| &i.cfg))
+            .finish()
+    }
+}
+
+impl AuthzLog {
+    /// new opens the sink of the configuration. Without one, nothing is logged.
+    pub fn new(cfg: Option<&Config>, metrics: Arc<Metrics>) -> io::Result<AuthzLog> {
+        let Some(cfg) = cfg else {
+            return Ok(AuthzLog::default());
+        };
+        let writer: Box<dyn Write + Send> = match &cfg.sink {
+            Sink::Stdout => Box::new(io::stdout()),
+            Sink::File(path) => Box::new(LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+        };
+        AuthzLog::with_writer(cfg.clone(), writer, QUEUE_CAPACITY, metrics)
+    }
+
+    /// with_writer starts a thread writing records to writer. It stops once every AuthzLog
+    /// sharing it is dropped and the queued records are written.
+    fn with_writer(
+        cfg: Config,
+        mut writer: Box<dyn Write + Send>,
+        capacity: usize,
+        metrics: Arc<Metrics>,
+    ) -> io::Result<AuthzLog> {
+        let (records, rx) = mpsc::sync_channel::<Vec<u8>>(capacity);
+        std::thread::Builder::new()
+            .name("authz-log".to_string())
+            .spawn(move || {
+                for line in rx {
+                    if let Err(e) = writer.write_all(&line) {
+                        warn!("failed to write authorization record: {e}");
+                    }
+                }
+                let _ = writer.flush();
+            })?;
+        Ok(AuthzLog {
+            inner: Some(Arc::new(Inner {
+                cfg,
+                records,
+                metrics,
+            })),
+        })
+    }
+
+    /// record logs the decision for a connection to dst. An allowed connection carries the ALLOW
+    /// policy that allowed it, if any.
+    pub fn record(
+        &self,
+        conn: &rbac::Connection,
+        dst: Option<&Workload>,
+        result: &Result<Option<&str>, Denial>,
+    ) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        if result.is_ok() && !inner.sample_allow() {
+            return;
+        }
+        let (action, policy, reason) = match result {
+            Ok(Some(policy)) => (
+                Some("ALLOW"),
+                Some(*policy),
+                format!("allowed by policy {policy}"),
+            ),
+            Ok(None) => (None, None, "no ALLOW policies".to_string()),
+            Err(denial) => match denial {
+                Denial::Policy(policy) => (Some("DENY"), Some(policy.as_str()), denial.to_string()),
+                Denial::Custom { policy, .. } => {
+                    (Some("CUSTOM"), Some(policy.as_str()), denial.to_string())
+                }
+                _ => (None, None, denial.to_string()),
+            },
+        };
+        let record = Record {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            source_ip: conn.src_ip,
+            source_identity: conn.src_identity.as_ref().map(ToString::to_string),
+            destination: conn.dst,
+            destination_network: &conn.dst_network,
+            destination_identity: dst.map(|w| w.identity().to_string()),
+            destination_workload: dst.map(|w| format!("{}/{}", w.namespace, w.name)),
+            decision: if result.is_ok() { "allow" } else { "deny" },
+            action,
+            policy,
+            reason,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("failed to encode authorization record: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+        match inner.records.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => inner.metrics.increment(&AuthzLogDropped),
+            Err(TrySendError::Disconnected(_)) => warn!("authorization log writer has stopped"),
+        }
+    }
+}
+
+impl Inner {
+    fn sample_allow(&self) -> bool {
+        if self.cfg.denials_only {
+            return false;
+        }
+        match self.cfg.allow_sample_percent {
+            0 => false,
+            100.. => true,
+            p => rand::random::<f64>() * 100.0 < p as f64,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Record<'a> {
+    timestamp: String,
+    source_ip: IpAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_identity: Option<String>,
+    destination: SocketAddr,
+    destination_network: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_identity: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_workload: Option<String>,
+    decision: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<&'a str>,
+    reason: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn records(&self) -> Vec<serde_json::Value> {
+            let buf = self.0.lock().unwrap();
+            std::str::from_utf8(&buf)
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect()
+        }
+
+        /// wait_records waits for the writer to catch up to at least n records.
+        fn wait_records(&self, n: usize) -> Vec<serde_json::Value> {
+            for _ in 0..500 {
+                let records = self.records();
+                if records.len() >= n {
+                    return records;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            self.records()
+        }
+    }
+
+    fn config(denials_only: bool, allow_sample_percent: u8) -> Config {
+        Config {
+            sink: Sink::Stdout,
+            denials_only,
+            allow_sample_percent,
+        }
+    }
+
+    fn log(denials_only: bool, allow_sample_percent: u8) -> (AuthzLog, Buffer) {
+        let buf = Buffer::default();
+        let cfg = config(denials_only, allow_sample_percent);
+        let metrics = Arc::new(Metrics::default());
+        let log = AuthzLog::with_writer(cfg, Box::new(buf.clone()), QUEUE_CAPACITY, metrics);
+        (log.unwrap(), buf)
+    }
+
+    fn conn() -> rbac::Connection {
+        rbac::Connection {
+            src_identity: Some(Identity::Spiffe {
+                trust_domain: "cluster.local".to_string(),
+                namespace: "ns".to_string(),
+                service_account: "client".to_string(),
+            }),
+            src_ip: "127.0.0.1".parse().unwrap(),
+            dst_network: "".to_string(),
+            dst: "127.0.0.2:8080".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
+        }
+    }
+
+    #[test]
+    fn records() {
+        let (log, buf) = log(false, 100);
+        log.record(&conn(), None, &Ok(Some("ns/allow")));
+        log.record(&conn(), None, &Err(Denial::Policy("ns/deny".to_string())));
+        log.record(&conn(), None, &Err(Denial::NotAllowed));
+
+        let records = buf.wait_records(3);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["decision"], "allow");
+        assert_eq!(records[0]["action"], "ALLOW");
+        assert_eq!(records[0]["policy"], "ns/allow");
+        assert_eq!(
+            records[0]["sourceIdentity"],
+            "spiffe://cluster.local/ns/ns/sa/client"
+        );
+        assert_eq!(records[0]["destination"], "127.0.0.2:8080");
+        assert_eq!(records[1]["decision"], "deny");
+        assert_eq!(records[1]["action"], "DENY");
+        assert_eq!(records[1]["reason"], "denied by policy ns/deny");
+        assert_eq!(records[2]["decision"], "deny");
+        assert!(records[2].get("action").is_none());
+        assert!(records[2].get("policy").is_none());
+    }
+
+    #[test]
+    fn denials_only() {
+        let (log, buf) = log(true, 100);
+        log.record(&conn(), None, &Ok(None));
+        log.record(&conn(), None, &Err(Denial::UnknownWorkload));
+        let records = buf.wait_records(1);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["reason"], "destination workload not found");
+    }
+
+    #[test]
+    fn allow_sampling() {
+        let (log, buf) = log(false, 0);
+        for _ in 0..10 {
+            log.record(&conn(), None, &Ok(None));
+        }
+        log.record(&conn(), None, &Err(Denial::NotAllowed));
+        assert_eq!(buf.wait_records(1).len(), 1);
+    }
+
+    /// BlockedWriter blocks writes until the gate is released, reporting each one it starts.
+    struct BlockedWriter {
+        started: mpsc::Sender<()>,
+        gate: Arc<Mutex<()>>,
+    }
+
+    impl Write for BlockedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.started.send(());
+            let _gate = self.gate.lock().unwrap();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drops_when_full() {
+        let mut registry = Registry::default();
+        let metrics = Arc::new(Metrics::from(&mut registry));
+        let (started, writes) = mpsc::channel();
+        let gate = Arc::new(Mutex::new(()));
+        let blocked = gate.lock().unwrap();
+        let writer = BlockedWriter {
+            started,
+            gate: gate.clone(),
+        };
+        let log = AuthzLog::with_writer(config(false, 100), Box::new(writer), 1, metrics).unwrap();
+
+        // The first record blocks the writer, the second fills the queue, and the rest are
+        // dropped rather than blocking the caller.
+        log.record(&conn(), None, &Err(Denial::NotAllowed));
+        writes.recv().unwrap();
+        for _ in 0..3 {
+            log.record(&conn(), None, &Err(Denial::NotAllowed));
+        }
+        drop(blocked);
+
+        let mut buf = String::new();
+        encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("istio_authz_log_dropped_records_total 2"));
+    }
+
+    #[test]
+    fn disabled() {
+        let log = AuthzLog::new(None, Arc::new(Metrics::default())).unwrap();
+        log.record(&conn(), None, &Err(Denial::NotAllowed));
+        assert!(log.inner.is_none());
+    }
+}
@@ -22,8 +22,17 @@ pub struct Baggage {
     pub cluster_id: Option<String>,
     pub namespace: Option<String>,
     pub workload_name: Option<String>,
+    pub workload_type: Option<String>,
     pub service_name: Option<String>,
     pub revision: Option<String>,
+    pub network: Option<String>,
+}
+
+impl Baggage {
+    fn set_workload(&mut self, workload_type: &str, name: Option<String>) {
+        self.workload_type = name.as_ref().map(|_| workload_type.to_string());
+        self.workload_name = name;
+    }
 }
 
 pub fn parse_baggage_header(headers: GetAll<HeaderValue>) -> Result<Baggage, ToStrError> {
@@ -42,12 +51,13 @@ pub fn parse_baggage_header(headers: GetAll<HeaderValue>) -> Result<Baggage, ToS
                 match parts[0] {
                     "k8s.cluster.name" => baggage.cluster_id = val,
                     "k8s.namespace.name" => baggage.namespace = val,
-                    "k8s.deployment.name"
-                    | "k8s.cronjob.name"
-                    | "k8s.pod.name"
-                    | "k8s.job.name" => baggage.workload_name = val,
+                    "k8s.deployment.name" => baggage.set_workload("deployment", val),
+                    "k8s.cronjob.name" => baggage.set_workload("cronjob", val),
+                    "k8s.pod.name" => baggage.set_workload("pod", val),
+                    "k8s.job.name" => baggage.set_workload("job", val),
                     "service.name" => baggage.service_name = val,
                     "service.version" => baggage.revision = val,
+                    "istio.network.name" => baggage.network = val,
                     _ => {}
                 }
             }
@@ -67,15 +77,17 @@ pub mod tests {
     #[test]
     fn baggage_parser() -> anyhow::Result<()> {
         let mut hm = HeaderMap::new();
-        let baggage_str = "k8s.cluster.name=K1,k8s.namespace.name=NS1,k8s.deployment.name=N1,service.name=N2,service.version=V1";
+        let baggage_str = "k8s.cluster.name=K1,k8s.namespace.name=NS1,k8s.deployment.name=N1,service.name=N2,service.version=V1,istio.network.name=NW1";
         let header_value = HeaderValue::from_str(baggage_str)?;
         hm.append(BAGGAGE_HEADER, header_value);
         let baggage = parse_baggage_header(hm.get_all(BAGGAGE_HEADER))?;
         assert_eq!(baggage.cluster_id, Some("K1".to_string()));
         assert_eq!(baggage.namespace, Some("NS1".to_string()));
         assert_eq!(baggage.workload_name, Some("N1".to_string()));
+        assert_eq!(baggage.workload_type, Some("deployment".to_string()));
         assert_eq!(baggage.service_name, Some("N2".to_string()));
         assert_eq!(baggage.revision, Some("V1".to_string()));
+        assert_eq!(baggage.network, Some("NW1".to_string()));
         Ok(())
     }
 
@@ -26,7 +26,7 @@ use hyper::http::uri::InvalidUri;
 use hyper::Uri;
 use tokio::time;
 
-use crate::identity;
+use crate::{authz_log, ext_authz, identity, tls};
 
 const KUBERNETES_SERVICE_HOST: &str = "KUBERNETES_SERVICE_HOST";
 const NETWORK: &str = "NETWORK";
@@ -39,9 +39,43 @@ const XDS_ON_DEMAND: &str = "XDS_ON_DEMAND";
 const XDS_ADDRESS: &str = "XDS_ADDRESS";
 const CA_ADDRESS: &str = "CA_ADDRESS";
 const FAKE_CA: &str = "FAKE_CA";
+const SPIFFE_ENDPOINT_SOCKET: &str = "SPIFFE_ENDPOINT_SOCKET";
+const WORKLOAD_CERT_DIR: &str = "WORKLOAD_CERT_DIR";
+const KEY_ALGORITHM: &str = "KEY_ALGORITHM";
+const SECRET_TTL: &str = "SECRET_TTL";
+const CERT_REFRESH_AT: &str = "CERT_REFRESH_AT";
+const CERT_REFRESH_JITTER: &str = "CERT_REFRESH_JITTER";
+const CERT_REFRESH_INITIAL_BACKOFF: &str = "CERT_REFRESH_INITIAL_BACKOFF";
+const CERT_REFRESH_MAX_BACKOFF: &str = "CERT_REFRESH_MAX_BACKOFF";
+const CERT_CACHE_DIR: &str = "CERT_CACHE_DIR";
+const CERT_CACHE_KEY_FILE: &str = "CERT_CACHE_KEY_FILE";
+const LOCAL_CA_DIR: &str = "LOCAL_CA_DIR";
+const LOCAL_CA_CERT_VALIDITY: &str = "LOCAL_CA_CERT_VALIDITY";
+const LOCAL_CA_SERVER_ADDRESS: &str = "LOCAL_CA_SERVER_ADDRESS";
+const LOCAL_CA_SERVER_HOSTS: &str = "LOCAL_CA_SERVER_HOSTS";
+const LOCAL_CA_SERVER_TOKEN: &str = "LOCAL_CA_SERVER_TOKEN";
 const ZTUNNEL_WORKER_THREADS: &str = "ZTUNNEL_WORKER_THREADS";
 const ENABLE_ORIG_SRC: &str = "ENABLE_ORIG_SRC";
 const PROXY_CONFIG: &str = "PROXY_CONFIG";
+const POD_LISTENER_SOCKET: &str = "POD_LISTENER_SOCKET";
+const HOT_RESTART_SOCKET: &str = "HOT_RESTART_SOCKET";
+const INBOUND_ADDR: &str = "INBOUND_ADDR";
+const INBOUND_PLAINTEXT_ADDR: &str = "INBOUND_PLAINTEXT_ADDR";
+const OUTBOUND_ADDR: &str = "OUTBOUND_ADDR";
+const SOCKS5_ADDR: &str = "SOCKS5_ADDR";
+const SOCKS5_PATH: &str = "SOCKS5_PATH";
+const SOCKS5_PATH_MODE: &str = "SOCKS5_PATH_MODE";
+const TRUST_DOMAIN_ALIASES: &str = "TRUST_DOMAIN_ALIASES";
+const TRUST_BUNDLE_DIR: &str = "TRUST_BUNDLE_DIR";
+const XDS_TRUST_BUNDLES: &str = "XDS_TRUST_BUNDLES";
+const XDS_PEER_AUTHENTICATION: &str = "XDS_PEER_AUTHENTICATION";
+const XDS_TRUST_DOMAIN_ALIASES: &str = "XDS_TRUST_DOMAIN_ALIASES";
+const CRL_SOURCES: &str = "CRL_SOURCES";
+const CRL_REFRESH_INTERVAL: &str = "CRL_REFRESH_INTERVAL";
+const CRL_FAIL_CLOSED: &str = "CRL_FAIL_CLOSED";
+const AUTHZ_LOG: &str = "AUTHZ_LOG";
+const AUTHZ_LOG_DENIALS_ONLY: &str = "AUTHZ_LOG_DENIALS_ONLY";
+const AUTHZ_LOG_ALLOW_SAMPLE_PERCENT: &str = "AUTHZ_LOG_ALLOW_SAMPLE_PERCENT";
 
 const DEFAULT_WORKER_THREADS: u16 = 2;
 const DEFAULT_ADMIN_PORT: u16 = 15000;
@@ -49,6 +83,10 @@ const DEFAULT_READINESS_PORT: u16 = 15021;
 const DEFAULT_STATS_PORT: u16 = 15020;
 const DEFAULT_SELFTERM_DEADLINE: Duration = Duration::from_secs(5);
 const DEFAULT_CLUSTER_ID: &str = "Kubernetes";
+const DEFAULT_TRUST_DOMAIN: &str = "cluster.local";
+const DEFAULT_CRL_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_LOCAL_CA_CERT_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_SECRET_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
 const ISTIO_META_PREFIX: &str = "ISTIO_META_";
 
@@ -61,6 +99,11 @@ const CERT_SYSTEM: &str = "SYSTEM";
 const PROXY_MODE_DEDICATED: &str = "dedicated";
 const PROXY_MODE_SHARED: &str = "shared";
 
+const KEY_ALGORITHM_ECDSA_P256: &str = "ECDSA_P256";
+const KEY_ALGORITHM_ECDSA_P384: &str = "ECDSA_P384";
+const KEY_ALGORITHM_RSA_2048: &str = "RSA_2048";
+const KEY_ALGORITHM_ED25519: &str = "ED25519";
+
 #[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum RootCert {
     File(PathBuf),
@@ -96,13 +139,24 @@ pub struct Config {
     pub connection_window_size: u32,
     pub frame_size: u32,
 
-    pub socks5_addr: SocketAddr,
     pub admin_addr: SocketAddr,
     pub stats_addr: SocketAddr,
     pub readiness_addr: SocketAddr,
-    pub inbound_addr: SocketAddr,
-    pub inbound_plaintext_addr: SocketAddr,
-    pub outbound_addr: SocketAddr,
+    /// Addresses of the proxy listeners. If unset, the listener is disabled.
+    pub socks5_addr: Option<SocketAddr>,
+    pub inbound_addr: Option<SocketAddr>,
+    pub inbound_plaintext_addr: Option<SocketAddr>,
+    pub outbound_addr: Option<SocketAddr>,
+    /// If set, SOCKS5 is also served on a Unix domain socket at this path. Clients connecting to
+    /// it are attributed to local_ip.
+    pub socks5_path: Option<PathBuf>,
+    /// File mode of the SOCKS5 Unix domain socket, which controls which local users may connect.
+    pub socks5_path_mode: u32,
+    /// If set, a node agent may hand us per-pod listeners over a Unix domain socket at this path.
+    pub pod_listener_socket: Option<PathBuf>,
+    /// If set, listeners are taken over from the ztunnel serving this Unix domain socket, and
+    /// handed over to the next ztunnel that connects to it.
+    pub hot_restart_socket: Option<PathBuf>,
 
     /// The network of the node this ztunnel is running on.
     pub network: String,
@@ -115,7 +169,8 @@ pub struct Config {
     /// The Cluster ID of the cluster that his ztunnel belongs to
     pub cluster_id: String,
 
-    /// CA address to use. If fake_ca is set, this will be None.
+    /// CA address to use. If fake_ca, spiffe_endpoint_socket, workload_cert_dir, or local_ca is
+    /// set, this will be None.
     /// Note: we do not implicitly use None when set to "" since using the fake_ca is not secure.
     pub ca_address: Option<String>,
     /// Root cert for CA TLS verification.
@@ -132,6 +187,24 @@ pub struct Config {
 
     /// If true, then use builtin fake CA with self-signed certificates.
     pub fake_ca: bool,
+    /// If set, certificates are fetched from the SPIFFE Workload API at this Unix socket path,
+    /// rather than from the CA.
+    pub spiffe_endpoint_socket: Option<PathBuf>,
+    /// If set, certificates are loaded from this directory, which holds a subdirectory per
+    /// identity with its cert-chain.pem, key.pem, and optionally root-cert.pem, rather than
+    /// fetched from the CA. Changes to the files are picked up without a restart.
+    pub workload_cert_dir: Option<PathBuf>,
+    /// If set, certificates are issued in-process by a CA loaded from disk.
+    pub local_ca: Option<identity::LocalCaConfig>,
+    /// The type of private key generated for workload certificates.
+    pub key_algorithm: tls::KeyAlgorithm,
+    /// The lifetime requested for workload certificates. The CA may issue shorter ones.
+    pub secret_ttl: Duration,
+    /// When workload certificates are refreshed, and how failed refreshes are retried.
+    pub cert_refresh: identity::RefreshPolicy,
+    /// If set, certificates fetched from the CA are kept on disk, encrypted, and reused after a
+    /// restart. Those not fetched within a few minutes of the restart are forgotten.
+    pub cert_cache: Option<identity::CertCacheConfig>,
     #[serde(skip_serializing)]
     pub auth: identity::AuthSource,
     // How long ztunnel should wait for in-flight requesthandlers to finish processing
@@ -148,6 +221,35 @@ pub struct Config {
 
     // CLI args passed to ztunnel at runtime
     pub proxy_args: String,
+
+    /// External authorization providers for CUSTOM policies, by name.
+    pub ext_authz_providers: HashMap<String, ext_authz::ProviderConfig>,
+
+    /// If set, authorization decisions are written to a dedicated log.
+    pub authz_log: Option<authz_log::Config>,
+
+    /// Trust domains treated as equivalent to the mesh trust domain when verifying peers and
+    /// matching authorization policies. These are used until aliases are received over XDS.
+    pub trust_domain_aliases: identity::TrustDomainAliases,
+    /// If true, trust domain aliases are also received over XDS, replacing the configured ones.
+    pub xds_trust_domain_aliases: bool,
+
+    /// If set, root certificates of federated trust domains are loaded from this directory, one
+    /// `<trust domain>.pem` file per trust domain, and reloaded periodically.
+    pub trust_bundle_dir: Option<PathBuf>,
+    /// If true, root certificates of federated trust domains are also received over XDS.
+    pub xds_trust_bundles: bool,
+    /// If true, namespace and mesh-wide mTLS modes are received over XDS.
+    pub xds_peer_authentication: bool,
+
+    /// Files or http(s) URLs of certificate revocation lists. Peers with a revoked certificate are
+    /// rejected.
+    pub crl_sources: Vec<String>,
+    /// How often certificate revocation lists are refreshed.
+    pub crl_refresh_interval: Duration,
+    /// If true, peers are rejected while a certificate revocation list has not been loaded, or
+    /// is past its next update. Otherwise, the last loaded lists are used.
+    pub crl_fail_closed: bool,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -158,6 +260,8 @@ pub enum Error {
     ProxyConfig(anyhow::Error),
     #[error("invalid uri: {0}")]
     InvalidUri(#[from] Arc<InvalidUri>),
+    #[error("key algorithm {0:?} is not allowed in FIPS mode")]
+    KeyAlgorithmNotAllowed(tls::KeyAlgorithm),
 }
 
 impl From<InvalidUri> for Error {
@@ -191,6 +295,29 @@ fn parse_default<T: FromStr>(env: &str, default: T) -> Result<T, Error> {
     parse(env).map(|v| v.unwrap_or(default))
 }
 
+/// parse_listener parses a listener address. An empty value, or "off", disables the listener.
+fn parse_listener(env: &str, default: SocketAddr) -> Result<Option<SocketAddr>, Error> {
+    match parse::<String>(env)?.as_deref() {
+        None => Ok(Some(default)),
+        Some("") | Some("off") => Ok(None),
+        Some(addr) => addr
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::EnvVar(env.to_string(), addr.to_string())),
+    }
+}
+
+/// parse_file_mode parses an octal file mode, such as "660".
+fn parse_file_mode(env: &str, default: u32) -> Result<u32, Error> {
+    match parse::<String>(env)? {
+        None => Ok(default),
+        Some(mode) => match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+            Ok(m) if m <= 0o777 => Ok(m),
+            _ => Err(Error::EnvVar(env.to_string(), mode)),
+        },
+    }
+}
+
 fn parse_args() -> String {
     let cli_args: Vec<String> = std::env::args().collect();
     cli_args[1..].join(" ")
@@ -209,6 +336,9 @@ fn parse_proxy_config() -> Result<ProxyConfig, Error> {
 }
 
 pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
+    // These borrow the whole proxy config, so are parsed before fields are moved out of it.
+    let local_ca = parse_local_ca(&pc)?;
+    let trust_domain_aliases = trust_domain_aliases(&pc)?;
     let default_istiod_address = if std::env::var(KUBERNETES_SERVICE_HOST).is_ok() {
         "https://istiod.istio-system.svc:15012".to_string()
     } else {
@@ -223,11 +353,21 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
     let cluster_id = parse_default(CLUSTER_ID, DEFAULT_CLUSTER_ID.to_string())?;
 
     let fake_ca = parse_default(FAKE_CA, false)?;
-    let ca_address = validate_uri(empty_to_none(if fake_ca {
-        None
-    } else {
-        Some(parse_default(CA_ADDRESS, default_istiod_address)?)
-    }))?;
+    // The Workload API address is a URI by convention, but only Unix sockets are supported.
+    let spiffe_endpoint_socket = parse::<String>(SPIFFE_ENDPOINT_SOCKET)?
+        .map(|s| PathBuf::from(s.strip_prefix("unix://").unwrap_or(&s)));
+    let workload_cert_dir: Option<PathBuf> = parse(WORKLOAD_CERT_DIR)?;
+    let ca_address = validate_uri(empty_to_none(
+        if fake_ca
+            || spiffe_endpoint_socket.is_some()
+            || workload_cert_dir.is_some()
+            || local_ca.is_some()
+        {
+            None
+        } else {
+            Some(parse_default(CA_ADDRESS, default_istiod_address)?)
+        },
+    ))?;
 
     let xds_root_cert_provider =
         parse_default(XDS_ROOT_CA_ENV, DEFAULT_ROOT_CERT_PROVIDER.to_string())?;
@@ -273,10 +413,26 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
             DEFAULT_READINESS_PORT, // There is no config for this in ProxyConfig currently
         ),
 
-        socks5_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 15080),
-        inbound_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15008),
-        inbound_plaintext_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15006),
-        outbound_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15001),
+        socks5_addr: parse_listener(
+            SOCKS5_ADDR,
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 15080),
+        )?,
+        inbound_addr: parse_listener(
+            INBOUND_ADDR,
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15008),
+        )?,
+        inbound_plaintext_addr: parse_listener(
+            INBOUND_PLAINTEXT_ADDR,
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15006),
+        )?,
+        outbound_addr: parse_listener(
+            OUTBOUND_ADDR,
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15001),
+        )?,
+        socks5_path: parse(SOCKS5_PATH)?,
+        socks5_path_mode: parse_file_mode(SOCKS5_PATH_MODE, 0o660)?,
+        pod_listener_socket: parse(POD_LISTENER_SOCKET)?,
+        hot_restart_socket: parse(HOT_RESTART_SOCKET)?,
 
         network: parse(NETWORK)?.unwrap_or_default(),
         local_node: parse(NODE_NAME)?,
@@ -300,6 +456,15 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
         proxy_metadata: pc.proxy_metadata,
 
         fake_ca,
+        spiffe_endpoint_socket,
+        workload_cert_dir,
+        local_ca,
+        key_algorithm: parse_key_algorithm()?,
+        secret_ttl: parse::<GoDuration>(SECRET_TTL)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_SECRET_TTL),
+        cert_refresh: parse_cert_refresh()?,
+        cert_cache: parse_cert_cache()?,
         auth: identity::AuthSource::Token(
             PathBuf::from(r"./var/run/secrets/tokens/istio-token"),
             cluster_id,
@@ -315,9 +480,196 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
 
         enable_original_source: parse(ENABLE_ORIG_SRC)?,
         proxy_args: parse_args(),
+        ext_authz_providers: ext_authz_providers(&pc.extension_providers)?,
+        authz_log: parse_authz_log()?,
+        trust_domain_aliases,
+        trust_bundle_dir: parse(TRUST_BUNDLE_DIR)?,
+        xds_trust_bundles: parse_default(XDS_TRUST_BUNDLES, false)?,
+        xds_peer_authentication: parse_default(XDS_PEER_AUTHENTICATION, false)?,
+        xds_trust_domain_aliases: parse_default(XDS_TRUST_DOMAIN_ALIASES, false)?,
+        crl_sources: parse::<String>(CRL_SOURCES)?
+            .map(|s| {
+                s.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        crl_refresh_interval: parse::<GoDuration>(CRL_REFRESH_INTERVAL)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_CRL_REFRESH_INTERVAL),
+        crl_fail_closed: parse_default(CRL_FAIL_CLOSED, false)?,
     })
 }
 
+/// trust_domain_aliases combines the trust domain aliases of the mesh config with those in
+/// TRUST_DOMAIN_ALIASES, a comma separated list.
+fn trust_domain_aliases(pc: &ProxyConfig) -> Result<identity::TrustDomainAliases, Error> {
+    let env = parse::<String>(TRUST_DOMAIN_ALIASES)?.unwrap_or_default();
+    let aliases = pc
+        .trust_domain_aliases
+        .iter()
+        .cloned()
+        .chain(env.split(',').map(|td| td.trim().to_string()));
+    Ok(identity::TrustDomainAliases::new(
+        pc.trust_domain.as_deref().unwrap_or(DEFAULT_TRUST_DOMAIN),
+        aliases,
+    ))
+}
+
+/// parse_authz_log parses the authorization log configuration. AUTHZ_LOG is either "stdout" or
+/// the path of a file to append to; if it is unset or empty, the log is disabled.
+fn parse_authz_log() -> Result<Option<authz_log::Config>, Error> {
+    let sink = match parse::<String>(AUTHZ_LOG)?.as_deref() {
+        None | Some("") => return Ok(None),
+        Some("stdout") => authz_log::Sink::Stdout,
+        Some(path) => authz_log::Sink::File(PathBuf::from(path)),
+    };
+    let allow_sample_percent = parse_default(AUTHZ_LOG_ALLOW_SAMPLE_PERCENT, 100u8)?;
+    if allow_sample_percent > 100 {
+        return Err(Error::EnvVar(
+            AUTHZ_LOG_ALLOW_SAMPLE_PERCENT.to_string(),
+            allow_sample_percent.to_string(),
+        ));
+    }
+    Ok(Some(authz_log::Config {
+        sink,
+        denials_only: parse_default(AUTHZ_LOG_DENIALS_ONLY, false)?,
+        allow_sample_percent,
+    }))
+}
+
+fn parse_key_algorithm() -> Result<tls::KeyAlgorithm, Error> {
+    let alg = match parse::<String>(KEY_ALGORITHM)? {
+        None => tls::KeyAlgorithm::default(),
+        Some(alg) => match alg.to_uppercase().as_str() {
+            KEY_ALGORITHM_ECDSA_P256 => tls::KeyAlgorithm::EcdsaP256,
+            KEY_ALGORITHM_ECDSA_P384 => tls::KeyAlgorithm::EcdsaP384,
+            KEY_ALGORITHM_RSA_2048 => tls::KeyAlgorithm::Rsa2048,
+            KEY_ALGORITHM_ED25519 => tls::KeyAlgorithm::Ed25519,
+            _ => return Err(Error::EnvVar(KEY_ALGORITHM.to_string(), alg)),
+        },
+    };
+    if !alg.is_allowed() {
+        return Err(Error::KeyAlgorithmNotAllowed(alg));
+    }
+    Ok(alg)
+}
+
+/// parse_cert_refresh reads the refresh policy. CERT_REFRESH_AT is either a percentage of the
+/// certificate lifetime, such as "80%", or a duration before expiry, such as "4h".
+fn parse_cert_refresh() -> Result<identity::RefreshPolicy, Error> {
+    let default = identity::RefreshPolicy::default();
+    let duration = |env: &str, default: Duration| {
+        Ok::<_, Error>(parse::<GoDuration>(env)?.map(|d| d.0).unwrap_or(default))
+    };
+    let at = match parse::<String>(CERT_REFRESH_AT)? {
+        None => default.at,
+        Some(at) => match at.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<u8>() {
+                Ok(p) if p > 0 && p <= 100 => identity::RefreshAt::Percent(p),
+                _ => return Err(Error::EnvVar(CERT_REFRESH_AT.to_string(), at)),
+            },
+            None => match at.parse::<GoDuration>() {
+                Ok(grace) => identity::RefreshAt::Grace(grace.0),
+                Err(_) => return Err(Error::EnvVar(CERT_REFRESH_AT.to_string(), at)),
+            },
+        },
+    };
+    let initial_backoff = duration(CERT_REFRESH_INITIAL_BACKOFF, default.initial_backoff)?;
+    let max_backoff = duration(CERT_REFRESH_MAX_BACKOFF, default.max_backoff)?;
+    if initial_backoff.is_zero() {
+        return Err(Error::EnvVar(
+            CERT_REFRESH_INITIAL_BACKOFF.to_string(),
+            format!("{initial_backoff:?}"),
+        ));
+    }
+    if max_backoff < initial_backoff {
+        return Err(Error::EnvVar(
+            CERT_REFRESH_MAX_BACKOFF.to_string(),
+            format!("{max_backoff:?}"),
+        ));
+    }
+    Ok(identity::RefreshPolicy {
+        at,
+        jitter: duration(CERT_REFRESH_JITTER, default.jitter)?,
+        initial_backoff,
+        max_backoff,
+    })
+}
+
+fn parse_cert_cache() -> Result<Option<identity::CertCacheConfig>, Error> {
+    let Some(dir) = parse::<PathBuf>(CERT_CACHE_DIR)? else {
+        return Ok(None);
+    };
+    // Keys are never written to disk unencrypted.
+    let key_file = parse(CERT_CACHE_KEY_FILE)?
+        .ok_or_else(|| Error::EnvVar(CERT_CACHE_KEY_FILE.to_string(), String::new()))?;
+    Ok(Some(identity::CertCacheConfig { dir, key_file }))
+}
+
+fn parse_local_ca(pc: &ProxyConfig) -> Result<Option<identity::LocalCaConfig>, Error> {
+    let Some(dir) = parse::<PathBuf>(LOCAL_CA_DIR)? else {
+        return Ok(None);
+    };
+    let server = match parse::<SocketAddr>(LOCAL_CA_SERVER_ADDRESS)? {
+        None => None,
+        Some(addr) => Some(identity::LocalCaServerConfig {
+            addr,
+            hosts: parse_default(LOCAL_CA_SERVER_HOSTS, "istiod.istio-system.svc".to_string())?
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect(),
+            // Serving certificates to anyone who can reach the address is never what we want.
+            token: parse(LOCAL_CA_SERVER_TOKEN)?
+                .ok_or_else(|| Error::EnvVar(LOCAL_CA_SERVER_TOKEN.to_string(), String::new()))?,
+        }),
+    };
+    Ok(Some(identity::LocalCaConfig {
+        dir,
+        trust_domain: pc
+            .trust_domain
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TRUST_DOMAIN.to_string()),
+        cert_validity: parse::<GoDuration>(LOCAL_CA_CERT_VALIDITY)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_LOCAL_CA_CERT_VALIDITY),
+        server,
+    }))
+}
+
+fn ext_authz_providers(
+    providers: &[ExtensionProvider],
+) -> Result<HashMap<String, ext_authz::ProviderConfig>, Error> {
+    providers
+        .iter()
+        .filter_map(|p| p.envoy_ext_authz_grpc.as_ref().map(|g| (&p.name, g)))
+        .map(|(name, g)| {
+            let duration = |d: &Option<String>, default: Duration| match d {
+                None => Ok(default),
+                Some(d) => d.parse::<GoDuration>().map(|d| d.0).map_err(|_| {
+                    Error::ProxyConfig(anyhow!("extension provider {name}: invalid duration {d}"))
+                }),
+            };
+            let address = if g.service.contains("://") {
+                format!("{}:{}", g.service, g.port)
+            } else {
+                format!("http://{}:{}", g.service, g.port)
+            };
+            let cfg = ext_authz::ProviderConfig {
+                address,
+                timeout: duration(&g.timeout, ext_authz::DEFAULT_TIMEOUT)?,
+                fail_open: g.fail_open,
+                cache_ttl: duration(&g.cache_ttl, Duration::ZERO)?,
+            };
+            Ok((name.clone(), cfg))
+        })
+        .collect()
+}
+
 // tries to parse the URI so we can fail early
 fn validate_uri(uri_str: Option<String>) -> Result<Option<String>, Error> {
     let Some(uri_str) = uri_str else {
@@ -334,6 +686,33 @@ fn validate_uri(uri_str: Option<String>) -> Result<Option<String>, Error> {
 #[serde(rename_all = "camelCase")]
 pub struct MeshConfig {
     pub default_config: Option<ProxyConfig>,
+    #[serde(default)]
+    pub extension_providers: Vec<ExtensionProvider>,
+    pub trust_domain: Option<String>,
+    #[serde(default)]
+    pub trust_domain_aliases: Vec<String>,
+}
+
+/// ExtensionProvider is an extension provider from the mesh config. Only external authorization
+/// over gRPC is supported; other kinds of providers are ignored.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionProvider {
+    pub name: String,
+    pub envoy_ext_authz_grpc: Option<EnvoyExtAuthzGrpcProvider>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvoyExtAuthzGrpcProvider {
+    /// Hostname of the provider. A scheme may be given to use TLS, as in https://authz.example.com.
+    pub service: String,
+    pub port: u16,
+    pub timeout: Option<String>,
+    #[serde(default)]
+    pub fail_open: bool,
+    /// How long decisions are cached for. Unlike the other fields, this is not part of Istio's API.
+    pub cache_ttl: Option<String>,
 }
 
 #[derive(serde::Deserialize, Default, Debug, Clone, PartialEq, Eq)]
@@ -345,6 +724,14 @@ pub struct ProxyConfig {
     pub concurrency: Option<u16>,
     pub termination_drain_duration: Option<Duration>,
     pub proxy_metadata: HashMap<String, String>,
+    /// Extension providers come from the mesh config, rather than its defaultConfig.
+    #[serde(skip)]
+    pub extension_providers: Vec<ExtensionProvider>,
+    /// The trust domain and its aliases also come from the mesh config.
+    #[serde(skip)]
+    pub trust_domain: Option<String>,
+    #[serde(skip)]
+    pub trust_domain_aliases: Vec<String>,
 }
 
 impl ProxyConfig {
@@ -363,8 +750,8 @@ impl ProxyConfig {
 
 fn construct_proxy_config(mc_path: &str, pc_env: Option<&str>) -> anyhow::Result<ProxyConfig> {
     let mesh_config = match fs::File::open(mc_path) {
-        Ok(f) => serde_yaml::from_reader(f)
-            .map(|v: MeshConfig| v.default_config)
+        Ok(f) => serde_yaml::from_reader::<_, MeshConfig>(f)
+            .map(Some)
             .map_err(anyhow::Error::new),
         Err(e) => {
             if e.kind() == std::io::ErrorKind::NotFound {
@@ -375,6 +762,7 @@ fn construct_proxy_config(mc_path: &str, pc_env: Option<&str>) -> anyhow::Result
         }
     }
     .map_err(|e| anyhow!("failed parsing mesh config file {}: {}", mc_path, e))?;
+    let mesh_config = mesh_config.unwrap_or_default();
 
     let proxy_config_env = pc_env
         .map(|pc_env| {
@@ -387,10 +775,13 @@ fn construct_proxy_config(mc_path: &str, pc_env: Option<&str>) -> anyhow::Result
         .unwrap_or(Ok(None))
         .map_err(|e| anyhow!("failed parsing proxy config env: {}", e))?;
 
-    let mut pc = [mesh_config, proxy_config_env]
+    let mut pc = [mesh_config.default_config, proxy_config_env]
         .into_iter()
         .flatten()
         .fold(ProxyConfig::default(), |pc, v| pc.merge(v));
+    pc.extension_providers = mesh_config.extension_providers;
+    pc.trust_domain = mesh_config.trust_domain;
+    pc.trust_domain_aliases = mesh_config.trust_domain_aliases;
 
     // only include ISTIO_META_ prefixed fields in this map
     // TODO we could use any other items here for the various env vars for construct_config?
@@ -445,6 +836,24 @@ pub mod tests {
         assert_eq!(cfg.admin_addr.port(), 15099);
         // TODO remove prefix
         assert_eq!(cfg.proxy_metadata["FOO"], "foo");
+        assert_eq!(
+            cfg.ext_authz_providers,
+            HashMap::from([(
+                "entitlements".to_string(),
+                ext_authz::ProviderConfig {
+                    address: "http://entitlements.ns.svc.cluster.local:9000".to_string(),
+                    timeout: Duration::from_millis(500),
+                    fail_open: true,
+                    cache_ttl: Duration::from_secs(30),
+                }
+            )])
+        );
+        assert!(cfg
+            .trust_domain_aliases
+            .equivalent("new.example", "old.example"));
+        assert!(!default_config
+            .trust_domain_aliases
+            .equivalent("cluster.local", "old.example"));
 
         // env only
         let pc_env = Some(
@@ -489,4 +898,24 @@ pub mod tests {
         assert_eq!(cfg.proxy_metadata["NO_PREFIX"], "no-prefix");
         assert_eq!(cfg.proxy_metadata["INCLUDE_THIS"], "foobar-env");
     }
+
+    #[test]
+    fn listener_addresses() {
+        let default: SocketAddr = "127.0.0.1:15080".parse().unwrap();
+        let env = "ZTUNNEL_TEST_LISTENER_ADDR";
+        assert_eq!(parse_listener(env, default).unwrap(), Some(default));
+
+        env::set_var(env, "[::1]:1080");
+        assert_eq!(
+            parse_listener(env, default).unwrap(),
+            Some("[::1]:1080".parse().unwrap())
+        );
+        env::set_var(env, "off");
+        assert_eq!(parse_listener(env, default).unwrap(), None);
+        env::set_var(env, "");
+        assert_eq!(parse_listener(env, default).unwrap(), None);
+        env::set_var(env, "localhost");
+        assert!(parse_listener(env, default).is_err());
+        env::remove_var(env);
+    }
 }
@@ -0,0 +1,344 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! External authorization for CUSTOM authorization policies. Providers are called over the Envoy
+//! ext_authz gRPC API, with the attributes of the connection being authorized.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::config::RootCert;
+use crate::identity::Identity;
+use crate::rbac;
+use crate::state::workload::Workload;
+use crate::tls::{self, TlsGrpcChannel};
+use crate::xds::service::auth::v3::attribute_context::Peer;
+use crate::xds::service::auth::v3::authorization_client::AuthorizationClient;
+use crate::xds::service::auth::v3::{Address, AttributeContext, CheckRequest, SocketAddress};
+
+/// The default time to wait for a provider to answer. This matches Envoy's default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The maximum number of decisions cached per provider.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+/// ProviderConfig configures an external authorization provider.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderConfig {
+    /// URI of the provider's gRPC service, for example http://authz.ns.svc.cluster.local:9000.
+    pub address: String,
+    /// How long to wait for a decision before falling back to fail_open.
+    pub timeout: Duration,
+    /// If true, connections are allowed when the provider fails or does not answer in time.
+    pub fail_open: bool,
+    /// How long decisions are cached for. Zero disables caching.
+    pub cache_ttl: Duration,
+}
+
+/// ExtAuthz holds the clients for all configured providers.
+#[derive(Clone, Default)]
+pub struct ExtAuthz {
+    providers: Arc<HashMap<String, Provider>>,
+}
+
+impl fmt::Debug for ExtAuthz {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtAuthz")
+            .field("providers", &self.providers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+struct Provider {
+    client: AuthorizationClient<TlsGrpcChannel>,
+    cfg: ProviderConfig,
+    cache: Mutex<HashMap<CacheKey, (bool, Instant)>>,
+}
+
+#[derive(Hash, PartialEq, Eq)]
+struct CacheKey {
+    policy: String,
+    src_identity: Option<Identity>,
+    src_ip: IpAddr,
+    dst: SocketAddr,
+}
+
+impl ExtAuthz {
+    pub fn new(providers: &HashMap<String, ProviderConfig>) -> Result<ExtAuthz, tls::Error> {
+        let providers = providers
+            .iter()
+            .map(|(name, cfg)| {
+                let channel = tls::grpc_connector(cfg.address.clone(), RootCert::Default)?;
+                let provider = Provider {
+                    client: AuthorizationClient::new(channel),
+                    cfg: cfg.clone(),
+                    cache: Default::default(),
+                };
+                Ok((name.clone(), provider))
+            })
+            .collect::<Result<_, tls::Error>>()?;
+        Ok(ExtAuthz {
+            providers: Arc::new(providers),
+        })
+    }
+
+    /// check asks the provider whether the connection to dst, matched by policy, is allowed.
+    /// Connections referencing an unknown provider are denied.
+    pub async fn check(
+        &self,
+        provider: &str,
+        policy: &str,
+        conn: &rbac::Connection,
+        dst: &Workload,
+    ) -> bool {
+        let Some(p) = self.providers.get(provider) else {
+            warn!(provider, policy, "unknown ext_authz provider, denying");
+            return false;
+        };
+        let key = CacheKey {
+            policy: policy.to_string(),
+            src_identity: conn.src_identity.clone(),
+            src_ip: conn.src_ip,
+            dst: conn.dst,
+        };
+        if let Some(allowed) = p.cached(&key) {
+            debug!(provider, policy, allowed, "cached ext_authz decision");
+            return allowed;
+        }
+
+        let req = check_request(policy, conn, dst);
+        let mut client = p.client.clone();
+        let res = match tokio::time::timeout(p.cfg.timeout, client.check(req)).await {
+            Ok(Ok(res)) => res.into_inner(),
+            Ok(Err(status)) => {
+                warn!(provider, policy, "ext_authz check failed: {status}");
+                return p.cfg.fail_open;
+            }
+            Err(_) => {
+                warn!(provider, policy, "ext_authz check timed out");
+                return p.cfg.fail_open;
+            }
+        };
+        // Like Envoy, a missing status is treated as OK.
+        let allowed = res.status.map_or(true, |s| s.code == 0);
+        debug!(provider, policy, allowed, "ext_authz decision");
+        p.cache(key, allowed);
+        allowed
+    }
+}
+
+impl Provider {
+    fn cached(&self, key: &CacheKey) -> Option<bool> {
+        let cache = self.cache.lock().unwrap();
+        match cache.get(key) {
+            Some((allowed, expiry)) if *expiry > Instant::now() => Some(*allowed),
+            _ => None,
+        }
+    }
+
+    fn cache(&self, key: CacheKey, allowed: bool) {
+        if self.cfg.cache_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, (_, expiry)| *expiry > now);
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (allowed, now + self.cfg.cache_ttl));
+    }
+}
+
+fn peer(ip: IpAddr, port: u16, principal: Option<String>) -> Peer {
+    Peer {
+        address: Some(Address {
+            socket_address: Some(SocketAddress {
+                address: ip.to_string(),
+                port_value: port as u32,
+            }),
+        }),
+        principal: principal.unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+fn check_request(policy: &str, conn: &rbac::Connection, dst: &Workload) -> CheckRequest {
+    let src_namespace = conn.src_identity.as_ref().map(|i| match i {
+        Identity::Spiffe { namespace, .. } => namespace.to_owned(),
+    });
+    let mut context_extensions = HashMap::from([
+        ("policy".to_string(), policy.to_string()),
+        ("destination.namespace".to_string(), dst.namespace.clone()),
+    ]);
+    if let Some(ns) = src_namespace {
+        context_extensions.insert("source.namespace".to_string(), ns);
+    }
+    CheckRequest {
+        attributes: Some(AttributeContext {
+            // Only the source IP is known, not its port.
+            source: Some(peer(
+                conn.src_ip,
+                0,
+                conn.src_identity.as_ref().map(Identity::to_string),
+            )),
+            destination: Some(Peer {
+                service: dst.canonical_name.clone(),
+                ..peer(
+                    conn.dst.ip(),
+                    conn.dst.port(),
+                    Some(dst.identity().to_string()),
+                )
+            }),
+            context_extensions,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::test_default_workload;
+    use crate::xds::service::auth::v3::authorization_server::{Authorization, AuthorizationServer};
+    use crate::xds::service::auth::v3::{CheckResponse, Status};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// TestProvider allows connections from principals ending in "allowed".
+    #[derive(Clone, Default)]
+    struct TestProvider {
+        calls: Arc<AtomicUsize>,
+        delay: Duration,
+    }
+
+    #[async_trait::async_trait]
+    impl Authorization for TestProvider {
+        async fn check(
+            &self,
+            request: tonic::Request<CheckRequest>,
+        ) -> Result<tonic::Response<CheckResponse>, tonic::Status> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            let attrs = request.into_inner().attributes.unwrap();
+            assert_eq!(attrs.context_extensions["destination.namespace"], "ns");
+            let code = if attrs.source.unwrap().principal.ends_with("allowed") {
+                0
+            } else {
+                7 // PERMISSION_DENIED
+            };
+            Ok(tonic::Response::new(CheckResponse {
+                status: Some(Status {
+                    code,
+                    message: String::new(),
+                }),
+            }))
+        }
+    }
+
+    async fn spawn(provider: TestProvider) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let srv = AuthorizationServer::new(provider);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let srv = srv.clone();
+                tokio::spawn(crate::hyper_util::http2_server().serve_connection(
+                    socket,
+                    tower_hyper_http_body_compat::TowerService03HttpServiceAsHyper1HttpService::new(
+                        srv,
+                    ),
+                ));
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    fn ext_authz(address: String, timeout: Duration, fail_open: bool) -> ExtAuthz {
+        let cfg = ProviderConfig {
+            address,
+            timeout,
+            fail_open,
+            cache_ttl: Duration::from_secs(60),
+        };
+        ExtAuthz::new(&HashMap::from([("authz".to_string(), cfg)])).unwrap()
+    }
+
+    fn conn(service_account: &str) -> rbac::Connection {
+        rbac::Connection {
+            src_identity: Some(Identity::Spiffe {
+                trust_domain: "cluster.local".to_string(),
+                namespace: "ns".to_string(),
+                service_account: service_account.to_string(),
+            }),
+            src_ip: "127.0.0.1".parse().unwrap(),
+            dst_network: "".to_string(),
+            dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
+        }
+    }
+
+    fn dst() -> Workload {
+        Workload {
+            namespace: "ns".to_string(),
+            ..test_default_workload()
+        }
+    }
+
+    #[tokio::test]
+    async fn check_and_cache() {
+        let provider = TestProvider::default();
+        let calls = provider.calls.clone();
+        let authz = ext_authz(spawn(provider).await, Duration::from_secs(5), false);
+
+        assert!(authz.check("authz", "ns/p", &conn("allowed"), &dst()).await);
+        assert!(!authz.check("authz", "ns/p", &conn("denied"), &dst()).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        // Both decisions are cached.
+        assert!(authz.check("authz", "ns/p", &conn("allowed"), &dst()).await);
+        assert!(!authz.check("authz", "ns/p", &conn("denied"), &dst()).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        assert!(
+            !authz
+                .check("unknown", "ns/p", &conn("allowed"), &dst())
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let provider = TestProvider {
+            delay: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let address = spawn(provider).await;
+
+        let closed = ext_authz(address.clone(), Duration::from_millis(10), false);
+        assert!(
+            !closed
+                .check("authz", "ns/p", &conn("allowed"), &dst())
+                .await
+        );
+        let open = ext_authz(address, Duration::from_millis(10), true);
+        assert!(open.check("authz", "ns/p", &conn("denied"), &dst()).await);
+    }
+}
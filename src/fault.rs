@@ -0,0 +1,268 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::state::workload::Workload;
+
+/// Fault describes a fault to inject into proxied connections, for chaos testing.
+/// Unset match fields match all connections.
+#[derive(Debug, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Fault {
+    pub name: String,
+    /// Matches the uid or name of the source workload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_workload: Option<String>,
+    /// Matches the uid or name of the destination workload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_workload: Option<String>,
+    /// Matches the service VIP the client connected to. This is only known on the outbound path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_vip: Option<IpAddr>,
+    /// Matches the port the connection was addressed to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_port: Option<u16>,
+    /// Percentage of matching connections the fault is applied to, from 0 to 100.
+    #[serde(default = "default_percentage")]
+    pub percentage: u32,
+    pub action: FaultAction,
+    /// For Delay, how long to wait before connecting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u64>,
+    /// For Reset, close the connection once this many bytes have been transferred.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset_after_bytes: Option<u64>,
+    /// For Reset, close the connection once it has been open this long.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset_after_ms: Option<u64>,
+}
+
+fn default_percentage() -> u32 {
+    100
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub enum FaultAction {
+    /// Delay establishing the connection.
+    Delay,
+    /// Reject the connection.
+    Reject,
+    /// Close an established connection after some bytes or time.
+    Reset,
+    /// Accept the connection, but never connect upstream and discard all data.
+    Blackhole,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum FaultError {
+    #[error("fault name is required")]
+    MissingName,
+    #[error("fault {0}: percentage must be between 0 and 100, got {1}")]
+    InvalidPercentage(String, u32),
+    #[error("fault {0}: {1:?} requires {2}")]
+    MissingParameter(String, FaultAction, &'static str),
+}
+
+/// Target describes the connection a fault is being matched against.
+pub struct Target<'a> {
+    pub source: Option<&'a Workload>,
+    pub destination: Option<&'a Workload>,
+    pub destination_vip: Option<IpAddr>,
+    pub destination_port: u16,
+}
+
+fn matches_workload(want: &Option<String>, got: Option<&Workload>) -> bool {
+    match want {
+        None => true,
+        Some(want) => got.map_or(false, |w| &w.uid == want || &w.name == want),
+    }
+}
+
+impl Fault {
+    pub fn validate(&self) -> Result<(), FaultError> {
+        if self.name.is_empty() {
+            return Err(FaultError::MissingName);
+        }
+        if self.percentage > 100 {
+            return Err(FaultError::InvalidPercentage(
+                self.name.clone(),
+                self.percentage,
+            ));
+        }
+        match self.action {
+            FaultAction::Delay if self.delay_ms.is_none() => Err(FaultError::MissingParameter(
+                self.name.clone(),
+                self.action,
+                "delayMs",
+            )),
+            FaultAction::Reset
+                if self.reset_after_bytes.is_none() && self.reset_after_ms.is_none() =>
+            {
+                Err(FaultError::MissingParameter(
+                    self.name.clone(),
+                    self.action,
+                    "resetAfterBytes or resetAfterMs",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn matches(&self, t: &Target) -> bool {
+        if !matches_workload(&self.source_workload, t.source) {
+            return false;
+        }
+        if !matches_workload(&self.destination_workload, t.destination) {
+            return false;
+        }
+        if self.destination_vip.is_some() && self.destination_vip != t.destination_vip {
+            return false;
+        }
+        if let Some(port) = self.destination_port {
+            if port != t.destination_port {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay_ms.unwrap_or_default())
+    }
+
+    pub fn reset_after(&self) -> Option<Duration> {
+        self.reset_after_ms.map(Duration::from_millis)
+    }
+}
+
+/// FaultStore holds the configured faults, keyed by name.
+#[derive(serde::Serialize, Default, Debug)]
+pub struct FaultStore {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    faults: BTreeMap<String, Fault>,
+}
+
+impl FaultStore {
+    /// insert adds a fault, replacing any existing fault with the same name.
+    pub fn insert(&mut self, fault: Fault) -> Result<(), FaultError> {
+        fault.validate()?;
+        self.faults.insert(fault.name.clone(), fault);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Fault> {
+        self.faults.remove(name)
+    }
+
+    pub fn list(&self) -> Vec<Fault> {
+        self.faults.values().cloned().collect()
+    }
+
+    /// find returns the first fault, in name order, that matches the target, if its percentage
+    /// selects this connection. Later matching faults are never tried, so overlapping faults do
+    /// not compound their percentages.
+    pub fn find(&self, t: &Target) -> Option<Fault> {
+        let fault = self.faults.values().find(|f| f.matches(t))?;
+        if rand::thread_rng().gen_range(0..100) < fault.percentage {
+            Some(fault.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fault(yaml: &str) -> Fault {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn validate() {
+        assert!(fault("{name: a, action: Delay, delayMs: 10}")
+            .validate()
+            .is_ok());
+        assert!(fault("{name: a, action: Delay}").validate().is_err());
+        assert!(fault("{name: a, action: Reset}").validate().is_err());
+        assert!(fault("{name: a, action: Reset, resetAfterBytes: 10}")
+            .validate()
+            .is_ok());
+        assert!(fault("{name: a, action: Reject, percentage: 101}")
+            .validate()
+            .is_err());
+        assert!(fault("{name: '', action: Blackhole}").validate().is_err());
+    }
+
+    #[test]
+    fn find() {
+        let src = Workload {
+            uid: "uid-src".to_string(),
+            name: "src".to_string(),
+            ..crate::test_helpers::test_default_workload()
+        };
+        let dst = Workload {
+            uid: "uid-dst".to_string(),
+            name: "dst".to_string(),
+            ..crate::test_helpers::test_default_workload()
+        };
+        let target = |port: u16| Target {
+            source: Some(&src),
+            destination: Some(&dst),
+            destination_vip: Some("10.0.0.1".parse().unwrap()),
+            destination_port: port,
+        };
+
+        let mut store = FaultStore::default();
+        assert_eq!(store.find(&target(80)), None);
+
+        store
+            .insert(fault(
+                "{name: b, action: Reject, destinationWorkload: dst, destinationPort: 80}",
+            ))
+            .unwrap();
+        store
+            .insert(fault(
+                "{name: a, action: Blackhole, sourceWorkload: uid-src, destinationVip: 10.0.0.2}",
+            ))
+            .unwrap();
+        store
+            .insert(fault("{name: c, action: Reject, percentage: 0}"))
+            .unwrap();
+        assert_eq!(store.find(&target(80)).map(|f| f.name), Some("b".into()));
+        assert_eq!(store.find(&target(81)), None);
+
+        store
+            .insert(fault(
+                "{name: a, action: Blackhole, sourceWorkload: uid-src, destinationVip: 10.0.0.1}",
+            ))
+            .unwrap();
+        assert_eq!(store.find(&target(81)).map(|f| f.name), Some("a".into()));
+
+        assert!(store.remove("a").is_some());
+        assert_eq!(store.list().len(), 2);
+
+        // Only the first matching fault is rolled; a miss does not fall through to the next one.
+        store
+            .insert(fault("{name: a, action: Reject, destinationPort: 80, percentage: 0}"))
+            .unwrap();
+        assert_eq!(store.find(&target(80)), None);
+    }
+}
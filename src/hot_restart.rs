@@ -0,0 +1,398 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hot restart hands the listening sockets of a running ztunnel to a new ztunnel process, so an
+//! upgrade never leaves a window where connections are refused.
+//!
+//! The handoff happens over a Unix domain socket, using the framing from [crate::proxy::inpod]:
+//! 1. The new process connects and sends `TakeOver`.
+//! 2. The old process replies with `Listeners`, passing all of its listening sockets.
+//! 3. The new process sets up its servers on those sockets, without accepting yet, and sends `Ready`.
+//! 4. The old process stops accepting, waits for its accept loops to exit, and replies `Released`.
+//!    It then drains its connections.
+//! 5. The new process starts accepting, and serves the Unix socket for the next upgrade.
+//!
+//! Connections arriving during the handoff wait in the kernel's accept queue of the shared socket.
+//!
+//! The pod listener and SOCKS5 Unix sockets are handed over as well. Node agents connected to the
+//! old process keep talking to it until it exits, and must then reconnect and add their pods again.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use drain::Watch;
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tracing::{error, info, warn};
+
+use crate::proxy::inpod::{recv_message, send_message};
+use crate::signal::ShutdownTrigger;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Message {
+    TakeOver,
+    Listeners { names: Vec<String> },
+    Ready,
+    Released,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to bind to unix socket {0:?}: {1}")]
+    Bind(std::path::PathBuf, io::Error),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+}
+
+/// Listeners tracks the listening sockets of this process by name, so they can be handed over on
+/// the next restart. Sockets inherited from a previous process, or through socket activation, are
+/// used in place of binding. The names are inbound, inbound_plaintext, outbound, socks5,
+/// socks5_unix, pods, admin, stats, readiness and local_ca.
+#[derive(Clone, Default)]
+pub struct Listeners(Arc<Mutex<ListenerSet>>);
+
+struct ListenerSet {
+    /// Sockets handed to us that have not been claimed yet.
+    inherited: HashMap<String, OwnedFd>,
+    /// Sockets we are serving on.
+    active: Vec<(String, OwnedFd)>,
+    /// Accept loops hold a clone of the watch, so we can stop them and wait for them to exit
+    /// once the sockets are handed over. Taken when accepting stops.
+    accepting: Option<(drain::Signal, Watch)>,
+}
+
+impl Default for ListenerSet {
+    fn default() -> Self {
+        ListenerSet {
+            inherited: HashMap::new(),
+            active: Vec::new(),
+            accepting: Some(drain::channel()),
+        }
+    }
+}
+
+impl Listeners {
+    pub fn inherited(fds: impl IntoIterator<Item = (String, OwnedFd)>) -> Listeners {
+        Listeners(Arc::new(Mutex::new(ListenerSet {
+            inherited: fds.into_iter().collect(),
+            ..Default::default()
+        })))
+    }
+
+    /// inherit adds sockets passed to us by other means, such as systemd socket activation.
+    /// Sockets taken over from a previous ztunnel take precedence.
+    pub fn inherit(&self, fds: impl IntoIterator<Item = (String, OwnedFd)>) {
+        let mut set = self.0.lock().unwrap();
+        for (name, fd) in fds {
+            set.inherited.entry(name).or_insert(fd);
+        }
+    }
+
+    /// bind returns the inherited listener with this name, if any, or binds a new one to addr.
+    pub async fn bind(&self, name: &str, addr: SocketAddr) -> io::Result<TcpListener> {
+        let inherited = self.0.lock().unwrap().inherited.remove(name);
+        let listener = match inherited {
+            Some(fd) => {
+                check_inherited(name, &fd, &[libc::AF_INET, libc::AF_INET6])?;
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                let listener = TcpListener::from_std(listener)?;
+                info!(name, address=%listener.local_addr()?, "using inherited listener");
+                listener
+            }
+            None => TcpListener::bind(addr).await?,
+        };
+        let fd = listener.as_fd().try_clone_to_owned()?;
+        self.0.lock().unwrap().active.push((name.to_string(), fd));
+        Ok(listener)
+    }
+
+    /// bind_unix returns the inherited Unix listener with this name, if any, or binds a new one to
+    /// path, replacing a stale socket left behind by a previous process.
+    pub fn bind_unix(&self, name: &str, path: &Path) -> io::Result<UnixListener> {
+        let inherited = self.0.lock().unwrap().inherited.remove(name);
+        let listener = match inherited {
+            Some(fd) => {
+                check_inherited(name, &fd, &[libc::AF_UNIX])?;
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                info!(name, address=%path.display(), "using inherited listener");
+                UnixListener::from_std(listener)?
+            }
+            None => {
+                if let Err(e) = std::fs::remove_file(path) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e);
+                    }
+                }
+                UnixListener::bind(path)?
+            }
+        };
+        let fd = listener.as_fd().try_clone_to_owned()?;
+        self.0.lock().unwrap().active.push((name.to_string(), fd));
+        Ok(listener)
+    }
+
+    /// accept runs an accept loop until it completes, or until the listeners are handed over to
+    /// a new process. Connections it accepted are not affected.
+    pub fn accept<F: Future<Output = ()>>(&self, accept: F) -> impl Future<Output = ()> {
+        let watch = self
+            .0
+            .lock()
+            .unwrap()
+            .accepting
+            .as_ref()
+            .map(|(_, watch)| watch.clone());
+        async move {
+            // Without a watch, the listeners were already handed over.
+            let Some(watch) = watch else {
+                return;
+            };
+            tokio::select! {
+                _ = accept => {}
+                _ = watch.signaled() => {}
+            }
+        }
+    }
+
+    /// stop_accepting stops all accept loops, and waits for them to exit.
+    async fn stop_accepting(&self) {
+        let accepting = self.0.lock().unwrap().accepting.take();
+        if let Some((signal, watch)) = accepting {
+            drop(watch);
+            signal.drain().await;
+        }
+    }
+
+    /// close_unclaimed closes inherited sockets that were not used, for example because the
+    /// listener was removed in the new version.
+    pub fn close_unclaimed(&self) {
+        for (name, _) in self.0.lock().unwrap().inherited.drain() {
+            warn!(name, "closing unused inherited listener");
+        }
+    }
+
+    fn active(&self) -> io::Result<Vec<(String, OwnedFd)>> {
+        self.0
+            .lock()
+            .unwrap()
+            .active
+            .iter()
+            .map(|(name, fd)| Ok((name.clone(), fd.try_clone()?)))
+            .collect()
+    }
+}
+
+fn check_inherited(name: &str, fd: &OwnedFd, families: &[libc::c_int]) -> io::Result<()> {
+    crate::socket::check_listener(fd, families).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("inherited listener {name} is invalid: {e}"),
+        )
+    })
+}
+
+/// Takeover is the new process' side of a handoff in progress.
+pub struct Takeover {
+    stream: UnixStream,
+}
+
+impl Takeover {
+    /// connect asks the ztunnel serving path to hand over its listeners. None is returned if
+    /// there is no running ztunnel to take over from.
+    pub async fn connect(path: &Path) -> Result<Option<(Takeover, Listeners)>, Error> {
+        let mut stream = match UnixStream::connect(path).await {
+            Ok(stream) => stream,
+            Err(e)
+                if e.kind() == io::ErrorKind::NotFound
+                    || e.kind() == io::ErrorKind::ConnectionRefused =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        send_message(&mut stream, &Message::TakeOver, &[]).await?;
+        let (names, fds) = match recv_message(&mut stream).await? {
+            Some((Message::Listeners { names }, fds)) if names.len() == fds.len() => (names, fds),
+            msg => return Err(Error::Protocol(format!("expected listeners, got {msg:?}"))),
+        };
+        info!(listeners=?names, "taking over listeners");
+        Ok(Some((
+            Takeover { stream },
+            Listeners::inherited(names.into_iter().zip(fds)),
+        )))
+    }
+
+    /// complete tells the old process we are ready to serve, and waits for it to stop accepting.
+    pub async fn complete(mut self) -> Result<(), Error> {
+        send_message(&mut self.stream, &Message::Ready, &[]).await?;
+        match recv_message(&mut self.stream).await? {
+            Some((Message::Released, _)) => {
+                info!("took over listeners");
+                Ok(())
+            }
+            msg => Err(Error::Protocol(format!("expected released, got {msg:?}"))),
+        }
+    }
+}
+
+/// Server hands our listeners over to the next process that asks for them.
+pub struct Server {
+    listener: UnixListener,
+    listeners: Listeners,
+    shutdown: ShutdownTrigger,
+    handed_off: Arc<AtomicBool>,
+    drain: Watch,
+}
+
+impl Server {
+    pub fn bind(
+        path: &Path,
+        listeners: Listeners,
+        shutdown: ShutdownTrigger,
+        handed_off: Arc<AtomicBool>,
+        drain: Watch,
+    ) -> Result<Server, Error> {
+        // The previous process, if any, has already handed over by now.
+        if let Err(e) = std::fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(Error::Bind(path.to_owned(), e));
+            }
+        }
+        let listener = UnixListener::bind(path).map_err(|e| Error::Bind(path.to_owned(), e))?;
+        info!(
+            address=%path.display(),
+            component="hot restart",
+            "listener established",
+        );
+        Ok(Server {
+            listener,
+            listeners,
+            shutdown,
+            handed_off,
+            drain,
+        })
+    }
+
+    pub async fn run(self) {
+        let accept = async {
+            loop {
+                let stream = match self.listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        if crate::proxy::util::is_runtime_shutdown(&e) {
+                            return;
+                        }
+                        error!("failed to accept hot restart connection: {}", e);
+                        continue;
+                    }
+                };
+                match self.hand_over(stream).await {
+                    Ok(()) => return,
+                    // The new process went away; keep serving until another one shows up.
+                    Err(e) => warn!("hot restart handoff failed: {}", e),
+                }
+            }
+        };
+        tokio::select! {
+            res = accept => { res }
+            _ = self.drain.clone().signaled() => {}
+        }
+    }
+
+    async fn hand_over(&self, mut stream: UnixStream) -> Result<(), Error> {
+        match recv_message(&mut stream).await? {
+            Some((Message::TakeOver, _)) => {}
+            msg => return Err(Error::Protocol(format!("expected takeover, got {msg:?}"))),
+        }
+        let (names, fds): (Vec<_>, Vec<_>) = self.listeners.active()?.into_iter().unzip();
+        let raw: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        info!(listeners=?names, "handing over listeners");
+        send_message(&mut stream, &Message::Listeners { names }, &raw).await?;
+
+        match recv_message(&mut stream).await? {
+            Some((Message::Ready, _)) => {}
+            msg => return Err(Error::Protocol(format!("expected ready, got {msg:?}"))),
+        }
+        // From here on the new process owns the listeners. Only let it accept once we no longer
+        // do, then drain our connections.
+        info!("new process is ready, shutting down");
+        self.handed_off.store(true, Ordering::SeqCst);
+        self.listeners.stop_accepting().await;
+        let released = send_message(&mut stream, &Message::Released, &[]).await;
+        self.shutdown.shutdown_now().await;
+        Ok(released?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::Shutdown;
+
+    #[tokio::test]
+    async fn hand_over() {
+        let dir = crate::test_helpers::temp_dir();
+        let path = dir.path().join("hot-restart");
+        assert!(Takeover::connect(&path).await.unwrap().is_none());
+
+        let old = Listeners::default();
+        let admin = old
+            .bind("admin", "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let admin_addr = admin.local_addr().unwrap();
+        let accepting = tokio::spawn(old.accept(async move {
+            loop {
+                let _ = admin.accept().await;
+            }
+        }));
+        let pods_path = path.with_extension("pods");
+        old.bind_unix("pods", &pods_path).unwrap();
+        let shutdown = Shutdown::new();
+        let handed_off = Arc::new(AtomicBool::new(false));
+        let (_drain_tx, drain_rx) = drain::channel();
+        let server =
+            Server::bind(&path, old, shutdown.trigger(), handed_off.clone(), drain_rx).unwrap();
+        let server = tokio::spawn(server.run());
+
+        let (takeover, new) = Takeover::connect(&path).await.unwrap().unwrap();
+        let inherited = new
+            .bind("admin", "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(inherited.local_addr().unwrap(), admin_addr);
+        // The Unix socket is inherited rather than replaced.
+        let pods = new.bind_unix("pods", &pods_path).unwrap();
+        let _agent = UnixStream::connect(&pods_path).await.unwrap();
+        pods.accept().await.unwrap();
+        assert!(!handed_off.load(Ordering::SeqCst));
+        assert!(!accepting.is_finished());
+
+        takeover.complete().await.unwrap();
+        assert!(handed_off.load(Ordering::SeqCst));
+        // The old accept loop exited before the handoff completed.
+        assert!(accepting.is_finished());
+        shutdown.wait().await;
+        server.await.unwrap();
+    }
+}
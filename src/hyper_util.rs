@@ -33,6 +33,7 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio_stream::Stream;
 use tracing::{debug, info, warn};
 
+use crate::hot_restart::Listeners;
 use crate::tls::{BoringTlsAcceptor, CertProvider};
 
 pub fn tls_server<T: CertProvider + Clone + 'static>(
@@ -175,16 +176,24 @@ pub fn plaintext_response(code: hyper::StatusCode, body: String) -> Response<Ful
 pub struct Server<S> {
     name: String,
     bind: TcpListener,
+    listeners: Listeners,
     drain_rx: Watch,
     state: Arc<S>,
 }
 
 impl<S> Server<S> {
-    pub async fn bind(name: &str, addr: SocketAddr, drain_rx: Watch, s: S) -> anyhow::Result<Self> {
-        let bind = TcpListener::bind(&addr).await?;
+    pub async fn bind(
+        name: &str,
+        addr: SocketAddr,
+        listeners: &Listeners,
+        drain_rx: Watch,
+        s: S,
+    ) -> anyhow::Result<Self> {
+        let bind = listeners.bind(name, addr).await?;
         Ok(Server {
             name: name.to_string(),
             bind,
+            listeners: listeners.clone(),
             drain_rx,
             state: Arc::new(s),
         })
@@ -213,7 +222,8 @@ impl<S> Server<S> {
             component=self.name,
             "listener established",
         );
-        tokio::spawn(async move {
+        let listeners = self.listeners.clone();
+        tokio::spawn(listeners.accept(async move {
             let stream = tokio_stream::wrappers::TcpListenerStream::new(self.bind);
             let mut stream = stream.take_until(Box::pin(drain_stream.signaled()));
             while let Some(Ok(socket)) = stream.next().await {
@@ -252,6 +262,6 @@ impl<S> Server<S> {
                 component=self.name,
                 "listener drained",
             );
-        });
+        }));
     }
 }
@@ -24,6 +24,21 @@ pub use manager::*;
 mod auth;
 pub use auth::*;
 
+mod trust_domain;
+pub use trust_domain::*;
+
+mod workload_api;
+pub use workload_api::*;
+
+mod file_client;
+pub use file_client::*;
+
+mod local_ca;
+pub use local_ca::*;
+
+mod cert_cache;
+pub use cert_cache::*;
+
 pub mod mock {
     pub use super::caclient::mock::CaClient;
     pub use super::manager::mock::{
@@ -47,4 +62,16 @@ pub enum Error {
     Spiffe(String),
     #[error("the identity is no longer needed")]
     Forgotten,
+    #[error("workload api: {0}")]
+    WorkloadApi(String),
+    #[error("workload api did not provide an SVID for: {0}")]
+    MissingSvid(Identity),
+    #[error("no valid certificate file for: {0}")]
+    MissingCertificateFile(Identity),
+    #[error("invalid CSR: {0}")]
+    InvalidCsr(String),
+    #[error("not allowed to issue a certificate for: {0}")]
+    UnauthorizedIdentity(Identity),
+    #[error("certificate cache: {0}")]
+    CertCache(String),
 }
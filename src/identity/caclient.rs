@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::collections::BTreeMap;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use prost_types::value::Kind;
@@ -24,7 +25,7 @@ use tracing::{instrument, warn};
 use crate::config::RootCert;
 use crate::identity::auth::AuthSource;
 use crate::identity::manager::Identity;
-use crate::identity::Error;
+use crate::identity::{Error, TrustDomainAliases};
 use crate::tls::{self, SanChecker, TlsGrpcChannel};
 use crate::xds::istio::ca::istio_certificate_service_client::IstioCertificateServiceClient;
 use crate::xds::istio::ca::IstioCertificateRequest;
@@ -32,6 +33,9 @@ use crate::xds::istio::ca::IstioCertificateRequest;
 pub struct CaClient {
     pub client: IstioCertificateServiceClient<InterceptedService<TlsGrpcChannel, AuthSource>>,
     pub enable_impersonated_identity: bool,
+    pub key_algorithm: tls::KeyAlgorithm,
+    // The lifetime requested for issued certificates.
+    pub validity: Duration,
 }
 
 impl CaClient {
@@ -40,6 +44,8 @@ impl CaClient {
         root_cert: RootCert,
         auth: AuthSource,
         enable_impersonated_identity: bool,
+        key_algorithm: tls::KeyAlgorithm,
+        validity: Duration,
     ) -> Result<CaClient, Error> {
         let svc = tls::grpc_connector(address, root_cert)?;
         // let client = IstioCertificateServiceClient::new(svc);
@@ -49,6 +55,8 @@ impl CaClient {
         Ok(CaClient {
             client,
             enable_impersonated_identity,
+            key_algorithm,
+            validity,
         })
     }
 }
@@ -58,6 +66,7 @@ impl CaClient {
     async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
         let cs = tls::CsrOptions {
             san: id.to_string(),
+            key_algorithm: self.key_algorithm,
         }
         .generate()?;
         let csr: Vec<u8> = cs.csr;
@@ -66,7 +75,7 @@ impl CaClient {
         let csr = std::str::from_utf8(&csr).map_err(Error::Utf8)?.to_string();
         let req = IstioCertificateRequest {
             csr,
-            validity_duration: 60 * 60 * 24, // 24 hours
+            validity_duration: self.validity.as_secs() as i64,
             metadata: {
                 if self.enable_impersonated_identity {
                     Some(Struct {
@@ -102,7 +111,7 @@ impl CaClient {
         let certs = tls::cert_from(&pkey, leaf, chain);
         if self.enable_impersonated_identity {
             certs
-                .verify_san(id)
+                .verify_san(id, &TrustDomainAliases::default())
                 .map_err(|_| Error::SanError(id.to_owned()))?;
         }
         Ok(certs)
@@ -143,6 +152,9 @@ pub mod mock {
         // returning. This is helpful to let tests that pause tokio time get more control over code
         // execution.
         pub fetch_latency: Duration,
+        // If set, each certificate gets a new key of this type. Otherwise all certificates share
+        // a test key, which is much cheaper.
+        pub key_algorithm: Option<tls::KeyAlgorithm>,
     }
 
     impl Default for ClientConfig {
@@ -151,6 +163,7 @@ pub mod mock {
                 fetch_latency: Duration::ZERO,
                 cert_lifetime: Duration::from_secs(10),
                 time_conv: crate::time::Converter::new(),
+                key_algorithm: None,
             }
         }
     }
@@ -210,9 +223,17 @@ pub mod mock {
             let not_after = not_before + self.cfg.cert_lifetime;
 
             let mut state = self.state.write().await;
-            let certs = state
-                .gen
-                .new_certs(&id.to_owned().into(), not_before, not_after);
+            let certs = match self.cfg.key_algorithm {
+                None => state
+                    .gen
+                    .new_certs(&id.to_owned().into(), not_before, not_after),
+                Some(alg) => state.gen.new_certs_with_key(
+                    &id.to_owned().into(),
+                    not_before,
+                    not_after,
+                    alg.generate()?,
+                ),
+            };
             state.fetches.push(id.to_owned());
             Ok(certs)
         }
@@ -0,0 +1,415 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ffi::OsStr;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::Write as _;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use boring::error::ErrorStack;
+use boring::hash::MessageDigest;
+use boring::pkey::PKey;
+use boring::sign::Signer;
+use boring::symm::{decrypt_aead, encrypt_aead, Cipher};
+use boring::x509::X509;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::identity::{Error, Identity, TrustDomainAliases};
+use crate::tls::{self, SanChecker};
+
+const ENTRY_EXTENSION: &str = "cert";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// HKDF salt and info the entry key is derived with, so the key file may be shared with other uses.
+const KEY_SALT: &[u8] = b"ztunnel";
+const KEY_INFO: &[u8] = b"ztunnel certificate cache v1";
+
+/// CertCacheConfig configures the on-disk certificate cache.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CertCacheConfig {
+    /// Directory the certificates are stored in. It is created if missing, and made readable by
+    /// ztunnel only.
+    pub dir: PathBuf,
+    /// File with the secret the cached certificates and keys are encrypted with.
+    pub key_file: PathBuf,
+}
+
+/// CertCache keeps workload certificates on disk, so a restarted ztunnel can serve them right away
+/// instead of requesting a certificate for every identity at once.
+///
+/// Each entry holds the identity, private key, and chain of one certificate, encrypted with
+/// AES-256-GCM under a key derived from the key file with HKDF-SHA256. Entries are written by a
+/// background task, in the order they were stored or removed.
+pub struct CertCache {
+    entries: Arc<Entries>,
+    writes: mpsc::UnboundedSender<Write>,
+}
+
+enum Write {
+    Store(Identity, tls::Certs),
+    Remove(Identity),
+}
+
+impl CertCache {
+    pub async fn new(cfg: &CertCacheConfig) -> Result<CertCache, Error> {
+        let cfg = cfg.clone();
+        let entries = tokio::task::spawn_blocking(move || Entries::open(&cfg))
+            .await
+            .map_err(|e| Error::CertCache(e.to_string()))??;
+        let entries = Arc::new(entries);
+        let (writes, rx) = mpsc::unbounded_channel();
+        // The task ends once the cache is dropped and the queued writes are done.
+        tokio::spawn(Self::write(entries.clone(), rx));
+        Ok(CertCache { entries, writes })
+    }
+
+    /// load returns the cached certificates that have not expired yet. Entries that expired, that
+    /// are not for the identity they are stored under, or that cannot be decrypted, such as after
+    /// the key file changed, are removed.
+    pub async fn load(&self) -> Vec<(Identity, tls::Certs)> {
+        let entries = self.entries.clone();
+        tokio::task::spawn_blocking(move || entries.load())
+            .await
+            .unwrap_or_else(|e| {
+                warn!("failed to load certificate cache: {e}");
+                Vec::new()
+            })
+    }
+
+    /// store replaces the cached certificate of the identity in the background.
+    pub fn store(&self, id: &Identity, certs: &tls::Certs) {
+        let _ = self.writes.send(Write::Store(id.clone(), certs.clone()));
+    }
+
+    /// remove deletes the cached certificate of the identity, if any, in the background.
+    pub fn remove(&self, id: &Identity) {
+        let _ = self.writes.send(Write::Remove(id.clone()));
+    }
+
+    async fn write(entries: Arc<Entries>, mut writes: mpsc::UnboundedReceiver<Write>) {
+        while let Some(write) = writes.recv().await {
+            let entries = entries.clone();
+            let _ = tokio::task::spawn_blocking(move || match write {
+                Write::Store(id, certs) => {
+                    if let Err(e) = entries.store(&id, &certs) {
+                        warn!(%id, "failed to cache certificate: {e}");
+                    }
+                }
+                Write::Remove(id) => {
+                    if let Err(e) = entries.remove(&id) {
+                        warn!(%id, "failed to remove cached certificate: {e}");
+                    }
+                }
+            })
+            .await;
+        }
+    }
+}
+
+/// Entries does the blocking I/O on the cache directory.
+struct Entries {
+    dir: PathBuf,
+    key: [u8; 32],
+}
+
+impl Entries {
+    fn open(cfg: &CertCacheConfig) -> Result<Entries, Error> {
+        let err =
+            |path: &Path, e: std::io::Error| Error::CertCache(format!("{}: {e}", path.display()));
+        let secret = std::fs::read(&cfg.key_file).map_err(|e| err(&cfg.key_file, e))?;
+        if secret.is_empty() {
+            return Err(Error::CertCache(format!(
+                "{}: key file is empty",
+                cfg.key_file.display()
+            )));
+        }
+        let mut dir = DirBuilder::new();
+        dir.recursive(true);
+        #[cfg(unix)]
+        dir.mode(0o700);
+        dir.create(&cfg.dir).map_err(|e| err(&cfg.dir, e))?;
+        // An existing directory keeps its mode, which may be more permissive.
+        #[cfg(unix)]
+        std::fs::set_permissions(&cfg.dir, std::fs::Permissions::from_mode(0o700))
+            .map_err(|e| err(&cfg.dir, e))?;
+        let key = hkdf_sha256(KEY_SALT, &secret, KEY_INFO)
+            .map_err(|e| Error::CertCache(format!("failed to derive key: {e}")))?;
+        Ok(Entries {
+            dir: cfg.dir.clone(),
+            key,
+        })
+    }
+
+    fn load(&self) -> Vec<(Identity, tls::Certs)> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(dir=%self.dir.display(), "failed to read certificate cache: {e}");
+                return Vec::new();
+            }
+        };
+        let mut loaded = Vec::new();
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.extension() != Some(OsStr::new(ENTRY_EXTENSION)) {
+                continue;
+            }
+            match self.read(&path) {
+                Ok((id, certs)) if !certs.is_expired() => loaded.push((id, certs)),
+                Ok((id, _)) => {
+                    debug!(%id, "removing expired cached certificate");
+                    let _ = std::fs::remove_file(&path);
+                }
+                Err(e) => {
+                    warn!(path=%path.display(), "removing invalid cached certificate: {e}");
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        info!(dir=%self.dir.display(), identities=loaded.len(), "loaded cached certificates");
+        loaded
+    }
+
+    fn read(&self, path: &Path) -> anyhow::Result<(Identity, tls::Certs)> {
+        let data = std::fs::read(path)?;
+        if data.len() < NONCE_LEN + TAG_LEN {
+            anyhow::bail!("entry is truncated");
+        }
+        let (nonce, data) = data.split_at(NONCE_LEN);
+        let (tag, ciphertext) = data.split_at(TAG_LEN);
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )?;
+        let (id, pem) = match plaintext.iter().position(|b| *b == b'\n') {
+            Some(i) => (&plaintext[..i], &plaintext[i + 1..]),
+            None => anyhow::bail!("entry has no identity"),
+        };
+        let id = Identity::from_str(std::str::from_utf8(id)?)?;
+        let key = PKey::private_key_from_pem(pem)?;
+        let mut chain = X509::stack_from_pem(pem)?;
+        if chain.is_empty() {
+            anyhow::bail!("entry has no certificate");
+        }
+        let leaf = chain.remove(0);
+        if path != self.path(&id) {
+            anyhow::bail!("entry is stored under another identity");
+        }
+        let certs = tls::Certs::new(key, leaf, chain);
+        certs.verify_san(&id, &TrustDomainAliases::default())?;
+        Ok((id, certs))
+    }
+
+    fn store(&self, id: &Identity, certs: &tls::Certs) -> anyhow::Result<()> {
+        let mut plaintext = format!("{id}\n").into_bytes();
+        plaintext.extend(certs.to_pem()?);
+        let mut nonce = [0u8; NONCE_LEN];
+        boring::rand::rand_bytes(&mut nonce)?;
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &[],
+            &plaintext,
+            &mut tag,
+        )?;
+
+        // Write a temporary file and rename it, so a crash never leaves a partial entry behind.
+        let path = self.path(id);
+        let tmp = path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(&nonce)?;
+        file.write_all(&tag)?;
+        file.write_all(&ciphertext)?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: &Identity) -> anyhow::Result<()> {
+        match std::fs::remove_file(self.path(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Entries are named by a hash of the identity, as identities may contain characters that are
+    // not allowed in file names.
+    fn path(&self, id: &Identity) -> PathBuf {
+        let name: String = boring::sha::sha256(id.to_string().as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        self.dir.join(name).with_extension(ENTRY_EXTENSION)
+    }
+}
+
+/// hkdf_sha256 derives a 32 byte key with HKDF-SHA256 (RFC 5869). A single expand block gives the
+/// 32 bytes AES-256 needs.
+fn hkdf_sha256(salt: &[u8], secret: &[u8], info: &[u8]) -> Result<[u8; 32], ErrorStack> {
+    let hmac = |key: &[u8], data: &[&[u8]]| -> Result<Vec<u8>, ErrorStack> {
+        let key = PKey::hmac(key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        for d in data {
+            signer.update(d)?;
+        }
+        signer.sign_to_vec()
+    };
+    let prk = hmac(salt, &[secret])?;
+    let okm = hmac(&prk, &[info, &[1]])?;
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&okm);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_helpers::{temp_dir, test_identity as identity};
+    use crate::tls::{generate_test_certs, TestIdentity};
+
+    fn certs(id: &Identity, valid_for: Duration) -> tls::Certs {
+        generate_test_certs(
+            &TestIdentity::from(id.clone()),
+            Duration::from_secs(0),
+            valid_for,
+        )
+    }
+
+    /// temp_cache returns the config of an empty cache with the given key, in the directory.
+    fn temp_cache(dir: &Path, key: &str) -> CertCacheConfig {
+        let key_file = dir.join("key");
+        std::fs::write(&key_file, key).unwrap();
+        CertCacheConfig {
+            dir: dir.join("certs"),
+            key_file,
+        }
+    }
+
+    #[tokio::test]
+    async fn store_and_load() {
+        let tmp = temp_dir();
+        let cfg = temp_cache(tmp.path(), "secret");
+        let cache = CertCache::new(&cfg).await.unwrap();
+        #[cfg(unix)]
+        {
+            let mode = std::fs::metadata(&cfg.dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        let entries = &cache.entries;
+        let (a, b, expired) = (identity("a"), identity("b"), identity("expired"));
+        let a_certs = certs(&a, Duration::from_secs(3600));
+        entries.store(&a, &a_certs).unwrap();
+        entries
+            .store(&b, &certs(&b, Duration::from_secs(3600)))
+            .unwrap();
+        entries
+            .store(&expired, &certs(&expired, Duration::ZERO))
+            .unwrap();
+        entries.remove(&b).unwrap();
+        entries.remove(&b).unwrap();
+        // An entry holding the certificate of another identity is not trusted.
+        let moved = identity("moved");
+        entries.store(&moved, &a_certs).unwrap();
+
+        let loaded = cache.load().await;
+        assert_eq!(loaded.len(), 1);
+        let (id, loaded_certs) = &loaded[0];
+        assert_eq!(id, &a);
+        assert_eq!(loaded_certs.to_pem().unwrap(), a_certs.to_pem().unwrap());
+        // The key is not stored in the clear.
+        let entry = std::fs::read(entries.path(&a)).unwrap();
+        assert!(!String::from_utf8_lossy(&entry).contains("PRIVATE KEY"));
+        // The expired and mismatched entries were removed.
+        assert!(!entries.path(&expired).exists());
+        assert!(!entries.path(&moved).exists());
+    }
+
+    #[tokio::test]
+    async fn background_writes() {
+        let tmp = temp_dir();
+        let cfg = temp_cache(tmp.path(), "secret");
+        let cache = CertCache::new(&cfg).await.unwrap();
+        let (a, b) = (identity("a"), identity("b"));
+        cache.store(&a, &certs(&a, Duration::from_secs(3600)));
+        cache.store(&b, &certs(&b, Duration::from_secs(3600)));
+        cache.remove(&a);
+
+        // Writes are applied in order.
+        let mut loaded = Vec::new();
+        for _ in 0..100 {
+            loaded = cache.load().await;
+            if loaded.len() == 1 && !cache.entries.path(&a).exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].0, b);
+    }
+
+    #[tokio::test]
+    async fn changed_key() {
+        let tmp = temp_dir();
+        let cfg = temp_cache(tmp.path(), "secret");
+        let id = identity("a");
+        CertCache::new(&cfg)
+            .await
+            .unwrap()
+            .entries
+            .store(&id, &certs(&id, Duration::from_secs(3600)))
+            .unwrap();
+
+        std::fs::write(&cfg.key_file, "other").unwrap();
+        let cache = CertCache::new(&cfg).await.unwrap();
+        assert!(cache.load().await.is_empty());
+        assert!(!cache.entries.path(&id).exists());
+
+        std::fs::write(&cfg.key_file, "").unwrap();
+        assert!(CertCache::new(&cfg).await.is_err());
+    }
+
+    #[test]
+    fn derived_key() {
+        // RFC 5869 test case 1, truncated to 32 bytes.
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        let okm: String = hkdf_sha256(&salt, &[0x0b; 22], &info)
+            .unwrap()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(
+            okm,
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf"
+        );
+    }
+}
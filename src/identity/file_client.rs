@@ -0,0 +1,302 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use boring::pkey::PKey;
+use boring::x509::X509;
+use tracing::{debug, info, warn};
+
+use crate::identity::{CaClientTrait, Error, Identity, RefreshPolicy, SecretManager};
+use crate::tls;
+
+/// How often the certificate directory is checked for rotated files.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+const CERT_CHAIN_FILE: &str = "cert-chain.pem";
+const KEY_FILE: &str = "key.pem";
+const ROOT_CERT_FILE: &str = "root-cert.pem";
+
+/// FileCaClient serves certificates issued outside of ztunnel, such as by cert-manager, from a
+/// directory instead of requesting them from a CA. Each subdirectory holds the certificate of one
+/// identity:
+///  * `cert-chain.pem`: the leaf certificate, followed by any intermediates.
+///  * `key.pem`: the private key of the leaf certificate.
+///  * `root-cert.pem` (optional): the root certificates.
+///
+/// The identity is the SPIFFE ID in the leaf certificate, and the key must match it. Rotated files
+/// are pushed into the SecretManager by `watch`, so certificates are only fetched again once they
+/// expire.
+#[derive(Clone)]
+pub struct FileCaClient {
+    dir: PathBuf,
+    // Keyed by subdirectory, so a file that fails to parse keeps its previous certificate.
+    certs: Arc<RwLock<HashMap<PathBuf, (Identity, tls::Certs)>>>,
+}
+
+impl FileCaClient {
+    /// new loads the certificates in dir.
+    pub async fn new(dir: PathBuf) -> anyhow::Result<FileCaClient> {
+        let client = FileCaClient {
+            dir,
+            certs: Default::default(),
+        };
+        let loaded = client.load().await?;
+        info!(dir=%client.dir.display(), identities=loaded.len(), "loaded certificates");
+        Ok(client)
+    }
+
+    /// load reloads the certificates from the directory, returning those that changed.
+    async fn load(&self) -> anyhow::Result<Vec<(Identity, tls::Certs)>> {
+        let mut results = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if !tokio::fs::metadata(&path).await?.is_dir() {
+                continue;
+            }
+            let res = load_certs(&path).await;
+            results.push((path, res));
+        }
+
+        let mut certs = self.certs.write().unwrap();
+        let mut loaded = HashMap::new();
+        for (path, res) in results {
+            match res {
+                Ok(cert) => {
+                    loaded.insert(path, cert);
+                }
+                Err(e) => {
+                    warn!(dir=%path.display(), "failed to load certificate: {e}");
+                    if let Some(previous) = certs.get(&path).cloned() {
+                        loaded.insert(path, previous);
+                    }
+                }
+            }
+        }
+        let changed = loaded
+            .iter()
+            .filter(|(path, (_, cert))| certs.get(*path).map(|(_, c)| c) != Some(cert))
+            .map(|(_, cert)| cert.clone())
+            .collect();
+        *certs = loaded;
+        Ok(changed)
+    }
+
+    /// reload reloads the certificates, and pushes those that changed into the SecretManager.
+    pub async fn reload(&self, sm: &SecretManager) -> anyhow::Result<()> {
+        for (id, certs) in self.load().await? {
+            debug!(%id, "certificate file changed");
+            sm.update_certificate(&id, certs).await;
+        }
+        Ok(())
+    }
+
+    /// watch reloads the certificates in the background every RELOAD_INTERVAL, until the
+    /// SecretManager is dropped. Only certificates that changed are pushed, so unchanged files
+    /// cost a read. A directory that fails to load leaves the previous certificates in place.
+    pub fn watch(self, sm: Weak<SecretManager>) {
+        tokio::spawn(self.poll(sm, RELOAD_INTERVAL));
+    }
+
+    async fn poll(self, sm: Weak<SecretManager>, reload_interval: Duration) {
+        let mut interval = tokio::time::interval(reload_interval);
+        // The first tick completes immediately, but the certificates were just loaded by new.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(sm) = sm.upgrade() else {
+                return;
+            };
+            if let Err(e) = self.reload(&sm).await {
+                warn!(dir=%self.dir.display(), "failed to reload certificates: {e}");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CaClientTrait for FileCaClient {
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        self.certs
+            .read()
+            .unwrap()
+            .values()
+            .find(|(cert_id, certs)| cert_id == id && !certs.is_expired())
+            .map(|(_, certs)| certs.clone())
+            .ok_or_else(|| Error::MissingCertificateFile(id.clone()))
+    }
+
+    fn refresh_at(&self, certs: &tls::Certs, _: &RefreshPolicy) -> SystemTime {
+        certs.not_after()
+    }
+}
+
+async fn load_certs(dir: &Path) -> anyhow::Result<(Identity, tls::Certs)> {
+    let mut chain = X509::stack_from_pem(&tokio::fs::read(dir.join(CERT_CHAIN_FILE)).await?)?;
+    if chain.is_empty() {
+        anyhow::bail!("{CERT_CHAIN_FILE} has no certificates");
+    }
+    let leaf = chain.remove(0);
+    let key = PKey::private_key_from_pem(&tokio::fs::read(dir.join(KEY_FILE)).await?)?;
+    // The files are not written atomically, so a rotation may be seen half way through.
+    if !leaf.public_key()?.public_eq(&key) {
+        anyhow::bail!("{KEY_FILE} does not match the certificate in {CERT_CHAIN_FILE}");
+    }
+    match tokio::fs::read(dir.join(ROOT_CERT_FILE)).await {
+        Ok(root) => chain.extend(X509::stack_from_pem(&root)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let id = tls::extract_sans(&leaf)
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{CERT_CHAIN_FILE} has no SPIFFE identity"))?;
+    Ok((id, tls::Certs::new(key, leaf, chain)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use matches::assert_matches;
+
+    use super::*;
+    use crate::test_helpers::{temp_dir, test_identity as identity};
+    use crate::tls::{generate_test_certs, TestIdentity};
+
+    const TEST_PKEY: &[u8] = include_bytes!("../tls/key.pem");
+    const TEST_ROOT: &[u8] = include_bytes!("../tls/root-cert.pem");
+
+    /// write_certs writes a certificate for the identity into dir/name, valid for the duration.
+    fn write_certs(dir: &Path, name: &str, id: &Identity, valid_for: Duration) -> tls::Certs {
+        let certs = generate_test_certs(
+            &TestIdentity::from(id.clone()),
+            Duration::from_secs(0),
+            valid_for,
+        );
+        let dir = dir.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(CERT_CHAIN_FILE), certs.x509().to_pem().unwrap()).unwrap();
+        std::fs::write(dir.join(KEY_FILE), TEST_PKEY).unwrap();
+        std::fs::write(dir.join(ROOT_CERT_FILE), TEST_ROOT).unwrap();
+        certs
+    }
+
+    #[tokio::test]
+    async fn fetch_certificate() {
+        let tmp = temp_dir();
+        let dir = tmp.path().to_path_buf();
+        let id = identity("sa");
+        let written = write_certs(&dir, "a", &id, Duration::from_secs(3600));
+        write_certs(&dir, "expired", &identity("expired"), Duration::ZERO);
+        std::fs::write(dir.join("README"), "ignored").unwrap();
+
+        let client = FileCaClient::new(dir.clone()).await.unwrap();
+        let certs = client.fetch_certificate(&id).await.unwrap();
+        assert_eq!(
+            certs.x509().to_der().unwrap(),
+            written.x509().to_der().unwrap()
+        );
+        assert_eq!(certs.iter_chain().count(), 1);
+        // The certificate is only fetched again once it expires.
+        assert_eq!(
+            client.refresh_at(&certs, &RefreshPolicy::default()),
+            certs.not_after()
+        );
+
+        assert_matches!(
+            client.fetch_certificate(&identity("other")).await,
+            Err(Error::MissingCertificateFile(_))
+        );
+        assert_matches!(
+            client.fetch_certificate(&identity("expired")).await,
+            Err(Error::MissingCertificateFile(_))
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_pushes_rotated_certificates() {
+        let tmp = temp_dir();
+        let dir = tmp.path().to_path_buf();
+        let id = identity("sa");
+        write_certs(&dir, "a", &id, Duration::from_secs(3600));
+
+        let client = FileCaClient::new(dir.clone()).await.unwrap();
+        let sm = Arc::new(SecretManager::new_with_client(client.clone()));
+        let initial = sm.fetch_certificate(&id).await.unwrap();
+
+        // A file that fails to parse keeps the previous certificate.
+        std::fs::write(dir.join("a").join(KEY_FILE), "invalid").unwrap();
+        client.reload(&sm).await.unwrap();
+        assert_eq!(sm.fetch_certificate(&id).await.unwrap(), initial);
+
+        // So does a key that does not match the certificate.
+        let other_key = tls::KeyAlgorithm::EcdsaP256.generate().unwrap();
+        std::fs::write(
+            dir.join("a").join(KEY_FILE),
+            other_key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        client.reload(&sm).await.unwrap();
+        assert_eq!(sm.fetch_certificate(&id).await.unwrap(), initial);
+
+        let rotated = write_certs(&dir, "a", &id, Duration::from_secs(7200));
+        client.reload(&sm).await.unwrap();
+        // The update is applied by the SecretManager worker in the background.
+        let mut certs = sm.fetch_certificate(&id).await.unwrap();
+        for _ in 0..100 {
+            if certs != initial {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            certs = sm.fetch_certificate(&id).await.unwrap();
+        }
+        assert_eq!(
+            certs.x509().to_der().unwrap(),
+            rotated.x509().to_der().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_reloads_changed_files() {
+        let tmp = temp_dir();
+        let dir = tmp.path().to_path_buf();
+        let id = identity("sa");
+        write_certs(&dir, "a", &id, Duration::from_secs(3600));
+
+        let client = FileCaClient::new(dir.clone()).await.unwrap();
+        let sm = Arc::new(SecretManager::new_with_client(client.clone()));
+        tokio::spawn(client.poll(Arc::downgrade(&sm), Duration::from_millis(10)));
+        let initial = sm.fetch_certificate(&id).await.unwrap();
+
+        let rotated = write_certs(&dir, "a", &id, Duration::from_secs(7200));
+        let mut certs = initial.clone();
+        for _ in 0..100 {
+            if certs != initial {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            certs = sm.fetch_certificate(&id).await.unwrap();
+        }
+        assert_eq!(
+            certs.x509().to_der().unwrap(),
+            rotated.x509().to_der().unwrap()
+        );
+    }
+}
@@ -0,0 +1,619 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use boring::asn1::Asn1Time;
+use boring::bn::BigNum;
+use boring::hash::MessageDigest;
+use boring::pkey::{HasPublic, PKey, PKeyRef, Private};
+use boring::ssl::SslAcceptor;
+use boring::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+};
+use boring::x509::{X509Req, X509};
+use drain::Watch;
+use futures::StreamExt;
+use prost_types::value::Kind;
+use rand::RngCore;
+use tokio::net::TcpStream;
+use tonic::metadata::MetadataMap;
+use tracing::{debug, info, warn};
+
+use crate::hot_restart::Listeners;
+use crate::identity::{CaClientTrait, Error, Identity, TrustDomainAliases};
+use crate::state::DemandProxyState;
+use crate::tls::{self, TlsError};
+use crate::xds::istio::ca::istio_certificate_service_server::{
+    IstioCertificateService, IstioCertificateServiceServer,
+};
+use crate::xds::istio::ca::{IstioCertificateRequest, IstioCertificateResponse};
+
+// The files of the CA, named as in Istio's cacerts secret.
+const CA_CERT_FILE: &str = "ca-cert.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+const CERT_CHAIN_FILE: &str = "cert-chain.pem";
+const ROOT_CERT_FILE: &str = "root-cert.pem";
+
+const IMPERSONATED_IDENTITY: &str = "ImpersonatedIdentity";
+
+/// How often the client tokens are reloaded.
+const TOKEN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// LocalCaConfig configures the built-in CA.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalCaConfig {
+    /// Directory with the CA certificate and key, as in Istio's cacerts secret: ca-cert.pem,
+    /// ca-key.pem, and optionally cert-chain.pem, from ca-cert.pem up to the root, and
+    /// root-cert.pem.
+    pub dir: PathBuf,
+    /// The trust domain certificates are issued in. Identities in its aliases are issued as well.
+    pub trust_domain: String,
+    /// How long issued certificates are valid for. Requests for longer are capped to this.
+    pub cert_validity: Duration,
+    /// If set, certificates are also issued to other nodes.
+    pub server: Option<LocalCaServerConfig>,
+}
+
+/// LocalCaServerConfig configures the IstioCertificateService endpoint of the built-in CA.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalCaServerConfig {
+    pub addr: SocketAddr,
+    /// The DNS names and IPs of the server certificate, which clients verify their CA address
+    /// against.
+    pub hosts: Vec<String>,
+    /// File with the tokens that clients present as their bearer token, one `<node> <token>` per
+    /// line. A client may only request the identities of workloads on its node.
+    pub token: PathBuf,
+}
+
+/// LocalCa issues workload certificates in-process, from a CA certificate and key on disk.
+pub struct LocalCa {
+    key: PKey<Private>,
+    cert: X509,
+    // The CA certificate, followed by the rest of its chain up to the root.
+    chain: Vec<X509>,
+    cert_validity: Duration,
+    key_algorithm: tls::KeyAlgorithm,
+    trust_domain: String,
+    aliases: TrustDomainAliases,
+}
+
+impl LocalCa {
+    pub fn load(
+        cfg: &LocalCaConfig,
+        aliases: TrustDomainAliases,
+        key_algorithm: tls::KeyAlgorithm,
+    ) -> anyhow::Result<LocalCa> {
+        let dir = &cfg.dir;
+        let read = |name: &str| {
+            std::fs::read(dir.join(name)).map_err(|e| anyhow::anyhow!("local CA {name}: {e}"))
+        };
+        let cert = X509::from_pem(&read(CA_CERT_FILE)?)?;
+        let key = PKey::private_key_from_pem(&read(CA_KEY_FILE)?)?;
+        if !cert.public_key()?.public_eq(&key) {
+            anyhow::bail!("local CA {CA_KEY_FILE} does not match {CA_CERT_FILE}");
+        }
+        let mut chain = if dir.join(CERT_CHAIN_FILE).exists() {
+            X509::stack_from_pem(&read(CERT_CHAIN_FILE)?)?
+        } else {
+            vec![cert.clone()]
+        };
+        if dir.join(ROOT_CERT_FILE).exists() {
+            for root in X509::stack_from_pem(&read(ROOT_CERT_FILE)?)? {
+                if !chain.iter().any(|c| c.to_der().ok() == root.to_der().ok()) {
+                    chain.push(root);
+                }
+            }
+        }
+        info!(dir=%dir.display(), chain=chain.len(), "loaded local CA");
+        Ok(LocalCa {
+            key,
+            cert,
+            chain,
+            cert_validity: cfg.cert_validity,
+            key_algorithm,
+            trust_domain: cfg.trust_domain.clone(),
+            aliases,
+        })
+    }
+
+    /// sign_csr issues a certificate for the identity to the public key of a PEM encoded CSR. The
+    /// identity is always the one given, regardless of the names requested in the CSR, and must
+    /// be in our trust domain or one of its aliases. Returns the chain, leaf first.
+    pub fn sign_csr(
+        &self,
+        csr: &[u8],
+        id: &Identity,
+        validity: Duration,
+    ) -> Result<Vec<X509>, Error> {
+        let Identity::Spiffe { trust_domain, .. } = id;
+        if !self.aliases.equivalent(trust_domain, &self.trust_domain) {
+            return Err(Error::UnauthorizedIdentity(id.clone()));
+        }
+        let csr = X509Req::from_pem(csr).map_err(|e| Error::InvalidCsr(e.to_string()))?;
+        let pubkey = csr.public_key().map_err(tls::Error::from)?;
+        if !csr.verify(&pubkey).map_err(tls::Error::from)? {
+            return Err(Error::InvalidCsr("signature mismatch".to_string()));
+        }
+        let leaf = self.issue(&pubkey, |san| san.uri(&id.to_string()), validity)?;
+        Ok(std::iter::once(leaf).chain(self.chain.clone()).collect())
+    }
+
+    fn issue<T: HasPublic>(
+        &self,
+        pubkey: &PKeyRef<T>,
+        names: impl FnOnce(&mut SubjectAlternativeName) -> &mut SubjectAlternativeName,
+        validity: Duration,
+    ) -> Result<X509, tls::Error> {
+        let now = SystemTime::now();
+        // Never outlive the CA.
+        let not_after = std::cmp::min(
+            now + std::cmp::min(validity, self.cert_validity),
+            tls::asn1_time_to_system_time(self.cert.not_after()),
+        );
+        let unix = |t: SystemTime| {
+            let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            Asn1Time::from_unix(secs.try_into().unwrap_or_default())
+        };
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        let mut serial = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut serial);
+        // Clear the most significant bit to make the serial number positive.
+        serial[0] &= 0x7f;
+        builder.set_serial_number(&BigNum::from_slice(&serial)?.to_asn1_integer()?)?;
+        builder.set_issuer_name(self.cert.subject_name())?;
+        builder.set_not_before(&unix(now)?)?;
+        builder.set_not_after(&unix(not_after)?)?;
+        builder.set_pubkey(pubkey)?;
+
+        let key_usage = KeyUsage::new()
+            .critical()
+            .digital_signature()
+            .key_encipherment()
+            .build()?;
+        let ext_key_usage = ExtendedKeyUsage::new()
+            .client_auth()
+            .server_auth()
+            .build()?;
+        let basic_constraints = BasicConstraints::new().critical().build()?;
+        let authority_key_identifier = AuthorityKeyIdentifier::new()
+            .keyid(false)
+            .issuer(false)
+            .build(&builder.x509v3_context(Some(&self.cert), None))?;
+        let subject_alternative_name = names(&mut SubjectAlternativeName::new())
+            .critical()
+            .build(&builder.x509v3_context(Some(&self.cert), None))?;
+        builder.append_extension(key_usage)?;
+        builder.append_extension(ext_key_usage)?;
+        builder.append_extension(basic_constraints)?;
+        builder.append_extension(authority_key_identifier)?;
+        builder.append_extension(subject_alternative_name)?;
+
+        builder.sign(&self.key, MessageDigest::sha256())?;
+        Ok(builder.build())
+    }
+
+    /// server_certs issues the certificate of the IstioCertificateService endpoint.
+    fn server_certs(&self, hosts: &[String]) -> Result<tls::Certs, tls::Error> {
+        let key = self.key_algorithm.generate()?;
+        let leaf = self.issue(
+            &key,
+            |san| {
+                for host in hosts {
+                    match IpAddr::from_str(host) {
+                        Ok(_) => san.ip(host),
+                        Err(_) => san.dns(host),
+                    };
+                }
+                san
+            },
+            self.cert_validity,
+        )?;
+        Ok(tls::Certs::new(key, leaf, self.chain.clone()))
+    }
+
+    /// serve issues certificates to other nodes over IstioCertificateService until drained,
+    /// returning the address it listens on. Callers are authorized against the workloads in
+    /// state. The listener is handed over on hot restart.
+    pub async fn serve(
+        self: Arc<Self>,
+        cfg: LocalCaServerConfig,
+        state: DemandProxyState,
+        listeners: &Listeners,
+        drain: Watch,
+    ) -> anyhow::Result<SocketAddr> {
+        let tokens = Tokens::default();
+        tokens.load(&cfg.token).await?;
+        tokio::spawn(tokens.clone().watch(cfg.token, drain.clone()));
+
+        let listener = listeners.bind("local_ca", cfg.addr).await?;
+        let addr = listener.local_addr()?;
+        let certs = ServerCerts {
+            ca: self.clone(),
+            hosts: cfg.hosts,
+            certs: Default::default(),
+        };
+        let srv = IstioCertificateServiceServer::new(LocalCaServer {
+            ca: self,
+            tokens,
+            state,
+        });
+        let tls_stream = crate::hyper_util::tls_server(certs, listener);
+        tokio::spawn(listeners.accept(async move {
+            let mut tls_stream = tls_stream.take_until(Box::pin(drain.clone().signaled()));
+            while let Some(socket) = tls_stream.next().await {
+                let srv = srv.clone();
+                let drain = drain.clone();
+                tokio::spawn(async move {
+                    let serve = crate::hyper_util::http2_server().serve_connection(
+                        socket,
+                        tower_hyper_http_body_compat::TowerService03HttpServiceAsHyper1HttpService::new(srv),
+                    );
+                    // On drain, let pending requests complete.
+                    let serve = Box::pin(serve);
+                    let res = match futures::future::select(Box::pin(drain.signaled()), serve)
+                        .await
+                    {
+                        futures::future::Either::Left((_shutdown, mut serve)) => {
+                            serve.as_mut().graceful_shutdown();
+                            serve.await
+                        }
+                        futures::future::Either::Right((res, _shutdown)) => res,
+                    };
+                    if let Err(err) = res {
+                        warn!("local CA connection error: {err}");
+                    }
+                });
+            }
+            info!(%addr, "local CA drained");
+        }));
+        info!(%addr, "local CA listening");
+        Ok(addr)
+    }
+}
+
+#[async_trait]
+impl CaClientTrait for LocalCa {
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        let cs = tls::CsrOptions {
+            san: id.to_string(),
+            key_algorithm: self.key_algorithm,
+        }
+        .generate()?;
+        let mut chain = self.sign_csr(&cs.csr, id, self.cert_validity)?.into_iter();
+        let leaf = chain
+            .next()
+            .ok_or_else(|| Error::EmptyResponse(id.clone()))?;
+        let key = PKey::private_key_from_pem(&cs.pkey).map_err(tls::Error::from)?;
+        Ok(tls::Certs::new(key, leaf, chain.collect()))
+    }
+}
+
+/// ServerCerts provides the certificate of the IstioCertificateService endpoint, reissuing it
+/// once it is due for refresh.
+#[derive(Clone)]
+struct ServerCerts {
+    ca: Arc<LocalCa>,
+    hosts: Vec<String>,
+    certs: Arc<Mutex<Option<tls::Certs>>>,
+}
+
+#[async_trait]
+impl tls::CertProvider for ServerCerts {
+    async fn fetch_cert(&mut self, _: &TcpStream) -> Result<SslAcceptor, TlsError> {
+        let certs = {
+            let mut certs = self.certs.lock().unwrap();
+            match &*certs {
+                Some(c) if c.refresh_at() > SystemTime::now() => c.clone(),
+                _ => certs.insert(self.ca.server_certs(&self.hosts)?).clone(),
+            }
+        };
+        Ok(certs.acceptor()?)
+    }
+}
+
+/// Tokens maps the bearer tokens of clients to the node they run on. Clones share the same
+/// tokens.
+#[derive(Clone, Default)]
+struct Tokens(Arc<RwLock<HashMap<String, String>>>);
+
+impl Tokens {
+    /// load replaces the tokens with those in the file, one `<node> <token>` per line.
+    async fn load(&self, path: &Path) -> anyhow::Result<()> {
+        let data = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("local CA token {}: {e}", path.display()))?;
+        let mut tokens = HashMap::new();
+        for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let Some((node, token)) = line.split_once(char::is_whitespace) else {
+                anyhow::bail!("local CA token {}: expected `<node> <token>`", path.display());
+            };
+            tokens.insert(token.trim().to_string(), node.to_string());
+        }
+        *self.0.write().unwrap() = tokens;
+        Ok(())
+    }
+
+    /// watch periodically reloads the tokens until drained. A file that fails to load keeps the
+    /// previous tokens.
+    async fn watch(self, path: PathBuf, drain: Watch) {
+        let reload = async {
+            let mut interval = tokio::time::interval(TOKEN_REFRESH_INTERVAL);
+            // The first tick completes immediately; the tokens were loaded at startup.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.load(&path).await {
+                    warn!("failed to reload local CA tokens: {e}");
+                }
+            }
+        };
+        tokio::select! {
+            _ = reload => {}
+            _ = drain.signaled() => debug!("stopped reloading local CA tokens"),
+        }
+    }
+
+    /// authenticate returns the node of the bearer token in the metadata.
+    fn authenticate(&self, metadata: &MetadataMap) -> Result<String, tonic::Status> {
+        let provided = metadata
+            .get("authorization")
+            .and_then(|v| v.as_bytes().strip_prefix(b"Bearer "))
+            .unwrap_or_default();
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(token, _)| {
+                let token = token.as_bytes();
+                provided.len() == token.len() && boring::memcmp::eq(provided, token)
+            })
+            .map(|(_, node)| node.clone())
+            .ok_or_else(|| tonic::Status::unauthenticated("invalid token"))
+    }
+}
+
+/// LocalCaServer implements IstioCertificateService on top of the local CA. Clients authenticate
+/// with the bearer token of their node, and send the identity they request as impersonated
+/// identity, as ztunnel does in shared proxy mode. Only identities of workloads on the client's
+/// node are issued.
+struct LocalCaServer {
+    ca: Arc<LocalCa>,
+    tokens: Tokens,
+    state: DemandProxyState,
+}
+
+#[async_trait]
+impl IstioCertificateService for LocalCaServer {
+    async fn create_certificate(
+        &self,
+        request: tonic::Request<IstioCertificateRequest>,
+    ) -> Result<tonic::Response<IstioCertificateResponse>, tonic::Status> {
+        let node = self.tokens.authenticate(request.metadata())?;
+        let req = request.into_inner();
+        let id = match req
+            .metadata
+            .as_ref()
+            .and_then(|m| m.fields.get(IMPERSONATED_IDENTITY))
+            .and_then(|v| v.kind.as_ref())
+        {
+            Some(Kind::StringValue(id)) => Identity::from_str(id)
+                .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?,
+            _ => {
+                return Err(tonic::Status::invalid_argument(
+                    "an impersonated identity is required",
+                ))
+            }
+        };
+        if !self.state.node_has_identity(&node, &id) {
+            return Err(tonic::Status::permission_denied(format!(
+                "no workload on node {node} runs as {id}"
+            )));
+        }
+        let validity = match req.validity_duration {
+            d if d > 0 => Duration::from_secs(d as u64),
+            _ => self.ca.cert_validity,
+        };
+        let chain = self
+            .ca
+            .sign_csr(req.csr.as_bytes(), &id, validity)
+            .map_err(|e| match e {
+                Error::UnauthorizedIdentity(_) => tonic::Status::permission_denied(e.to_string()),
+                _ => tonic::Status::invalid_argument(e.to_string()),
+            })?;
+        let cert_chain = chain
+            .iter()
+            .map(|c| Ok(String::from_utf8_lossy(&c.to_pem()?).into_owned()))
+            .collect::<Result<_, boring::error::ErrorStack>>()
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+        info!(%id, node, "local CA issued certificate");
+        Ok(tonic::Response::new(IstioCertificateResponse {
+            cert_chain,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matches::assert_matches;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::RootCert;
+    use crate::hot_restart::Listeners;
+    use crate::identity::{AuthSource, CaClient};
+    use crate::state::workload::Workload;
+    use crate::state::ProxyState;
+    use crate::test_helpers::{temp_dir, test_default_workload, test_identity as identity};
+    use crate::tls::SanChecker;
+
+    const TEST_ROOT: &[u8] = include_bytes!("../tls/root-cert.pem");
+    const TEST_ROOT_KEY: &[u8] = include_bytes!("../tls/ca-key.pem");
+
+    /// ca_dir returns a temporary directory holding the test CA.
+    fn ca_dir() -> TempDir {
+        let dir = temp_dir();
+        std::fs::write(dir.path().join(CA_CERT_FILE), TEST_ROOT).unwrap();
+        std::fs::write(dir.path().join(CA_KEY_FILE), TEST_ROOT_KEY).unwrap();
+        dir
+    }
+
+    fn load(dir: &Path) -> anyhow::Result<LocalCa> {
+        let cfg = LocalCaConfig {
+            dir: dir.to_path_buf(),
+            trust_domain: "cluster.local".to_string(),
+            cert_validity: Duration::from_secs(3600),
+            server: None,
+        };
+        let aliases = TrustDomainAliases::new("cluster.local", ["old.local".to_string()]);
+        LocalCa::load(&cfg, aliases, Default::default())
+    }
+
+    #[tokio::test]
+    async fn fetch_certificate() {
+        let tmp = ca_dir();
+        let dir = tmp.path().to_path_buf();
+        let ca = load(&dir).unwrap();
+        let id = identity("sa");
+
+        let certs = ca.fetch_certificate(&id).await.unwrap();
+        certs
+            .verify_san(&id, &TrustDomainAliases::default())
+            .unwrap();
+        let root = X509::from_pem(TEST_ROOT).unwrap();
+        assert!(certs.x509().verify(&root.public_key().unwrap()).unwrap());
+        assert_eq!(
+            certs
+                .iter_chain()
+                .map(|c| c.to_der().unwrap())
+                .collect::<Vec<_>>(),
+            vec![root.to_der().unwrap()]
+        );
+        let validity = certs.not_after().duration_since(SystemTime::now()).unwrap();
+        assert!(validity <= Duration::from_secs(3600));
+
+        // Only identities in our trust domain or its aliases are issued.
+        let alias = Identity::Spiffe {
+            trust_domain: "old.local".to_string(),
+            namespace: "ns".to_string(),
+            service_account: "sa".to_string(),
+        };
+        ca.fetch_certificate(&alias).await.unwrap();
+        let other = Identity::Spiffe {
+            trust_domain: "other.local".to_string(),
+            namespace: "ns".to_string(),
+            service_account: "sa".to_string(),
+        };
+        assert_matches!(
+            ca.fetch_certificate(&other).await,
+            Err(Error::UnauthorizedIdentity(_))
+        );
+
+        // A key that does not belong to the CA certificate is rejected.
+        std::fs::write(dir.join(CA_KEY_FILE), include_bytes!("../tls/key.pem")).unwrap();
+        assert!(load(&dir).is_err());
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let tmp = ca_dir();
+        let dir = tmp.path().to_path_buf();
+        let tokens = dir.join("tokens");
+        std::fs::write(&tokens, "node-a secret\nnode-b other\n").unwrap();
+        let mut state = ProxyState::default();
+        state
+            .workloads
+            .insert_workload(Workload {
+                uid: "remote".to_string(),
+                namespace: "ns".to_string(),
+                service_account: "remote".to_string(),
+                node: "node-a".to_string(),
+                ..test_default_workload()
+            })
+            .unwrap();
+        let state = DemandProxyState::new(Arc::new(RwLock::new(state)), None);
+        let (drain_tx, drain_rx) = drain::channel();
+        let ca = Arc::new(load(&dir).unwrap());
+        let addr = ca
+            .serve(
+                LocalCaServerConfig {
+                    addr: "127.0.0.1:0".parse().unwrap(),
+                    hosts: vec!["127.0.0.1".to_string()],
+                    token: tokens,
+                },
+                state,
+                &Listeners::default(),
+                drain_rx,
+            )
+            .await
+            .unwrap();
+
+        let client = |token: PathBuf| {
+            CaClient::new(
+                format!("https://{addr}"),
+                RootCert::Static(TEST_ROOT.into()),
+                AuthSource::Token(token, String::new()),
+                true,
+                tls::KeyAlgorithm::default(),
+                Duration::from_secs(3600),
+            )
+            .unwrap()
+        };
+        let node_a = dir.join("node-a-token");
+        std::fs::write(&node_a, "secret").unwrap();
+        let id = identity("remote");
+        let certs = CaClientTrait::fetch_certificate(&client(node_a.clone()), &id)
+            .await
+            .unwrap();
+        certs
+            .verify_san(&id, &TrustDomainAliases::default())
+            .unwrap();
+
+        // Identities of workloads on other nodes are not issued.
+        let other_node = dir.join("other-node-token");
+        std::fs::write(&other_node, "other").unwrap();
+        assert_matches!(
+            CaClientTrait::fetch_certificate(&client(other_node), &id).await,
+            Err(Error::SigningRequest(s)) if s.code() == tonic::Code::PermissionDenied
+        );
+        assert_matches!(
+            CaClientTrait::fetch_certificate(&client(node_a.clone()), &identity("sa")).await,
+            Err(Error::SigningRequest(s)) if s.code() == tonic::Code::PermissionDenied
+        );
+
+        let wrong = dir.join("wrong-token");
+        std::fs::write(&wrong, "guess").unwrap();
+        assert_matches!(
+            CaClientTrait::fetch_certificate(&client(wrong), &id).await,
+            Err(Error::SigningRequest(s)) if s.code() == tonic::Code::Unauthenticated
+        );
+
+        // Once drained, no new connections are accepted.
+        drain_tx.drain().await;
+        assert!(CaClientTrait::fetch_certificate(&client(node_a), &id)
+            .await
+            .is_err());
+    }
+}
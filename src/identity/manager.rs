@@ -12,26 +12,38 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Write;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
 
 use crate::config::ProxyMode;
 use async_trait::async_trait;
 
+use once_cell::sync::OnceCell;
 use prometheus_client::encoding::{EncodeLabelValue, LabelValueEncoder};
+use rand::Rng;
 use tokio::sync::{mpsc, watch, Mutex};
 use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{debug, warn};
 
+use crate::metrics::identity::{
+    CertExpiry, CertStatus, CsrOutcome, CsrRequest, IdentityState, QueueDepth,
+};
+use crate::metrics::{Metrics, Recorder};
 use crate::tls;
 
-use super::CaClient;
 use super::Error::{self, Spiffe};
+use super::{CaClient, CertCache};
 
-const CERT_REFRESH_FAILURE_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How often the state and expiry of certificates is recorded in metrics.
+const CERT_METRICS_INTERVAL: Duration = Duration::from_secs(15);
+/// Certificates loaded from the cache are forgotten if they are not fetched within this long of
+/// starting, so identities that are no longer on the node are not refreshed forever.
+const UNUSED_CACHED_TIMEOUT: Duration = Duration::from_secs(300);
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum Identity {
@@ -103,9 +115,90 @@ impl Default for Identity {
 #[async_trait]
 pub trait CaClientTrait: Send + Sync {
     async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error>;
+
+    /// refresh_at returns when the certificate should be fetched again. Clients that push new
+    /// certificates through SecretManager::update_certificate only need another fetch once the
+    /// certificate expires, and clients whose certificates are rotated elsewhere may ignore the
+    /// policy.
+    fn refresh_at(&self, certs: &tls::Certs, policy: &RefreshPolicy) -> SystemTime {
+        policy.refresh_at(certs)
+    }
+}
+
+#[async_trait]
+impl<T: CaClientTrait + ?Sized> CaClientTrait for Arc<T> {
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        (**self).fetch_certificate(id).await
+    }
+
+    fn refresh_at(&self, certs: &tls::Certs, policy: &RefreshPolicy) -> SystemTime {
+        (**self).refresh_at(certs, policy)
+    }
+}
+
+/// RefreshAt is the point in a certificate's lifetime at which it is refreshed.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RefreshAt {
+    /// Once this percentage of the lifetime has passed.
+    Percent(u8),
+    /// This long before the certificate expires.
+    Grace(Duration),
+}
+
+/// RefreshPolicy controls when certificates are refreshed, and how failed refreshes are retried.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshPolicy {
+    pub at: RefreshAt,
+    /// Refreshes happen up to this much earlier, picked at random, so certificates issued together
+    /// are not all refreshed together.
+    pub jitter: Duration,
+    /// The delay before retrying a failed fetch. It doubles with every consecutive failure, up to
+    /// max_backoff, and is shortened by up to a fifth at random so failed fetches are not all
+    /// retried together.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RefreshPolicy {
+    fn default() -> Self {
+        RefreshPolicy {
+            at: RefreshAt::Percent(50),
+            jitter: Duration::ZERO,
+            initial_backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RefreshPolicy {
+    pub fn refresh_at(&self, certs: &tls::Certs) -> SystemTime {
+        let (not_before, not_after) = (certs.not_before(), certs.not_after());
+        let lifetime = not_after.duration_since(not_before).unwrap_or_default();
+        let at = match self.at {
+            RefreshAt::Percent(p) => not_before + lifetime * u32::from(p.min(100)) / 100,
+            RefreshAt::Grace(grace) => not_after - grace.min(lifetime),
+        };
+        if self.jitter.is_zero() {
+            return at;
+        }
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.jitter);
+        std::cmp::max(not_before, at.checked_sub(jitter).unwrap_or(not_before))
+    }
+
+    /// backoff returns the delay before retrying after the given number of consecutive failures.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        let backoff = std::cmp::min(
+            self.max_backoff,
+            self.initial_backoff.saturating_mul(factor),
+        );
+        backoff - rand::thread_rng().gen_range(Duration::ZERO..=backoff / 5)
+    }
 }
 
-#[derive(PartialOrd, PartialEq, Eq, Ord, Debug, Copy, Clone)]
+#[derive(PartialOrd, PartialEq, Eq, Ord, Debug, Copy, Clone, Hash)]
 pub enum Priority {
     // Needs to be in the order of the lowest priority.
     Background,
@@ -142,9 +235,11 @@ struct CertChannel {
     // While this makes the code simpler, do note that it makes it impossible to use sender closure
     // as an indication of the background task failing.
     tx: watch::Sender<CertState>,
+    // Set for certificates loaded from the cache until they are fetched.
+    unused: bool,
 }
 
-#[derive(Eq, PartialEq)]
+#[derive(Eq, PartialEq, Clone, Copy)]
 struct PendingPriority(Priority, Instant);
 
 impl Ord for PendingPriority {
@@ -163,6 +258,94 @@ impl PartialOrd for PendingPriority {
     }
 }
 
+/// PendingFetches holds the identities waiting for a certificate fetch. Refreshes scheduled in the
+/// future are kept apart until they are due, so the number of fetches waiting to start at each
+/// priority is kept up to date as fetches are queued and started.
+#[derive(Default)]
+struct PendingFetches {
+    // Fetches that are due, ordered by priority and time.
+    due: priority_queue::PriorityQueue<Identity, PendingPriority>,
+    // Background refreshes scheduled in the future, ordered by time.
+    scheduled: priority_queue::PriorityQueue<Identity, Reverse<Instant>>,
+    // The number of due fetches at each priority.
+    depth: HashMap<Priority, usize>,
+}
+
+impl PendingFetches {
+    /// push queues a fetch, replacing the one queued for the identity, if any.
+    fn push(&mut self, id: Identity, pri: PendingPriority) {
+        self.remove(&id);
+        if pri.0 == Priority::Background && pri.1 > Instant::now() {
+            self.scheduled.push(id, Reverse(pri.1));
+        } else {
+            *self.depth.entry(pri.0).or_default() += 1;
+            self.due.push(id, pri);
+        }
+    }
+
+    /// push_increase queues a fetch, unless the one queued for the identity has a higher priority.
+    fn push_increase(&mut self, id: Identity, pri: PendingPriority) {
+        let current = match self.due.get_priority(&id) {
+            Some(current) => Some(*current),
+            None => self
+                .scheduled
+                .get_priority(&id)
+                .map(|Reverse(at)| PendingPriority(Priority::Background, *at)),
+        };
+        if current.map_or(true, |current| current < pri) {
+            self.push(id, pri);
+        }
+    }
+
+    fn remove(&mut self, id: &Identity) {
+        match self.due.remove(id) {
+            Some((_, pri)) => *self.depth.entry(pri.0).or_default() -= 1,
+            None => {
+                self.scheduled.remove(id);
+            }
+        }
+    }
+
+    /// next returns when the next fetch is to start, if any.
+    fn next(&mut self) -> Option<Instant> {
+        self.promote();
+        match self.due.peek() {
+            Some((_, pri)) => Some(pri.1),
+            None => self.scheduled.peek().map(|(_, Reverse(at))| *at),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Identity> {
+        self.promote();
+        match self.due.pop() {
+            Some((id, pri)) => {
+                *self.depth.entry(pri.0).or_default() -= 1;
+                Some(id)
+            }
+            None => self.scheduled.pop().map(|(id, _)| id),
+        }
+    }
+
+    /// depth returns the number of due fetches at the priority.
+    fn depth(&self, pri: Priority) -> usize {
+        self.depth.get(&pri).copied().unwrap_or_default()
+    }
+
+    // Moves the scheduled refreshes that are due.
+    fn promote(&mut self) {
+        let now = Instant::now();
+        while let Some((_, Reverse(at))) = self.scheduled.peek() {
+            if *at > now {
+                break;
+            }
+            let at = *at;
+            let (id, _) = self.scheduled.pop().unwrap();
+            *self.depth.entry(Priority::Background).or_default() += 1;
+            self.due.push(id, PendingPriority(Priority::Background, at));
+        }
+    }
+}
+
 // Implements the actual logic behind SecretManager.
 struct Worker {
     client: Box<dyn CaClientTrait>,
@@ -176,6 +359,11 @@ struct Worker {
     certs: Mutex<HashMap<Identity, CertChannel>>,
     // How many concurrent fetch_certificate calls can be pending at a time.
     concurrency: u16,
+    refresh: RefreshPolicy,
+    cache: Option<CertCache>,
+    unused_cached_timeout: Duration,
+    // Set once metrics are available, which is after the SecretManager is created.
+    metrics: OnceCell<Arc<Metrics>>,
 }
 
 impl Worker {
@@ -187,11 +375,26 @@ impl Worker {
         if cfg.concurrency == 0 {
             panic!("concurrency cannot be 0, operations would block forever");
         }
+        let certs = cfg
+            .cached
+            .into_iter()
+            .map(|(id, certs)| {
+                // Send the certificate rather than making it the initial value, so receivers
+                // cloned out of the channel see it as changed.
+                let (tx, rx) = watch::channel(CertState::Initializing(Priority::Background));
+                tx.send_replace(CertState::Available(certs));
+                (id, CertChannel { rx, tx, unused: true })
+            })
+            .collect();
         let worker = Arc::new(Self {
             client,
             time_conv: cfg.time_conv,
             concurrency: cfg.concurrency,
-            certs: Default::default(),
+            refresh: cfg.refresh,
+            cache: cfg.cache,
+            unused_cached_timeout: cfg.unused_cached_timeout,
+            metrics: OnceCell::new(),
+            certs: Mutex::new(certs),
         });
 
         // Process requests in the background. The task will terminate on its own when the
@@ -209,7 +412,6 @@ impl Worker {
     async fn run(&self, mut requests: mpsc::Receiver<Request>) {
         use futures::stream::FuturesUnordered;
         use futures::StreamExt;
-        use priority_queue::PriorityQueue;
 
         #[derive(Eq, PartialEq)]
         enum Fetch {
@@ -231,10 +433,33 @@ impl Worker {
         // the future, for all other priorities Instant::now() is used as the scheduled time of the
         // refresh. In other words, at any point in time, there are no high-priority
         // (not Background) items scheduled to run in the future.
-        let mut pending: PriorityQueue<Identity, PendingPriority> = PriorityQueue::new();
+        let mut pending = PendingFetches::default();
+        // The number of consecutive failed fetches of each identity, for backoff.
+        let mut failures: HashMap<Identity, u32> = HashMap::new();
+
+        // Certificates loaded from the cache are refreshed like fetched ones.
+        let mut forget_unused = false;
+        for (id, chan) in self.certs.lock().await.iter() {
+            if let CertState::Available(certs) = &*chan.rx.borrow() {
+                forget_unused = true;
+                let refresh_at = self.refresh_instant(certs);
+                pending.push(
+                    id.clone(),
+                    PendingPriority(Priority::Background, refresh_at),
+                );
+            }
+        }
+        // Those still unused by then are forgotten.
+        let forget_unused_at = Instant::now() + self.unused_cached_timeout;
 
         'main: loop {
-            let next = pending.peek().map(|(_, PendingPriority(_, ts))| *ts);
+            let next = pending.next();
+            if let Some(metrics) = self.metrics.get() {
+                for pri in [Priority::Background, Priority::Warmup, Priority::RealTime] {
+                    let depth = pending.depth(pri) as u64;
+                    metrics.record(&QueueDepth { priority: pri.into() }, depth);
+                }
+            }
             tokio::select! {
                 // Handle requests from SecretManager. Those are generally split between the
                 // client-side processing (operations on the `certs` map) and the worker-side
@@ -269,6 +494,13 @@ impl Worker {
                             Some(Fetch::Processing) => (),
                         }
                     },
+                    Some(Request::Update(id, certs)) => {
+                        let refresh_at = self.refresh_instant(&certs);
+                        // A fetch in progress is rescheduled once it completes.
+                        if self.update_certs(&id, CertState::Available(certs)).await && !processing.contains_key(&id) {
+                            pending.push(id, PendingPriority(Priority::Background, refresh_at));
+                        }
+                    },
                     Some(Request::Forget(id)) => {
                         if self.has_id(&id).await {
                             // After the forget was queued, there was another request to start
@@ -280,15 +512,26 @@ impl Worker {
                                 pending.remove(&id);
                             },
                             Some(Fetch::Processing) => {
-                                processing.insert(id, Fetch::Forgetting);
+                                processing.insert(id.clone(), Fetch::Forgetting);
                             },
                             Some(Fetch::Forgetting) => (),
                         }
+                        failures.remove(&id);
+                        if let Some(cache) = &self.cache {
+                            cache.remove(&id);
+                        }
                     },
                     None => break 'main,
                 },
                 // Handle fetch results.
-                Some((id, res)) = fetches.next() => {
+                Some((id, res, elapsed)) = fetches.next() => {
+                    if let Some(metrics) = self.metrics.get() {
+                        let outcome = match res {
+                            Ok(_) => CsrOutcome::success,
+                            Err(_) => CsrOutcome::failure,
+                        };
+                        metrics.record(&CsrRequest { outcome }, elapsed);
+                    }
                     match processing.remove(&id) {
                         Some(Fetch::Processing) => (),
                         Some(Fetch::Forgetting) => continue 'main,
@@ -296,25 +539,25 @@ impl Worker {
                     }
                     let (state, refresh_at) = match res {
                         Err(err) => {
-                            let refresh_at = Instant::now() + CERT_REFRESH_FAILURE_RETRY_DELAY;
-                            (CertState::Unavailable(err), refresh_at)
+                            let failed = failures.entry(id.clone()).or_default();
+                            *failed += 1;
+                            let refresh_at = Instant::now() + self.refresh.backoff(*failed);
+                            // Keep serving a certificate that is still valid, such as one loaded
+                            // from the cache while the CA is unreachable.
+                            match self.valid_certs(&id).await {
+                                Some(certs) => {
+                                    warn!(%id, "failed to refresh certificate, keeping the current one: {err}");
+                                    (CertState::Available(certs), refresh_at)
+                                },
+                                None => (CertState::Unavailable(err), refresh_at),
+                            }
                         },
                         Ok(certs) => {
-                            let certs: tls::Certs = certs; // Type annotation.
-                            let refresh_at = self.time_conv.system_time_to_instant(certs.refresh_at());
-                            let refresh_at = if let Some(t) = refresh_at {
-                                t.into()
-                            } else {
-                                // Malformed certificate (not_after is way too much into the
-                                // past or the future). Queue another refresh soon.
-                                //
-                                // TODO: This is a bit inconsistent since we still return the
-                                // certificate to the caller successfully. Basically the
-                                // behavior is silly, but simple and avoid panics in time math.
-                                // We'll try to get rid of the SystemTime <-> Instant
-                                // conversion here, so for now leaving the code as is.
-                                Instant::now()
-                            };
+                            failures.remove(&id);
+                            if let Some(cache) = &self.cache {
+                                cache.store(&id, &certs);
+                            }
+                            let refresh_at = self.refresh_instant(&certs);
                             (CertState::Available(certs), refresh_at)
                         },
                     };
@@ -322,13 +565,38 @@ impl Worker {
                         pending.push_increase(id, PendingPriority(Priority::Background, refresh_at));
                     }
                 },
+                // Forget the cached certificates that nothing fetched after starting.
+                _ = sleep_until(forget_unused_at), if forget_unused => {
+                    forget_unused = false;
+                    let mut certs = self.certs.lock().await;
+                    let unused: Vec<Identity> = certs
+                        .iter()
+                        .filter(|(_, chan)| chan.unused)
+                        .map(|(id, _)| id.clone())
+                        .collect();
+                    for id in unused {
+                        debug!(%id, "forgetting unused cached certificate");
+                        certs.remove(&id);
+                        match processing.get(&id) {
+                            None => pending.remove(&id),
+                            Some(_) => {
+                                processing.insert(id.clone(), Fetch::Forgetting);
+                            },
+                        }
+                        failures.remove(&id);
+                        if let Some(cache) = &self.cache {
+                            cache.remove(&id);
+                        }
+                    }
+                },
                 // Initiate the next fetch.
                 true = maybe_sleep_until(next), if fetches.len() < self.concurrency as usize => {
-                    let (id, _) = pending.pop().unwrap();
+                    let id = pending.pop().unwrap();
                     processing.insert(id.to_owned(), Fetch::Processing);
                     fetches.push(async move {
+                        let start = Instant::now();
                         let res = self.client.fetch_certificate(&id).await;
-                        (id, res)
+                        (id, res, start.elapsed())
                     });
                 },
             };
@@ -337,6 +605,87 @@ impl Worker {
         while fetches.next().await.is_some() {}
     }
 
+    fn refresh_instant(&self, certs: &tls::Certs) -> Instant {
+        match self
+            .time_conv
+            .system_time_to_instant(self.client.refresh_at(certs, &self.refresh))
+        {
+            Some(t) => t.into(),
+            // Malformed certificate (not_after is way too much into the past or the future).
+            // Queue another refresh soon.
+            //
+            // TODO: This is a bit inconsistent since we still return the certificate to the
+            // caller successfully. Basically the behavior is silly, but simple and avoid panics in
+            // time math. We'll try to get rid of the SystemTime <-> Instant conversion here, so
+            // for now leaving the code as is.
+            None => Instant::now(),
+        }
+    }
+
+    // Periodically records the state of the certificates in metrics, until the Worker is dropped.
+    async fn record_certs(worker: Weak<Worker>) {
+        // The identities whose expiry is currently reported.
+        let mut expiring = HashSet::new();
+        let mut interval = tokio::time::interval(CERT_METRICS_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(worker) = worker.upgrade() else {
+                return;
+            };
+            let Some(metrics) = worker.metrics.get() else {
+                return;
+            };
+            let mut states = HashMap::from([
+                (CertStatus::initializing, 0),
+                (CertStatus::available, 0),
+                (CertStatus::unavailable, 0),
+            ]);
+            let mut available = HashSet::new();
+            for (id, chan) in worker.certs.lock().await.iter() {
+                let status = match &*chan.rx.borrow() {
+                    CertState::Initializing(_) => CertStatus::initializing,
+                    CertState::Available(certs) => {
+                        let left = certs
+                            .not_after()
+                            .duration_since(SystemTime::now())
+                            .unwrap_or_default();
+                        metrics.record(
+                            &CertExpiry {
+                                identity: id.clone(),
+                            },
+                            left.as_secs(),
+                        );
+                        available.insert(id.clone());
+                        CertStatus::available
+                    }
+                    CertState::Unavailable(_) => CertStatus::unavailable,
+                };
+                *states.entry(status).or_default() += 1;
+            }
+            for id in expiring.difference(&available) {
+                metrics.remove_cert_expiry(&CertExpiry {
+                    identity: id.clone(),
+                });
+            }
+            expiring = available;
+            for (state, count) in states {
+                metrics.record(&IdentityState { state }, count);
+            }
+        }
+    }
+
+    // Returns the certificate of the Identity, if one is available and has not expired.
+    async fn valid_certs(&self, id: &Identity) -> Option<tls::Certs> {
+        match &*self.certs.lock().await.get(id)?.rx.borrow() {
+            CertState::Available(certs) => self
+                .time_conv
+                .system_time_to_instant(certs.not_after())
+                .filter(|&t| Instant::from(t) > Instant::now())
+                .map(|_| certs.clone()),
+            _ => None,
+        }
+    }
+
     // Returns whether the Identity is still managed.
     async fn update_certs(&self, id: &Identity, certs: CertState) -> bool {
         // Both errors (lack of entry in the `certs` map and a send error) are handled the same way
@@ -367,12 +716,19 @@ async fn maybe_sleep_until(till: Option<Instant>) -> bool {
 
 enum Request {
     Fetch(Identity, Priority),
+    Update(Identity, tls::Certs),
     Forget(Identity),
 }
 
 pub struct SecretManagerConfig {
     time_conv: crate::time::Converter,
     concurrency: u16,
+    refresh: RefreshPolicy,
+    cache: Option<CertCache>,
+    // Certificates loaded from the cache, served until they are refreshed.
+    cached: Vec<(Identity, tls::Certs)>,
+    // How long cached certificates are kept without being fetched.
+    unused_cached_timeout: Duration,
 }
 
 /// SecretManager provides a wrapper around a CaClient with caching.
@@ -382,25 +738,60 @@ pub struct SecretManager {
     // sent for must have a corresponding entry in the worker's certs map (which is where the
     // result can be read from).
     requests: mpsc::Sender<Request>,
+    // Trust bundles and revocation lists the client receives alongside certificates.
+    trust_bundles: tls::TrustBundles,
+    crls: tls::Crls,
 }
 
 impl SecretManager {
-    pub fn new(cfg: crate::config::Config) -> Result<Self, Error> {
+    pub async fn new(cfg: crate::config::Config) -> Result<Self, Error> {
         let caclient = CaClient::new(
             cfg.ca_address.unwrap(),
             cfg.ca_root_cert,
             cfg.auth,
             cfg.proxy_mode == ProxyMode::Shared,
+            cfg.key_algorithm,
+            cfg.secret_ttl,
         )?;
-        Ok(Self::new_with_client(caclient))
+        let (cache, cached) = match &cfg.cert_cache {
+            Some(cache_cfg) => {
+                let cache = CertCache::new(cache_cfg).await?;
+                let cached = cache.load().await;
+                (Some(cache), cached)
+            }
+            None => (None, Vec::new()),
+        };
+        Ok(Self::new_internal(
+            Box::new(caclient),
+            SecretManagerConfig {
+                time_conv: crate::time::Converter::new(),
+                concurrency: 8,
+                refresh: cfg.cert_refresh,
+                cache,
+                cached,
+                unused_cached_timeout: UNUSED_CACHED_TIMEOUT,
+            },
+        )
+        .0)
     }
 
     pub fn new_with_client<C: 'static + CaClientTrait>(client: C) -> Self {
+        Self::new_with_client_and_refresh(client, RefreshPolicy::default())
+    }
+
+    pub fn new_with_client_and_refresh<C: 'static + CaClientTrait>(
+        client: C,
+        refresh: RefreshPolicy,
+    ) -> Self {
         Self::new_internal(
             Box::new(client),
             SecretManagerConfig {
                 time_conv: crate::time::Converter::new(),
                 concurrency: 8,
+                refresh,
+                cache: None,
+                cached: Vec::new(),
+                unused_cached_timeout: UNUSED_CACHED_TIMEOUT,
             },
         )
         .0
@@ -416,11 +807,36 @@ impl SecretManager {
             Self {
                 worker,
                 requests: tx,
+                trust_bundles: Default::default(),
+                crls: Default::default(),
             },
             handle,
         )
     }
 
+    /// with_trust sets the trust bundles and revocation lists the client fills, so peers are
+    /// verified against them as well.
+    pub fn with_trust(mut self, trust_bundles: tls::TrustBundles, crls: tls::Crls) -> Self {
+        self.trust_bundles = trust_bundles;
+        self.crls = crls;
+        self
+    }
+
+    pub fn trust_bundles(&self) -> &tls::TrustBundles {
+        &self.trust_bundles
+    }
+
+    pub fn crls(&self) -> &tls::Crls {
+        &self.crls
+    }
+
+    /// set_metrics starts recording the state of certificates, and the requests made for them.
+    pub fn set_metrics(&self, metrics: Arc<Metrics>) {
+        if self.worker.metrics.set(metrics).is_ok() {
+            tokio::spawn(Worker::record_certs(Arc::downgrade(&self.worker)));
+        }
+    }
+
     async fn post(&self, req: Request) {
         if let Err(e) = self.requests.send(req).await {
             unreachable!("SecretManager worker died: {e}");
@@ -433,9 +849,10 @@ impl SecretManager {
         pri: Priority,
     ) -> Result<watch::Receiver<CertState>, Error> {
         let mut certs = self.worker.certs.lock().await;
-        match certs.get(id) {
+        match certs.get_mut(id) {
             // Identity found in cache and is already being refreshed. Bump the priority if needed.
             Some(st) => {
+                st.unused = false;
                 let rx = st.rx.clone();
                 drop(certs);
 
@@ -449,7 +866,12 @@ impl SecretManager {
             // New identity, start managing it and return the newly created channel.
             None => {
                 let (tx, rx) = watch::channel(CertState::Initializing(pri));
-                certs.insert(id.to_owned(), CertChannel { rx: rx.clone(), tx });
+                let chan = CertChannel {
+                    rx: rx.clone(),
+                    tx,
+                    unused: false,
+                };
+                certs.insert(id.to_owned(), chan);
                 drop(certs);
                 // Notify the background worker to start refreshing the certificate.
                 self.post(Request::Fetch(id.to_owned(), pri)).await;
@@ -495,6 +917,15 @@ impl SecretManager {
         self.fetch_certificate_pri(id, Priority::RealTime).await
     }
 
+    /// update_certificate replaces the certificate of an identity with one the client obtained
+    /// without being asked, such as a rotated certificate file. Identities that are not managed
+    /// are ignored.
+    pub async fn update_certificate(&self, id: &Identity, certs: tls::Certs) {
+        if self.worker.has_id(id).await {
+            self.post(Request::Update(id.clone(), certs)).await;
+        }
+    }
+
     pub async fn forget_certificate(&self, id: &Identity) {
         if self.worker.certs.lock().await.remove(id).is_some() {
             self.post(Request::Forget(id.clone())).await;
@@ -535,6 +966,7 @@ pub mod mock {
         pub cert_lifetime: Duration,
         pub fetch_latency: Duration,
         pub epoch: Option<SystemTime>,
+        pub key_algorithm: Option<crate::tls::KeyAlgorithm>,
     }
 
     pub fn new_secret_manager(cert_lifetime: Duration) -> Arc<SecretManager> {
@@ -542,6 +974,7 @@ pub mod mock {
             cert_lifetime,
             fetch_latency: Duration::ZERO,
             epoch: None,
+            key_algorithm: None,
         })
     }
 
@@ -553,6 +986,7 @@ pub mod mock {
             cert_lifetime: cfg.cert_lifetime,
             fetch_latency: cfg.fetch_latency,
             time_conv: time_conv.clone(),
+            key_algorithm: cfg.key_algorithm,
         });
         Arc::new(
             SecretManager::new_internal(
@@ -560,6 +994,10 @@ pub mod mock {
                 super::SecretManagerConfig {
                     time_conv,
                     concurrency: 2,
+                    refresh: Default::default(),
+                    cache: None,
+                    cached: Vec::new(),
+                    unused_cached_timeout: super::UNUSED_CACHED_TIMEOUT,
                 },
             )
             .0,
@@ -739,12 +1177,17 @@ mod tests {
             time_conv: time_conv.clone(),
             fetch_latency: SEC,
             cert_lifetime: 2 * CERT_HALFLIFE,
+            key_algorithm: None,
         });
         let (secret_manager, worker) = SecretManager::new_internal(
             Box::new(caclient.clone()),
             SecretManagerConfig {
                 time_conv,
                 concurrency,
+                refresh: Default::default(),
+                cache: None,
+                cached: Vec::new(),
+                unused_cached_timeout: UNUSED_CACHED_TIMEOUT,
             },
         );
         Test {
@@ -964,6 +1407,246 @@ mod tests {
         test.tear_down().await;
     }
 
+    #[tokio::test]
+    async fn test_cert_cache() {
+        let dir = crate::test_helpers::temp_dir();
+        let dir = dir.path();
+        std::fs::write(dir.join("key"), "secret").unwrap();
+        let cfg = CertCacheConfig {
+            dir: dir.join("certs"),
+            key_file: dir.join("key"),
+        };
+        let cached = identity("cached");
+        let cached_certs = crate::tls::generate_test_certs(
+            &cached.clone().into(),
+            Duration::ZERO,
+            Duration::from_secs(3600),
+        );
+        let unused = identity("unused");
+        let unused_certs = crate::tls::generate_test_certs(
+            &unused.clone().into(),
+            Duration::ZERO,
+            Duration::from_secs(3600),
+        );
+        let writer = CertCache::new(&cfg).await.unwrap();
+        writer.store(&cached, &cached_certs);
+        writer.store(&unused, &unused_certs);
+        let cache = CertCache::new(&cfg).await.unwrap();
+        let mut loaded = Vec::new();
+        for _ in 0..100 {
+            loaded = cache.load().await;
+            if loaded.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let caclient = MockCaClient::new(Default::default());
+        let (sm, _) = SecretManager::new_internal(
+            Box::new(caclient.clone()),
+            SecretManagerConfig {
+                time_conv: crate::time::Converter::new(),
+                concurrency: 1,
+                refresh: Default::default(),
+                cache: Some(cache),
+                cached: loaded,
+                unused_cached_timeout: Duration::from_millis(200),
+            },
+        );
+        // The cached certificate is served without asking the CA.
+        let certs = sm.fetch_certificate(&cached).await.unwrap();
+        assert_eq!(certs.to_pem().unwrap(), cached_certs.to_pem().unwrap());
+        assert!(caclient.fetches().await.is_empty());
+
+        // Fetched certificates are cached, forgotten ones are removed.
+        let fetched = identity("fetched");
+        let fetched_certs = sm.fetch_certificate(&fetched).await.unwrap();
+        sm.forget_certificate(&cached).await;
+        // So are cached certificates that are not fetched soon after starting.
+        for _ in 0..100 {
+            if sm.cache_len().await == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(sm.cache_len().await, 1);
+        drop(sm);
+        let mut loaded = Vec::new();
+        for _ in 0..100 {
+            loaded = CertCache::new(&cfg).await.unwrap().load().await;
+            if loaded.len() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].0, fetched);
+        assert_eq!(
+            loaded[0].1.to_pem().unwrap(),
+            fetched_certs.to_pem().unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_metrics() {
+        use prometheus_client::encoding::text::encode;
+        use prometheus_client::registry::Registry;
+
+        let mut registry = Registry::default();
+        let sm = mock::new_secret_manager(Duration::from_secs(3600));
+        sm.set_metrics(Arc::new(Metrics::from(&mut registry)));
+        let id = identity("metrics");
+        let failing = Identity::Spiffe {
+            trust_domain: "error".to_string(),
+            namespace: "forgotten".to_string(),
+            service_account: "sa".to_string(),
+        };
+        sm.fetch_certificate(&id).await.unwrap();
+        assert_matches!(sm.fetch_certificate(&failing).await, Err(Error::Forgotten));
+        tokio::time::sleep(CERT_METRICS_INTERVAL).await;
+
+        let mut buf = String::new();
+        encode(&mut buf, &registry).unwrap();
+        let expiry = format!("istio_cert_expiry_seconds{{identity=\"{id}\"}} ");
+        let left: u64 = buf
+            .lines()
+            .find_map(|l| l.strip_prefix(&expiry))
+            .expect("expiry is recorded")
+            .parse()
+            .unwrap();
+        assert!((3500..=3600).contains(&left), "{left}");
+        assert!(buf.contains(r#"istio_csr_requests_total{outcome="success"} 1"#));
+        assert!(buf.contains(r#"istio_csr_requests_total{outcome="failure"}"#));
+        assert!(buf.contains(r#"istio_csr_request_duration_seconds_count{outcome="success"} 1"#));
+        assert!(buf.contains(r#"istio_managed_identities{state="available"} 1"#));
+        assert!(buf.contains(r#"istio_managed_identities{state="unavailable"} 1"#));
+        assert!(buf.contains(r#"istio_managed_identities{state="initializing"} 0"#));
+        // Refreshes scheduled in the future are not waiting to start yet.
+        assert!(buf.contains(r#"istio_cert_queue_depth{priority="background"} 0"#));
+        assert!(buf.contains(r#"istio_cert_queue_depth{priority="warmup"} 0"#));
+        assert!(buf.contains(r#"istio_cert_queue_depth{priority="realtime"} 0"#));
+
+        // Forgotten identities are no longer reported.
+        sm.forget_certificate(&id).await;
+        tokio::time::sleep(CERT_METRICS_INTERVAL).await;
+        let mut buf = String::new();
+        encode(&mut buf, &registry).unwrap();
+        assert!(!buf.contains(&expiry));
+        assert!(buf.contains(r#"istio_managed_identities{state="available"} 0"#));
+    }
+
+    #[test]
+    fn refresh_policy() {
+        let certs = crate::tls::generate_test_certs(
+            &Identity::default().into(),
+            Duration::ZERO,
+            Duration::from_secs(100),
+        );
+        let (not_before, not_after) = (certs.not_before(), certs.not_after());
+
+        let policy = RefreshPolicy::default();
+        assert_eq!(
+            policy.refresh_at(&certs),
+            not_before + Duration::from_secs(50)
+        );
+        let policy = RefreshPolicy {
+            at: RefreshAt::Percent(80),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.refresh_at(&certs),
+            not_before + Duration::from_secs(80)
+        );
+        let policy = RefreshPolicy {
+            at: RefreshAt::Grace(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.refresh_at(&certs),
+            not_after - Duration::from_secs(10)
+        );
+        // A grace period longer than the lifetime refreshes right away.
+        let policy = RefreshPolicy {
+            at: RefreshAt::Grace(Duration::from_secs(1000)),
+            ..Default::default()
+        };
+        assert_eq!(policy.refresh_at(&certs), not_before);
+
+        let policy = RefreshPolicy {
+            jitter: Duration::from_secs(20),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let at = policy.refresh_at(&certs);
+            assert!(at >= not_before + Duration::from_secs(30));
+            assert!(at <= not_before + Duration::from_secs(50));
+        }
+        // Jitter never moves the refresh before the certificate is valid.
+        let policy = RefreshPolicy {
+            at: RefreshAt::Percent(10),
+            jitter: Duration::from_secs(1000),
+            ..Default::default()
+        };
+        assert!(policy.refresh_at(&certs) >= not_before);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_fetches() {
+        let mut pending = PendingFetches::default();
+        let now = Instant::now();
+        let (a, b, c) = (identity("a"), identity("b"), identity("c"));
+        pending.push(
+            a.clone(),
+            PendingPriority(Priority::Background, now + Duration::from_secs(10)),
+        );
+        pending.push(b.clone(), PendingPriority(Priority::Background, now));
+        pending.push_increase(c.clone(), PendingPriority(Priority::Warmup, now));
+        // Lower priorities do not replace queued fetches.
+        pending.push_increase(c.clone(), PendingPriority(Priority::Background, now));
+        assert_eq!(pending.next(), Some(now));
+        assert_eq!(pending.depth(Priority::Background), 1);
+        assert_eq!(pending.depth(Priority::Warmup), 1);
+
+        assert_eq!(pending.pop(), Some(c));
+        assert_eq!(pending.pop(), Some(b));
+        assert_eq!(pending.depth(Priority::Warmup), 0);
+        assert_eq!(pending.depth(Priority::Background), 0);
+        assert_eq!(pending.next(), Some(now + Duration::from_secs(10)));
+
+        // Scheduled refreshes are counted once due, and move up when requested.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(pending.next(), Some(now + Duration::from_secs(10)));
+        assert_eq!(pending.depth(Priority::Background), 1);
+        pending.push_increase(a.clone(), PendingPriority(Priority::RealTime, Instant::now()));
+        assert_eq!(pending.depth(Priority::Background), 0);
+        assert_eq!(pending.depth(Priority::RealTime), 1);
+        pending.remove(&a);
+        assert_eq!(pending.depth(Priority::RealTime), 0);
+        assert_eq!(pending.next(), None);
+    }
+
+    #[test]
+    fn refresh_backoff() {
+        let policy = RefreshPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let cases = [(1, 1), (2, 2), (3, 4), (4, 8), (5, 10), (6, 10), (u32::MAX, 10)];
+        for (failures, secs) in cases {
+            let want = Duration::from_secs(secs);
+            for _ in 0..20 {
+                let backoff = policy.backoff(failures);
+                assert!(backoff <= want, "{failures}: {backoff:?} > {want:?}");
+                assert!(backoff >= want * 4 / 5, "{failures}: {backoff:?} < {want:?}");
+            }
+        }
+
+        // By default, failed fetches are retried after about a minute.
+        let backoff = RefreshPolicy::default().backoff(1);
+        assert!(backoff >= Duration::from_secs(48) && backoff <= Duration::from_secs(60));
+    }
+
     #[test]
     fn identity_from_string() {
         assert_eq!(
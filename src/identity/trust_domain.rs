@@ -0,0 +1,152 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use super::Identity;
+
+/// TrustDomainAliases are the groups of trust domains that are treated as equivalent to a mesh
+/// trust domain, and to each other. This allows peers to keep their identity while the mesh
+/// migrates from one trust domain to another.
+///
+/// Each trust domain maps to the trust domains it is equivalent to, including itself.
+#[derive(serde::Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrustDomainAliases(Arc<BTreeMap<String, BTreeSet<String>>>);
+
+impl TrustDomainAliases {
+    /// new makes trust_domain and its aliases equivalent. Without aliases, trust domains are only
+    /// equivalent to themselves.
+    pub fn new(trust_domain: &str, aliases: impl IntoIterator<Item = String>) -> Self {
+        let mut domains: BTreeSet<String> = aliases
+            .into_iter()
+            .filter(|td| !td.is_empty() && td != trust_domain)
+            .collect();
+        if domains.is_empty() {
+            return TrustDomainAliases::default();
+        }
+        domains.insert(trust_domain.to_string());
+        TrustDomainAliases(Arc::new(
+            domains
+                .iter()
+                .map(|td| (td.clone(), domains.clone()))
+                .collect(),
+        ))
+    }
+
+    /// union makes trust domains equivalent if they are equivalent in any of the aliases. Groups
+    /// sharing a trust domain are not merged, so each group only extends the trust domains in it.
+    pub fn union<'a>(all: impl IntoIterator<Item = &'a TrustDomainAliases>) -> Self {
+        let mut union: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for aliases in all {
+            for (td, equivalent) in aliases.0.iter() {
+                union
+                    .entry(td.clone())
+                    .or_default()
+                    .extend(equivalent.iter().cloned());
+            }
+        }
+        TrustDomainAliases(Arc::new(union))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// equivalent returns true if the trust domains are the same, or aliases of each other.
+    pub fn equivalent(&self, a: &str, b: &str) -> bool {
+        a == b || self.0.get(a).map_or(false, |equivalent| equivalent.contains(b))
+    }
+
+    /// aliases returns the trust domains equivalent to trust_domain, other than itself.
+    pub fn aliases<'a>(&'a self, trust_domain: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .get(trust_domain)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .filter(move |td| *td != trust_domain)
+    }
+
+    /// identities_match returns true if the identities are the same, apart from trust domains that
+    /// are equivalent.
+    pub fn identities_match(&self, a: &Identity, b: &Identity) -> bool {
+        let (
+            Identity::Spiffe {
+                trust_domain: a_td,
+                namespace: a_ns,
+                service_account: a_sa,
+            },
+            Identity::Spiffe {
+                trust_domain: b_td,
+                namespace: b_ns,
+                service_account: b_sa,
+            },
+        ) = (a, b);
+        a_ns == b_ns && a_sa == b_sa && self.equivalent(a_td, b_td)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(trust_domain: &str) -> Identity {
+        Identity::Spiffe {
+            trust_domain: trust_domain.to_string(),
+            namespace: "ns".to_string(),
+            service_account: "sa".to_string(),
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let aliases = TrustDomainAliases::new(
+            "new.example",
+            ["old.example".to_string(), "older.example".to_string()],
+        );
+        assert!(aliases.equivalent("new.example", "old.example"));
+        assert!(aliases.equivalent("old.example", "older.example"));
+        assert!(aliases.equivalent("other.example", "other.example"));
+        assert!(!aliases.equivalent("new.example", "other.example"));
+        assert_eq!(
+            aliases.aliases("old.example").collect::<Vec<_>>(),
+            vec!["new.example", "older.example"]
+        );
+        assert_eq!(aliases.aliases("other.example").count(), 0);
+
+        assert!(aliases.identities_match(&identity("new.example"), &identity("old.example")));
+        assert!(!aliases.identities_match(&identity("new.example"), &identity("other.example")));
+
+        let none = TrustDomainAliases::new("new.example", [String::new()]);
+        assert!(none.is_empty());
+        assert!(!none.equivalent("new.example", "old.example"));
+    }
+
+    #[test]
+    fn union() {
+        let a = TrustDomainAliases::new("a.example", ["old.example".to_string()]);
+        let b = TrustDomainAliases::new("b.example", ["old.example".to_string()]);
+        let union = TrustDomainAliases::union([&a, &b, &TrustDomainAliases::default()]);
+        assert!(union.equivalent("a.example", "old.example"));
+        assert!(union.equivalent("b.example", "old.example"));
+        assert_eq!(
+            union.aliases("old.example").collect::<Vec<_>>(),
+            vec!["a.example", "b.example"]
+        );
+        // Trust domains aliasing the same one are not aliases of each other.
+        assert!(!union.equivalent("a.example", "b.example"));
+        assert_eq!(TrustDomainAliases::union([&a]), a);
+    }
+}
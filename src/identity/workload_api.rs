@@ -0,0 +1,538 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use boring::pkey::PKey;
+use boring::x509::X509;
+use hyper::client::conn::http2;
+use hyper::{Request, Response, Uri};
+use tokio::net::UnixStream;
+use tokio::sync::watch;
+use tonic::body::BoxBody;
+use tonic::metadata::MetadataValue;
+use tower_hyper_http_body_compat::{HttpBody04ToHttpBody1, HttpBody1ToHttpBody04};
+use tracing::{debug, info, warn};
+
+use crate::identity::{CaClientTrait, Error, Identity, RefreshPolicy};
+use crate::tls::{self, DefaultIncoming};
+use crate::xds::spiffe::spiffe_workload_api_client::SpiffeWorkloadApiClient;
+use crate::xds::spiffe::{X509svidRequest, X509svidResponse};
+
+/// Every Workload API request must carry this header, so the agent can reject requests that were
+/// forwarded on behalf of another workload.
+const SECURITY_HEADER: &str = "workload.spiffe.io";
+
+/// The source name of the revocation lists pushed by the Workload API.
+const CRL_SOURCE: &str = "workload-api";
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(15);
+
+/// The latest SVIDs from the Workload API. None until the first response, or the error of the
+/// first connection attempt if it failed.
+type Svids = Option<Result<Arc<HashMap<Identity, tls::Certs>>, Error>>;
+
+/// WorkloadApiClient fetches certificates from a SPIFFE Workload API agent, such as SPIRE, over a
+/// Unix domain socket. Rather than requesting each certificate, a single stream is kept open and
+/// the agent pushes new SVIDs whenever they are issued or rotated. The bundles of federated trust
+/// domains and the revocation lists pushed with them are kept up to date as well.
+pub struct WorkloadApiClient {
+    svids: watch::Receiver<Svids>,
+    // The certs last returned for each identity, so a refresh waits for the agent to rotate them.
+    returned: Mutex<HashMap<Identity, tls::Certs>>,
+    trust_bundles: tls::TrustBundles,
+    crls: tls::Crls,
+}
+
+impl WorkloadApiClient {
+    /// new streams SVIDs from the Workload API at the socket path in the background.
+    pub fn new(path: PathBuf) -> WorkloadApiClient {
+        let (tx, rx) = watch::channel(None);
+        let trust_bundles = tls::TrustBundles::default();
+        let crls = tls::Crls::default();
+        tokio::spawn(watch_svids(path, tx, trust_bundles.clone(), crls.clone()));
+        WorkloadApiClient {
+            svids: rx,
+            returned: Default::default(),
+            trust_bundles,
+            crls,
+        }
+    }
+
+    /// trust_bundles returns the bundles of federated trust domains pushed by the agent.
+    pub fn trust_bundles(&self) -> &tls::TrustBundles {
+        &self.trust_bundles
+    }
+
+    /// crls returns the revocation lists pushed by the agent.
+    pub fn crls(&self) -> &tls::Crls {
+        &self.crls
+    }
+}
+
+#[async_trait]
+impl CaClientTrait for WorkloadApiClient {
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        let mut svids = self.svids.clone();
+        loop {
+            if let Some(res) = self.current(&mut svids, id) {
+                return res;
+            }
+            svids
+                .changed()
+                .await
+                .map_err(|_| Error::WorkloadApi("stream closed".to_string()))?;
+        }
+    }
+
+    // SVIDs are rotated by the agent, so refresh on its schedule rather than the configured one.
+    fn refresh_at(&self, certs: &tls::Certs, _: &RefreshPolicy) -> SystemTime {
+        certs.refresh_at()
+    }
+}
+
+impl WorkloadApiClient {
+    /// current returns the SVID of the identity, or None if we need to wait for the next update.
+    fn current(
+        &self,
+        svids: &mut watch::Receiver<Svids>,
+        id: &Identity,
+    ) -> Option<Result<tls::Certs, Error>> {
+        let svids = svids.borrow_and_update();
+        let svids = match svids.as_ref()? {
+            Ok(svids) => svids,
+            Err(e) => return Some(Err(e.clone())),
+        };
+        let Some(certs) = svids.get(id) else {
+            return Some(Err(Error::MissingSvid(id.clone())));
+        };
+        let mut returned = self.returned.lock().unwrap();
+        // The agent rotates SVIDs on its own schedule. Returning the same certs again once they
+        // are due for refresh would only have them refreshed again right away.
+        if certs.refresh_at() <= SystemTime::now() && returned.get(id) == Some(certs) {
+            debug!(%id, "waiting for the workload api to rotate the SVID");
+            return None;
+        }
+        returned.insert(id.clone(), certs.clone());
+        Some(Ok(certs.clone()))
+    }
+}
+
+/// watch_svids keeps a stream to the Workload API open, reconnecting with backoff, and publishes
+/// every response. Once SVIDs were received, they are kept while reconnecting.
+async fn watch_svids(
+    path: PathBuf,
+    tx: watch::Sender<Svids>,
+    trust_bundles: tls::TrustBundles,
+    crls: tls::Crls,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let mut received = false;
+        let err = stream_svids(&path, &tx, &trust_bundles, &crls, &mut received).await;
+        warn!(path=%path.display(), "workload api stream failed: {err}");
+        if !matches!(*tx.borrow(), Some(Ok(_))) {
+            tx.send_replace(Some(Err(err)));
+        }
+        if tx.is_closed() {
+            return;
+        }
+        backoff = if received {
+            INITIAL_BACKOFF
+        } else {
+            std::cmp::min(MAX_BACKOFF, backoff * 2)
+        };
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// stream_svids publishes the responses of a single stream, until it fails.
+async fn stream_svids(
+    path: &Path,
+    tx: &watch::Sender<Svids>,
+    trust_bundles: &tls::TrustBundles,
+    crls: &tls::Crls,
+    received: &mut bool,
+) -> Error {
+    let channel = match UdsGrpcChannel::connect(path).await {
+        Ok(channel) => channel,
+        Err(e) => return e,
+    };
+    let mut req = tonic::Request::new(X509svidRequest {});
+    req.metadata_mut()
+        .insert(SECURITY_HEADER, MetadataValue::from_static("true"));
+    let mut stream = match SpiffeWorkloadApiClient::new(channel)
+        .fetch_x509svid(req)
+        .await
+    {
+        Ok(resp) => resp.into_inner(),
+        Err(e) => return e.into(),
+    };
+    loop {
+        let resp = match stream.message().await {
+            Ok(Some(resp)) => resp,
+            Ok(None) => return Error::WorkloadApi("stream ended".to_string()),
+            Err(e) => return e.into(),
+        };
+        if let Err(e) = update_trust(&resp, trust_bundles, crls) {
+            // Keep the previous bundles and lists, but still use the SVIDs.
+            warn!("invalid workload api trust bundles: {e}");
+        }
+        match parse_svids(&resp) {
+            Ok(svids) => {
+                info!(count = svids.len(), "received SVIDs from workload api");
+                *received = true;
+                tx.send_replace(Some(Ok(Arc::new(svids))));
+            }
+            // Keep the previous SVIDs; the agent sends a new response on the next change.
+            Err(e) => warn!("invalid workload api response: {e}"),
+        }
+    }
+}
+
+/// update_trust replaces the federated bundles and revocation lists with those of the response.
+fn update_trust(
+    resp: &X509svidResponse,
+    trust_bundles: &tls::TrustBundles,
+    crls: &tls::Crls,
+) -> Result<(), Error> {
+    let mut bundles = HashMap::new();
+    for (td, der) in &resp.federated_bundles {
+        // Bundles are keyed by the SPIFFE ID of the trust domain.
+        let td = td.strip_prefix("spiffe://").unwrap_or(td);
+        bundles.insert(td.to_string(), parse_der_certs(der)?);
+    }
+    crls.set(CRL_SOURCE, &resp.crl)
+        .map_err(|e| Error::WorkloadApi(e.to_string()))?;
+    trust_bundles.replace(tls::BundleSource::WorkloadApi, bundles)?;
+    Ok(())
+}
+
+fn parse_svids(resp: &X509svidResponse) -> Result<HashMap<Identity, tls::Certs>, Error> {
+    let mut svids = HashMap::new();
+    for svid in &resp.svids {
+        let id = match Identity::from_str(&svid.spiffe_id) {
+            Ok(id) => id,
+            Err(e) => {
+                debug!("skipping SVID: {e}");
+                continue;
+            }
+        };
+        let mut certs = parse_der_certs(&svid.x509_svid)?.into_iter();
+        let leaf = certs
+            .next()
+            .ok_or_else(|| Error::EmptyResponse(id.clone()))?;
+        // The chain sent to peers is the intermediates, followed by the roots of the bundle.
+        let chain = certs.chain(parse_der_certs(&svid.bundle)?).collect();
+        let key = PKey::private_key_from_der(&svid.x509_svid_key).map_err(tls::Error::from)?;
+        svids.insert(id, tls::Certs::new(key, leaf, chain));
+    }
+    Ok(svids)
+}
+
+/// parse_der_certs splits concatenated DER certificates.
+fn parse_der_certs(mut der: &[u8]) -> Result<Vec<X509>, Error> {
+    let mut certs = Vec::new();
+    while !der.is_empty() {
+        let Some(len) = der_len(der).filter(|len| *len <= der.len()) else {
+            return Err(Error::WorkloadApi("malformed DER certificate".to_string()));
+        };
+        let (cert, rest) = der.split_at(len);
+        certs.push(X509::from_der(cert).map_err(tls::Error::from)?);
+        der = rest;
+    }
+    Ok(certs)
+}
+
+/// der_len returns the length of the DER element at the start of der, including its tag and
+/// length octets.
+fn der_len(der: &[u8]) -> Option<usize> {
+    let (&len, rest) = der.get(1..)?.split_first()?;
+    if len < 0x80 {
+        return Some(2 + len as usize);
+    }
+    // Long form: the low bits give the number of length octets. DER forbids the indefinite form,
+    // and no certificate needs more than four octets.
+    let octets = (len & 0x7f) as usize;
+    if octets == 0 || octets > 4 {
+        return None;
+    }
+    let len = rest
+        .get(..octets)?
+        .iter()
+        .fold(0, |len, b| (len << 8) | *b as usize);
+    Some(2 + octets + len)
+}
+
+type BoxBody1 = HttpBody04ToHttpBody1<BoxBody>;
+
+/// UdsGrpcChannel sends gRPC requests over a single HTTP/2 connection to a Unix domain socket.
+#[derive(Clone)]
+struct UdsGrpcChannel(http2::SendRequest<BoxBody1>);
+
+impl UdsGrpcChannel {
+    async fn connect(path: &Path) -> Result<UdsGrpcChannel, Error> {
+        let io = UnixStream::connect(path)
+            .await
+            .map_err(|e| Error::WorkloadApi(format!("connect {}: {e}", path.display())))?;
+        let (sender, conn) = crate::hyper_util::http2_client()
+            .handshake(io)
+            .await
+            .map_err(|e| Error::WorkloadApi(format!("handshake: {e}")))?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                debug!("workload api connection closed: {e}");
+            }
+        });
+        Ok(UdsGrpcChannel(sender))
+    }
+}
+
+impl tower::Service<Request<BoxBody>> for UdsGrpcChannel {
+    type Response = Response<HttpBody1ToHttpBody04<DefaultIncoming>>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        let mut req = req.map(HttpBody04ToHttpBody1::new);
+        // The authority is meaningless over a Unix domain socket, but HTTP/2 requires one.
+        let uri = Uri::builder()
+            .scheme("http")
+            .authority("localhost")
+            .path_and_query(req.uri().path_and_query().unwrap().to_owned())
+            .build()
+            .unwrap();
+        *req.uri_mut() = uri;
+        let future = self.0.send_request(req);
+        Box::pin(async move {
+            let res = future.await?;
+            Ok(res
+                .map(DefaultIncoming::Some)
+                .map(HttpBody1ToHttpBody04::new))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use boring::x509::X509Crl;
+    use futures::Stream;
+    use matches::assert_matches;
+    use tempfile::TempDir;
+    use tokio::net::UnixListener;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    use super::*;
+    use crate::test_helpers::{temp_dir, test_identity as identity};
+    use crate::tls::mock::CertGenerator;
+    use crate::xds::spiffe::spiffe_workload_api_server::{
+        SpiffeWorkloadApi, SpiffeWorkloadApiServer,
+    };
+    use crate::xds::spiffe::X509svid;
+
+    const TEST_PKEY: &[u8] = include_bytes!("../tls/key.pem");
+    const TEST_ROOT: &[u8] = include_bytes!("../tls/root-cert.pem");
+    const TEST_CRL: &[u8] = include_bytes!("../tls/crl.pem");
+
+    type Responses = mpsc::Receiver<Result<X509svidResponse, tonic::Status>>;
+
+    /// TestAgent streams the responses sent on its channel to the first caller.
+    #[derive(Clone)]
+    struct TestAgent(Arc<Mutex<Option<Responses>>>);
+
+    #[async_trait]
+    impl SpiffeWorkloadApi for TestAgent {
+        type FetchX509SVIDStream =
+            Pin<Box<dyn Stream<Item = Result<X509svidResponse, tonic::Status>> + Send>>;
+
+        async fn fetch_x509svid(
+            &self,
+            request: tonic::Request<X509svidRequest>,
+        ) -> Result<tonic::Response<Self::FetchX509SVIDStream>, tonic::Status> {
+            if request.metadata().get(SECURITY_HEADER).is_none() {
+                return Err(tonic::Status::invalid_argument("missing security header"));
+            }
+            let responses = self.0.lock().unwrap().take().unwrap();
+            Ok(tonic::Response::new(Box::pin(ReceiverStream::new(
+                responses,
+            ))))
+        }
+    }
+
+    fn spawn() -> (
+        TempDir,
+        PathBuf,
+        mpsc::Sender<Result<X509svidResponse, tonic::Status>>,
+    ) {
+        let dir = temp_dir();
+        let path = dir.path().join("agent.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (tx, rx) = mpsc::channel(4);
+        let srv = SpiffeWorkloadApiServer::new(TestAgent(Arc::new(Mutex::new(Some(rx)))));
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let srv = srv.clone();
+                tokio::spawn(crate::hyper_util::http2_server().serve_connection(
+                    socket,
+                    tower_hyper_http_body_compat::TowerService03HttpServiceAsHyper1HttpService::new(
+                        srv,
+                    ),
+                ));
+            }
+        });
+        (dir, path, tx)
+    }
+
+    /// svid returns an SVID for the identity, valid from not_before for an hour.
+    fn svid(id: &Identity, not_before: SystemTime) -> (X509svid, X509) {
+        let certs = CertGenerator::default().new_certs(
+            &id.clone().into(),
+            not_before,
+            not_before + Duration::from_secs(3600),
+        );
+        let key = PKey::private_key_from_pem(TEST_PKEY).unwrap();
+        let svid = X509svid {
+            spiffe_id: id.to_string(),
+            x509_svid: certs.x509().to_der().unwrap(),
+            x509_svid_key: key.private_key_to_der().unwrap(),
+            bundle: X509::from_pem(TEST_ROOT).unwrap().to_der().unwrap(),
+            hint: String::new(),
+        };
+        (svid, certs.x509().clone())
+    }
+
+    fn response(svids: Vec<X509svid>) -> Result<X509svidResponse, tonic::Status> {
+        Ok(X509svidResponse {
+            svids,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn fetch_certificate() {
+        let (_dir, path, agent) = spawn();
+        let client = WorkloadApiClient::new(path);
+        let id = identity("sa");
+
+        let (svid, leaf) = svid(&id, SystemTime::now());
+        agent.send(response(vec![svid])).await.unwrap();
+        let certs = client.fetch_certificate(&id).await.unwrap();
+        assert_eq!(certs.x509().to_der().unwrap(), leaf.to_der().unwrap());
+        assert_eq!(
+            certs.iter_chain().next().unwrap().to_der().unwrap(),
+            X509::from_pem(TEST_ROOT).unwrap().to_der().unwrap()
+        );
+
+        assert_matches!(
+            client.fetch_certificate(&identity("other")).await,
+            Err(Error::MissingSvid(_))
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_waits_for_rotation() {
+        let (_dir, path, agent) = spawn();
+        let client = Arc::new(WorkloadApiClient::new(path));
+        let id = identity("sa");
+
+        // Already past its refresh time, but still returned the first time.
+        let (stale, stale_leaf) = svid(&id, SystemTime::now() - Duration::from_secs(3000));
+        agent.send(response(vec![stale.clone()])).await.unwrap();
+        let certs = client.fetch_certificate(&id).await.unwrap();
+        assert_eq!(certs.x509().to_der().unwrap(), stale_leaf.to_der().unwrap());
+
+        // Refreshing it again waits for the agent to push a rotated SVID.
+        let refresh = {
+            let (client, id) = (client.clone(), id.clone());
+            tokio::spawn(async move { client.fetch_certificate(&id).await })
+        };
+        agent.send(response(vec![stale])).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!refresh.is_finished());
+
+        let (rotated, rotated_leaf) = svid(&id, SystemTime::now());
+        agent.send(response(vec![rotated])).await.unwrap();
+        let certs = refresh.await.unwrap().unwrap();
+        assert_eq!(
+            certs.x509().to_der().unwrap(),
+            rotated_leaf.to_der().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn federated_bundles_and_crls() {
+        let (_dir, path, agent) = spawn();
+        let client = WorkloadApiClient::new(path);
+        let id = identity("sa");
+
+        let (svid, _) = svid(&id, SystemTime::now());
+        let root = X509::from_pem(TEST_ROOT).unwrap().to_der().unwrap();
+        let crl = X509Crl::from_pem(TEST_CRL).unwrap().to_der().unwrap();
+        agent
+            .send(Ok(X509svidResponse {
+                svids: vec![svid],
+                crl: vec![crl],
+                federated_bundles: HashMap::from([(
+                    "spiffe://partner.example".to_string(),
+                    root,
+                )]),
+            }))
+            .await
+            .unwrap();
+        client.fetch_certificate(&id).await.unwrap();
+        assert_eq!(
+            client.trust_bundles().trust_domains(),
+            BTreeMap::from([("partner.example".to_string(), 1)])
+        );
+        assert!(!client.crls().is_empty());
+    }
+
+    #[test]
+    fn parse_concatenated_certs() {
+        let root = X509::from_pem(TEST_ROOT).unwrap().to_der().unwrap();
+        let der = [root.clone(), root].concat();
+        assert_eq!(parse_der_certs(&der).unwrap().len(), 2);
+        assert_matches!(
+            parse_der_certs(&der[..der.len() - 1]),
+            Err(Error::WorkloadApi(_))
+        );
+    }
+
+    #[tokio::test]
+    async fn unavailable() {
+        let path = std::env::temp_dir().join("ztunnel-workload-api-missing.sock");
+        let client = WorkloadApiClient::new(path);
+        assert_matches!(
+            client.fetch_certificate(&identity("sa")).await,
+            Err(Error::WorkloadApi(_))
+        );
+    }
+}
@@ -14,9 +14,13 @@
 
 pub mod admin;
 pub mod app;
+pub mod authz_log;
 pub mod baggage;
 pub mod cert_fetcher;
 pub mod config;
+pub mod ext_authz;
+pub mod fault;
+pub mod hot_restart;
 pub mod identity;
 pub mod metrics;
 pub mod proxy;
@@ -26,6 +26,9 @@ use ztunnel::*;
 // static GLOBAL: tcmalloc::TCMalloc = tcmalloc::TCMalloc;
 
 fn main() -> anyhow::Result<()> {
+    // This clears the socket activation environment, which is only safe before any other threads
+    // are started.
+    socket::capture_listen_fds()?;
     telemetry::setup_logging();
     let config: config::Config = config::parse_config()?;
 
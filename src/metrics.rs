@@ -17,7 +17,10 @@ use std::mem;
 use prometheus_client::registry::Registry;
 use tracing::error;
 
+pub mod crl;
+pub mod identity;
 mod meta;
+pub mod rbac;
 #[allow(non_camel_case_types)]
 pub mod traffic;
 pub mod xds;
@@ -28,6 +31,9 @@ pub struct Metrics {
     #[allow(dead_code)]
     meta: meta::Metrics,
     traffic: traffic::Metrics,
+    rbac: rbac::Metrics,
+    crl: crl::Metrics,
+    identity: identity::Metrics,
 }
 
 impl Metrics {
@@ -36,6 +42,9 @@ impl Metrics {
             xds: xds::Metrics::new(registry),
             meta: meta::Metrics::new(registry),
             traffic: traffic::Metrics::new(registry),
+            rbac: rbac::Metrics::new(registry),
+            crl: crl::Metrics::new(registry),
+            identity: identity::Metrics::new(registry),
         }
     }
 }
@@ -0,0 +1,73 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+
+use crate::metrics::Recorder;
+
+pub(super) struct Metrics {
+    pub(super) age: Family<Crl, Gauge>,
+    pub(super) rejections: Family<Crl, Counter>,
+}
+
+/// Crl identifies a certificate revocation list.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct Crl {
+    /// The file or URL the CRL is loaded from.
+    pub source: String,
+}
+
+/// CrlAge records the seconds since a CRL was issued.
+pub struct CrlAge(pub Crl);
+
+/// CrlRejection records a peer rejected because the CRL lists its certificate.
+pub struct CrlRejection(pub Crl);
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let age = Family::default();
+        registry.register(
+            "crl_age_seconds",
+            "The seconds since the certificate revocation list was issued",
+            age.clone(),
+        );
+        let rejections = Family::default();
+        registry.register(
+            "crl_rejections",
+            "The total number of peers rejected because their certificate was revoked",
+            rejections.clone(),
+        );
+
+        Self { age, rejections }
+    }
+}
+
+impl Recorder<CrlAge, u64> for super::Metrics {
+    fn record(&self, event: &CrlAge, seconds: u64) {
+        self.crl
+            .age
+            .get_or_create(&event.0)
+            .set(seconds.try_into().unwrap_or(i64::MAX));
+    }
+}
+
+impl Recorder<CrlRejection, u64> for super::Metrics {
+    fn record(&self, event: &CrlRejection, count: u64) {
+        self.crl.rejections.get_or_create(&event.0).inc_by(count);
+    }
+}
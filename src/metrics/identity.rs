@@ -0,0 +1,178 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+use crate::identity::{Identity, Priority};
+use crate::metrics::Recorder;
+
+pub(super) struct Metrics {
+    pub(super) cert_expiry: Family<CertExpiry, Gauge>,
+    pub(super) identities: Family<IdentityState, Gauge>,
+    pub(super) csr_requests: Family<CsrRequest, Counter>,
+    pub(super) csr_duration: Family<CsrRequest, Histogram, fn() -> Histogram>,
+    pub(super) queue_depth: Family<QueueDepth, Gauge>,
+}
+
+/// CertExpiry records the seconds until the certificate of an identity expires.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct CertExpiry {
+    pub identity: Identity,
+}
+
+/// IdentityState records the number of managed identities in a state.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct IdentityState {
+    pub state: CertStatus,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, EncodeLabelValue)]
+pub enum CertStatus {
+    initializing,
+    available,
+    unavailable,
+}
+
+/// CsrRequest records a certificate request, and how long it took.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct CsrRequest {
+    pub outcome: CsrOutcome,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, EncodeLabelValue)]
+pub enum CsrOutcome {
+    success,
+    failure,
+}
+
+/// QueueDepth records the number of due certificate fetches waiting to start at a priority.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct QueueDepth {
+    pub priority: QueuePriority,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, EncodeLabelValue)]
+pub enum QueuePriority {
+    background,
+    warmup,
+    realtime,
+}
+
+impl From<Priority> for QueuePriority {
+    fn from(pri: Priority) -> Self {
+        match pri {
+            Priority::Background => QueuePriority::background,
+            Priority::Warmup => QueuePriority::warmup,
+            Priority::RealTime => QueuePriority::realtime,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let cert_expiry = Family::default();
+        registry.register(
+            "cert_expiry_seconds",
+            "The seconds until the certificate of the identity expires",
+            cert_expiry.clone(),
+        );
+        let identities = Family::default();
+        registry.register(
+            "managed_identities",
+            "The number of identities with a managed certificate, by state",
+            identities.clone(),
+        );
+        let csr_requests = Family::default();
+        registry.register(
+            "csr_requests",
+            "The total number of certificate signing requests",
+            csr_requests.clone(),
+        );
+        let csr_duration: Family<CsrRequest, Histogram, fn() -> Histogram> =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.01, 2.0, 12)));
+        registry.register(
+            "csr_request_duration_seconds",
+            "The duration of certificate signing requests",
+            csr_duration.clone(),
+        );
+        let queue_depth = Family::default();
+        registry.register(
+            "cert_queue_depth",
+            "The number of certificate fetches waiting to start, by priority",
+            queue_depth.clone(),
+        );
+
+        Self {
+            cert_expiry,
+            identities,
+            csr_requests,
+            csr_duration,
+            queue_depth,
+        }
+    }
+}
+
+impl super::Metrics {
+    /// remove_cert_expiry stops reporting the expiry of an identity that is no longer managed.
+    pub fn remove_cert_expiry(&self, event: &CertExpiry) {
+        self.identity.cert_expiry.remove(event);
+    }
+}
+
+impl Recorder<CertExpiry, u64> for super::Metrics {
+    fn record(&self, event: &CertExpiry, seconds: u64) {
+        self.identity
+            .cert_expiry
+            .get_or_create(event)
+            .set(seconds.try_into().unwrap_or(i64::MAX));
+    }
+}
+
+impl Recorder<IdentityState, u64> for super::Metrics {
+    fn record(&self, event: &IdentityState, count: u64) {
+        self.identity
+            .identities
+            .get_or_create(event)
+            .set(count.try_into().unwrap_or(i64::MAX));
+    }
+}
+
+impl Recorder<CsrRequest, Duration> for super::Metrics {
+    fn record(&self, event: &CsrRequest, duration: Duration) {
+        self.identity.csr_requests.get_or_create(event).inc();
+        self.identity
+            .csr_duration
+            .get_or_create(event)
+            .observe(duration.as_secs_f64());
+    }
+}
+
+impl Recorder<QueueDepth, u64> for super::Metrics {
+    fn record(&self, event: &QueueDepth, depth: u64) {
+        self.identity
+            .queue_depth
+            .get_or_create(event)
+            .set(depth.try_into().unwrap_or(i64::MAX));
+    }
+}
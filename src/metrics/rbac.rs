@@ -0,0 +1,77 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::registry::Registry;
+
+use crate::metrics::Recorder;
+
+pub(super) struct Metrics {
+    pub(super) policy_audits: Family<PolicyAudit, Counter>,
+    pub(super) authz_log_dropped: Counter,
+}
+
+/// PolicyAudit records the evaluation of an AUDIT policy against a connection.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct PolicyAudit {
+    /// The policy, as namespace/name.
+    pub policy: String,
+    pub result: AuditResult,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, EncodeLabelValue)]
+pub enum AuditResult {
+    matched,
+    not_matched,
+}
+
+/// AuthzLogDropped records authorization log records dropped because the writer fell behind.
+pub struct AuthzLogDropped;
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let policy_audits = Family::default();
+        registry.register(
+            "policy_audits",
+            "The total number of connections evaluated against audit authorization policies",
+            policy_audits.clone(),
+        );
+        let authz_log_dropped = Counter::default();
+        registry.register(
+            "authz_log_dropped_records",
+            "The total number of authorization log records dropped because the log fell behind",
+            authz_log_dropped.clone(),
+        );
+
+        Self {
+            policy_audits,
+            authz_log_dropped,
+        }
+    }
+}
+
+impl Recorder<PolicyAudit, u64> for super::Metrics {
+    fn record(&self, audit: &PolicyAudit, count: u64) {
+        self.rbac.policy_audits.get_or_create(audit).inc_by(count);
+    }
+}
+
+impl Recorder<AuthzLogDropped, u64> for super::Metrics {
+    fn record(&self, _: &AuthzLogDropped, count: u64) {
+        self.rbac.authz_log_dropped.inc_by(count);
+    }
+}
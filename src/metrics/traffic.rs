@@ -14,6 +14,7 @@
 
 use std::fmt::Write;
 
+use crate::fault::{Fault, FaultAction};
 use crate::identity::Identity;
 use crate::metrics::traffic::Reporter::source;
 use crate::metrics::Recorder;
@@ -28,9 +29,12 @@ pub(super) struct Metrics {
     pub(super) connection_close: Family<CommonTrafficLabels, Counter>,
     pub(super) received_bytes: Family<CommonTrafficLabels, Counter>,
     pub(super) sent_bytes: Family<CommonTrafficLabels, Counter>,
+    pub(super) policy_terminations: Family<CommonTrafficLabels, Counter>,
 }
 
-#[derive(Clone, Copy, Default, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+#[derive(
+    Clone, Copy, Default, Debug, Hash, PartialEq, Eq, EncodeLabelValue, serde::Serialize,
+)]
 pub enum Reporter {
     #[default]
     source,
@@ -50,12 +54,28 @@ pub enum RequestProtocol {
 pub enum ResponseFlags {
     #[default]
     none,
+    /// The connection was delayed by an injected fault.
+    delay_injected,
+    /// The connection was rejected, reset or blackholed by an injected fault.
+    fault_injected,
 }
 
 impl EncodeLabelValue for ResponseFlags {
     fn encode(&self, writer: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
         match self {
             ResponseFlags::none => writer.write_str("-"),
+            ResponseFlags::delay_injected => writer.write_str("DI"),
+            ResponseFlags::fault_injected => writer.write_str("FI"),
+        }
+    }
+}
+
+impl From<Option<&Fault>> for ResponseFlags {
+    fn from(fault: Option<&Fault>) -> Self {
+        match fault.map(|f| f.action) {
+            None => ResponseFlags::none,
+            Some(FaultAction::Delay) => ResponseFlags::delay_injected,
+            Some(_) => ResponseFlags::fault_injected,
         }
     }
 }
@@ -106,6 +126,8 @@ pub struct ConnectionClose<'a>(&'a ConnectionOpen);
 
 pub struct BytesTransferred<'a>(&'a ConnectionOpen);
 
+pub struct PolicyTermination<'a>(&'a ConnectionOpen);
+
 #[derive(Clone, Debug, Default)]
 pub struct DerivedWorkload {
     pub workload_name: Option<String>,
@@ -126,6 +148,7 @@ pub struct ConnectionOpen {
     pub destination_service_namespace: Option<String>,
     pub destination_service_name: Option<String>,
     pub connection_security_policy: SecurityPolicy,
+    pub response_flags: ResponseFlags,
 }
 
 impl<'a> From<&'a ConnectionOpen> for ConnectionClose<'a> {
@@ -140,6 +163,12 @@ impl<'a> From<&'a ConnectionOpen> for BytesTransferred<'a> {
     }
 }
 
+impl<'a> From<&'a ConnectionOpen> for PolicyTermination<'a> {
+    fn from(c: &'a ConnectionOpen) -> Self {
+        PolicyTermination(c)
+    }
+}
+
 impl CommonTrafficLabels {
     fn new() -> Self {
         Default::default()
@@ -202,7 +231,7 @@ impl From<&ConnectionOpen> for CommonTrafficLabels {
         CommonTrafficLabels {
             reporter: c.reporter,
             request_protocol: RequestProtocol::tcp,
-            response_flags: ResponseFlags::none,
+            response_flags: c.response_flags,
             connection_security_policy: c.connection_security_policy,
             ..CommonTrafficLabels::new()
                 // Intentionally before with_source; source is more reliable
@@ -273,11 +302,19 @@ impl Metrics {
             sent_bytes.clone(),
         );
 
+        let policy_terminations = Family::default();
+        registry.register(
+            "tcp_connections_policy_terminated",
+            "The total number of established TCP connections closed because updated authorization policy no longer allows them",
+            policy_terminations.clone(),
+        );
+
         Self {
             connection_opens,
             connection_close,
             received_bytes,
             sent_bytes,
+            policy_terminations,
         }
     }
 }
@@ -300,6 +337,15 @@ impl Recorder<ConnectionClose<'_>, u64> for super::Metrics {
     }
 }
 
+impl Recorder<PolicyTermination<'_>, u64> for super::Metrics {
+    fn record(&self, reason: &PolicyTermination, count: u64) {
+        self.traffic
+            .policy_terminations
+            .get_or_create(&CommonTrafficLabels::from(reason.0))
+            .inc_by(count);
+    }
+}
+
 impl Recorder<BytesTransferred<'_>, (u64, u64)> for super::Metrics {
     fn record(&self, event: &BytesTransferred<'_>, m: (u64, u64)) {
         let (sent, recv) = if event.0.reporter == source {
@@ -12,11 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::hot_restart::Listeners;
 use crate::identity::SecretManager;
 use crate::metrics::{traffic, Metrics, Recorder};
+use crate::proxy::connection_manager::{ByteCounters, ConnectionGuard, ConnectionManager};
 use crate::proxy::inbound_passthrough::InboundPassthrough;
+use crate::proxy::inpod::{PodManager, PodNetns};
 use crate::proxy::outbound::Outbound;
 use crate::proxy::socks5::Socks5;
+use crate::readiness::BlockReady;
 use crate::state::workload::Workload;
 use crate::state::DemandProxyState;
 use crate::{config, identity, socket, tls};
@@ -26,26 +30,38 @@ use hyper::{header, Request};
 use inbound::Inbound;
 use rand::Rng;
 use std::fmt::Debug;
+use std::future::Future;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use std::{fmt, io};
-use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixStream};
 use tokio::time::timeout;
 use tracing::{error, trace, warn, Instrument};
 
+pub mod connection_manager;
 mod inbound;
 mod inbound_passthrough;
+pub mod inpod;
 mod outbound;
 mod pool;
 mod socks5;
-mod util;
+pub(crate) mod util;
+
+/// The port HBONE is served on, if we do not serve it ourselves.
+const DEFAULT_HBONE_PORT: u16 = 15008;
 
 pub struct Proxy {
-    inbound: Inbound,
-    inbound_passthrough: InboundPassthrough,
-    outbound: Outbound,
-    socks5: Socks5,
+    // Each listener holds up readiness until its accept loop has started.
+    inbound: Option<(Inbound, BlockReady)>,
+    inbound_passthrough: Option<(InboundPassthrough, BlockReady)>,
+    outbound: Option<(Outbound, BlockReady)>,
+    socks5: Option<(Socks5, BlockReady)>,
+    socks5_unix: Option<(Socks5, BlockReady)>,
+    pods: Option<PodManager>,
+    listeners: Listeners,
 }
 
 #[derive(Clone)]
@@ -56,6 +72,12 @@ pub(super) struct ProxyInputs {
     pub state: DemandProxyState,
     metrics: Arc<Metrics>,
     pool: pool::Pool,
+    connection_manager: ConnectionManager,
+    /// If set, the uid of the pod these listeners were handed over for. Traffic on them is
+    /// attributed to this pod, rather than looked up by address.
+    pod: Option<String>,
+    /// If set, the network namespace of the pod. Upstream connections are opened from within it.
+    netns: Option<PodNetns>,
 }
 
 impl Proxy {
@@ -64,6 +86,9 @@ impl Proxy {
         state: DemandProxyState,
         cert_manager: Arc<SecretManager>,
         metrics: Arc<Metrics>,
+        connection_manager: ConnectionManager,
+        listeners: &Listeners,
+        ready: &BlockReady,
         drain: Watch,
     ) -> Result<Proxy, Error> {
         let mut pi = ProxyInputs {
@@ -73,47 +98,141 @@ impl Proxy {
             metrics,
             pool: pool::Pool::new(),
             hbone_port: 0,
+            connection_manager,
+            pod: None,
+            netns: None,
+        };
+        // We setup all the listeners first so we can capture any errors that should block startup.
+        // Only enabled listeners are set up, and each holds up readiness until it is accepting.
+        let inbound = match pi.cfg.inbound_addr {
+            Some(addr) => {
+                let task = ready.subtask("inbound listener");
+                let inbound = Inbound::new(pi.clone(), addr, listeners, drain.clone()).await?;
+                Some((inbound, task))
+            }
+            None => None,
+        };
+        pi.hbone_port = inbound
+            .as_ref()
+            .map_or(DEFAULT_HBONE_PORT, |(inbound, _)| inbound.address().port());
+
+        let inbound_passthrough = match pi.cfg.inbound_plaintext_addr {
+            Some(addr) => {
+                let task = ready.subtask("inbound plaintext listener");
+                let inbound_passthrough =
+                    InboundPassthrough::new(pi.clone(), addr, listeners, drain.clone()).await?;
+                Some((inbound_passthrough, task))
+            }
+            None => None,
+        };
+        let outbound = match pi.cfg.outbound_addr {
+            Some(addr) => {
+                let task = ready.subtask("outbound listener");
+                let outbound = Outbound::new(pi.clone(), addr, listeners, drain.clone()).await?;
+                Some((outbound, task))
+            }
+            None => None,
+        };
+        let socks5 = match pi.cfg.socks5_addr {
+            Some(addr) => {
+                let task = ready.subtask("socks5 listener");
+                let socks5 = Socks5::new(pi.clone(), addr, listeners, drain.clone()).await?;
+                Some((socks5, task))
+            }
+            None => None,
+        };
+        let socks5_unix = match pi.cfg.socks5_path.clone() {
+            Some(path) => {
+                let task = ready.subtask("socks5 unix listener");
+                let socks5 = Socks5::bind_unix(pi.clone(), &path, listeners, drain.clone())?;
+                Some((socks5, task))
+            }
+            None => None,
+        };
+        let pods = match pi.cfg.pod_listener_socket.clone() {
+            Some(path) => Some(PodManager::new(pi, &path, listeners, drain)?),
+            None => None,
         };
-        // We setup all the listeners first so we can capture any errors that should block startup
-        let inbound = Inbound::new(pi.clone(), drain.clone()).await?;
-        pi.hbone_port = inbound.address().port();
-
-        let inbound_passthrough = InboundPassthrough::new(pi.clone()).await?;
-        let outbound = Outbound::new(pi.clone(), drain.clone()).await?;
-        let socks5 = Socks5::new(pi.clone(), drain).await?;
         Ok(Proxy {
             inbound,
             inbound_passthrough,
             outbound,
             socks5,
+            socks5_unix,
+            pods,
+            listeners: listeners.clone(),
         })
     }
 
     pub async fn run(self) {
-        let tasks = vec![
-            tokio::spawn(self.inbound_passthrough.run().in_current_span()),
-            tokio::spawn(self.inbound.run().in_current_span()),
-            tokio::spawn(self.outbound.run().in_current_span()),
-            tokio::spawn(self.socks5.run().in_current_span()),
-        ];
+        // Accepting stops early if our listeners are handed over to a new process.
+        let listeners = &self.listeners;
+        let mut tasks = vec![];
+        if let Some((inbound_passthrough, task)) = self.inbound_passthrough {
+            tasks.push(tokio::spawn(
+                accept(listeners, task, inbound_passthrough.run()).in_current_span(),
+            ));
+        }
+        if let Some((inbound, task)) = self.inbound {
+            tasks.push(tokio::spawn(
+                accept(listeners, task, inbound.run()).in_current_span(),
+            ));
+        }
+        if let Some((outbound, task)) = self.outbound {
+            tasks.push(tokio::spawn(
+                accept(listeners, task, outbound.run()).in_current_span(),
+            ));
+        }
+        if let Some((socks5, task)) = self.socks5 {
+            tasks.push(tokio::spawn(
+                accept(listeners, task, socks5.run()).in_current_span(),
+            ));
+        }
+        if let Some((socks5_unix, task)) = self.socks5_unix {
+            tasks.push(tokio::spawn(
+                accept(listeners, task, socks5_unix.run()).in_current_span(),
+            ));
+        }
+        if let Some(pods) = self.pods {
+            tasks.push(tokio::spawn(pods.run().in_current_span()));
+        }
 
         futures::future::join_all(tasks).await;
     }
 
     pub fn addresses(&self) -> Addresses {
         Addresses {
-            outbound: self.outbound.address(),
-            inbound: self.inbound.address(),
-            socks5: self.socks5.address(),
+            outbound: self.outbound.as_ref().map(|(o, _)| o.address()),
+            inbound: self.inbound.as_ref().map(|(i, _)| i.address()),
+            socks5: self.socks5.as_ref().and_then(|(s, _)| s.address()),
         }
     }
 }
 
+/// accept runs an accept loop, releasing its hold on readiness once the loop is polled.
+fn accept<F: Future<Output = ()>>(
+    listeners: &Listeners,
+    task: BlockReady,
+    accept: F,
+) -> impl Future<Output = ()> {
+    let accept = listeners.accept(accept);
+    async move {
+        futures::pin_mut!(accept);
+        // The first poll starts accepting; connections that arrived earlier wait in the backlog.
+        let done = futures::poll!(accept.as_mut()).is_ready();
+        drop(task);
+        if !done {
+            accept.await
+        }
+    }
+}
+
+/// Addresses of the active TCP proxy listeners.
 #[derive(Copy, Clone)]
 pub struct Addresses {
-    pub outbound: SocketAddr,
-    pub inbound: SocketAddr,
-    pub socks5: SocketAddr,
+    pub outbound: Option<SocketAddr>,
+    pub inbound: Option<SocketAddr>,
+    pub socks5: Option<SocketAddr>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -121,6 +240,12 @@ pub enum Error {
     #[error("failed to bind to address {0}: {1}")]
     Bind(SocketAddr, io::Error),
 
+    #[error("failed to bind to unix socket {0:?}: {1}")]
+    BindUnix(PathBuf, io::Error),
+
+    #[error("pod listeners: {0}")]
+    PodListeners(String),
+
     #[error("io error: {0}")]
     Io(#[from] io::Error),
     //
@@ -176,46 +301,60 @@ pub enum Error {
 
     #[error("unsupported feature: {0}")]
     UnsupportedFeature(String),
+
+    #[error("connection terminated")]
+    ConnectionTerminated,
+
+    #[error("connection rejected by injected fault {0}")]
+    FaultInjected(String),
+
+    #[error("plaintext connection to {0} rejected: mTLS is required")]
+    PlaintextRejected(SocketAddr),
 }
 
 // TLS record size max is 16k. But we also have a H2 frame header, so leave a bit of room for that.
 const HBONE_BUFFER_SIZE: usize = 16_384 - 64;
 
-pub async fn copy_hbone(
+pub async fn copy_hbone<S: AsyncRead + AsyncWrite + Unpin>(
     upgraded: &mut hyper::upgrade::Upgraded,
-    stream: &mut TcpStream,
+    stream: &mut S,
     metrics: impl AsRef<Metrics>,
     transferred_bytes: traffic::BytesTransferred<'_>,
+    conn: &ConnectionGuard,
 ) -> Result<(), Error> {
     use tokio::io::AsyncWriteExt;
     let (mut ri, mut wi) = tokio::io::split(upgraded);
-    let (mut ro, mut wo) = stream.split();
-
-    let (mut sent, mut received): (u64, u64) = (0, 0);
+    let (mut ro, mut wo) = tokio::io::split(stream);
 
     let client_to_server = async {
         let mut ri = tokio::io::BufReader::with_capacity(HBONE_BUFFER_SIZE, &mut ri);
+        let mut wo = conn.bytes().count_received(&mut wo);
         let res = tokio::io::copy_buf(&mut ri, &mut wo).await;
         trace!(?res, "hbone -> tcp");
-        received = res?;
+        res?;
         wo.shutdown().await
     };
 
     let server_to_client = async {
         let mut ro = tokio::io::BufReader::with_capacity(HBONE_BUFFER_SIZE, &mut ro);
+        let mut wi = conn.bytes().count_sent(&mut wi);
         let res = tokio::io::copy_buf(&mut ro, &mut wi).await;
         trace!(?res, "tcp -> hbone");
-        sent = res?;
+        res?;
         wi.shutdown().await
     };
 
-    tokio::try_join!(client_to_server, server_to_client)?;
+    let res = tokio::select! {
+        res = async { tokio::try_join!(client_to_server, server_to_client) } => res.map(|_| ()).map_err(Error::Io),
+        _ = conn.closed() => Err(Error::ConnectionTerminated),
+    };
 
+    let (sent, received) = conn.bytes().totals();
     trace!(sent, recv = received, "copy hbone complete");
     metrics
         .as_ref()
         .record(&transferred_bytes, (sent, received));
-    Ok(())
+    res
 }
 
 /// Represents a traceparent, as defined by https://www.w3.org/TR/trace-context/
@@ -327,6 +466,16 @@ pub fn get_original_src_from_fwded<T>(req: &Request<T>) -> Option<IpAddr> {
         })
 }
 
+/// get_forwarded_host returns the host a request was originally addressed to, if the client
+/// reported it.
+pub fn get_forwarded_host<T>(req: &Request<T>) -> Option<String> {
+    req.headers()
+        .get(header::FORWARDED)
+        .and_then(|rh| rh.to_str().ok())
+        .and_then(|rh| http_types::proxies::Forwarded::parse(rh).ok())
+        .and_then(|ph| ph.host().map(str::to_string))
+}
+
 pub fn get_original_src_from_stream(stream: &TcpStream) -> Option<IpAddr> {
     stream
         .peer_addr()
@@ -335,25 +484,34 @@ pub fn get_original_src_from_stream(stream: &TcpStream) -> Option<IpAddr> {
 
 const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub async fn freebind_connect(local: Option<IpAddr>, addr: SocketAddr) -> io::Result<TcpStream> {
-    async fn connect(local: Option<IpAddr>, addr: SocketAddr) -> io::Result<TcpStream> {
+pub async fn freebind_connect(
+    netns: Option<&PodNetns>,
+    local: Option<IpAddr>,
+    addr: SocketAddr,
+) -> io::Result<TcpStream> {
+    async fn connect(
+        netns: Option<&PodNetns>,
+        local: Option<IpAddr>,
+        addr: SocketAddr,
+    ) -> io::Result<TcpStream> {
+        let new_socket = |ip: IpAddr| match netns {
+            Some(netns) => netns.tcp_socket(ip),
+            None if ip.is_ipv4() => TcpSocket::new_v4(),
+            None => TcpSocket::new_v6(),
+        };
         match local {
             None => {
                 trace!(dest=%addr, "no local address, connect directly");
-                Ok(TcpStream::connect(addr).await?)
+                Ok(new_socket(addr.ip())?.connect(addr).await?)
             }
             // TODO: Need figure out how to handle case of loadbalancing to itself.
             //       We use ztunnel addr instead, otherwise app side will be confused.
             Some(src) if src == socket::to_canonical(addr).ip() => {
                 trace!(%src, dest=%addr, "dest and source are the same, connect directly");
-                Ok(TcpStream::connect(addr).await?)
+                Ok(new_socket(addr.ip())?.connect(addr).await?)
             }
             Some(src) => {
-                let socket = if src.is_ipv4() {
-                    TcpSocket::new_v4()?
-                } else {
-                    TcpSocket::new_v6()?
-                };
+                let socket = new_socket(src)?;
 
                 let local_addr = SocketAddr::new(src, 0);
                 match socket::set_freebind_and_transparent(&socket) {
@@ -370,24 +528,117 @@ pub async fn freebind_connect(local: Option<IpAddr>, addr: SocketAddr) -> io::Re
         }
     }
     // Wrap the entire connect function in a timeout
-    timeout(CONNECTION_TIMEOUT, connect(local, addr))
+    timeout(CONNECTION_TIMEOUT, connect(netns, local, addr))
         .await
         .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?
 }
 
-pub async fn relay(
-    downstream: &mut tokio::net::TcpStream,
+pub async fn relay<D: Downstream>(
+    downstream: &mut D,
     upstream: &mut tokio::net::TcpStream,
     metrics: impl AsRef<Metrics>,
     transferred_bytes: traffic::BytesTransferred<'_>,
+    conn: &ConnectionGuard,
 ) -> Result<(u64, u64), Error> {
-    match socket::relay(downstream, upstream).await {
-        Ok(transferred) => {
-            trace!(sent = transferred.0, recv = transferred.1, "relay complete");
-            metrics.as_ref().record(&transferred_bytes, transferred);
-            Ok(transferred)
+    // Byte limits from injected faults need to observe each write, so they cannot use zero-copy.
+    // Neither can downstreams that are not TCP.
+    let counted = conn.bytes().limited() || downstream.as_tcp().is_none();
+    let res = tokio::select! {
+        res = async {
+            match downstream.as_tcp() {
+                Some(downstream) if !counted => socket::relay(downstream, upstream).await,
+                _ => copy_counted(downstream, upstream, conn.bytes()).await,
+            }
+        } => res.map_err(Error::Io),
+        _ = conn.closed() => Err(Error::ConnectionTerminated),
+    };
+    if let Ok(transferred) = res {
+        trace!(sent = transferred.0, recv = transferred.1, "relay complete");
+        if !counted {
+            // The relay may use zero-copy, so we only learn the byte counts once it completes.
+            conn.bytes().add_sent(transferred.0);
+            conn.bytes().add_received(transferred.1);
+        }
+    }
+    // Record the bytes transferred even if the relay failed or the connection was terminated.
+    // A zero-copy relay that did not complete has no byte counts to report.
+    metrics
+        .as_ref()
+        .record(&transferred_bytes, conn.bytes().totals());
+    res
+}
+
+/// copy_counted copies data in both directions like socket::relay, but counts each write as it
+/// happens.
+async fn copy_counted<D: AsyncRead + AsyncWrite + Unpin>(
+    downstream: &mut D,
+    upstream: &mut TcpStream,
+    bytes: &ByteCounters,
+) -> io::Result<(u64, u64)> {
+    use tokio::io::AsyncWriteExt;
+    let (mut rd, mut wd) = tokio::io::split(downstream);
+    let (mut ru, mut wu) = upstream.split();
+
+    let downstream_to_upstream = async {
+        let mut wu = bytes.count_sent(&mut wu);
+        let n = tokio::io::copy(&mut rd, &mut wu).await?;
+        wu.shutdown().await?;
+        Ok::<_, io::Error>(n)
+    };
+
+    let upstream_to_downstream = async {
+        let mut wd = bytes.count_received(&mut wd);
+        let n = tokio::io::copy(&mut ru, &mut wd).await?;
+        wd.shutdown().await?;
+        Ok::<_, io::Error>(n)
+    };
+
+    tokio::try_join!(downstream_to_upstream, upstream_to_downstream)
+}
+
+/// Downstream is a client connection being proxied. This is usually TCP, but SOCKS5 clients may
+/// also connect over a Unix domain socket, which cannot use the TCP specific fast paths.
+pub trait Downstream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    fn as_tcp(&mut self) -> Option<&mut TcpStream>;
+
+    fn into_tcp(self) -> Result<TcpStream, Self>
+    where
+        Self: Sized;
+}
+
+impl Downstream for TcpStream {
+    fn as_tcp(&mut self) -> Option<&mut TcpStream> {
+        Some(self)
+    }
+
+    fn into_tcp(self) -> Result<TcpStream, Self> {
+        Ok(self)
+    }
+}
+
+impl Downstream for UnixStream {
+    fn as_tcp(&mut self) -> Option<&mut TcpStream> {
+        None
+    }
+
+    fn into_tcp(self) -> Result<TcpStream, Self> {
+        Err(self)
+    }
+}
+
+/// blackhole reads and discards everything from the downstream without ever responding, until the
+/// client closes the connection or it is terminated.
+pub async fn blackhole<S: AsyncRead + Unpin>(
+    downstream: &mut S,
+    conn: &ConnectionGuard,
+) -> Result<(), Error> {
+    tokio::select! {
+        res = tokio::io::copy(downstream, &mut tokio::io::sink()) => {
+            let n = res?;
+            trace!(discarded = n, "blackhole complete");
+            Ok(())
         }
-        Err(e) => Err(Error::Io(e)),
+        _ = conn.closed() => Err(Error::ConnectionTerminated),
     }
 }
 
@@ -0,0 +1,690 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use tokio::io::AsyncWrite;
+use tokio::sync::{watch, Notify};
+use tracing::{debug, info};
+
+use crate::fault::{Fault, FaultAction};
+use crate::metrics::traffic::{ConnectionOpen, PolicyTermination, Reporter};
+use crate::metrics::{IncrementRecorder, Metrics};
+use crate::proxy::outbound::RequestType;
+use crate::rbac;
+use crate::state::workload::Workload;
+use crate::state::DemandProxyState;
+
+/// How many connections are re-authorized at a time before yielding to other tasks.
+const REAUTHORIZE_BATCH_SIZE: usize = 128;
+
+/// ConnectionManager keeps track of all live proxied connections, so they can be inspected and
+/// terminated out of band (for example, from the admin server).
+/// It is shared by all of the proxy listeners.
+#[derive(Clone, Default)]
+pub struct ConnectionManager {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    connections: HashMap<u64, Arc<TrackedConnection>>,
+}
+
+struct TrackedConnection {
+    id: u64,
+    request_type: Option<RequestType>,
+    src_ip: IpAddr,
+    dst: SocketAddr,
+    start_time: SystemTime,
+    connection: ConnectionOpen,
+    /// The connection that was authorized when the connection was established, if policy was enforced.
+    rbac: Option<rbac::Connection>,
+    bytes: ByteCounters,
+    closing: AtomicBool,
+    close: Notify,
+}
+
+impl TrackedConnection {
+    /// terminate signals the connection to close. Returns false if it was already signaled.
+    fn terminate(&self) -> bool {
+        if self.closing.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        self.close.notify_one();
+        true
+    }
+}
+
+pub struct ByteCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    /// Total bytes allowed before writes fail, set by injected faults.
+    limit: AtomicU64,
+}
+
+impl Default for ByteCounters {
+    fn default() -> Self {
+        ByteCounters {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            limit: AtomicU64::new(u64::MAX),
+        }
+    }
+}
+
+impl ByteCounters {
+    pub fn add_sent(&self, n: u64) {
+        self.sent.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, n: u64) {
+        self.received.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// totals returns the (sent, received) bytes.
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.sent.load(Ordering::Relaxed),
+            self.received.load(Ordering::Relaxed),
+        )
+    }
+
+    /// limited returns true if the connection is only allowed to transfer a fixed number of bytes.
+    /// Limits are only enforced by the CountingWriters.
+    pub fn limited(&self) -> bool {
+        self.limit.load(Ordering::Relaxed) != u64::MAX
+    }
+
+    fn remaining(&self) -> u64 {
+        let (sent, received) = self.totals();
+        self.limit
+            .load(Ordering::Relaxed)
+            .saturating_sub(sent + received)
+    }
+
+    /// count_sent wraps a writer, counting all bytes written as sent.
+    pub fn count_sent<W>(&self, inner: W) -> CountingWriter<'_, W> {
+        CountingWriter {
+            inner,
+            counters: self,
+            counter: &self.sent,
+        }
+    }
+
+    /// count_received wraps a writer, counting all bytes written as received.
+    pub fn count_received<W>(&self, inner: W) -> CountingWriter<'_, W> {
+        CountingWriter {
+            inner,
+            counters: self,
+            counter: &self.received,
+        }
+    }
+}
+
+/// CountingWriter is an AsyncWrite that keeps a running count of bytes written, so the counters
+/// are up to date while a connection is still live.
+pub struct CountingWriter<'a, W> {
+    inner: W,
+    counters: &'a ByteCounters,
+    counter: &'a AtomicU64,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let remaining = self.counters.remaining();
+        if remaining == 0 && !buf.is_empty() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection reset by injected fault",
+            )));
+        }
+        let buf = &buf[..buf.len().min(remaining.try_into().unwrap_or(usize::MAX))];
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// ConnectionGuard represents a single tracked connection. The connection is removed from the
+/// manager once the guard is dropped.
+pub struct ConnectionGuard {
+    manager: ConnectionManager,
+    conn: Arc<TrackedConnection>,
+}
+
+impl ConnectionGuard {
+    pub fn id(&self) -> u64 {
+        self.conn.id
+    }
+
+    pub fn bytes(&self) -> &ByteCounters {
+        &self.conn.bytes
+    }
+
+    /// closed completes once the connection has been requested to be terminated.
+    pub async fn closed(&self) {
+        self.conn.close.notified().await
+    }
+
+    /// inject_fault arms the parts of a fault that apply to an established connection.
+    pub fn inject_fault(&self, fault: &Fault) {
+        if fault.action != FaultAction::Reset {
+            return;
+        }
+        if let Some(bytes) = fault.reset_after_bytes {
+            self.conn.bytes.limit.store(bytes, Ordering::Relaxed);
+        }
+        if let Some(after) = fault.reset_after() {
+            // Hold a weak reference, so the timer does not keep a finished connection alive.
+            let conn = Arc::downgrade(&self.conn);
+            tokio::spawn(async move {
+                tokio::time::sleep(after).await;
+                if let Some(conn) = conn.upgrade() {
+                    if conn.terminate() {
+                        debug!(id = conn.id, "connection reset by injected fault");
+                    }
+                }
+            });
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.manager
+            .inner
+            .lock()
+            .unwrap()
+            .connections
+            .remove(&self.conn.id);
+    }
+}
+
+/// ConnectionFilter selects a subset of the tracked connections.
+/// Unset fields match all connections.
+#[derive(Default, Debug, Clone)]
+pub struct ConnectionFilter {
+    pub id: Option<u64>,
+    /// Matches the uid or name of either the source or destination workload.
+    pub workload: Option<String>,
+    /// Matches the namespace of either the source or destination workload.
+    pub namespace: Option<String>,
+    /// Matches the identity of either the source or destination.
+    pub identity: Option<String>,
+}
+
+impl ConnectionFilter {
+    pub fn is_empty(&self) -> bool {
+        self.id.is_none()
+            && self.workload.is_none()
+            && self.namespace.is_none()
+            && self.identity.is_none()
+    }
+
+    fn matches(&self, c: &TrackedConnection) -> bool {
+        if let Some(id) = self.id {
+            if c.id != id {
+                return false;
+            }
+        }
+        let workloads = [
+            c.connection.source.as_ref(),
+            c.connection.destination.as_ref(),
+        ];
+        let workloads = workloads.iter().flatten();
+        if let Some(want) = &self.workload {
+            if !workloads.clone().any(|w| &w.uid == want || &w.name == want) {
+                return false;
+            }
+        }
+        if let Some(want) = &self.namespace {
+            if !workloads.clone().any(|w| &w.namespace == want) {
+                return false;
+            }
+        }
+        if let Some(want) = &self.identity {
+            let (src, dst) = identities(&c.connection);
+            if src.as_ref() != Some(want) && dst.as_ref() != Some(want) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct ConnectionDump {
+    id: u64,
+    reporter: Reporter,
+    request_type: Option<String>,
+    src_ip: IpAddr,
+    dst: SocketAddr,
+    source_workload: Option<String>,
+    source_namespace: Option<String>,
+    source_principal: Option<String>,
+    destination_workload: Option<String>,
+    destination_namespace: Option<String>,
+    destination_principal: Option<String>,
+    start_time: String,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+fn identities(c: &ConnectionOpen) -> (Option<String>, Option<String>) {
+    let src = c
+        .source
+        .as_ref()
+        .map(Workload::identity)
+        .or_else(|| c.derived_source.as_ref().and_then(|d| d.identity.clone()))
+        .map(|i| i.to_string());
+    let dst = c.destination.as_ref().map(|w| w.identity().to_string());
+    (src, dst)
+}
+
+impl TrackedConnection {
+    fn dump(&self) -> ConnectionDump {
+        use chrono::prelude::{DateTime, Utc};
+
+        let start: DateTime<Utc> = self.start_time.into();
+        let (source_principal, destination_principal) = identities(&self.connection);
+        let source = self.connection.source.as_ref();
+        let destination = self.connection.destination.as_ref();
+        ConnectionDump {
+            id: self.id,
+            reporter: self.connection.reporter,
+            request_type: self.request_type.as_ref().map(|r| format!("{r:?}")),
+            src_ip: self.src_ip,
+            dst: self.dst,
+            source_workload: source.map(|w| w.uid.clone()),
+            source_namespace: source.map(|w| w.namespace.clone()).or_else(|| {
+                self.connection
+                    .derived_source
+                    .as_ref()
+                    .and_then(|d| d.namespace.clone())
+            }),
+            source_principal,
+            destination_workload: destination.map(|w| w.uid.clone()),
+            destination_namespace: destination.map(|w| w.namespace.clone()),
+            destination_principal,
+            start_time: start.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            bytes_sent: self.bytes.totals().0,
+            bytes_received: self.bytes.totals().1,
+        }
+    }
+}
+
+impl ConnectionManager {
+    /// track registers a new live connection. The connection remains tracked until the returned
+    /// guard is dropped.
+    pub(crate) fn track(
+        &self,
+        connection: &ConnectionOpen,
+        rbac: Option<rbac::Connection>,
+        request_type: Option<RequestType>,
+        src_ip: IpAddr,
+        dst: SocketAddr,
+    ) -> ConnectionGuard {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let conn = Arc::new(TrackedConnection {
+            id: inner.next_id,
+            request_type,
+            src_ip,
+            dst,
+            start_time: SystemTime::now(),
+            connection: connection.clone(),
+            rbac,
+            bytes: Default::default(),
+            closing: AtomicBool::new(false),
+            close: Notify::new(),
+        });
+        inner.connections.insert(conn.id, conn.clone());
+        ConnectionGuard {
+            manager: self.clone(),
+            conn,
+        }
+    }
+
+    /// connections returns a snapshot of all tracked connections matching the filter, sorted by id.
+    pub fn connections(&self, filter: &ConnectionFilter) -> Vec<ConnectionDump> {
+        let inner = self.inner.lock().unwrap();
+        let mut dump: Vec<ConnectionDump> = inner
+            .connections
+            .values()
+            .filter(|c| filter.matches(c))
+            .map(|c| c.dump())
+            .collect();
+        dump.sort_by_key(|c| c.id);
+        dump
+    }
+
+    /// close terminates all tracked connections matching the filter, returning the number of
+    /// connections that were signaled.
+    pub fn close(&self, filter: &ConnectionFilter) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let mut closed = 0;
+        for c in inner.connections.values().filter(|c| filter.matches(c)) {
+            if c.terminate() {
+                info!(id = c.id, src=%c.src_ip, dst=%c.dst, "closing connection");
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    /// enforce_policy_updates re-authorizes all connections that had policy enforced whenever
+    /// policies change, closing any connections that are no longer allowed.
+    pub async fn enforce_policy_updates(
+        self,
+        state: DemandProxyState,
+        mut updates: watch::Receiver<()>,
+        metrics: Arc<Metrics>,
+    ) {
+        while updates.changed().await.is_ok() {
+            self.reauthorize(&state, &metrics).await;
+        }
+    }
+
+    async fn reauthorize(&self, state: &DemandProxyState, metrics: &Metrics) {
+        // Snapshot the connections so we do not hold the lock while evaluating policy.
+        let connections: Vec<Arc<TrackedConnection>> = self
+            .inner
+            .lock()
+            .unwrap()
+            .connections
+            .values()
+            .filter(|c| c.rbac.is_some() && !c.closing.load(Ordering::Relaxed))
+            .cloned()
+            .collect();
+        debug!(
+            connections = connections.len(),
+            "policy changed, re-authorizing connections"
+        );
+        for batch in connections.chunks(REAUTHORIZE_BATCH_SIZE) {
+            for c in batch {
+                let Some(conn) = &c.rbac else {
+                    continue;
+                };
+                // The connection was already authorized in full when it was established; only the
+                // compiled policies are re-checked, so audit results, authorization logs, and
+                // external authorization calls are not repeated.
+                let denial = match state.evaluate_rbac(conn) {
+                    Ok(()) => continue,
+                    // A removed destination workload is not a policy change. Its connections are
+                    // left to end on their own, rather than closed on the next policy push.
+                    Err(rbac::Denial::UnknownWorkload) => continue,
+                    Err(denial) => denial,
+                };
+                if c.terminate() {
+                    info!(
+                        %conn,
+                        id = c.id,
+                        reason = %denial,
+                        "connection no longer allowed by policy, closing"
+                    );
+                    metrics.increment(&PolicyTermination::from(&c.connection));
+                }
+            }
+            // Let other tasks, such as xDS updates waiting on the state lock, run between batches.
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::traffic;
+    use crate::test_helpers::new_proxy_state;
+    use crate::xds::istio::security::Action as XdsAction;
+    use crate::xds::istio::security::Address as XdsAddress;
+    use crate::xds::istio::security::Authorization as XdsAuthorization;
+    use crate::xds::istio::security::Clause as XdsClause;
+    use crate::xds::istio::security::Match as XdsMatch;
+    use crate::xds::istio::security::Rule as XdsRule;
+    use crate::xds::istio::security::Scope as XdsScope;
+    use crate::xds::istio::workload::Workload as XdsWorkload;
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    fn workload(uid: &str, name: &str, namespace: &str) -> Workload {
+        Workload {
+            uid: uid.to_string(),
+            name: name.to_string(),
+            namespace: namespace.to_string(),
+            ..crate::test_helpers::test_default_workload()
+        }
+    }
+
+    fn connection(src: Workload, dst: Workload) -> ConnectionOpen {
+        ConnectionOpen {
+            reporter: Reporter::source,
+            source: Some(src),
+            derived_source: None,
+            destination: Some(dst),
+            connection_security_policy: traffic::SecurityPolicy::mutual_tls,
+            response_flags: traffic::ResponseFlags::none,
+            destination_service: None,
+            destination_service_namespace: None,
+            destination_service_name: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn track_and_close() {
+        let cm = ConnectionManager::default();
+        let a = workload("uid-a", "a", "ns1");
+        let b = workload("uid-b", "b", "ns2");
+        let c = workload("uid-c", "c", "ns2");
+        let g1 = cm.track(
+            &connection(a.clone(), b.clone()),
+            None,
+            Some(RequestType::Direct),
+            "127.0.0.1".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+        );
+        let g2 = cm.track(
+            &connection(b, c),
+            None,
+            None,
+            "127.0.0.2".parse().unwrap(),
+            "127.0.0.3:80".parse().unwrap(),
+        );
+
+        assert_eq!(cm.connections(&Default::default()).len(), 2);
+        let by_workload = ConnectionFilter {
+            workload: Some("uid-a".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(cm.connections(&by_workload).len(), 1);
+        let by_namespace = ConnectionFilter {
+            namespace: Some("ns2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(cm.connections(&by_namespace).len(), 2);
+        let by_identity = ConnectionFilter {
+            identity: Some(a.identity().to_string()),
+            ..Default::default()
+        };
+        assert_eq!(cm.connections(&by_identity).len(), 1);
+
+        let by_id = ConnectionFilter {
+            id: Some(g2.id()),
+            ..Default::default()
+        };
+        assert_eq!(cm.close(&by_id), 1);
+        tokio::time::timeout(Duration::from_secs(1), g2.closed())
+            .await
+            .expect("connection should be closed");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), g1.closed())
+                .await
+                .is_err(),
+            "unrelated connection should not be closed"
+        );
+
+        drop(g2);
+        let remaining = cm.connections(&Default::default());
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, g1.id());
+        drop(g1);
+        assert!(cm.connections(&Default::default()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn inject_reset_fault() {
+        use tokio::io::AsyncWriteExt;
+
+        let cm = ConnectionManager::default();
+        let guard = cm.track(
+            &connection(workload("uid-a", "a", "ns"), workload("uid-b", "b", "ns")),
+            None,
+            None,
+            "127.0.0.1".parse().unwrap(),
+            "127.0.0.2:80".parse().unwrap(),
+        );
+        assert!(!guard.bytes().limited());
+        let fault: Fault = serde_yaml::from_str(
+            "{name: reset, action: Reset, resetAfterBytes: 5, resetAfterMs: 10}",
+        )
+        .unwrap();
+        guard.inject_fault(&fault);
+        assert!(guard.bytes().limited());
+
+        let mut out = Vec::new();
+        let mut w = guard.bytes().count_sent(&mut out);
+        w.write_all(b"abc").await.unwrap();
+        let err = w.write_all(b"defg").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        drop(w);
+        assert_eq!(out, b"abcde");
+        assert_eq!(guard.bytes().totals(), (5, 0));
+
+        tokio::time::timeout(Duration::from_secs(1), guard.closed())
+            .await
+            .expect("connection should be reset after the deadline");
+    }
+
+    #[tokio::test]
+    async fn reauthorize_on_policy_change() {
+        let workloads = || {
+            vec![XdsWorkload {
+                uid: "cluster1//v1/Pod/ns/dst".to_string(),
+                name: "dst".to_string(),
+                namespace: "ns".to_string(),
+                addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+                ..Default::default()
+            }]
+        };
+        let deny = XdsAuthorization {
+            name: "deny".to_string(),
+            namespace: "ns".to_string(),
+            scope: XdsScope::Global as i32,
+            action: XdsAction::Deny as i32,
+            rules: vec![XdsRule {
+                clauses: vec![XdsClause {
+                    matches: vec![XdsMatch {
+                        source_ips: vec![XdsAddress {
+                            address: Bytes::copy_from_slice(&[127, 0, 0, 1]),
+                            length: 32,
+                        }],
+                        ..Default::default()
+                    }],
+                }],
+            }],
+            provider: "".to_string(),
+        };
+        let allowed = new_proxy_state(workloads(), vec![], vec![]).unwrap();
+        let denied = new_proxy_state(workloads(), vec![], vec![deny]).unwrap();
+        let metrics = Metrics::default();
+
+        let cm = ConnectionManager::default();
+        let rbac_conn = rbac::Connection {
+            src_identity: None,
+            src_ip: "127.0.0.1".parse().unwrap(),
+            dst_network: "".to_string(),
+            dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
+        };
+        let src = workload("uid-a", "a", "ns");
+        let dst = workload("cluster1//v1/Pod/ns/dst", "dst", "ns");
+        let enforced = cm.track(
+            &connection(src.clone(), dst.clone()),
+            Some(rbac_conn.clone()),
+            None,
+            rbac_conn.src_ip,
+            rbac_conn.dst,
+        );
+        // Connections that skipped policy (e.g. from a waypoint) are never re-evaluated.
+        let skipped = cm.track(
+            &connection(src, dst),
+            None,
+            None,
+            rbac_conn.src_ip,
+            rbac_conn.dst,
+        );
+
+        cm.reauthorize(&allowed, &metrics).await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), enforced.closed())
+                .await
+                .is_err(),
+            "allowed connection should not be closed"
+        );
+
+        let removed = new_proxy_state(vec![], vec![], vec![]).unwrap();
+        cm.reauthorize(&removed, &metrics).await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), enforced.closed())
+                .await
+                .is_err(),
+            "connection to a removed workload should not be closed by a policy change"
+        );
+
+        cm.reauthorize(&denied, &metrics).await;
+        tokio::time::timeout(Duration::from_secs(1), enforced.closed())
+            .await
+            .expect("denied connection should be closed");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), skipped.closed())
+                .await
+                .is_err(),
+            "connection without enforced policy should not be closed"
+        );
+    }
+}
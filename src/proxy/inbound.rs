@@ -15,13 +15,18 @@
 use super::Error;
 use crate::baggage::parse_baggage_header;
 use crate::config::Config;
+use crate::fault;
+use crate::fault::{Fault, FaultAction};
+use crate::hot_restart::Listeners;
 use crate::identity::SecretManager;
 use crate::metrics::traffic::{ConnectionOpen, Reporter};
 use crate::metrics::{traffic, Metrics, Recorder};
 use crate::proxy;
+use crate::proxy::connection_manager::{ConnectionGuard, ConnectionManager};
 use crate::proxy::inbound::InboundConnect::{DirectPath, Hbone};
+use crate::proxy::inpod::PodNetns;
 use crate::proxy::{ProxyInputs, TraceParent, BAGGAGE_HEADER, TRACEPARENT_HEADER};
-use crate::rbac::Connection;
+use crate::rbac::{Connection, Denial, SourceWorkload};
 use crate::socket::to_canonical;
 use crate::state::workload::{address, gatewayaddress, GatewayAddress, NetworkAddress, Workload};
 use crate::state::DemandProxyState;
@@ -48,13 +53,33 @@ pub(super) struct Inbound {
     state: DemandProxyState,
     drain: Watch,
     metrics: Arc<Metrics>,
+    connection_manager: ConnectionManager,
+    /// If set, the uid of the only workload this listener serves.
+    pod: Option<String>,
+    /// If set, the network namespace of that workload, which upstream connections are opened from.
+    netns: Option<PodNetns>,
 }
 
 impl Inbound {
-    pub(super) async fn new(mut pi: ProxyInputs, drain: Watch) -> Result<Inbound, Error> {
-        let listener: TcpListener = TcpListener::bind(pi.cfg.inbound_addr)
+    pub(super) async fn new(
+        pi: ProxyInputs,
+        addr: SocketAddr,
+        listeners: &Listeners,
+        drain: Watch,
+    ) -> Result<Inbound, Error> {
+        let listener: TcpListener = listeners
+            .bind("inbound", addr)
             .await
-            .map_err(|e| Error::Bind(pi.cfg.inbound_addr, e))?;
+            .map_err(|e| Error::Bind(addr, e))?;
+        Self::from_listener(pi, listener, drain)
+    }
+
+    /// from_listener serves inbound traffic on an already established listener.
+    pub(super) fn from_listener(
+        mut pi: ProxyInputs,
+        listener: TcpListener,
+        drain: Watch,
+    ) -> Result<Inbound, Error> {
         let transparent = super::maybe_set_transparent(&pi, &listener)?;
         // Override with our explicitly configured setting
         pi.cfg.enable_original_source = Some(transparent);
@@ -62,6 +87,7 @@ impl Inbound {
             address=%listener.local_addr().unwrap(),
             component="inbound",
             transparent,
+            pod=pi.pod.as_deref(),
             "listener established",
         );
         Ok(Inbound {
@@ -70,6 +96,9 @@ impl Inbound {
             listener,
             cert_manager: pi.cert_manager,
             metrics: pi.metrics,
+            connection_manager: pi.connection_manager,
+            pod: pi.pod,
+            netns: pi.netns,
             drain,
         })
     }
@@ -84,6 +113,7 @@ impl Inbound {
             state: self.state.clone(),
             cert_manager: self.cert_manager.clone(),
             network: self.cfg.network.clone(),
+            pod: self.pod.clone(),
         };
         let drain_stream = self.drain.clone();
         let stream = crate::hyper_util::tls_server(acceptor, self.listener);
@@ -91,6 +121,9 @@ impl Inbound {
         while let Some(socket) = stream.next().await {
             let state = self.state.clone();
             let metrics = self.metrics.clone();
+            let connection_manager = self.connection_manager.clone();
+            let pod = self.pod.clone();
+            let netns = self.netns.clone();
             let drain = self.drain.clone();
             let network = self.cfg.network.clone();
             tokio::task::spawn(async move {
@@ -103,6 +136,9 @@ impl Inbound {
                     src_ip: to_canonical(socket.get_ref().peer_addr().unwrap()).ip(),
                     dst_network: network, // inbound request must be on our network
                     dst,
+                    // Filled in once the request is known.
+                    src_workload: None,
+                    dst_service: None,
                 };
                 debug!(%conn, "accepted connection");
                 let enable_original_source = self.cfg.enable_original_source;
@@ -119,6 +155,9 @@ impl Inbound {
                                 enable_original_source.unwrap_or_default(),
                                 req,
                                 metrics.clone(),
+                                connection_manager.clone(),
+                                pod.clone(),
+                                netns.clone(),
                             )
                         }),
                     );
@@ -141,14 +180,59 @@ impl Inbound {
     /// handle_inbound serves an inbound connection with a target address `addr`.
     pub(super) async fn handle_inbound(
         request_type: InboundConnect,
+        netns: Option<&PodNetns>,
         orig_src: Option<IpAddr>,
         addr: SocketAddr,
         metrics: Arc<Metrics>,
         connection_metrics: ConnectionOpen,
         extra_connection_metrics: Option<ConnectionOpen>,
+        connection: ConnectionGuard,
+        fault: Option<Fault>,
     ) -> Result<(), std::io::Error> {
+        if let Some(fault) = &fault {
+            info!(fault = fault.name, action = ?fault.action, "injecting fault");
+            match fault.action {
+                FaultAction::Delay => tokio::time::sleep(fault.delay()).await,
+                FaultAction::Reject => {
+                    let _connection_close =
+                        metrics.increment_defer::<_, traffic::ConnectionClose>(&connection_metrics);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        format!("connection rejected by injected fault {}", fault.name),
+                    ));
+                }
+                FaultAction::Blackhole => {
+                    tokio::task::spawn(
+                        (async move {
+                            let _connection_close = metrics
+                                .increment_defer::<_, traffic::ConnectionClose>(
+                                    &connection_metrics,
+                                );
+                            let res = match request_type {
+                                DirectPath(mut incoming) => {
+                                    proxy::blackhole(&mut incoming, &connection).await
+                                }
+                                Hbone(req) => match hyper::upgrade::on(req).await {
+                                    Ok(mut upgraded) => {
+                                        proxy::blackhole(&mut upgraded, &connection).await
+                                    }
+                                    Err(e) => Err(e.into()),
+                                },
+                            };
+                            if let Err(e) = res {
+                                debug!("blackhole: {}", e);
+                            }
+                        })
+                        .in_current_span(),
+                    );
+                    // Respond with our 200 as usual, so the client believes it is connected.
+                    return Ok(());
+                }
+                FaultAction::Reset => connection.inject_fault(fault),
+            }
+        }
         let start = Instant::now();
-        let stream = super::freebind_connect(orig_src, addr).await;
+        let stream = super::freebind_connect(netns, orig_src, addr).await;
         match stream {
             Err(err) => {
                 warn!(dur=?start.elapsed(), "connection to {} failed: {}", addr, err);
@@ -176,6 +260,7 @@ impl Inbound {
                                     &mut stream,
                                     &metrics,
                                     transferred_bytes,
+                                    &connection,
                                 )
                                 .await
                                 {
@@ -199,6 +284,7 @@ impl Inbound {
                                         &mut stream,
                                         &metrics,
                                         transferred_bytes,
+                                        &connection,
                                     )
                                     .instrument(trace_span!("hbone server"))
                                     .await
@@ -242,6 +328,9 @@ impl Inbound {
         enable_original_source: bool,
         req: Request<Incoming>,
         metrics: Arc<Metrics>,
+        connection_manager: ConnectionManager,
+        pod: Option<String>,
+        netns: Option<PodNetns>,
     ) -> Result<Response<Empty<Bytes>>, hyper::Error> {
         match req.method() {
             &Method::CONNECT => {
@@ -277,28 +366,63 @@ impl Inbound {
                         .body(Empty::new())
                         .unwrap());
                 };
+                if pod.as_ref().map_or(false, |uid| uid != &upstream.uid) {
+                    info!(%conn, "Sending 400, destination is not the pod bound to this listener");
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Empty::new())
+                        .unwrap());
+                }
                 let has_waypoint = upstream.waypoint.is_some();
                 let from_waypoint = Self::check_waypoint(state.clone(), &upstream, &conn)
                     .await
                     .unwrap();
-                let from_gateway = Self::check_gateway(state.clone(), &upstream, &conn)
+                let gateway = Self::check_gateway(state.clone(), &upstream, &conn)
                     .await
                     .unwrap();
+                let from_gateway = gateway.is_some();
 
                 if from_gateway {
                     debug!("request from gateway");
                 }
+                let baggage =
+                    parse_baggage_header(req.headers().get_all(BAGGAGE_HEADER)).unwrap_or_default();
+                let src_workload = match &gateway {
+                    // Behind a gateway, the source is on another network, so we cannot look up
+                    // the source workload. Policies match the gateway, which was authenticated;
+                    // the client's baggage is never trusted for authorization.
+                    Some(gateway) => Some(SourceWorkload::from(gateway)),
+                    None => state.source_workload(&conn, &conn.dst_network).await,
+                };
+                // The client reports the service it addressed, which is used if it routes here.
+                let dst_service = state.destination_service(
+                    &dst_network_addr,
+                    addr.port(),
+                    super::get_forwarded_host(&req).as_deref(),
+                );
+                let conn = Connection {
+                    src_workload,
+                    dst_service,
+                    ..conn
+                };
                 if from_waypoint {
                     debug!("request from waypoint, skipping policy");
-                } else if !state.assert_rbac(&conn).await {
-                    info!(%conn, "RBAC rejected");
+                } else if let Err(denial) = state.assert_rbac(&conn, &metrics).await {
+                    info!(%conn, reason = %denial, "RBAC rejected");
                     return Ok(Response::builder()
                         .status(StatusCode::UNAUTHORIZED)
                         .body(Empty::new())
                         .unwrap());
                 }
+                // Policy is only re-evaluated for connections we enforced it on.
+                let rbac_conn = (!from_waypoint).then(|| conn.clone());
                 if has_waypoint && !from_waypoint {
                     info!(%conn, "bypassed waypoint");
+                    state.authz_log().record(
+                        &conn,
+                        Some(&upstream),
+                        &Err(Denial::WaypointBypassed),
+                    );
                     return Ok(Response::builder()
                         .status(StatusCode::UNAUTHORIZED)
                         .body(Empty::new())
@@ -314,9 +438,6 @@ impl Inbound {
                     conn.src_ip
                 };
 
-                let baggage =
-                    parse_baggage_header(req.headers().get_all(BAGGAGE_HEADER)).unwrap_or_default();
-
                 let source = match from_gateway {
                     true => None, // we cannot lookup source workload since we don't know the network, see https://github.com/istio/ztunnel/issues/515
                     false => {
@@ -338,23 +459,36 @@ impl Inbound {
                     revision: baggage.revision,
                     ..Default::default()
                 };
+                let fault = state.find_fault(&fault::Target {
+                    source: source.as_ref(),
+                    destination: Some(&upstream),
+                    // The service VIP has already been resolved by the client.
+                    destination_vip: None,
+                    destination_port: addr.port(),
+                });
                 let connection_metrics = ConnectionOpen {
                     reporter: Reporter::destination,
                     source,
                     derived_source: Some(derived_source),
                     destination: Some(upstream),
                     connection_security_policy: traffic::SecurityPolicy::mutual_tls,
+                    response_flags: fault.as_ref().into(),
                     destination_service: None,
                     destination_service_namespace: None,
                     destination_service_name: None,
                 };
+                let connection =
+                    connection_manager.track(&connection_metrics, rbac_conn, None, source_ip, addr);
                 let status_code = match Self::handle_inbound(
                     Hbone(req),
+                    netns.as_ref(),
                     enable_original_source.then_some(source_ip),
                     addr,
                     metrics,
                     connection_metrics,
                     None,
+                    connection,
+                    fault,
                 )
                 .in_current_span()
                 .await
@@ -384,22 +518,28 @@ impl Inbound {
         upstream: &Workload,
         conn: &Connection,
     ) -> Result<bool, Error> {
-        Self::check_gateway_address(state, conn, upstream.waypoint.as_ref()).await
+        Self::check_gateway_address(state, conn, upstream.waypoint.as_ref())
+            .await
+            .map(|wl| wl.is_some())
     }
 
+    /// check_gateway returns the network gateway workload of the upstream, if the connection
+    /// comes from it.
     async fn check_gateway(
         state: DemandProxyState,
         upstream: &Workload,
         conn: &Connection,
-    ) -> Result<bool, Error> {
+    ) -> Result<Option<Workload>, Error> {
         Self::check_gateway_address(state, conn, upstream.network_gateway.as_ref()).await
     }
 
+    /// check_gateway_address returns the workload at the gateway address whose identity is the
+    /// peer identity of the connection, if any.
     async fn check_gateway_address(
         state: DemandProxyState,
         conn: &Connection,
         gateway_address: Option<&GatewayAddress>,
-    ) -> Result<bool, Error> {
+    ) -> Result<Option<Workload>, Error> {
         let gateway_nw_addr = match gateway_address.as_ref() {
             Some(addr) => match &addr.destination {
                 gatewayaddress::Destination::Address(gateway_ip) => Ok(gateway_ip),
@@ -407,21 +547,25 @@ impl Inbound {
                     "hostname lookup not supported yet".to_string(),
                 )),
             },
-            None => return Ok(false),
+            None => return Ok(None),
         }?;
-        let from_gateway = match state.fetch_address(gateway_nw_addr).await {
-            Some(address::Address::Workload(wl)) => Some(wl.identity()) == conn.src_identity,
+        let gateway = match state.fetch_address(gateway_nw_addr).await {
+            Some(address::Address::Workload(wl)) => {
+                (Some(wl.identity()) == conn.src_identity).then_some(*wl)
+            }
             Some(address::Address::Service(svc)) => {
                 for (ip, _ep) in svc.endpoints.iter() {
-                    if state.fetch_workload(ip).await.map(|w| w.identity()) == conn.src_identity {
-                        return Ok(true);
+                    if let Some(wl) = state.fetch_workload(ip).await {
+                        if Some(wl.identity()) == conn.src_identity {
+                            return Ok(Some(wl));
+                        }
                     }
                 }
-                false
+                None
             }
-            None => false,
+            None => None,
         };
-        Ok(from_gateway)
+        Ok(gateway)
     }
 }
 
@@ -450,13 +594,20 @@ struct InboundCertProvider {
     cert_manager: Arc<SecretManager>,
     state: DemandProxyState,
     network: String,
+    /// If set, always serve the certificate of this workload.
+    pod: Option<String>,
 }
 
 #[async_trait::async_trait]
 impl crate::tls::CertProvider for InboundCertProvider {
     async fn fetch_cert(&mut self, fd: &TcpStream) -> Result<boring::ssl::SslAcceptor, TlsError> {
         let orig_dst_addr = crate::socket::orig_dst_addr_or_default(fd);
-        let identity = {
+        let identity = if let Some(uid) = &self.pod {
+            self.state
+                .find_workload_by_uid(uid)
+                .ok_or_else(|| TlsError::PodLookup(uid.clone()))?
+                .identity()
+        } else {
             let wip = NetworkAddress {
                 network: self.network.clone(), // inbound cert provider gets cert for the dest, which must be on our network
                 address: orig_dst_addr.ip(),
@@ -473,7 +624,12 @@ impl crate::tls::CertProvider for InboundCertProvider {
             "fetching cert"
         );
         let cert = self.cert_manager.fetch_certificate(&identity).await?;
-        let acc = cert.mtls_acceptor(Some(&identity))?;
+        let acc = cert.mtls_acceptor(
+            Some(&identity),
+            &self.state.trust_domain_aliases(),
+            self.state.trust_bundles(),
+            self.state.crls(),
+        )?;
         Ok(acc)
     }
 }
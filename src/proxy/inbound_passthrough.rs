@@ -13,14 +13,16 @@
 // limitations under the License.
 
 use crate::config::ProxyMode;
+use crate::hot_restart::Listeners;
 use crate::metrics::traffic;
 use crate::metrics::traffic::Reporter;
 use crate::proxy::outbound::OutboundConnection;
 use crate::proxy::{util, ProxyInputs};
 use crate::proxy::{Error, TraceParent};
 use crate::rbac;
-use crate::state::workload::NetworkAddress;
+use crate::state::workload::{MtlsMode, NetworkAddress, Protocol};
 use crate::{proxy, socket};
+use drain::Watch;
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
 use tracing::{error, info, trace, warn, Instrument};
@@ -28,13 +30,29 @@ use tracing::{error, info, trace, warn, Instrument};
 pub(super) struct InboundPassthrough {
     listener: TcpListener,
     pi: ProxyInputs,
+    drain: Watch,
 }
 
 impl InboundPassthrough {
-    pub(super) async fn new(mut pi: ProxyInputs) -> Result<InboundPassthrough, Error> {
-        let listener: TcpListener = TcpListener::bind(pi.cfg.inbound_plaintext_addr)
+    pub(super) async fn new(
+        pi: ProxyInputs,
+        addr: SocketAddr,
+        listeners: &Listeners,
+        drain: Watch,
+    ) -> Result<InboundPassthrough, Error> {
+        let listener: TcpListener = listeners
+            .bind("inbound_plaintext", addr)
             .await
-            .map_err(|e| Error::Bind(pi.cfg.inbound_plaintext_addr, e))?;
+            .map_err(|e| Error::Bind(addr, e))?;
+        Self::from_listener(pi, listener, drain)
+    }
+
+    /// from_listener serves inbound plaintext traffic on an already established listener.
+    pub(super) fn from_listener(
+        mut pi: ProxyInputs,
+        listener: TcpListener,
+        drain: Watch,
+    ) -> Result<InboundPassthrough, Error> {
         let transparent = super::maybe_set_transparent(&pi, &listener)?;
         // Override with our explicitly configured setting
         pi.cfg.enable_original_source = Some(transparent);
@@ -43,38 +61,54 @@ impl InboundPassthrough {
             address=%listener.local_addr().unwrap(),
             component="inbound plaintext",
             transparent,
+            pod=pi.pod.as_deref(),
             "listener established",
         );
-        Ok(InboundPassthrough { listener, pi })
+        Ok(InboundPassthrough {
+            listener,
+            pi,
+            drain,
+        })
     }
 
     pub(super) async fn run(self) {
-        loop {
-            // Asynchronously wait for an inbound socket.
-            let socket = self.listener.accept().await;
-            let pi = self.pi.clone();
-            match socket {
-                Ok((stream, remote)) => {
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::proxy_inbound_plaintext(
-                            pi, // pi cloned above; OK to move
-                            socket::to_canonical(remote),
-                            stream,
-                        )
-                        .await
-                        {
-                            warn!(source=%socket::to_canonical(remote), component="inbound plaintext", "proxying failed: {}", e)
+        let accept = async move {
+            loop {
+                // Asynchronously wait for an inbound socket.
+                let socket = self.listener.accept().await;
+                let pi = self.pi.clone();
+                match socket {
+                    Ok((stream, remote)) => {
+                        tokio::spawn(async move {
+                            if let Err(e) = Self::proxy_inbound_plaintext(
+                                pi, // pi cloned above; OK to move
+                                socket::to_canonical(remote),
+                                stream,
+                            )
+                            .await
+                            {
+                                warn!(source=%socket::to_canonical(remote), component="inbound plaintext", "proxying failed: {}", e)
+                            }
+                        }.in_current_span());
+                    }
+                    Err(e) => {
+                        if util::is_runtime_shutdown(&e) {
+                            return;
                         }
-                    }.in_current_span());
-                }
-                Err(e) => {
-                    if util::is_runtime_shutdown(&e) {
-                        return;
+                        error!("Failed TCP handshake {}", e);
                     }
-                    error!("Failed TCP handshake {}", e);
                 }
             }
         }
+        .in_current_span();
+
+        // Stop accepting once we drain.
+        tokio::select! {
+            res = accept => { res }
+            _ = self.drain.signaled() => {
+                info!("inbound plaintext drained");
+            }
+        }
     }
 
     async fn proxy_inbound_plaintext(
@@ -95,6 +129,9 @@ impl InboundPassthrough {
         let Some(upstream) = pi.state.fetch_workload(&network_addr).await else {
             return Err(Error::UnknownDestination(orig.ip()))
         };
+        if pi.pod.as_ref().map_or(false, |uid| uid != &upstream.uid) {
+            return Err(Error::UnknownDestination(orig.ip()));
+        }
         if upstream.waypoint.is_some() {
             // This is an inbound request not over HBONE, but we have a waypoint.
             // The request needs to go through the waypoint for policy enforcement.
@@ -110,6 +147,13 @@ impl InboundPassthrough {
             oc.pi.cfg.enable_original_source = Some(false);
             return oc.proxy_to(inbound, source.ip(), orig, false).await;
         }
+        // Workloads that can receive HBONE may require all traffic to arrive over mTLS.
+        // Hairpins through the waypoint are handled above, and arrive back at us over HBONE.
+        if upstream.protocol == Protocol::HBONE && pi.state.mtls_mode(&upstream) == MtlsMode::Strict
+        {
+            info!(%source, destination=%orig, component="inbound plaintext", "plaintext connection rejected by STRICT mTLS mode");
+            return Err(Error::PlaintextRejected(orig));
+        }
 
         // We enforce RBAC only for non-hairpin cases. This is because we may not be able to properly
         // enforce the policy (for example, if it has L7 attributes), while waypoint will.
@@ -123,9 +167,18 @@ impl InboundPassthrough {
             // by definition, without the gateway our source must be on our network.
             dst_network: pi.cfg.network.clone(),
             dst: orig,
+            src_workload: None,
+            dst_service: None,
+        };
+        // Plaintext clients have already resolved the service, so it is only known if exactly one
+        // routes to the destination.
+        let conn = rbac::Connection {
+            src_workload: pi.state.source_workload(&conn, &pi.cfg.network).await,
+            dst_service: pi.state.destination_service(&network_addr, orig.port(), None),
+            ..conn
         };
-        if !pi.state.assert_rbac(&conn).await {
-            info!(%conn, "RBAC rejected");
+        if let Err(denial) = pi.state.assert_rbac(&conn, &pi.metrics).await {
+            info!(%conn, reason = %denial, "RBAC rejected");
             return Ok(());
         }
         let source_ip = super::get_original_src_from_stream(&inbound);
@@ -136,7 +189,7 @@ impl InboundPassthrough {
             .then_some(source_ip)
             .flatten();
         trace!(%source, destination=%orig, component="inbound plaintext", "connect to {orig:?} from {orig_src:?}");
-        let mut outbound = super::freebind_connect(orig_src, orig).await?;
+        let mut outbound = super::freebind_connect(pi.netns.as_ref(), orig_src, orig).await?;
         trace!(%source, destination=%orig, component="inbound plaintext", "connected");
 
         // Find source info. We can lookup by XDS or from connection attributes
@@ -153,7 +206,7 @@ impl InboundPassthrough {
             None
         };
         let derived_source = traffic::DerivedWorkload {
-            identity: conn.src_identity,
+            identity: conn.src_identity.clone(),
             ..Default::default()
         };
         let connection_metrics = traffic::ConnectionOpen {
@@ -162,15 +215,26 @@ impl InboundPassthrough {
             derived_source: Some(derived_source),
             destination: Some(upstream),
             connection_security_policy: traffic::SecurityPolicy::unknown,
+            response_flags: traffic::ResponseFlags::none,
             destination_service: None,
             destination_service_namespace: None,
             destination_service_name: None,
         };
+        let connection = pi
+            .connection_manager
+            .track(&connection_metrics, Some(conn), None, source.ip(), orig);
         let _connection_close = pi
             .metrics
             .increment_defer::<_, traffic::ConnectionClose>(&connection_metrics);
         let transferred_bytes = traffic::BytesTransferred::from(&connection_metrics);
-        proxy::relay(&mut outbound, &mut inbound, &pi.metrics, transferred_bytes).await?;
+        proxy::relay(
+            &mut outbound,
+            &mut inbound,
+            &pi.metrics,
+            transferred_bytes,
+            &connection,
+        )
+        .await?;
         info!(%source, destination=%orig, component="inbound plaintext", "connection complete");
         Ok(())
     }
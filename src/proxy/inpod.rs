@@ -0,0 +1,494 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-pod listeners.
+//!
+//! A node agent creates listening sockets inside each pod's network namespace and hands them to
+//! us over a Unix domain socket with `SCM_RIGHTS`. We then serve the pod's traffic on them, with
+//! the pod's identity bound to the listeners, until the agent removes the pod.
+//!
+//! Upstream connections for a pod are opened from within its network namespace, which the node
+//! agent hands over along with the listeners.
+//!
+//! Each message is framed as a 4 byte big endian length followed by a JSON body. File
+//! descriptors, if any, are attached to the length prefix.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use drain::Watch;
+use futures::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::{TcpListener, TcpSocket, UnixListener, UnixStream};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::hot_restart::Listeners;
+use crate::proxy::inbound::Inbound;
+use crate::proxy::inbound_passthrough::InboundPassthrough;
+use crate::proxy::outbound::Outbound;
+use crate::proxy::{pool, util, Error, ProxyInputs};
+
+/// The most file descriptors a single message may carry.
+const MAX_FDS: usize = 16;
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+#[cfg(target_os = "linux")]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(target_os = "linux"))]
+const RECV_FLAGS: libc::c_int = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ListenerKind {
+    /// HBONE listener, in place of inbound_addr.
+    Inbound,
+    /// Plaintext inbound listener, in place of inbound_plaintext_addr.
+    InboundPlaintext,
+    /// Outbound listener, in place of outbound_addr.
+    Outbound,
+}
+
+/// Message is sent from the node agent. Each message is answered with an Ack.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Message {
+    /// AddPod starts serving a pod on the attached listeners. The first attached descriptor is the
+    /// pod's network namespace, such as an open `/proc/<pid>/ns/net`, followed by the listeners
+    /// in the same order as `listeners`. Adding a pod that already exists replaces its listeners.
+    #[serde(rename_all = "camelCase")]
+    AddPod {
+        uid: String,
+        listeners: Vec<ListenerKind>,
+    },
+    /// RemovePod drains the pod's listeners. The Ack is sent once draining completes.
+    #[serde(rename_all = "camelCase")]
+    RemovePod { uid: String },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Ack {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// send_message writes a message to the stream, passing along the file descriptors.
+pub async fn send_message<T: Serialize>(
+    stream: &mut UnixStream,
+    msg: &T,
+    fds: &[RawFd],
+) -> io::Result<()> {
+    let body = serde_json::to_vec(msg)?;
+    if body.len() > MAX_MESSAGE_SIZE || fds.len() > MAX_FDS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "message too large",
+        ));
+    }
+    let header = (body.len() as u32).to_be_bytes();
+    let fd = stream.as_raw_fd();
+    let sent = loop {
+        stream.writable().await?;
+        match stream.try_io(Interest::WRITABLE, || send_with_fds(fd, &header, fds)) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            res => break res?,
+        }
+    };
+    stream.write_all(&header[sent..]).await?;
+    stream.write_all(&body).await?;
+    Ok(())
+}
+
+/// recv_message reads a message and any file descriptors passed with it from the stream.
+/// None is returned once the peer closes the stream.
+pub async fn recv_message<T: DeserializeOwned>(
+    stream: &mut UnixStream,
+) -> io::Result<Option<(T, Vec<OwnedFd>)>> {
+    let mut header = [0u8; 4];
+    let mut fds = Vec::new();
+    let fd = stream.as_raw_fd();
+    let read = loop {
+        stream.readable().await?;
+        match stream.try_io(Interest::READABLE, || {
+            recv_with_fds(fd, &mut header, &mut fds)
+        }) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            res => break res?,
+        }
+    };
+    if read == 0 {
+        return Ok(None);
+    }
+    stream.read_exact(&mut header[read..]).await?;
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {len} bytes is too large"),
+        ));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    Ok(Some((serde_json::from_slice(&body)?, fds)))
+}
+
+/// cmsg_buffer returns a buffer, suitably aligned for a cmsghdr, with room for `fds` descriptors.
+fn cmsg_buffer(fds: usize) -> Vec<u64> {
+    // Safety: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE((fds * mem::size_of::<RawFd>()) as u32) } as usize;
+    vec![0; (space + mem::size_of::<u64>() - 1) / mem::size_of::<u64>()]
+}
+
+fn send_with_fds(sock: RawFd, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut cmsg = cmsg_buffer(fds.len());
+    // Safety: msghdr is plain data, and all pointers in it outlive the sendmsg call.
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            let data_len = fds.len() * mem::size_of::<RawFd>();
+            msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(data_len as u32) as _;
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            (*hdr).cmsg_level = libc::SOL_SOCKET;
+            (*hdr).cmsg_type = libc::SCM_RIGHTS;
+            (*hdr).cmsg_len = libc::CMSG_LEN(data_len as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr() as *const u8,
+                libc::CMSG_DATA(hdr),
+                data_len,
+            );
+        }
+        let n = libc::sendmsg(sock, &msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+fn recv_with_fds(sock: RawFd, buf: &mut [u8], fds: &mut Vec<OwnedFd>) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut cmsg = cmsg_buffer(MAX_FDS);
+    // Safety: msghdr is plain data, and all pointers in it outlive the recvmsg call. The kernel
+    // only writes complete control messages into the buffer.
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = (cmsg.len() * mem::size_of::<u64>()) as _;
+        let n = libc::recvmsg(sock, &mut msg, RECV_FLAGS);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Take ownership of all descriptors first, so they are closed if we bail out below.
+        let mut hdr = libc::CMSG_FIRSTHDR(&msg);
+        while !hdr.is_null() {
+            if (*hdr).cmsg_level == libc::SOL_SOCKET && (*hdr).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(hdr) as *const RawFd;
+                let count = ((*hdr).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            hdr = libc::CMSG_NXTHDR(&msg, hdr);
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("too many file descriptors, at most {MAX_FDS} are allowed"),
+            ));
+        }
+        Ok(n as usize)
+    }
+}
+
+/// PodNetns is the network namespace of a pod, which upstream connections for the pod are opened
+/// from.
+#[derive(Clone, Debug)]
+pub struct PodNetns(Arc<OwnedFd>);
+
+impl PodNetns {
+    pub fn new(fd: OwnedFd) -> PodNetns {
+        PodNetns(Arc::new(fd))
+    }
+
+    /// tcp_socket creates a socket in the pod's network namespace. A socket stays in the namespace
+    /// it was created in, so the calling thread only switches namespaces for the duration of this
+    /// call.
+    #[cfg(target_os = "linux")]
+    pub fn tcp_socket(&self, ip: IpAddr) -> io::Result<TcpSocket> {
+        let current = std::fs::File::open("/proc/thread-self/ns/net")?;
+        setns(self.0.as_raw_fd())?;
+        let socket = if ip.is_ipv4() {
+            TcpSocket::new_v4()
+        } else {
+            TcpSocket::new_v6()
+        };
+        if let Err(e) = setns(current.as_raw_fd()) {
+            // Carrying on would silently send all traffic handled by this thread from the pod.
+            error!("failed to restore network namespace: {}", e);
+            std::process::abort();
+        }
+        socket
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn tcp_socket(&self, _ip: IpAddr) -> io::Result<TcpSocket> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pod network namespaces are only supported on linux",
+        ))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn setns(fd: RawFd) -> io::Result<()> {
+    // Safety: setns only reads its arguments.
+    if unsafe { libc::setns(fd, libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+type Pods = Arc<Mutex<HashMap<String, drain::Signal>>>;
+
+/// PodManager accepts node agent connections, and runs proxies on the listeners they hand over.
+pub struct PodManager {
+    pi: ProxyInputs,
+    listener: UnixListener,
+    listeners: Listeners,
+    drain: Watch,
+}
+
+impl PodManager {
+    pub(super) fn new(
+        pi: ProxyInputs,
+        path: &Path,
+        listeners: &Listeners,
+        drain: Watch,
+    ) -> Result<PodManager, Error> {
+        let listener = listeners
+            .bind_unix("pods", path)
+            .map_err(|e| Error::BindUnix(path.to_owned(), e))?;
+        info!(
+            address=%path.display(),
+            component="pod listeners",
+            "listener established",
+        );
+        Ok(PodManager {
+            pi,
+            listener,
+            listeners: listeners.clone(),
+            drain,
+        })
+    }
+
+    pub(super) async fn run(self) {
+        let PodManager {
+            pi,
+            listener,
+            listeners,
+            drain,
+        } = self;
+        let pods: Pods = Default::default();
+        let accept = async {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(
+                            handle_agent(pi.clone(), pods.clone(), stream).in_current_span(),
+                        );
+                    }
+                    Err(e) => {
+                        if util::is_runtime_shutdown(&e) {
+                            return;
+                        }
+                        error!("failed to accept node agent connection: {}", e);
+                    }
+                }
+            }
+        };
+
+        // Once the socket is handed over to a new process, node agents connect there instead. Pods
+        // that were already added keep being served until we drain.
+        let handed_over = drain.clone();
+        let release = tokio::select! {
+            _ = listeners.accept(accept) => handed_over.signaled().await,
+            release = drain.signaled() => release,
+        };
+        // Hold off completing our own drain until every pod has drained.
+        let signals: Vec<_> = pods.lock().unwrap().drain().map(|(_, s)| s).collect();
+        info!(pods = signals.len(), "draining pod listeners");
+        futures::future::join_all(signals.into_iter().map(|s| s.drain())).await;
+        drop(release);
+    }
+}
+
+async fn handle_agent(pi: ProxyInputs, pods: Pods, mut stream: UnixStream) {
+    loop {
+        let (msg, fds) = match recv_message::<Message>(&mut stream).await {
+            Ok(Some(m)) => m,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("failed to read node agent message: {}", e);
+                return;
+            }
+        };
+        let res = match msg {
+            Message::AddPod { uid, listeners } => add_pod(&pi, &pods, uid, listeners, fds),
+            Message::RemovePod { uid } => remove_pod(&pi, &pods, &uid).await,
+        };
+        if let Err(e) = &res {
+            warn!("node agent request failed: {}", e);
+        }
+        let ack = Ack {
+            error: res.err().map(|e| e.to_string()),
+        };
+        if let Err(e) = send_message(&mut stream, &ack, &[]).await {
+            warn!("failed to write node agent ack: {}", e);
+            return;
+        }
+    }
+}
+
+fn add_pod(
+    pi: &ProxyInputs,
+    pods: &Pods,
+    uid: String,
+    kinds: Vec<ListenerKind>,
+    fds: Vec<OwnedFd>,
+) -> Result<(), Error> {
+    let mut fds = fds.into_iter();
+    let Some(netns) = fds.next() else {
+        return Err(Error::PodListeners(format!(
+            "pod {uid}: no network namespace was passed"
+        )));
+    };
+    if kinds.len() != fds.len() {
+        return Err(Error::PodListeners(format!(
+            "pod {uid}: {} listeners declared, but {} were passed",
+            kinds.len(),
+            fds.len()
+        )));
+    }
+    let mut pi = pi.clone();
+    pi.pod = Some(uid.clone());
+    pi.netns = Some(PodNetns::new(netns));
+    // Pooled connections belong to the network namespace they were opened in.
+    pi.pool = pool::Pool::new();
+    let (signal, drain) = drain::channel();
+
+    // Set up all proxies before starting any, so a bad listener rejects the pod as a whole.
+    let mut proxies: Vec<BoxFuture<'static, ()>> = Vec::with_capacity(kinds.len());
+    for (kind, fd) in kinds.into_iter().zip(fds) {
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let proxy: BoxFuture<'static, ()> = match kind {
+            ListenerKind::Inbound => {
+                Box::pin(Inbound::from_listener(pi.clone(), listener, drain.clone())?.run())
+            }
+            ListenerKind::InboundPlaintext => Box::pin(
+                InboundPassthrough::from_listener(pi.clone(), listener, drain.clone())?.run(),
+            ),
+            ListenerKind::Outbound => {
+                Box::pin(Outbound::from_listener(pi.clone(), listener, drain.clone())?.run())
+            }
+        };
+        proxies.push(proxy);
+    }
+
+    if let Some(old) = pods.lock().unwrap().insert(uid.clone(), signal) {
+        info!(pod = uid, "replacing pod listeners");
+        tokio::spawn(old.drain());
+    }
+    info!(pod = uid, listeners = proxies.len(), "pod listeners added");
+    let span = info_span!("pod", uid);
+    for proxy in proxies {
+        tokio::spawn(proxy.instrument(span.clone()));
+    }
+    Ok(())
+}
+
+async fn remove_pod(pi: &ProxyInputs, pods: &Pods, uid: &str) -> Result<(), Error> {
+    let signal = pods.lock().unwrap().remove(uid);
+    let Some(signal) = signal else {
+        return Err(Error::PodListeners(format!("pod {uid} is not known")));
+    };
+    info!(pod = uid, "draining pod listeners");
+    if tokio::time::timeout(pi.cfg.self_termination_deadline, signal.drain())
+        .await
+        .is_err()
+    {
+        warn!(pod = uid, "timed out draining pod listeners");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pass_listeners() {
+        let (mut agent, mut ztunnel) = UnixStream::pair().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let msg = Message::AddPod {
+            uid: "uid".to_string(),
+            listeners: vec![ListenerKind::Inbound],
+        };
+
+        send_message(&mut agent, &msg, &[listener.as_raw_fd()])
+            .await
+            .unwrap();
+        drop(listener);
+        let (got, fds) = recv_message::<Message>(&mut ztunnel)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(got, msg);
+        assert_eq!(fds.len(), 1);
+
+        // The received descriptor is the same listening socket.
+        let listener = std::net::TcpListener::from(fds.into_iter().next().unwrap());
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        send_message(&mut ztunnel, &Ack::default(), &[])
+            .await
+            .unwrap();
+        let (ack, fds) = recv_message::<Ack>(&mut agent).await.unwrap().unwrap();
+        assert_eq!(ack, Ack::default());
+        assert!(fds.is_empty());
+
+        drop(agent);
+        assert!(recv_message::<Ack>(&mut ztunnel).await.unwrap().is_none());
+    }
+}
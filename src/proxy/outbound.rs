@@ -25,13 +25,18 @@ use tokio::net::{TcpListener, TcpStream};
 use tracing::{debug, error, info, info_span, trace, trace_span, warn, Instrument};
 
 use crate::config::ProxyMode;
+use crate::fault;
+use crate::fault::FaultAction;
+use crate::hot_restart::Listeners;
 use crate::identity::Identity;
 use crate::metrics::traffic;
 use crate::metrics::traffic::Reporter;
 use crate::proxy::inbound::{Inbound, InboundConnect};
 use crate::proxy::pool;
-use crate::proxy::{util, Error, ProxyInputs, TraceParent, BAGGAGE_HEADER, TRACEPARENT_HEADER};
-use crate::state::workload::{NetworkAddress, Protocol, Workload};
+use crate::proxy::{
+    util, Downstream, Error, ProxyInputs, TraceParent, BAGGAGE_HEADER, TRACEPARENT_HEADER,
+};
+use crate::state::workload::{network_addr, MtlsMode, NetworkAddress, Protocol, Workload};
 use crate::{hyper_util, proxy, rbac, socket};
 
 pub struct Outbound {
@@ -41,10 +46,25 @@ pub struct Outbound {
 }
 
 impl Outbound {
-    pub(super) async fn new(mut pi: ProxyInputs, drain: Watch) -> Result<Outbound, Error> {
-        let listener: TcpListener = TcpListener::bind(pi.cfg.outbound_addr)
+    pub(super) async fn new(
+        pi: ProxyInputs,
+        addr: SocketAddr,
+        listeners: &Listeners,
+        drain: Watch,
+    ) -> Result<Outbound, Error> {
+        let listener: TcpListener = listeners
+            .bind("outbound", addr)
             .await
-            .map_err(|e| Error::Bind(pi.cfg.outbound_addr, e))?;
+            .map_err(|e| Error::Bind(addr, e))?;
+        Self::from_listener(pi, listener, drain)
+    }
+
+    /// from_listener serves outbound traffic on an already established listener.
+    pub(super) fn from_listener(
+        mut pi: ProxyInputs,
+        listener: TcpListener,
+        drain: Watch,
+    ) -> Result<Outbound, Error> {
         let transparent = super::maybe_set_transparent(&pi, &listener)?;
         // Override with our explicitly configured setting
         pi.cfg.enable_original_source = Some(transparent);
@@ -53,6 +73,7 @@ impl Outbound {
             address=%listener.local_addr().unwrap(),
             component="outbound",
             transparent,
+            pod=pi.pod.as_deref(),
             "listener established",
         );
         Ok(Outbound {
@@ -124,9 +145,9 @@ impl OutboundConnection {
         self.proxy_to(stream, peer.ip(), orig_dst_addr, false).await
     }
 
-    pub async fn proxy_to(
+    pub async fn proxy_to<S: Downstream>(
         &mut self,
-        mut stream: TcpStream,
+        mut stream: S,
         remote_addr: IpAddr,
         orig_dst_addr: SocketAddr,
         block_passthrough: bool,
@@ -146,13 +167,23 @@ impl OutboundConnection {
             // domains. But for socks5
             return Err(Error::UnknownDestination(req.destination.ip()));
         }
+        // Pod listeners cannot take the fast path, as the connection to the destination must be
+        // opened from the destination pod's network namespace, which only its own inbound knows.
         let can_fastpath = self.pi.cfg.proxy_mode == ProxyMode::Shared
+            && self.pi.netns.is_none()
+            && stream.as_tcp().is_some()
             && req.protocol == Protocol::HBONE
             && !req
                 .destination_workload
                 .as_ref()
                 .map(|w| w.native_tunnel)
                 .unwrap_or(false);
+        let fault = self.pi.state.find_fault(&fault::Target {
+            source: Some(&req.source),
+            destination: req.destination_workload.as_ref(),
+            destination_vip: Some(orig_dst_addr.ip()),
+            destination_port: orig_dst_addr.port(),
+        });
         let connection_metrics = traffic::ConnectionOpen {
             reporter: Reporter::source,
             derived_source: None,
@@ -163,17 +194,50 @@ impl OutboundConnection {
             } else {
                 traffic::SecurityPolicy::unknown
             },
+            response_flags: fault.as_ref().into(),
             destination_service: None,
             destination_service_namespace: None,
             destination_service_name: None,
         };
 
+        if let Some(fault) = &fault {
+            info!(fault = fault.name, action = ?fault.action, "injecting fault");
+            match fault.action {
+                FaultAction::Delay => tokio::time::sleep(fault.delay()).await,
+                FaultAction::Reject => {
+                    let _connection_close = self
+                        .pi
+                        .metrics
+                        .increment_defer::<_, traffic::ConnectionClose>(&connection_metrics);
+                    return Err(Error::FaultInjected(fault.name.clone()));
+                }
+                FaultAction::Blackhole => {
+                    let connection = self.pi.connection_manager.track(
+                        &connection_metrics,
+                        None,
+                        Some(req.request_type),
+                        remote_addr,
+                        req.destination,
+                    );
+                    let _connection_close = self
+                        .pi
+                        .metrics
+                        .increment_defer::<_, traffic::ConnectionClose>(&connection_metrics);
+                    return super::blackhole(&mut stream, &connection).await;
+                }
+                FaultAction::Reset => {}
+            }
+        }
+
         if req.request_type == RequestType::DirectLocal && can_fastpath {
             // For same node, we just access it directly rather than making a full network connection.
             // Pass our `stream` over to the inbound handler, which will process as usual
             // We *could* apply this to all traffic, rather than just for destinations that are "captured"
             // However, we would then get inconsistent behavior where only node-local pods have RBAC enforced.
             info!("proxying to {} using node local fast path", req.destination);
+            let Ok(stream) = stream.into_tcp() else {
+                unreachable!("fast path requires a TCP stream");
+            };
             let origin_src = if self.pi.cfg.enable_original_source.unwrap_or_default() {
                 super::get_original_src_from_stream(&stream)
             } else {
@@ -184,11 +248,26 @@ impl OutboundConnection {
                 src_ip: remote_addr,
                 dst_network: req.source.network.clone(), // since this is node local, it's the same network
                 dst: req.destination,
+                src_workload: Some((&req.source).into()),
+                dst_service: self
+                    .pi
+                    .state
+                    .service_hostname(&network_addr(&req.source.network, orig_dst_addr.ip())),
             };
-            if !self.pi.state.assert_rbac(&conn).await {
-                info!(%conn, "RBAC rejected");
+            if let Err(denial) = self.pi.state.assert_rbac(&conn, &self.pi.metrics).await {
+                info!(%conn, reason = %denial, "RBAC rejected");
                 return Err(Error::HttpStatus(StatusCode::UNAUTHORIZED));
             }
+            let connection = self.pi.connection_manager.track(
+                &connection_metrics,
+                Some(conn),
+                Some(req.request_type),
+                remote_addr,
+                req.destination,
+            );
+            if let Some(fault) = &fault {
+                connection.inject_fault(fault);
+            }
             // same as above but inverted, this is the "inbound" metric
             let inbound_connection_metrics = traffic::ConnectionOpen {
                 reporter: Reporter::destination,
@@ -200,22 +279,37 @@ impl OutboundConnection {
                 } else {
                     traffic::SecurityPolicy::unknown
                 },
+                response_flags: fault.as_ref().into(),
                 destination_service: None,
                 destination_service_namespace: None,
                 destination_service_name: None,
             };
             return Inbound::handle_inbound(
                 InboundConnect::DirectPath(stream),
+                None,
                 origin_src,
                 req.destination,
                 self.pi.metrics.to_owned(), // self is a borrow so this clone is to return an owned
                 connection_metrics,
                 Some(inbound_connection_metrics),
+                connection,
+                // The fault was already injected on the outbound side.
+                None,
             )
             .await
             .map_err(Error::Io);
         }
 
+        let connection = self.pi.connection_manager.track(
+            &connection_metrics,
+            None,
+            Some(req.request_type),
+            remote_addr,
+            req.destination,
+        );
+        if let Some(fault) = &fault {
+            connection.inject_fault(fault);
+        }
         let transferred_bytes = traffic::BytesTransferred::from(&connection_metrics);
 
         // _connection_close will record once dropped
@@ -260,10 +354,16 @@ impl OutboundConnection {
                     let id = &req.source.identity();
                     let cert = self.pi.cert_manager.fetch_certificate(id).await?;
                     let connector = cert
-                        .connector(dst_identity)?
+                        .connector(
+                            dst_identity,
+                            &self.pi.state.trust_domain_aliases(),
+                            self.pi.state.trust_bundles(),
+                            self.pi.state.crls(),
+                        )?
                         .configure()
                         .expect("configure");
-                    let tcp_stream = super::freebind_connect(local, req.gateway).await?;
+                    let tcp_stream =
+                        super::freebind_connect(self.pi.netns.as_ref(), local, req.gateway).await?;
                     tcp_stream.set_nodelay(true)?; // TODO: this is backwards of expectations
                     let tls_stream = connect_tls(connector, tcp_stream).await?;
                     let (request_sender, connection) = builder
@@ -282,6 +382,14 @@ impl OutboundConnection {
 
                 let mut f = http_types::proxies::Forwarded::new();
                 f.add_for(remote_addr.to_string());
+                // Tell the destination which service was addressed, so its policies can match it.
+                let service = self
+                    .pi
+                    .state
+                    .service_hostname(&network_addr(&req.source.network, orig_dst_addr.ip()));
+                if let Some(service) = &service {
+                    f.set_host(service);
+                }
 
                 let request = hyper::Request::builder()
                     .uri(&req.destination.to_string())
@@ -309,6 +417,7 @@ impl OutboundConnection {
                     &mut stream,
                     &self.pi.metrics,
                     transferred_bytes,
+                    &connection,
                 )
                 .instrument(trace_span!("hbone client"))
                 .await
@@ -320,17 +429,21 @@ impl OutboundConnection {
                 );
                 // Create a TCP connection to upstream
                 let local = if self.pi.cfg.enable_original_source.unwrap_or_default() {
-                    super::get_original_src_from_stream(&stream)
+                    stream
+                        .as_tcp()
+                        .and_then(|s| super::get_original_src_from_stream(s))
                 } else {
                     None
                 };
-                let mut outbound = super::freebind_connect(local, req.gateway).await?;
+                let mut outbound =
+                    super::freebind_connect(self.pi.netns.as_ref(), local, req.gateway).await?;
                 // Proxying data between downstrean and upstream
                 proxy::relay(
                     &mut stream,
                     &mut outbound,
                     &self.pi.metrics,
                     transferred_bytes,
+                    &connection,
                 )
                 .await
                 .map(|_| ())
@@ -347,9 +460,13 @@ impl OutboundConnection {
             network: self.pi.cfg.network.clone(),
             address: downstream,
         };
-        let source_workload = match self.pi.state.fetch_workload(&downstream_network_addr).await {
-            Some(wl) => wl,
-            None => return Err(Error::UnknownSource(downstream)),
+        // Listeners bound to a pod only carry that pod's traffic, so we know the source directly.
+        let source_workload = match &self.pi.pod {
+            Some(uid) => self.pi.state.find_workload_by_uid(uid),
+            None => self.pi.state.fetch_workload(&downstream_network_addr).await,
+        };
+        let Some(source_workload) = source_workload else {
+            return Err(Error::UnknownSource(downstream));
         };
 
         // TODO: we want a single lock for source and upstream probably...?
@@ -399,6 +516,23 @@ impl OutboundConnection {
             // we expected the workload to have a waypoint, but could not find one
             Err(e) => return Err(Error::UnknownWaypoint(e.to_string())),
         }
+        // Workloads with mTLS disabled receive plaintext, even if they can receive HBONE.
+        if us.workload.protocol == Protocol::HBONE
+            && self.pi.state.mtls_mode(&us.workload) == MtlsMode::Disable
+        {
+            let destination =
+                SocketAddr::from((self.pi.state.choose_workload_ip(&us.workload)?, us.port));
+            return Ok(Request {
+                protocol: Protocol::TCP,
+                source: source_workload,
+                destination,
+                destination_workload: Some(us.workload),
+                expected_identity: None,
+                gateway: destination,
+                direction: Direction::Outbound,
+                request_type: RequestType::Direct,
+            });
+        }
         if us.workload.gateway_address.is_none() {
             return Err(Error::NoGatewayAddress(Box::new(us.workload.clone())));
         }
@@ -456,12 +590,13 @@ impl OutboundConnection {
 }
 
 fn baggage(r: &Request, cluster: String) -> String {
-    format!("k8s.cluster.name={cluster},k8s.namespace.name={namespace},k8s.{workload_type}.name={workload_name},service.name={name},service.version={version}",
+    format!("k8s.cluster.name={cluster},k8s.namespace.name={namespace},k8s.{workload_type}.name={workload_name},service.name={name},service.version={version},istio.network.name={network}",
             namespace = r.source.namespace,
             workload_type = r.source.workload_type,
             workload_name = r.source.workload_name,
             name = r.source.canonical_name,
             version = r.source.canonical_revision,
+            network = r.source.network,
     )
 }
 
@@ -487,8 +622,8 @@ enum Direction {
     Outbound,
 }
 
-#[derive(PartialEq, Debug)]
-enum RequestType {
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub(super) enum RequestType {
     /// ToServerWaypoint refers to requests targeting a server waypoint proxy
     ToServerWaypoint,
     /// Direct requests are made directly to a intended backend pod
@@ -556,6 +691,9 @@ mod tests {
                 cfg,
                 metrics: Arc::new(Default::default()),
                 pool: pool::Pool::new(),
+                connection_manager: Default::default(),
+                pod: None,
+                netns: None,
             },
             id: TraceParent::new(),
         };
@@ -671,6 +809,31 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn build_request_known_dest_mtls_disabled() {
+        run_build_request(
+            "127.0.0.1",
+            "127.0.0.2:80",
+            XdsWorkload {
+                uid: "cluster1//v1/Pod/ns/test-tcp".to_string(),
+                name: "test-tcp".to_string(),
+                namespace: "ns".to_string(),
+                addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+                tunnel_protocol: XdsProtocol::Hbone as i32,
+                node: "remote-node".to_string(),
+                mtls_mode: xds::istio::workload::MtlsMode::Disable as i32,
+                ..Default::default()
+            },
+            Some(ExpectedRequest {
+                protocol: Protocol::TCP,
+                destination: "127.0.0.2:80",
+                gateway: "127.0.0.2:80",
+                request_type: RequestType::Direct,
+            }),
+        )
+        .await;
+    }
+
     #[tokio::test]
     async fn build_request_known_dest_local_node_hbone() {
         run_build_request(
@@ -15,28 +15,44 @@
 use anyhow::Result;
 use byteorder::{BigEndian, ByteOrder};
 use drain::Watch;
+use std::fs::Permissions;
 use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, UnixListener};
 use tracing::{error, info, warn};
 
+use crate::hot_restart::Listeners;
 use crate::proxy::outbound::OutboundConnection;
-use crate::proxy::{util, Error, ProxyInputs, TraceParent};
+use crate::proxy::{util, Downstream, Error, ProxyInputs, TraceParent};
 use crate::socket;
 
 pub(super) struct Socks5 {
     pi: ProxyInputs,
-    listener: TcpListener,
+    listener: Listener,
     drain: Watch,
 }
 
+enum Listener {
+    Tcp(TcpListener),
+    /// Unix listeners have no peer address, so clients are attributed to the given IP.
+    Unix(UnixListener, IpAddr),
+}
+
 impl Socks5 {
-    pub(super) async fn new(pi: ProxyInputs, drain: Watch) -> Result<Socks5, Error> {
-        let listener: TcpListener = TcpListener::bind(pi.cfg.socks5_addr)
+    pub(super) async fn new(
+        pi: ProxyInputs,
+        addr: SocketAddr,
+        listeners: &Listeners,
+        drain: Watch,
+    ) -> Result<Socks5, Error> {
+        let listener: TcpListener = listeners
+            .bind("socks5", addr)
             .await
-            .map_err(|e| Error::Bind(pi.cfg.socks5_addr, e))?;
+            .map_err(|e| Error::Bind(addr, e))?;
 
         info!(
             address=%listener.local_addr().unwrap(),
@@ -46,39 +62,78 @@ impl Socks5 {
 
         Ok(Socks5 {
             pi,
-            listener,
+            listener: Listener::Tcp(listener),
+            drain,
+        })
+    }
+
+    /// bind_unix serves SOCKS5 on a Unix domain socket. Clients are attributed to our own
+    /// instance IP.
+    pub(super) fn bind_unix(
+        pi: ProxyInputs,
+        path: &Path,
+        listeners: &Listeners,
+        drain: Watch,
+    ) -> Result<Socks5, Error> {
+        let Some(source) = pi.cfg.local_ip else {
+            return Err(Error::UnsupportedFeature(
+                "SOCKS5 over a unix socket requires INSTANCE_IP to be set".to_string(),
+            ));
+        };
+        let listener = listeners
+            .bind_unix("socks5_unix", path)
+            .map_err(|e| Error::BindUnix(path.to_owned(), e))?;
+        // Anyone who can connect may send traffic as this node, so never rely on the umask.
+        std::fs::set_permissions(path, Permissions::from_mode(pi.cfg.socks5_path_mode))
+            .map_err(|e| Error::BindUnix(path.to_owned(), e))?;
+
+        info!(
+            address=%path.display(),
+            component="socks5",
+            "listener established",
+        );
+
+        Ok(Socks5 {
+            pi,
+            listener: Listener::Unix(listener, source),
             drain,
         })
     }
 
-    pub(super) fn address(&self) -> SocketAddr {
-        self.listener.local_addr().unwrap()
+    /// address returns the TCP address we are listening on, if any.
+    pub(super) fn address(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => Some(listener.local_addr().unwrap()),
+            Listener::Unix(..) => None,
+        }
     }
 
     pub async fn run(self) {
         let accept = async move {
             loop {
                 // Asynchronously wait for an inbound socket.
-                let socket = self.listener.accept().await;
-                match socket {
-                    Ok((stream, remote)) => {
+                let oc = OutboundConnection {
+                    pi: self.pi.clone(),
+                    id: TraceParent::new(),
+                };
+                let res = match &self.listener {
+                    Listener::Tcp(listener) => listener.accept().await.map(|(stream, remote)| {
+                        let remote = socket::to_canonical(remote);
                         info!("accepted outbound connection from {}", remote);
-                        let oc = OutboundConnection {
-                            pi: self.pi.clone(),
-                            id: TraceParent::new(),
-                        };
-                        tokio::spawn(async move {
-                            if let Err(err) = handle(oc, stream).await {
-                                log::error!("handshake error: {}", err);
-                            }
-                        });
+                        spawn_handle(oc, stream, remote.ip());
+                    }),
+                    Listener::Unix(listener, source) => {
+                        listener.accept().await.map(|(stream, _)| {
+                            info!("accepted outbound connection on unix socket");
+                            spawn_handle(oc, stream, *source);
+                        })
                     }
-                    Err(e) => {
-                        if util::is_runtime_shutdown(&e) {
-                            return;
-                        }
-                        error!("Failed TCP handshake {}", e);
+                };
+                if let Err(e) = res {
+                    if util::is_runtime_shutdown(&e) {
+                        return;
                     }
+                    error!("Failed TCP handshake {}", e);
                 }
             }
         };
@@ -92,11 +147,23 @@ impl Socks5 {
     }
 }
 
+fn spawn_handle<S: Downstream>(oc: OutboundConnection, stream: S, remote_addr: IpAddr) {
+    tokio::spawn(async move {
+        if let Err(err) = handle(oc, stream, remote_addr).await {
+            log::error!("handshake error: {}", err);
+        }
+    });
+}
+
 // hande will process a SOCKS5 connection. This supports a minimal subset of the protocol,
 // sufficient to integrate with common clients:
 // - only unauthenticated requests
 // - only CONNECT, with IPv4 or IPv6
-async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(), anyhow::Error> {
+async fn handle<S: Downstream>(
+    mut oc: OutboundConnection,
+    mut stream: S,
+    remote_addr: IpAddr,
+) -> Result<(), anyhow::Error> {
     // Version(5), Number of auth methods
     let mut version = [0u8; 2];
     stream.read_exact(&mut version).await?;
@@ -176,8 +243,6 @@ async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(),
 
     let host = SocketAddr::new(ip, port);
 
-    let remote_addr = socket::to_canonical(stream.peer_addr().expect("must receive peer addr"));
-
     // Send dummy values - the client generally ignores it.
     let buf = [
         0x05u8, // versuib
@@ -189,7 +254,7 @@ async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(),
 
     info!("accepted connection from {remote_addr} to {host}");
     tokio::spawn(async move {
-        let res = oc.proxy_to(stream, remote_addr.ip(), host, true).await;
+        let res = oc.proxy_to(stream, remote_addr, host, true).await;
         match res {
             Ok(_) => {}
             Err(ref e) => warn!("outbound proxy failed: {}", e),
@@ -16,19 +16,25 @@ use ipnet::IpNet;
 use std::convert::Into;
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, SocketAddr};
-use tracing::{instrument, trace};
 use xds::istio::security::string_match::MatchType;
 use xds::istio::security::Address as XdsAddress;
 use xds::istio::security::Authorization as XdsRbac;
 use xds::istio::security::Match;
 use xds::istio::security::StringMatch as XdsStringMatch;
 
-use crate::identity::Identity;
+use crate::identity::{Identity, TrustDomainAliases};
 use crate::state::workload::WorkloadError::EnumParse;
-use crate::state::workload::{byte_to_ip, WorkloadError};
+use crate::state::workload::{byte_to_ip, Workload, WorkloadError};
 use crate::xds;
 
+mod compiled;
+pub use compiled::{
+    ClauseExplanation, Decision, Explanation, MatchExplanation, PolicyExplanation, PolicySet,
+    RuleExplanation,
+};
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Authorization {
@@ -37,6 +43,9 @@ pub struct Authorization {
     pub scope: RbacScope,
     pub action: RbacAction,
     pub rules: Vec<Vec<Vec<RbacMatch>>>,
+    /// For CUSTOM policies, the name of the external authorization provider.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -45,6 +54,59 @@ pub struct Connection {
     pub src_ip: IpAddr,
     pub dst_network: String,
     pub dst: SocketAddr,
+    /// Metadata of the source workload, if it is known.
+    pub src_workload: Option<SourceWorkload>,
+    /// Hostname of the destination service, if the connection was addressed to a service VIP.
+    pub dst_service: Option<String>,
+}
+
+/// SourceWorkload is the metadata of a connection's source workload that policies can match.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceWorkload {
+    pub network: String,
+    pub cluster: String,
+    pub canonical_name: String,
+    pub workload_type: String,
+}
+
+impl From<&Workload> for SourceWorkload {
+    fn from(w: &Workload) -> Self {
+        SourceWorkload {
+            network: w.network.clone(),
+            cluster: w.cluster_id.clone(),
+            canonical_name: w.canonical_name.clone(),
+            workload_type: w.workload_type.clone(),
+        }
+    }
+}
+
+/// Denial is the reason a connection was rejected by policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Denial {
+    /// The destination workload is not known, so its policies cannot be evaluated.
+    UnknownWorkload,
+    /// The named DENY policy matched.
+    Policy(String),
+    /// There are ALLOW policies, but none matched.
+    NotAllowed,
+    /// The provider of the named CUSTOM policy denied the connection.
+    Custom { policy: String, provider: String },
+    /// The destination has a waypoint, but the connection did not come through it.
+    WaypointBypassed,
+}
+
+impl Display for Denial {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Denial::UnknownWorkload => write!(f, "destination workload not found"),
+            Denial::Policy(policy) => write!(f, "denied by policy {policy}"),
+            Denial::NotAllowed => write!(f, "no ALLOW policy matched"),
+            Denial::Custom { policy, provider } => {
+                write!(f, "denied by provider {provider} of policy {policy}")
+            }
+            Denial::WaypointBypassed => write!(f, "bypassed waypoint"),
+        }
+    }
 }
 
 struct OptionDisplay<'a, T>(&'a Option<T>);
@@ -75,122 +137,10 @@ impl Authorization {
         format!("{}/{}", self.namespace, self.name)
     }
 
-    #[instrument(level = "trace", skip_all, fields(policy=self.to_key()))]
-    pub fn matches(&self, conn: &Connection) -> bool {
-        let id = conn
-            .src_identity
-            .as_ref()
-            .map(|i| i.to_string())
-            .unwrap_or_default();
-        let ns = conn
-            .src_identity
-            .as_ref()
-            .map(|i| match i {
-                Identity::Spiffe { namespace, .. } => namespace.to_owned(), // may be more clear if we use to_owned() to denote change from borrowed to owned
-            })
-            .unwrap_or_default();
-        if self.rules.is_empty() {
-            trace!(matches = false, "empty rules");
-            return false;
-        }
-        // An Authorization Policy can have multiple rules
-        // If ANY rule matches it's a match...
-        for rule in self.rules.iter() {
-            // Rule typically has 1-3 clauses (from,to,when)
-            // If ALL clauses match, it is a match...
-            let mut rule_match = true;
-            for clause in rule.iter() {
-                // We can have multiple mg (RbacMatch) in a clause, for example "Match ns=A,SA=B or ns=C"
-                // So we need ANY mg to match...
-                let mut clause_match = false;
-                for mg in clause.iter() {
-                    if mg.is_empty() {
-                        trace!(matches = false, "empty clause");
-                        continue;
-                    }
-                    // We need ALL of these to match. Within each type, ANY must match
-                    let mut m = true;
-                    m &= Self::matches_internal(
-                        "destination_ip",
-                        &mg.destination_ips,
-                        &mg.not_destination_ips,
-                        |i| i.contains(&conn.dst.ip()),
-                    );
-                    m &= Self::matches_internal(
-                        "source_ips",
-                        &mg.source_ips,
-                        &mg.not_source_ips,
-                        |i| i.contains(&conn.src_ip),
-                    );
-                    m &= Self::matches_internal(
-                        "destination_ports",
-                        &mg.destination_ports,
-                        &mg.not_destination_ports,
-                        |p| *p == conn.dst.port(),
-                    );
-                    m &= Self::matches_internal(
-                        "principals",
-                        &mg.principals,
-                        &mg.not_principals,
-                        |p| p.matches_principal(&id),
-                    );
-                    m &= Self::matches_internal(
-                        "namespaces",
-                        &mg.namespaces,
-                        &mg.not_namespaces,
-                        |p| p.matches(&ns),
-                    );
-
-                    if m {
-                        clause_match = true;
-                        break;
-                    }
-                }
-
-                if clause.is_empty() {
-                    clause_match = true;
-                    trace!(matches = clause_match, "empty clause");
-                } else {
-                    trace!(matches = clause_match, "clause");
-                }
-                rule_match &= clause_match;
-                if !rule_match {
-                    // Short circuit
-                    break;
-                }
-            }
-            trace!(matches = rule_match, "rule");
-            if rule_match {
-                return true;
-            }
-        }
-        false
-    }
-
-    #[instrument(name= "match", level = "trace", skip_all, fields(%desc))]
-    fn matches_internal<T: fmt::Debug>(
-        desc: &'static str,
-        positive: &Vec<T>,
-        negative: &Vec<T>,
-        mut predicate: impl FnMut(&T) -> bool,
-    ) -> bool {
-        let pm = if positive.is_empty() {
-            trace!(matches = true, "type" = "positive", "no match declared");
-            true
-        } else {
-            let matches = positive.iter().any(&mut predicate);
-            trace!(%matches, "type"="positive", "{positive:?}");
-            matches
-        };
-        let nm = if negative.is_empty() {
-            trace!(matches = true, "type" = "negative", "no match declared");
-            true
-        } else {
-            let matches = !negative.iter().any(&mut predicate);
-            trace!(%matches, "type"="negative", "{negative:?}");
-            matches
-        };
-        pm && nm
+    /// explain matches the policy against the connection, returning the result of each rule,
+    /// clause and match.
+    pub fn explain(&self, conn: &Connection, aliases: &TrustDomainAliases) -> PolicyExplanation {
+        compiled::CompiledPolicy::new(self).explain(self.action, conn, aliases)
     }
 }
 
@@ -216,6 +166,26 @@ pub struct RbacMatch {
     pub destination_ports: Vec<u16>,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub not_destination_ports: Vec<u16>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub destination_services: Vec<StringMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub not_destination_services: Vec<StringMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub source_networks: Vec<StringMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub not_source_networks: Vec<StringMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub source_clusters: Vec<StringMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub not_source_clusters: Vec<StringMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub source_canonical_names: Vec<StringMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub not_source_canonical_names: Vec<StringMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub source_workload_types: Vec<StringMatch>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub not_source_workload_types: Vec<StringMatch>,
 }
 
 impl RbacMatch {
@@ -230,6 +200,16 @@ impl RbacMatch {
             && self.not_destination_ips.is_empty()
             && self.destination_ports.is_empty()
             && self.not_destination_ports.is_empty()
+            && self.destination_services.is_empty()
+            && self.not_destination_services.is_empty()
+            && self.source_networks.is_empty()
+            && self.not_source_networks.is_empty()
+            && self.source_clusters.is_empty()
+            && self.not_source_clusters.is_empty()
+            && self.source_canonical_names.is_empty()
+            && self.not_source_canonical_names.is_empty()
+            && self.source_workload_types.is_empty()
+            && self.not_source_workload_types.is_empty()
     }
 }
 
@@ -239,17 +219,121 @@ pub enum StringMatch {
     Suffix(String),
     Exact(String),
     Presence(),
+    Regex(#[serde(deserialize_with = "deserialize_regex")] Pattern),
+    Glob(#[serde(deserialize_with = "deserialize_glob")] Pattern),
+}
+
+/// The maximum size of a compiled pattern, bounding the memory a single policy can use.
+const MAX_PATTERN_SIZE: usize = 1 << 20;
+
+/// Pattern is a regular expression, compiled when its policy is received. The regex crate
+/// follows RE2: it has no backreferences or lookaround, and matching is linear in the input.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// The pattern as configured.
+    source: String,
+    regex: regex::Regex,
+}
+
+impl Pattern {
+    /// regex compiles a regular expression, which must match the entire string.
+    pub fn regex(source: &str) -> Result<Pattern, WorkloadError> {
+        Pattern::new(source, &format!("^(?:{source})$"))
+    }
+
+    /// glob compiles a glob, where `*` matches any sequence of characters other than `/`, and `?`
+    /// matches any single character other than `/`.
+    pub fn glob(source: &str) -> Result<Pattern, WorkloadError> {
+        let mut re = String::from("^");
+        for c in source.chars() {
+            match c {
+                '*' => re.push_str("[^/]*"),
+                '?' => re.push_str("[^/]"),
+                c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        re.push('$');
+        Pattern::new(source, &re)
+    }
+
+    fn new(source: &str, re: &str) -> Result<Pattern, WorkloadError> {
+        let regex = regex::RegexBuilder::new(re)
+            .size_limit(MAX_PATTERN_SIZE)
+            .build()
+            .map_err(|e| WorkloadError::InvalidPattern(source.to_string(), e.to_string()))?;
+        Ok(Pattern {
+            source: source.to_string(),
+            regex,
+        })
+    }
+
+    pub fn is_match(&self, check: &str) -> bool {
+        self.regex.is_match(check)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Pattern {}
+
+impl Hash for Pattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.source.hash(state)
+    }
+}
+
+impl serde::Serialize for Pattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+fn deserialize_regex<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Pattern, D::Error> {
+    let source = <String as serde::Deserialize>::deserialize(deserializer)?;
+    Pattern::regex(&source).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_glob<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Pattern, D::Error> {
+    let source = <String as serde::Deserialize>::deserialize(deserializer)?;
+    Pattern::glob(&source).map_err(serde::de::Error::custom)
 }
 
 impl StringMatch {
-    pub fn matches_principal(&self, check: &str) -> bool {
+    pub fn matches_principal(&self, check: &str, aliases: &TrustDomainAliases) -> bool {
         // Istio matches all assumes spiffe:// prefix. This includes prefix matches.
         // A prefix match for "*foo" means "spiffe://*foo".
         // So we strip it, and fail if it isn't present.
-        let Some(check) = check.strip_prefix("spiffe://") else {
+        let Some(stripped) = check.strip_prefix("spiffe://") else {
             return false
         };
-        self.matches(check)
+        // Regexes and globs can't be prefixed consistently, so they see the full principal.
+        let matches = |full: &str, stripped: &str| match self {
+            StringMatch::Regex(p) | StringMatch::Glob(p) => p.is_match(full),
+            _ => self.matches(stripped),
+        };
+        if matches(check, stripped) {
+            return true;
+        }
+        // The principal also matches if it does in an alias of its trust domain.
+        let Some((trust_domain, rest)) = stripped.split_once('/') else {
+            return false;
+        };
+        aliases.aliases(trust_domain).any(|alias| {
+            let full = format!("spiffe://{alias}/{rest}");
+            matches(&full, &full["spiffe://".len()..])
+        })
     }
 
     pub fn matches(&self, check: &str) -> bool {
@@ -258,6 +342,7 @@ impl StringMatch {
             StringMatch::Suffix(suf) => check.ends_with(suf),
             StringMatch::Exact(exact) => exact == check,
             StringMatch::Presence() => !check.is_empty(),
+            StringMatch::Regex(p) | StringMatch::Glob(p) => p.is_match(check),
         }
     }
 }
@@ -286,6 +371,10 @@ impl TryFrom<Option<xds::istio::security::Scope>> for RbacScope {
 pub enum RbacAction {
     Allow,
     Deny,
+    /// Audit policies are evaluated and recorded, but never affect whether a connection is allowed.
+    Audit,
+    /// Custom policies ask an external authorization provider whether to allow matching connections.
+    Custom,
 }
 
 impl TryFrom<Option<xds::istio::security::Action>> for RbacAction {
@@ -295,6 +384,8 @@ impl TryFrom<Option<xds::istio::security::Action>> for RbacAction {
         match value {
             Some(xds::istio::security::Action::Allow) => Ok(RbacAction::Allow),
             Some(xds::istio::security::Action::Deny) => Ok(RbacAction::Deny),
+            Some(xds::istio::security::Action::Audit) => Ok(RbacAction::Audit),
+            Some(xds::istio::security::Action::Custom) => Ok(RbacAction::Custom),
             None => Err(EnumParse("unknown type".into())),
         }
     }
@@ -320,12 +411,18 @@ impl TryFrom<&XdsRbac> for Authorization {
                     .collect::<Result<Vec<_>, _>>()
             })
             .collect::<Result<Vec<_>, _>>()?;
+        let action = RbacAction::try_from(xds::istio::security::Action::from_i32(resource.action))?;
+        let provider = Some(resource.provider).filter(|p| !p.is_empty());
+        if action == RbacAction::Custom && provider.is_none() {
+            return Err(WorkloadError::MissingProvider(resource.name));
+        }
         Ok(Authorization {
             name: resource.name,
             namespace: resource.namespace,
             scope: RbacScope::try_from(xds::istio::security::Scope::from_i32(resource.scope))?,
-            action: RbacAction::try_from(xds::istio::security::Action::from_i32(resource.action))?,
+            action,
             rules,
+            provider,
         })
     }
 }
@@ -335,18 +432,10 @@ impl TryFrom<&Match> for RbacMatch {
 
     fn try_from(resource: &Match) -> Result<Self, Self::Error> {
         Ok(RbacMatch {
-            namespaces: resource.namespaces.iter().filter_map(From::from).collect(),
-            not_namespaces: resource
-                .not_namespaces
-                .iter()
-                .filter_map(From::from)
-                .collect(),
-            principals: resource.principals.iter().filter_map(From::from).collect(),
-            not_principals: resource
-                .not_principals
-                .iter()
-                .filter_map(From::from)
-                .collect(),
+            namespaces: string_matches(&resource.namespaces)?,
+            not_namespaces: string_matches(&resource.not_namespaces)?,
+            principals: string_matches(&resource.principals)?,
+            not_principals: string_matches(&resource.not_principals)?,
             source_ips: resource
                 .source_ips
                 .iter()
@@ -377,6 +466,16 @@ impl TryFrom<&Match> for RbacMatch {
                 .iter()
                 .map(|p| *p as u16)
                 .collect(),
+            destination_services: string_matches(&resource.destination_services)?,
+            not_destination_services: string_matches(&resource.not_destination_services)?,
+            source_networks: string_matches(&resource.source_networks)?,
+            not_source_networks: string_matches(&resource.not_source_networks)?,
+            source_clusters: string_matches(&resource.source_clusters)?,
+            not_source_clusters: string_matches(&resource.not_source_clusters)?,
+            source_canonical_names: string_matches(&resource.source_canonical_names)?,
+            not_source_canonical_names: string_matches(&resource.not_source_canonical_names)?,
+            source_workload_types: string_matches(&resource.source_workload_types)?,
+            not_source_workload_types: string_matches(&resource.not_source_workload_types)?,
         })
     }
 }
@@ -391,17 +490,34 @@ impl TryFrom<&XdsAddress> for IpNet {
     }
 }
 
-impl From<&XdsStringMatch> for Option<StringMatch> {
-    fn from(resource: &XdsStringMatch) -> Self {
-        resource.match_type.as_ref().map(|m| match m {
-            MatchType::Exact(s) => StringMatch::Exact(s.to_owned()),
-            MatchType::Prefix(s) => StringMatch::Prefix(s.to_owned()),
-            MatchType::Suffix(s) => StringMatch::Suffix(s.to_owned()),
-            MatchType::Presence(_) => StringMatch::Presence(),
-        })
+impl TryFrom<&XdsStringMatch> for Option<StringMatch> {
+    type Error = WorkloadError;
+    fn try_from(resource: &XdsStringMatch) -> Result<Self, Self::Error> {
+        resource
+            .match_type
+            .as_ref()
+            .map(|m| {
+                Ok(match m {
+                    MatchType::Exact(s) => StringMatch::Exact(s.to_owned()),
+                    MatchType::Prefix(s) => StringMatch::Prefix(s.to_owned()),
+                    MatchType::Suffix(s) => StringMatch::Suffix(s.to_owned()),
+                    MatchType::Presence(_) => StringMatch::Presence(),
+                    MatchType::Regex(s) => StringMatch::Regex(Pattern::regex(s)?),
+                    MatchType::Glob(s) => StringMatch::Glob(Pattern::glob(s)?),
+                })
+            })
+            .transpose()
     }
 }
 
+/// string_matches converts string matches, skipping unset ones. Malformed patterns are rejected.
+fn string_matches(resources: &[XdsStringMatch]) -> Result<Vec<StringMatch>, WorkloadError> {
+    resources
+        .iter()
+        .filter_map(|m| Option::<StringMatch>::try_from(m).transpose())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use test_case::test_case;
@@ -421,12 +537,17 @@ mod tests {
                 };
                 let pol = allow_policy(stringify!($name).to_string(), vec![vec![vec![m]]]);
                 $(
-                    assert_eq!(pol.matches($con), $res, "{}", $con);
+                    assert_eq!(matches(&pol, $con), $res, "{}", $con);
                 )*
             }
         };
     }
 
+    /// matches returns true if the ALLOW policy matches the connection.
+    fn matches(pol: &Authorization, conn: &Connection) -> bool {
+        matches!(PolicySet::new([pol]).decide(conn), Decision::Allowed(_))
+    }
+
     fn allow_policy(name: String, rules: Vec<Vec<Vec<RbacMatch>>>) -> Authorization {
         Authorization {
             name,
@@ -434,6 +555,7 @@ mod tests {
             scope: RbacScope::Global,
             action: RbacAction::Allow,
             rules,
+            provider: None,
         }
     }
 
@@ -443,6 +565,8 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "".to_string(),
             dst: "127.0.0.2:8080".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }
     }
 
@@ -456,6 +580,8 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "".to_string(),
             dst: "127.0.0.2:8080".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }
     }
 
@@ -469,21 +595,23 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 3]),
             dst_network: "".to_string(),
             dst: "127.0.0.4:9090".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }
     }
 
     #[test]
     fn rbac_empty_policy() {
-        assert!(!allow_policy(
-            "empty".to_string(),
-            vec![vec![vec![RbacMatch {
+        let empty = |rules| allow_policy("empty".to_string(), rules);
+        assert!(!matches(
+            &empty(vec![vec![vec![RbacMatch {
                 ..Default::default()
-            }]]]
-        )
-        .matches(&plaintext_conn()));
-        assert!(allow_policy("empty".to_string(), vec![vec![vec![]]]).matches(&plaintext_conn()));
-        assert!(allow_policy("empty".to_string(), vec![vec![]]).matches(&plaintext_conn()));
-        assert!(!allow_policy("empty".to_string(), vec![]).matches(&plaintext_conn()));
+            }]]]),
+            &plaintext_conn()
+        ));
+        assert!(matches(&empty(vec![vec![vec![]]]), &plaintext_conn()));
+        assert!(matches(&empty(vec![vec![]]), &plaintext_conn()));
+        assert!(!matches(&empty(vec![]), &plaintext_conn()));
     }
 
     #[test]
@@ -508,7 +636,7 @@ mod tests {
             ]],
         );
         // Can match either namespace...
-        assert!(pol.matches(&Connection {
+        assert!(matches(&pol, &Connection {
             src_identity: Some(Identity::Spiffe {
                 trust_domain: "td".to_string(),
                 namespace: "a".to_string(),
@@ -517,8 +645,10 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "".to_string(),
             dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }));
-        assert!(pol.matches(&Connection {
+        assert!(matches(&pol, &Connection {
             src_identity: Some(Identity::Spiffe {
                 trust_domain: "td".to_string(),
                 namespace: "b".to_string(),
@@ -527,9 +657,11 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "".to_string(),
             dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }));
         // Policy is applied regardless of network
-        assert!(pol.matches(&Connection {
+        assert!(matches(&pol, &Connection {
             src_identity: Some(Identity::Spiffe {
                 trust_domain: "td".to_string(),
                 namespace: "b".to_string(),
@@ -538,9 +670,11 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "remote".to_string(),
             dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }));
         // Wrong namespace
-        assert!(!pol.matches(&Connection {
+        assert!(!matches(&pol, &Connection {
             src_identity: Some(Identity::Spiffe {
                 trust_domain: "td".to_string(),
                 namespace: "bad".to_string(),
@@ -549,9 +683,11 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "".to_string(),
             dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }));
         // Wrong port
-        assert!(!pol.matches(&Connection {
+        assert!(!matches(&pol, &Connection {
             src_identity: Some(Identity::Spiffe {
                 trust_domain: "td".to_string(),
                 namespace: "b".to_string(),
@@ -560,6 +696,8 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "".to_string(),
             dst: "127.0.0.2:12345".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }));
     }
 
@@ -579,7 +717,7 @@ mod tests {
             ],
         );
         // Can match either namespace...
-        assert!(pol.matches(&Connection {
+        assert!(matches(&pol, &Connection {
             src_identity: Some(Identity::Spiffe {
                 trust_domain: "td".to_string(),
                 namespace: "a".to_string(),
@@ -588,8 +726,10 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "".to_string(),
             dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }));
-        assert!(pol.matches(&Connection {
+        assert!(matches(&pol, &Connection {
             src_identity: Some(Identity::Spiffe {
                 trust_domain: "td".to_string(),
                 namespace: "b".to_string(),
@@ -598,9 +738,11 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "".to_string(),
             dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }));
         // Wrong namespace
-        assert!(!pol.matches(&Connection {
+        assert!(!matches(&pol, &Connection {
             src_identity: Some(Identity::Spiffe {
                 trust_domain: "td".to_string(),
                 namespace: "bad".to_string(),
@@ -609,9 +751,24 @@ mod tests {
             src_ip: IpAddr::from([127, 0, 0, 1]),
             dst_network: "".to_string(),
             dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
         }));
     }
 
+    fn workload_conn() -> Connection {
+        Connection {
+            src_workload: Some(SourceWorkload {
+                network: "network".to_string(),
+                cluster: "cluster".to_string(),
+                canonical_name: "client".to_string(),
+                workload_type: "deployment".to_string(),
+            }),
+            dst_service: Some("svc.namespace.svc.cluster.local".to_string()),
+            ..tls_conn()
+        }
+    }
+
     rbac_test!(namespaces, vec![StringMatch::Exact("namespace".to_string())],
         &plaintext_conn() => false,
         &tls_conn() => true,
@@ -629,6 +786,14 @@ mod tests {
         &plaintext_conn() => true,
         &tls_conn() => false,
         &tls_conn_alt() => true);
+    rbac_test!(principal_glob, principals, vec![StringMatch::Glob(Pattern::glob("spiffe://*/ns/name*/sa/*").unwrap())],
+        &plaintext_conn() => false,
+        &tls_conn() => true,
+        &tls_conn_alt() => false);
+    rbac_test!(namespace_regex, namespaces, vec![StringMatch::Regex(Pattern::regex("ns-(alt|other)").unwrap())],
+        &plaintext_conn() => false,
+        &tls_conn() => false,
+        &tls_conn_alt() => true);
 
     rbac_test!(source_ips, vec![IpNet::new("127.0.0.1".parse().unwrap(), 32).unwrap()],
         &plaintext_conn() => true,
@@ -661,6 +826,62 @@ mod tests {
         &tls_conn() => false,
         &tls_conn_alt() => true);
 
+    rbac_test!(destination_services, vec![StringMatch::Suffix(".namespace.svc.cluster.local".to_string())],
+        &plaintext_conn() => false,
+        &tls_conn() => false,
+        &workload_conn() => true);
+    rbac_test!(not_destination_services, vec![StringMatch::Suffix(".namespace.svc.cluster.local".to_string())],
+        &plaintext_conn() => true,
+        &tls_conn() => true,
+        &workload_conn() => false);
+
+    rbac_test!(source_networks, vec![StringMatch::Exact("network".to_string())],
+        &plaintext_conn() => false,
+        &workload_conn() => true);
+    rbac_test!(source_clusters, vec![StringMatch::Exact("other".to_string())],
+        &plaintext_conn() => false,
+        &workload_conn() => false);
+    rbac_test!(source_canonical_names, vec![StringMatch::Prefix("cli".to_string())],
+        &plaintext_conn() => false,
+        &workload_conn() => true);
+    rbac_test!(source_workload_types, vec![StringMatch::Exact("deployment".to_string())],
+        &plaintext_conn() => false,
+        &tls_conn() => false,
+        &workload_conn() => true);
+    rbac_test!(not_source_workload_types, vec![StringMatch::Exact("deployment".to_string())],
+        &plaintext_conn() => true,
+        &tls_conn() => true,
+        &workload_conn() => false);
+
+    #[test]
+    fn principal_trust_domain_aliases() {
+        let m = StringMatch::Exact("old.example/ns/a/sa/b".to_string());
+        let aliases = TrustDomainAliases::new("new.example", ["old.example".to_string()]);
+        let none = TrustDomainAliases::default();
+        assert!(m.matches_principal("spiffe://old.example/ns/a/sa/b", &none));
+        assert!(!m.matches_principal("spiffe://new.example/ns/a/sa/b", &none));
+        assert!(m.matches_principal("spiffe://new.example/ns/a/sa/b", &aliases));
+        assert!(!m.matches_principal("spiffe://other.example/ns/a/sa/b", &aliases));
+        assert!(!m.matches_principal("new.example/ns/a/sa/b", &aliases));
+    }
+
+    #[test]
+    fn principal_patterns() {
+        let glob = StringMatch::Glob(Pattern::glob("spiffe://*/ns/team-*/sa/ingest-*").unwrap());
+        let regex =
+            StringMatch::Regex(Pattern::regex("spiffe://old\\.example/ns/team-.*").unwrap());
+        let aliases = TrustDomainAliases::new("new.example", ["old.example".to_string()]);
+        let none = TrustDomainAliases::default();
+        assert!(glob.matches_principal("spiffe://td/ns/team-a/sa/ingest-1", &none));
+        assert!(!glob.matches_principal("spiffe://td/ns/team-a/sa/egress", &none));
+        assert!(!glob.matches_principal("spiffe://td/ns/other/sa/ingest-1", &none));
+        // The trust domain can't be skipped by a glob.
+        assert!(!glob.matches_principal("spiffe://a/b/ns/team-a/sa/ingest-1", &none));
+        assert!(regex.matches_principal("spiffe://old.example/ns/team-a/sa/x", &none));
+        assert!(!regex.matches_principal("spiffe://new.example/ns/team-a/sa/x", &none));
+        assert!(regex.matches_principal("spiffe://new.example/ns/team-a/sa/x", &aliases));
+    }
+
     #[test_case(StringMatch::Exact("foo".to_string()), "foo", true; "exact match")]
     #[test_case(StringMatch::Exact("foo".to_string()), "not", false; "exact mismatch")]
     #[test_case(StringMatch::Exact("foo".to_string()), "", false; "exact empty mismatch")]
@@ -672,7 +893,37 @@ mod tests {
     #[test_case(StringMatch::Suffix("foo".to_string()), "", false; "suffix empty mismatch")]
     #[test_case(StringMatch::Presence(), "foo", true; "presence match")]
     #[test_case(StringMatch::Presence(), "", false; "presence mismatch")]
+    #[test_case(StringMatch::Regex(Pattern::regex("f.o").unwrap()), "foo", true; "regex match")]
+    #[test_case(StringMatch::Regex(Pattern::regex("f.o").unwrap()), "foobar", false; "regex is anchored")]
+    #[test_case(StringMatch::Regex(Pattern::regex("a|b").unwrap()), "ab", false; "regex alternation is anchored")]
+    #[test_case(StringMatch::Glob(Pattern::glob("team-*").unwrap()), "team-a", true; "glob match")]
+    #[test_case(StringMatch::Glob(Pattern::glob("team-*").unwrap()), "team-a/b", false; "glob star stops at slash")]
+    #[test_case(StringMatch::Glob(Pattern::glob("a.?").unwrap()), "a.b", true; "glob question mark")]
+    #[test_case(StringMatch::Glob(Pattern::glob("a.?").unwrap()), "abb", false; "glob escapes regex syntax")]
     fn string_match(matcher: StringMatch, matchee: &str, expect: bool) {
         assert_eq!(matcher.matches(matchee), expect)
     }
+
+    #[test]
+    fn malformed_patterns_are_rejected() {
+        let with = |match_type| Match {
+            principals: vec![XdsStringMatch {
+                match_type: Some(match_type),
+            }],
+            ..Default::default()
+        };
+        assert!(RbacMatch::try_from(&with(MatchType::Regex("a.*".to_string()))).is_ok());
+        assert!(matches!(
+            RbacMatch::try_from(&with(MatchType::Regex("a(".to_string()))),
+            Err(WorkloadError::InvalidPattern(..))
+        ));
+        assert!(matches!(
+            RbacMatch::try_from(&with(MatchType::Regex("a{1000}{1000}".to_string()))),
+            Err(WorkloadError::InvalidPattern(..))
+        ));
+
+        let m: StringMatch = serde_yaml::from_str("!Glob team-*").unwrap();
+        assert!(m.matches("team-a"));
+        assert!(serde_yaml::from_str::<StringMatch>("!Regex a(").is_err());
+    }
 }
@@ -0,0 +1,928 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Policies compiled for evaluation on the connection hot path.
+//!
+//! The policies applying to a workload are compiled into a [PolicySet] whenever they change.
+//! ALLOW and DENY rules are bucketed by the destination port or IP they require, and string
+//! matches are compiled into tries, so evaluating a connection does not allocate unless a
+//! principal is matched against a regex or glob.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use ipnet::IpNet;
+use tracing::trace;
+
+use super::{
+    Authorization, Connection, Pattern, RbacAction, RbacMatch, SourceWorkload, StringMatch,
+};
+use crate::identity::{Identity, TrustDomainAliases};
+
+/// Decision is the outcome of evaluating the ALLOW and DENY policies for a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision<'a> {
+    /// The named DENY policy matched.
+    Denied(&'a str),
+    /// There are ALLOW policies, but none matched.
+    NotAllowed,
+    /// The named ALLOW policy matched.
+    Allowed(&'a str),
+    /// There are no ALLOW policies.
+    AllowedByDefault,
+}
+
+impl Decision<'_> {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Decision::Allowed(_) | Decision::AllowedByDefault)
+    }
+
+    /// policy returns the key of the policy that decided the connection, if any.
+    pub fn policy(&self) -> Option<&str> {
+        match self {
+            Decision::Denied(p) | Decision::Allowed(p) => Some(p),
+            Decision::NotAllowed | Decision::AllowedByDefault => None,
+        }
+    }
+}
+
+impl fmt::Display for Decision<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decision::Denied(p) => write!(f, "denied by policy {p}"),
+            Decision::NotAllowed => write!(f, "no ALLOW policy matched"),
+            Decision::Allowed(p) => write!(f, "allowed by policy {p}"),
+            Decision::AllowedByDefault => write!(f, "no ALLOW policies"),
+        }
+    }
+}
+
+/// Explanation describes how the policies for a workload decide a connection.
+/// CUSTOM policies are listed, but their providers are not called.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Explanation {
+    /// The destination workload, as namespace/name.
+    pub workload: String,
+    pub allowed: bool,
+    /// A description of the decision.
+    pub decision: String,
+    /// The key of the policy that decided the connection, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    /// Every policy considered, in no particular order.
+    pub policies: Vec<PolicyExplanation>,
+}
+
+/// PolicyExplanation is the result of matching a policy against a connection.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyExplanation {
+    pub policy: String,
+    pub action: RbacAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    pub matched: bool,
+    pub rules: Vec<RuleExplanation>,
+}
+
+/// RuleExplanation is the result of matching a rule. A rule matches if all its clauses match.
+#[derive(serde::Serialize, Debug)]
+pub struct RuleExplanation {
+    pub matched: bool,
+    pub clauses: Vec<ClauseExplanation>,
+}
+
+/// ClauseExplanation is the result of matching a clause. A clause matches if any of its matches
+/// do; a clause without matches matches everything, unless all its matches were empty.
+#[derive(serde::Serialize, Debug)]
+pub struct ClauseExplanation {
+    pub matched: bool,
+    pub matches: Vec<MatchExplanation>,
+}
+
+/// MatchExplanation is the result of matching each type declared in a match.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchExplanation {
+    pub matched: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination_ports: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination_ips: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_ips: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespaces: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub principals: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination_services: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_networks: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_clusters: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_canonical_names: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_workload_types: Option<bool>,
+}
+
+/// PolicySet holds the compiled policies that apply to a workload.
+#[derive(Debug, Default)]
+pub struct PolicySet {
+    allow: RuleIndex,
+    deny: RuleIndex,
+    /// Whether there are any ALLOW policies, even ones that can never match.
+    has_allow: bool,
+    /// Trust domains treated as equivalent when matching principals.
+    aliases: TrustDomainAliases,
+    audit: Vec<CompiledPolicy>,
+    custom: Vec<CompiledPolicy>,
+}
+
+impl PolicySet {
+    pub fn new<'a>(policies: impl IntoIterator<Item = &'a Authorization>) -> PolicySet {
+        let mut set = PolicySet::default();
+        for pol in policies {
+            let compiled = CompiledPolicy::new(pol);
+            match pol.action {
+                RbacAction::Allow => {
+                    set.has_allow = true;
+                    set.allow.insert(compiled);
+                }
+                RbacAction::Deny => set.deny.insert(compiled),
+                RbacAction::Audit => set.audit.push(compiled),
+                RbacAction::Custom => set.custom.push(compiled),
+            }
+        }
+        set
+    }
+
+    /// with_trust_domain_aliases makes principals match in any trust domain equivalent to theirs.
+    pub fn with_trust_domain_aliases(mut self, aliases: TrustDomainAliases) -> PolicySet {
+        self.aliases = aliases;
+        self
+    }
+
+    /// decide evaluates the ALLOW and DENY policies, following
+    /// https://istio.io/latest/docs/reference/config/security/authorization-policy/
+    pub fn decide(&self, conn: &Connection) -> Decision<'_> {
+        let ctx = Context::new(conn, &self.aliases);
+        // "If there are any DENY policies that match the request, deny the request."
+        if let Some(policy) = self.deny.find(&ctx) {
+            return Decision::Denied(policy);
+        }
+        // "If there are no ALLOW policies for the workload, allow the request."
+        if !self.has_allow {
+            return Decision::AllowedByDefault;
+        }
+        // "If any of the ALLOW policies match the request, allow the request."
+        match self.allow.find(&ctx) {
+            Some(policy) => Decision::Allowed(policy),
+            // "Deny the request."
+            None => Decision::NotAllowed,
+        }
+    }
+
+    /// audit returns each AUDIT policy, and whether it matches the connection.
+    pub fn audit<'a>(&'a self, conn: &'a Connection) -> impl Iterator<Item = (&'a str, bool)> {
+        let ctx = Context::new(conn, &self.aliases);
+        self.audit
+            .iter()
+            .map(move |pol| (&*pol.key, pol.matches(&ctx)))
+    }
+
+    /// custom returns the policy and provider of each CUSTOM policy matching the connection.
+    pub fn custom<'a>(&'a self, conn: &'a Connection) -> impl Iterator<Item = (&'a str, &'a str)> {
+        let ctx = Context::new(conn, &self.aliases);
+        self.custom
+            .iter()
+            .filter(move |pol| pol.matches(&ctx))
+            .map(|pol| (&*pol.key, pol.provider.as_deref().unwrap_or_default()))
+    }
+}
+
+/// Context is the view of a connection that policies are matched against.
+struct Context<'a> {
+    conn: &'a Connection,
+    /// The source principal without its spiffe:// prefix, split into segments to avoid building
+    /// the string.
+    principal: Option<[&'a str; 5]>,
+    namespace: &'a str,
+    aliases: &'a TrustDomainAliases,
+}
+
+impl<'a> Context<'a> {
+    fn new(conn: &'a Connection, aliases: &'a TrustDomainAliases) -> Context<'a> {
+        match &conn.src_identity {
+            Some(Identity::Spiffe {
+                trust_domain,
+                namespace,
+                service_account,
+            }) => Context {
+                conn,
+                principal: Some([
+                    trust_domain.as_str(),
+                    "/ns/",
+                    namespace.as_str(),
+                    "/sa/",
+                    service_account.as_str(),
+                ]),
+                namespace,
+                aliases,
+            },
+            None => Context {
+                conn,
+                principal: None,
+                namespace: "",
+                aliases,
+            },
+        }
+    }
+
+    /// principals returns the source principal, followed by the same principal in each alias of
+    /// its trust domain.
+    fn principals(&self) -> impl Iterator<Item = [&'a str; 5]> + 'a {
+        let aliases = self.aliases;
+        self.principal.into_iter().flat_map(move |p| {
+            std::iter::once(p).chain(
+                aliases
+                    .aliases(p[0])
+                    .map(move |td| [td, p[1], p[2], p[3], p[4]]),
+            )
+        })
+    }
+}
+
+/// CompiledPolicy is a policy with its string matches compiled.
+#[derive(Debug)]
+pub(super) struct CompiledPolicy {
+    key: Arc<str>,
+    provider: Option<String>,
+    rules: Vec<Rule>,
+}
+
+impl CompiledPolicy {
+    pub(super) fn new(pol: &Authorization) -> CompiledPolicy {
+        let key: Arc<str> = pol.to_key().into();
+        let rules = pol
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| Rule {
+                policy: key.clone(),
+                index,
+                clauses: rule
+                    .iter()
+                    .map(|clause| {
+                        // An empty clause matches everything, while empty matches never match.
+                        (!clause.is_empty()).then(|| {
+                            clause
+                                .iter()
+                                .filter(|m| !m.is_empty())
+                                .map(CompiledMatch::new)
+                                .collect()
+                        })
+                    })
+                    .collect(),
+            })
+            .collect();
+        CompiledPolicy {
+            key,
+            provider: pol.provider.clone(),
+            rules,
+        }
+    }
+
+    pub(super) fn explain(
+        &self,
+        action: RbacAction,
+        conn: &Connection,
+        aliases: &TrustDomainAliases,
+    ) -> PolicyExplanation {
+        let ctx = Context::new(conn, aliases);
+        PolicyExplanation {
+            policy: self.key.to_string(),
+            action,
+            provider: self.provider.clone(),
+            matched: self.matches(&ctx),
+            rules: self
+                .rules
+                .iter()
+                .map(|rule| RuleExplanation {
+                    matched: rule.matches(&ctx),
+                    clauses: rule
+                        .clauses
+                        .iter()
+                        .map(|clause| ClauseExplanation {
+                            matched: Rule::clause_matches(clause, &ctx),
+                            matches: clause.iter().flatten().map(|m| m.explain(&ctx)).collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn matches(&self, ctx: &Context) -> bool {
+        // If ANY rule matches it's a match.
+        self.rules.iter().any(|r| r.matches(ctx) && r.trace_match())
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    policy: Arc<str>,
+    /// The position of the rule in its policy.
+    index: usize,
+    /// Clauses are AND-ed; None matches everything.
+    clauses: Vec<Option<Vec<CompiledMatch>>>,
+}
+
+impl Rule {
+    fn matches(&self, ctx: &Context) -> bool {
+        self.clauses
+            .iter()
+            .all(|clause| Rule::clause_matches(clause, ctx))
+    }
+
+    /// trace_match logs that the rule matched, and returns true.
+    fn trace_match(&self) -> bool {
+        trace!(policy = &*self.policy, rule = self.index, "rule matched");
+        true
+    }
+
+    fn clause_matches(clause: &Option<Vec<CompiledMatch>>, ctx: &Context) -> bool {
+        match clause {
+            None => true,
+            Some(matches) => matches.iter().any(|m| m.matches(ctx)),
+        }
+    }
+
+    /// never_matches returns true if some clause has only empty matches.
+    fn never_matches(&self) -> bool {
+        self.clauses
+            .iter()
+            .any(|c| c.as_ref().map_or(false, |m| m.is_empty()))
+    }
+
+    /// ports returns the destination ports the rule can match, if it is restricted to some.
+    fn ports(&self) -> Option<Vec<u16>> {
+        self.required(|m| (!m.destination_ports.is_empty()).then(|| m.destination_ports.clone()))
+    }
+
+    /// ips returns the destination IPs the rule can match, if it is restricted to single hosts.
+    fn ips(&self) -> Option<Vec<IpAddr>> {
+        self.required(|m| {
+            let hosts = m
+                .destination_ips
+                .iter()
+                .map(|net| (net.prefix_len() == net.max_prefix_len()).then(|| net.addr()))
+                .collect::<Option<Vec<_>>>()?;
+            (!hosts.is_empty()).then_some(hosts)
+        })
+    }
+
+    /// required finds a clause where every match requires one of a set of values, and returns the
+    /// union of those sets. A connection can only match the rule if it has one of these values.
+    fn required<T>(&self, values: impl Fn(&CompiledMatch) -> Option<Vec<T>>) -> Option<Vec<T>> {
+        self.clauses.iter().flatten().find_map(|matches| {
+            matches
+                .iter()
+                .map(&values)
+                .try_fold(Vec::new(), |mut all, v| {
+                    all.extend(v?);
+                    Some(all)
+                })
+                .filter(|all| !all.is_empty())
+        })
+    }
+}
+
+/// RuleIndex buckets rules by the destination port or IP they require, so only rules that could
+/// match a connection are evaluated.
+#[derive(Debug, Default)]
+struct RuleIndex {
+    rules: Vec<Rule>,
+    by_port: HashMap<u16, Vec<usize>>,
+    by_ip: HashMap<IpAddr, Vec<usize>>,
+    rest: Vec<usize>,
+}
+
+impl RuleIndex {
+    fn insert(&mut self, pol: CompiledPolicy) {
+        for rule in pol.rules {
+            if rule.never_matches() {
+                continue;
+            }
+            let idx = self.rules.len();
+            if let Some(ports) = rule.ports() {
+                for port in ports {
+                    let bucket = self.by_port.entry(port).or_default();
+                    if bucket.last() != Some(&idx) {
+                        bucket.push(idx);
+                    }
+                }
+            } else if let Some(ips) = rule.ips() {
+                for ip in ips {
+                    let bucket = self.by_ip.entry(ip).or_default();
+                    if bucket.last() != Some(&idx) {
+                        bucket.push(idx);
+                    }
+                }
+            } else {
+                self.rest.push(idx);
+            }
+            self.rules.push(rule);
+        }
+    }
+
+    /// find returns the policy of a rule matching the connection, if any.
+    fn find(&self, ctx: &Context) -> Option<&str> {
+        let dst = ctx.conn.dst;
+        self.by_port
+            .get(&dst.port())
+            .into_iter()
+            .chain(self.by_ip.get(&dst.ip()))
+            .flatten()
+            .chain(self.rest.iter())
+            .map(|idx| &self.rules[*idx])
+            .find(|rule| rule.matches(ctx) && rule.trace_match())
+            .map(|rule| &*rule.policy)
+    }
+}
+
+/// CompiledMatch is an RbacMatch with its string matches compiled. Within each type ANY value
+/// must match, and ALL types must match.
+#[derive(Debug)]
+struct CompiledMatch {
+    namespaces: StringField,
+    principals: StringField,
+    source_ips: Vec<IpNet>,
+    not_source_ips: Vec<IpNet>,
+    destination_ips: Vec<IpNet>,
+    not_destination_ips: Vec<IpNet>,
+    destination_ports: Vec<u16>,
+    not_destination_ports: Vec<u16>,
+    destination_services: StringField,
+    source_networks: StringField,
+    source_clusters: StringField,
+    source_canonical_names: StringField,
+    source_workload_types: StringField,
+}
+
+impl CompiledMatch {
+    fn new(m: &RbacMatch) -> CompiledMatch {
+        CompiledMatch {
+            namespaces: StringField::new(&m.namespaces, &m.not_namespaces),
+            principals: StringField::new(&m.principals, &m.not_principals),
+            source_ips: m.source_ips.clone(),
+            not_source_ips: m.not_source_ips.clone(),
+            destination_ips: m.destination_ips.clone(),
+            not_destination_ips: m.not_destination_ips.clone(),
+            destination_ports: m.destination_ports.clone(),
+            not_destination_ports: m.not_destination_ports.clone(),
+            destination_services: StringField::new(
+                &m.destination_services,
+                &m.not_destination_services,
+            ),
+            source_networks: StringField::new(&m.source_networks, &m.not_source_networks),
+            source_clusters: StringField::new(&m.source_clusters, &m.not_source_clusters),
+            source_canonical_names: StringField::new(
+                &m.source_canonical_names,
+                &m.not_source_canonical_names,
+            ),
+            source_workload_types: StringField::new(
+                &m.source_workload_types,
+                &m.not_source_workload_types,
+            ),
+        }
+    }
+
+    fn matches(&self, ctx: &Context) -> bool {
+        self.destination_ports_match(ctx)
+            && self.destination_ips_match(ctx)
+            && self.source_ips_match(ctx)
+            && self.namespaces_match(ctx)
+            && self.principals_match(ctx)
+            && self.destination_services_match(ctx)
+            && self.source_match(ctx, &self.source_networks, |w| &w.network)
+            && self.source_match(ctx, &self.source_clusters, |w| &w.cluster)
+            && self.source_match(ctx, &self.source_canonical_names, |w| &w.canonical_name)
+            && self.source_match(ctx, &self.source_workload_types, |w| &w.workload_type)
+    }
+
+    fn explain(&self, ctx: &Context) -> MatchExplanation {
+        let declared = |declared: bool, matched: bool| declared.then_some(matched);
+        let field = |field: &StringField, matched: bool| declared(field.is_declared(), matched);
+        MatchExplanation {
+            matched: self.matches(ctx),
+            destination_ports: declared(
+                !self.destination_ports.is_empty() || !self.not_destination_ports.is_empty(),
+                self.destination_ports_match(ctx),
+            ),
+            destination_ips: declared(
+                !self.destination_ips.is_empty() || !self.not_destination_ips.is_empty(),
+                self.destination_ips_match(ctx),
+            ),
+            source_ips: declared(
+                !self.source_ips.is_empty() || !self.not_source_ips.is_empty(),
+                self.source_ips_match(ctx),
+            ),
+            namespaces: field(&self.namespaces, self.namespaces_match(ctx)),
+            principals: field(&self.principals, self.principals_match(ctx)),
+            destination_services: field(
+                &self.destination_services,
+                self.destination_services_match(ctx),
+            ),
+            source_networks: field(
+                &self.source_networks,
+                self.source_match(ctx, &self.source_networks, |w| &w.network),
+            ),
+            source_clusters: field(
+                &self.source_clusters,
+                self.source_match(ctx, &self.source_clusters, |w| &w.cluster),
+            ),
+            source_canonical_names: field(
+                &self.source_canonical_names,
+                self.source_match(ctx, &self.source_canonical_names, |w| &w.canonical_name),
+            ),
+            source_workload_types: field(
+                &self.source_workload_types,
+                self.source_match(ctx, &self.source_workload_types, |w| &w.workload_type),
+            ),
+        }
+    }
+
+    fn destination_ports_match(&self, ctx: &Context) -> bool {
+        let port = ctx.conn.dst.port();
+        matches_list(&self.destination_ports, &self.not_destination_ports, |p| {
+            *p == port
+        })
+    }
+
+    fn destination_ips_match(&self, ctx: &Context) -> bool {
+        let ip = ctx.conn.dst.ip();
+        matches_list(&self.destination_ips, &self.not_destination_ips, |i| {
+            i.contains(&ip)
+        })
+    }
+
+    fn source_ips_match(&self, ctx: &Context) -> bool {
+        let ip = ctx.conn.src_ip;
+        matches_list(&self.source_ips, &self.not_source_ips, |i| i.contains(&ip))
+    }
+
+    fn namespaces_match(&self, ctx: &Context) -> bool {
+        self.namespaces.matches(Some(&[ctx.namespace]))
+    }
+
+    fn principals_match(&self, ctx: &Context) -> bool {
+        // Principals never match connections without an identity.
+        if ctx.principal.is_none() {
+            return self.principals.matches(None);
+        }
+        // Otherwise the principal matches if it does in any trust domain equivalent to its own.
+        self.principals
+            .matches_with(|m| ctx.principals().any(|p| m.matches_principal(&p)))
+    }
+
+    fn destination_services_match(&self, ctx: &Context) -> bool {
+        match &ctx.conn.dst_service {
+            Some(svc) => self.destination_services.matches(Some(&[svc.as_str()])),
+            None => self.destination_services.matches(None),
+        }
+    }
+
+    /// source_match matches an attribute of the source workload. Attributes of unknown workloads
+    /// are missing, like the namespace of a plaintext connection.
+    fn source_match(
+        &self,
+        ctx: &Context,
+        field: &StringField,
+        attribute: impl Fn(&SourceWorkload) -> &str,
+    ) -> bool {
+        match &ctx.conn.src_workload {
+            Some(w) => field.matches(Some(&[attribute(w)])),
+            None => field.matches(None),
+        }
+    }
+}
+
+fn matches_list<T>(positive: &[T], negative: &[T], predicate: impl Fn(&T) -> bool) -> bool {
+    (positive.is_empty() || positive.iter().any(&predicate)) && !negative.iter().any(&predicate)
+}
+
+/// StringField matches a string attribute against its positive and negative matches.
+#[derive(Debug)]
+struct StringField {
+    positive: Option<StringMatcher>,
+    negative: Option<StringMatcher>,
+}
+
+impl StringField {
+    fn new(positive: &[StringMatch], negative: &[StringMatch]) -> StringField {
+        StringField {
+            positive: StringMatcher::new(positive),
+            negative: StringMatcher::new(negative),
+        }
+    }
+
+    fn is_declared(&self) -> bool {
+        self.positive.is_some() || self.negative.is_some()
+    }
+
+    /// matches checks the value, given as segments. A missing value matches nothing.
+    fn matches(&self, value: Option<&[&str]>) -> bool {
+        self.matches_with(|m| value.map_or(false, |v| m.matches(v)))
+    }
+
+    /// matches_with checks the positive and negative matches with check.
+    fn matches_with(&self, check: impl Fn(&StringMatcher) -> bool) -> bool {
+        self.positive.as_ref().map_or(true, &check) && !self.negative.as_ref().map_or(false, &check)
+    }
+}
+
+/// StringMatcher matches a string, given as segments, against any of a set of StringMatches.
+#[derive(Debug, Default)]
+struct StringMatcher {
+    /// Exact and prefix matches.
+    forward: ByteTrie,
+    /// Suffix matches, reversed.
+    reverse: ByteTrie,
+    presence: bool,
+    /// Regex and glob matches.
+    patterns: Vec<Pattern>,
+}
+
+impl StringMatcher {
+    fn new(matches: &[StringMatch]) -> Option<StringMatcher> {
+        if matches.is_empty() {
+            return None;
+        }
+        let mut m = StringMatcher::default();
+        for sm in matches {
+            match sm {
+                StringMatch::Exact(s) => m.forward.insert(s.bytes(), Terminal::Exact),
+                StringMatch::Prefix(s) => m.forward.insert(s.bytes(), Terminal::Prefix),
+                StringMatch::Suffix(s) => m.reverse.insert(s.bytes().rev(), Terminal::Prefix),
+                StringMatch::Presence() => m.presence = true,
+                StringMatch::Regex(p) | StringMatch::Glob(p) => m.patterns.push(p.clone()),
+            }
+        }
+        Some(m)
+    }
+
+    fn matches(&self, segments: &[&str]) -> bool {
+        self.matches_literal(segments)
+            || (!self.patterns.is_empty() && {
+                // Patterns need the string in one piece, so only they may allocate.
+                let check = match segments {
+                    [s] => Cow::Borrowed(*s),
+                    _ => Cow::Owned(segments.concat()),
+                };
+                self.matches_patterns(&check)
+            })
+    }
+
+    /// matches_principal matches a principal given without its spiffe:// prefix. Like Istio,
+    /// literal matches omit the prefix, but patterns see the full principal.
+    fn matches_principal(&self, principal: &[&str]) -> bool {
+        self.matches_literal(principal)
+            || (!self.patterns.is_empty()
+                && self.matches_patterns(&format!("spiffe://{}", principal.concat())))
+    }
+
+    fn matches_literal(&self, segments: &[&str]) -> bool {
+        let bytes = || segments.iter().flat_map(|s| s.bytes());
+        (self.presence && segments.iter().any(|s| !s.is_empty()))
+            || self.forward.matches(bytes())
+            || self.reverse.matches(bytes().rev())
+    }
+
+    fn matches_patterns(&self, check: &str) -> bool {
+        self.patterns.iter().any(|p| p.is_match(check))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Terminal {
+    Exact,
+    Prefix,
+}
+
+/// ByteTrie matches keys against a set of exact values and prefixes.
+#[derive(Debug, Default)]
+struct ByteTrie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    exact: bool,
+    prefix: bool,
+    /// Children sorted by byte.
+    children: Vec<(u8, usize)>,
+}
+
+impl ByteTrie {
+    fn insert(&mut self, key: impl Iterator<Item = u8>, terminal: Terminal) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+        let mut cur = 0;
+        for b in key {
+            cur = match self.nodes[cur]
+                .children
+                .binary_search_by_key(&b, |(c, _)| *c)
+            {
+                Ok(i) => self.nodes[cur].children[i].1,
+                Err(i) => {
+                    let next = self.nodes.len();
+                    self.nodes.push(Node::default());
+                    self.nodes[cur].children.insert(i, (b, next));
+                    next
+                }
+            };
+        }
+        match terminal {
+            Terminal::Exact => self.nodes[cur].exact = true,
+            Terminal::Prefix => self.nodes[cur].prefix = true,
+        }
+    }
+
+    /// matches returns true if the key equals an exact value or starts with a prefix.
+    fn matches(&self, key: impl Iterator<Item = u8>) -> bool {
+        let Some(mut node) = self.nodes.first() else {
+            return false;
+        };
+        for b in key {
+            if node.prefix {
+                return true;
+            }
+            match node.children.binary_search_by_key(&b, |(c, _)| *c) {
+                Ok(i) => node = &self.nodes[node.children[i].1],
+                Err(_) => return false,
+            }
+        }
+        node.exact || node.prefix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rbac::RbacScope;
+
+    fn policy(name: &str, action: RbacAction, m: RbacMatch) -> Authorization {
+        Authorization {
+            name: name.to_string(),
+            namespace: "ns".to_string(),
+            scope: RbacScope::Global,
+            action,
+            rules: vec![vec![vec![m]]],
+            provider: None,
+        }
+    }
+
+    fn conn(ns: &str, dst: &str) -> Connection {
+        Connection {
+            src_identity: Some(Identity::Spiffe {
+                trust_domain: "td".to_string(),
+                namespace: ns.to_string(),
+                service_account: "sa".to_string(),
+            }),
+            src_ip: "127.0.0.1".parse().unwrap(),
+            dst_network: "".to_string(),
+            dst: dst.parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
+        }
+    }
+
+    #[test]
+    fn decide() {
+        assert_eq!(
+            PolicySet::new([]).decide(&conn("a", "127.0.0.2:80")),
+            Decision::AllowedByDefault
+        );
+
+        let deny_port = policy(
+            "deny-port",
+            RbacAction::Deny,
+            RbacMatch {
+                destination_ports: vec![81],
+                ..Default::default()
+            },
+        );
+        let allow_ip = policy(
+            "allow-ip",
+            RbacAction::Allow,
+            RbacMatch {
+                destination_ips: vec!["127.0.0.2/32".parse().unwrap()],
+                namespaces: vec![StringMatch::Prefix("a".to_string())],
+                ..Default::default()
+            },
+        );
+        let allow_principal = policy(
+            "allow-principal",
+            RbacAction::Allow,
+            RbacMatch {
+                principals: vec![StringMatch::Suffix("/sa/sa".to_string())],
+                not_namespaces: vec![StringMatch::Exact("c".to_string())],
+                ..Default::default()
+            },
+        );
+        let set = PolicySet::new([&deny_port, &allow_ip]);
+        assert_eq!(set.deny.by_port.len(), 1);
+        assert_eq!(set.allow.by_ip.len(), 1);
+        assert_eq!(
+            set.decide(&conn("a", "127.0.0.2:81")),
+            Decision::Denied("ns/deny-port")
+        );
+        assert_eq!(
+            set.decide(&conn("ab", "127.0.0.2:80")),
+            Decision::Allowed("ns/allow-ip")
+        );
+        assert_eq!(set.decide(&conn("b", "127.0.0.2:80")), Decision::NotAllowed);
+        assert_eq!(set.decide(&conn("a", "127.0.0.3:80")), Decision::NotAllowed);
+
+        let set = PolicySet::new([&allow_principal]);
+        assert_eq!(
+            set.decide(&conn("b", "127.0.0.3:80")),
+            Decision::Allowed("ns/allow-principal")
+        );
+        assert_eq!(set.decide(&conn("c", "127.0.0.3:80")), Decision::NotAllowed);
+        let plaintext = Connection {
+            src_identity: None,
+            ..conn("b", "127.0.0.3:80")
+        };
+        assert_eq!(set.decide(&plaintext), Decision::NotAllowed);
+    }
+
+    #[test]
+    fn trust_domain_aliases() {
+        let allow = policy(
+            "allow",
+            RbacAction::Allow,
+            RbacMatch {
+                principals: vec![StringMatch::Exact("old.example/ns/a/sa/sa".to_string())],
+                ..Default::default()
+            },
+        );
+        let deny = policy(
+            "deny",
+            RbacAction::Deny,
+            RbacMatch {
+                not_principals: vec![StringMatch::Prefix("old.example/".to_string())],
+                ..Default::default()
+            },
+        );
+        // The connection comes from td, which is the new name of old.example.
+        let c = conn("a", "127.0.0.2:80");
+        let set = PolicySet::new([&allow, &deny]);
+        assert_eq!(set.decide(&c), Decision::Denied("ns/deny"));
+
+        let aliases = TrustDomainAliases::new("td", ["old.example".to_string()]);
+        let set = PolicySet::new([&allow, &deny]).with_trust_domain_aliases(aliases);
+        assert_eq!(set.decide(&c), Decision::Allowed("ns/allow"));
+        assert_eq!(set.decide(&conn("b", "127.0.0.2:80")), Decision::NotAllowed);
+    }
+
+    #[test]
+    fn string_matcher() {
+        let m = StringMatcher::new(&[
+            StringMatch::Exact("td/ns/a/sa/b".to_string()),
+            StringMatch::Prefix("other/".to_string()),
+            StringMatch::Suffix("/sa/admin".to_string()),
+        ])
+        .unwrap();
+        assert!(m.matches(&["td", "/ns/", "a", "/sa/", "b"]));
+        assert!(!m.matches(&["td", "/ns/", "a", "/sa/", "bc"]));
+        assert!(!m.matches(&["td", "/ns/", "a", "/sa/", ""]));
+        assert!(m.matches(&["other", "/ns/", "a", "/sa/", "b"]));
+        assert!(m.matches(&["td", "/ns/", "x", "/sa/", "admin"]));
+        assert!(!m.matches(&[""]));
+
+        let empty_prefix = StringMatcher::new(&[StringMatch::Prefix("".to_string())]).unwrap();
+        assert!(empty_prefix.matches(&[""]));
+        let presence = StringMatcher::new(&[StringMatch::Presence()]).unwrap();
+        assert!(presence.matches(&["", "x"]));
+        assert!(!presence.matches(&["", ""]));
+    }
+}
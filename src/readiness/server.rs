@@ -21,6 +21,7 @@ use hyper::body::Incoming;
 use hyper::{Request, Response};
 use itertools::Itertools;
 
+use crate::hot_restart::Listeners;
 use crate::hyper_util::{empty_response, plaintext_response, Server};
 use crate::{config, readiness};
 
@@ -32,11 +33,18 @@ impl Service {
     pub async fn new(
         config: config::Config,
         ready: readiness::Ready,
+        listeners: &Listeners,
         drain_rx: Watch,
     ) -> anyhow::Result<Self> {
-        Server::<readiness::Ready>::bind("readiness", config.readiness_addr, drain_rx, ready)
-            .await
-            .map(|s| Service { s })
+        Server::<readiness::Ready>::bind(
+            "readiness",
+            config.readiness_addr,
+            listeners,
+            drain_rx,
+            ready,
+        )
+        .await
+        .map(|s| Service { s })
     }
 
     pub fn address(&self) -> SocketAddr {
@@ -14,6 +14,10 @@
 
 use std::io::Error;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+#[cfg(unix)]
+use std::sync::Mutex;
 
 use tokio::io;
 use tokio::net::TcpListener;
@@ -118,6 +122,113 @@ pub fn set_transparent(_: &TcpListener) -> io::Result<()> {
     ))
 }
 
+/// The first file descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed through socket activation, until claimed by [listen_fds].
+#[cfg(unix)]
+static LISTEN_FDS: Mutex<Vec<(String, OwnedFd)>> = Mutex::new(Vec::new());
+
+/// capture_listen_fds takes the sockets passed to us through systemd socket activation
+/// (`LISTEN_FDS`/`LISTEN_FDNAMES`), along with their FileDescriptorName. The variables are
+/// cleared, so they are not passed on to child processes.
+///
+/// This modifies the environment, so it must be called before any other thread is started.
+#[cfg(unix)]
+pub fn capture_listen_fds() -> io::Result<()> {
+    let [pid, fds, names] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].map(|k| {
+        let v = std::env::var(k).ok();
+        std::env::remove_var(k);
+        v
+    });
+    let fds = listen_fd_names(pid.as_deref(), fds.as_deref(), names.as_deref())?
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| {
+            // Safety: systemd hands ownership of these descriptors over to us.
+            let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START + i as RawFd) };
+            socket2::SockRef::from(&fd).set_cloexec(true)?;
+            Ok((name, fd))
+        })
+        .collect::<io::Result<_>>()?;
+    *LISTEN_FDS.lock().unwrap() = fds;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn capture_listen_fds() -> io::Result<()> {
+    Ok(())
+}
+
+/// listen_fds returns the sockets taken by [capture_listen_fds] that were not claimed yet.
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<(String, OwnedFd)> {
+    std::mem::take(&mut *LISTEN_FDS.lock().unwrap())
+}
+
+/// check_listener verifies that a socket handed to us is a listening stream socket of one of the
+/// address families, so a misconfigured socket is rejected at startup rather than on accept.
+#[cfg(unix)]
+pub fn check_listener(fd: &OwnedFd, families: &[libc::c_int]) -> io::Result<()> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+    let sock = socket2::SockRef::from(fd);
+    if sock.r#type()? != socket2::Type::STREAM {
+        return Err(invalid("not a stream socket"));
+    }
+    let mut listening: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&listening) as libc::socklen_t;
+    // Safety: getsockopt writes at most len bytes to listening.
+    let ret = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if listening == 0 {
+        return Err(invalid("not a listening socket"));
+    }
+    let family = sock.local_addr()?.family() as libc::c_int;
+    if !families.contains(&family) {
+        return Err(invalid(&format!("unexpected address family {family}")));
+    }
+    Ok(())
+}
+
+/// listen_fd_names returns the names of the sockets passed through socket activation, in order.
+#[cfg(unix)]
+fn listen_fd_names(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+) -> io::Result<Vec<String>> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(Vec::new());
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        // The sockets were meant for another process, such as our parent.
+        return Ok(Vec::new());
+    }
+    let count: usize = fds.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid LISTEN_FDS={fds}"),
+        )
+    })?;
+    let mut names: Vec<String> = names
+        .map(|n| n.split(':').map(String::from).collect())
+        .unwrap_or_default();
+    // systemd names sockets without a FileDescriptorName "unknown".
+    names.resize(count, "unknown".to_string());
+    Ok(names)
+}
+
 #[cfg(target_os = "linux")]
 #[allow(unsafe_code)]
 mod linux {
@@ -175,3 +286,46 @@ pub async fn relay(
 ) -> Result<(u64, u64), Error> {
     tokio::io::copy_bidirectional(downstream, upstream).await
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_fd_names() {
+        let pid = std::process::id().to_string();
+        let names = |fds, names| super::listen_fd_names(Some(&pid), fds, names).unwrap();
+        assert_eq!(
+            names(Some("2"), Some("inbound:outbound")),
+            vec!["inbound", "outbound"]
+        );
+        assert_eq!(names(Some("2"), None), vec!["unknown", "unknown"]);
+        assert!(names(None, None).is_empty());
+        assert!(super::listen_fd_names(Some("1"), Some("1"), Some("admin"))
+            .unwrap()
+            .is_empty());
+        assert!(super::listen_fd_names(Some(&pid), Some("x"), None).is_err());
+    }
+
+    #[test]
+    fn check_listener() {
+        let inet = [libc::AF_INET, libc::AF_INET6];
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(super::check_listener(&OwnedFd::from(listener), &inet).is_ok());
+
+        let unbound =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        assert!(super::check_listener(&OwnedFd::from(unbound), &inet).is_err());
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(super::check_listener(&OwnedFd::from(udp), &inet).is_err());
+
+        let dir = crate::test_helpers::temp_dir();
+        let path = dir.path().join("check.sock");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let unix = OwnedFd::from(unix);
+        assert!(super::check_listener(&unix, &inet).is_err());
+        assert!(super::check_listener(&unix, &[libc::AF_UNIX]).is_ok());
+    }
+}
@@ -12,16 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::identity::SecretManager;
-use crate::metrics::Metrics;
+use crate::authz_log::AuthzLog;
+use crate::ext_authz::ExtAuthz;
+use crate::fault::{Fault, FaultError, FaultStore};
+use crate::identity::{SecretManager, TrustDomainAliases};
+use crate::metrics::rbac::{AuditResult, PolicyAudit};
+use crate::metrics::{IncrementRecorder, Metrics};
 use crate::proxy::Error;
+use crate::rbac::{Decision, Denial, Explanation, PolicySet};
 use crate::state::service::ServiceStore;
 use crate::state::workload::address::Address;
 use crate::state::workload::{
-    gatewayaddress, network_addr, NetworkAddress, Protocol, WaypointError, Workload, WorkloadStore,
+    gatewayaddress, network_addr, MtlsMode, NetworkAddress, Protocol, WaypointError, Workload,
+    WorkloadStore,
 };
+use crate::tls::{Crls, TrustBundles};
 use crate::xds::{AdsClient, Demander, LocalClient, ProxyStateUpdater};
-use crate::{cert_fetcher, config, rbac, readiness, xds};
+use crate::{cert_fetcher, config, fault, rbac, readiness, xds};
 use rand::prelude::IteratorRandom;
 use rand::seq::SliceRandom;
 use std::convert::Into;
@@ -29,7 +36,8 @@ use std::default::Default;
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, RwLock};
-use tracing::{debug, trace};
+use tokio::sync::watch;
+use tracing::{debug, info};
 
 pub mod service;
 pub mod workload;
@@ -65,6 +73,9 @@ pub struct ProxyState {
 
     #[serde(flatten)]
     pub services: ServiceStore,
+
+    #[serde(flatten)]
+    pub faults: FaultStore,
 }
 
 /// Wrapper around [ProxyState] that provides additional methods for requesting information
@@ -77,78 +88,289 @@ pub struct DemandProxyState {
     /// If present, used to request on-demand updates for workloads.
     #[serde(skip_serializing)]
     demand: Option<Demander>,
+
+    /// Clients for the external authorization providers of CUSTOM policies.
+    #[serde(skip_serializing)]
+    ext_authz: ExtAuthz,
+
+    /// Records authorization decisions.
+    #[serde(skip_serializing)]
+    authz_log: AuthzLog,
+
+    /// Root certificates of federated trust domains.
+    #[serde(skip_serializing)]
+    trust_bundles: TrustBundles,
+
+    /// Revocation lists that peer certificates are checked against.
+    #[serde(skip_serializing)]
+    crls: Crls,
 }
 
 impl DemandProxyState {
     pub fn new(state: Arc<RwLock<ProxyState>>, demand: Option<Demander>) -> Self {
-        Self { state, demand }
+        Self {
+            state,
+            demand,
+            ext_authz: ExtAuthz::default(),
+            authz_log: AuthzLog::default(),
+            trust_bundles: TrustBundles::default(),
+            crls: Crls::default(),
+        }
+    }
+
+    /// with_ext_authz sets the providers used to evaluate CUSTOM policies.
+    pub fn with_ext_authz(mut self, ext_authz: ExtAuthz) -> Self {
+        self.ext_authz = ext_authz;
+        self
+    }
+
+    /// with_authz_log sets the log that authorization decisions are recorded in.
+    pub fn with_authz_log(mut self, authz_log: AuthzLog) -> Self {
+        self.authz_log = authz_log;
+        self
+    }
+
+    /// with_trust_bundles sets the root certificates of federated trust domains.
+    pub fn with_trust_bundles(mut self, trust_bundles: TrustBundles) -> Self {
+        self.trust_bundles = trust_bundles;
+        self
+    }
+
+    /// with_crls sets the revocation lists that peer certificates are checked against.
+    pub fn with_crls(mut self, crls: Crls) -> Self {
+        self.crls = crls;
+        self
+    }
+
+    /// find_fault returns the fault to inject into a connection to the target, if any.
+    pub fn find_fault(&self, target: &fault::Target) -> Option<Fault> {
+        self.state.read().unwrap().faults.find(target)
+    }
+
+    pub fn faults(&self) -> Vec<Fault> {
+        self.state.read().unwrap().faults.list()
+    }
+
+    pub fn insert_fault(&self, fault: Fault) -> Result<(), FaultError> {
+        self.state.write().unwrap().faults.insert(fault)
+    }
+
+    pub fn remove_fault(&self, name: &str) -> Option<Fault> {
+        self.state.write().unwrap().faults.remove(name)
+    }
+
+    /// authz_log returns the log that authorization decisions are recorded in.
+    pub fn authz_log(&self) -> &AuthzLog {
+        &self.authz_log
+    }
+
+    /// trust_bundles returns the root certificates of federated trust domains, used to verify
+    /// peers that do not chain to our own roots.
+    pub fn trust_bundles(&self) -> &TrustBundles {
+        &self.trust_bundles
     }
 
-    pub async fn assert_rbac(&self, conn: &rbac::Connection) -> bool {
+    /// trust_domain_aliases returns the trust domains currently treated as equivalent.
+    pub fn trust_domain_aliases(&self) -> TrustDomainAliases {
+        self.state
+            .read()
+            .unwrap()
+            .workloads
+            .trust_domain_aliases()
+            .clone()
+    }
+
+    /// crls returns the revocation lists that peer certificates are checked against.
+    pub fn crls(&self) -> &Crls {
+        &self.crls
+    }
+
+    /// assert_rbac checks whether the connection is allowed by policy, returning why if it is not.
+    /// AUDIT policies are evaluated as well, and their result is logged and recorded in metrics.
+    /// The decision is recorded in the authorization log.
+    pub async fn assert_rbac(
+        &self,
+        conn: &rbac::Connection,
+        metrics: &Metrics,
+    ) -> Result<(), Denial> {
         let nw_addr = network_addr(&conn.dst_network, conn.dst.ip());
         let Some(wl) = self.fetch_workload(&nw_addr).await else {
             debug!("destination workload not found {}", nw_addr);
-            return false;
+            self.authz_log.record(conn, None, &Err(Denial::UnknownWorkload));
+            return Err(Denial::UnknownWorkload);
         };
 
+        let policies = self.state.read().unwrap().workloads.policies_for(&wl);
+        let result = self.authorize(conn, &wl, &policies, metrics).await;
+        self.authz_log.record(conn, Some(&wl), &result);
+        result.map(|_| ())
+    }
+
+    /// evaluate_rbac checks whether the connection is allowed by the local policies, like
+    /// assert_rbac but without side effects: AUDIT results and the authorization log are not
+    /// recorded, external authorization providers are not called, and the destination workload is
+    /// never fetched on demand. This is cheap enough to re-check many connections at once.
+    pub fn evaluate_rbac(&self, conn: &rbac::Connection) -> Result<(), Denial> {
+        let nw_addr = network_addr(&conn.dst_network, conn.dst.ip());
         let state = self.state.read().unwrap();
+        let Some(wl) = state.workloads.find_workload(&nw_addr) else {
+            return Err(Denial::UnknownWorkload);
+        };
+        match state.workloads.policies_for(&wl).decide(conn) {
+            Decision::Denied(policy) => Err(Denial::Policy(policy.to_string())),
+            Decision::NotAllowed => Err(Denial::NotAllowed),
+            Decision::Allowed(_) | Decision::AllowedByDefault => Ok(()),
+        }
+    }
 
-        // We can get policies from namespace, global, and workload...
-        let ns = state
-            .workloads
-            .policies_by_namespace
-            .get(&wl.namespace)
-            .into_iter()
-            .flatten();
-        let global = state
-            .workloads
-            .policies_by_namespace
-            .get("")
-            .into_iter()
-            .flatten();
-        let workload = wl.authorization_policies.iter();
-
-        // Aggregate all of them based on type
-        let (allow, deny): (Vec<_>, Vec<_>) = ns
-            .chain(global)
-            .chain(workload)
-            .filter_map(|k| state.workloads.policies.get(k))
-            .partition(|p| p.action == rbac::RbacAction::Allow);
-
-        trace!(
-            allow = allow.len(),
-            deny = deny.len(),
-            "checking connection"
-        );
+    /// authorize decides the connection to wl with its policies. An allowed connection returns the
+    /// ALLOW policy that allowed it, if any.
+    async fn authorize<'p>(
+        &self,
+        conn: &rbac::Connection,
+        wl: &Workload,
+        policies: &'p PolicySet,
+        metrics: &Metrics,
+    ) -> Result<Option<&'p str>, Denial> {
+        // Audit policies only record what they would have decided; they never affect the result.
+        for (policy, matched) in policies.audit(conn) {
+            let result = if matched {
+                info!(policy, %conn, "audit policy match");
+                AuditResult::matched
+            } else {
+                debug!(policy, %conn, "audit policy does not match");
+                AuditResult::not_matched
+            };
+            metrics.increment(&PolicyAudit {
+                policy: policy.to_string(),
+                result,
+            });
+        }
 
         // Allow and deny logic follows https://istio.io/latest/docs/reference/config/security/authorization-policy/
+        let allowed_by = match policies.decide(conn) {
+            Decision::Denied(policy) => {
+                debug!(policy, "deny policy match");
+                return Err(Denial::Policy(policy.to_string()));
+            }
+            Decision::NotAllowed => {
+                debug!("no allow policies matched");
+                return Err(Denial::NotAllowed);
+            }
+            Decision::Allowed(policy) => {
+                debug!(policy, "allow policy match");
+                Some(policy)
+            }
+            Decision::AllowedByDefault => {
+                debug!("no allow policies, allow");
+                None
+            }
+        };
 
-        // "If there are any DENY policies that match the request, deny the request."
-        for pol in deny.iter() {
-            if pol.matches(conn) {
-                debug!(policy = pol.to_key(), "deny policy match");
-                return false;
-            } else {
-                trace!(policy = pol.to_key(), "deny policy does not match");
+        // "If there are any CUSTOM policies that match the request, evaluate and deny the request
+        // if the evaluation result is deny."
+        // These are checked last, so we only call out to providers for connections we would allow.
+        for (policy, provider) in policies.custom(conn) {
+            if !self.ext_authz.check(provider, policy, conn, wl).await {
+                debug!(policy, provider, "custom policy denied");
+                return Err(Denial::Custom {
+                    policy: policy.to_string(),
+                    provider: provider.to_string(),
+                });
             }
         }
-        // "If there are no ALLOW policies for the workload, allow the request."
-        if allow.is_empty() {
-            debug!("no allow policies, allow");
-            return true;
+        Ok(allowed_by)
+    }
+
+    /// explain_rbac explains how the local policies decide the connection, without calling
+    /// external authorization providers or recording audit results.
+    pub async fn explain_rbac(&self, conn: &rbac::Connection) -> Option<Explanation> {
+        let nw_addr = network_addr(&conn.dst_network, conn.dst.ip());
+        let wl = self.fetch_workload(&nw_addr).await?;
+
+        let state = self.state.read().unwrap();
+        let policies = state.workloads.policies_for(&wl);
+        let decision = policies.decide(conn);
+        Some(Explanation {
+            workload: format!("{}/{}", wl.namespace, wl.name),
+            allowed: decision.is_allowed(),
+            decision: decision.to_string(),
+            policy: decision.policy().map(str::to_string),
+            policies: state
+                .workloads
+                .policies_applying(&wl)
+                .map(|pol| pol.explain(conn, state.workloads.trust_domain_aliases()))
+                .collect(),
+        })
+    }
+
+    /// source_workload looks up the workload a connection comes from by its source address on
+    /// network. When the connection has an identity, the workload must have the same identity, so
+    /// its metadata cannot be claimed by spoofing its address, for example through a gateway.
+    pub async fn source_workload(
+        &self,
+        conn: &rbac::Connection,
+        network: &str,
+    ) -> Option<rbac::SourceWorkload> {
+        let wl = self
+            .fetch_workload(&network_addr(network, conn.src_ip))
+            .await?;
+        if conn
+            .src_identity
+            .as_ref()
+            .map_or(false, |id| *id != wl.identity())
+        {
+            return None;
         }
-        // "If any of the ALLOW policies match the request, allow the request."
-        for pol in allow.iter() {
-            if pol.matches(conn) {
-                debug!(policy = pol.to_key(), "allow policy match");
-                return true;
-            } else {
-                trace!(policy = pol.to_key(), "allow policy does not match");
-            }
+        Some(rbac::SourceWorkload::from(&wl))
+    }
+
+    /// service_hostname returns the hostname of the service with the given VIP, if any.
+    pub fn service_hostname(&self, vip: &NetworkAddress) -> Option<String> {
+        self.state
+            .read()
+            .unwrap()
+            .services
+            .get_by_vip(vip)
+            .map(|svc| svc.hostname)
+    }
+
+    /// destination_service returns the hostname of the service a connection to the workload
+    /// address was sent to. A hostname claimed by the client is only used if the service routes
+    /// to the address; otherwise the service is only known if exactly one routes to it.
+    pub fn destination_service(
+        &self,
+        addr: &NetworkAddress,
+        port: u16,
+        claimed: Option<&str>,
+    ) -> Option<String> {
+        let state = self.state.read().unwrap();
+        let hostnames = state.services.hostnames_for_endpoint(addr, port);
+        match claimed {
+            Some(hostname) => hostnames.contains(hostname).then(|| hostname.to_string()),
+            None if hostnames.len() == 1 => hostnames.into_iter().next().map(str::to_string),
+            None => None,
         }
-        // "Deny the request."
-        debug!("no allow policies matched");
-        false
+    }
+
+    /// mtls_mode returns the effective mTLS mode for traffic to the workload.
+    pub fn mtls_mode(&self, w: &Workload) -> MtlsMode {
+        self.state.read().unwrap().workloads.mtls_mode(w)
+    }
+
+    /// node_has_identity returns true if a workload on the node runs as the identity. This is
+    /// never fetched on demand.
+    pub fn node_has_identity(&self, node: &str, id: &Identity) -> bool {
+        self.state
+            .read()
+            .unwrap()
+            .workloads
+            .node_has_identity(node, id)
+    }
+
+    /// find_workload_by_uid looks up a workload by uid. This is never fetched on demand.
+    pub fn find_workload_by_uid(&self, uid: &str) -> Option<Workload> {
+        self.state.read().unwrap().workloads.find_uid(uid)
     }
 
     // only support workload
@@ -359,6 +581,10 @@ pub struct ProxyStateManager {
 
     #[serde(skip_serializing)]
     xds_client: Option<AdsClient>,
+
+    /// Signaled whenever authorization policies change.
+    #[serde(skip_serializing)]
+    policy_updates: watch::Receiver<()>,
 }
 
 impl ProxyStateManager {
@@ -367,19 +593,55 @@ impl ProxyStateManager {
         metrics: Arc<Metrics>,
         awaiting_ready: readiness::BlockReady,
         cert_manager: Arc<SecretManager>,
+        drain: drain::Watch,
     ) -> anyhow::Result<ProxyStateManager> {
+        // Bundles and CRLs received alongside certificates are shared with those configured here.
+        let trust_bundles = cert_manager.trust_bundles().clone();
+        let crls = cert_manager.crls().clone();
         let cert_fetcher = cert_fetcher::new(&config, cert_manager);
-        let state: Arc<RwLock<ProxyState>> = Arc::new(RwLock::new(ProxyState::default()));
+        let ext_authz = ExtAuthz::new(&config.ext_authz_providers)?;
+        let authz_log = AuthzLog::new(config.authz_log.as_ref(), metrics.clone())?;
+        if let Some(dir) = &config.trust_bundle_dir {
+            trust_bundles.load_and_watch_dir(dir, drain.clone())?;
+        }
+        let crls = crls.load(
+            &config.crl_sources,
+            config.crl_refresh_interval,
+            config.crl_fail_closed,
+            metrics.clone(),
+            drain,
+        );
+        let state: Arc<RwLock<ProxyState>> = Arc::new(RwLock::new(ProxyState {
+            workloads: WorkloadStore::default()
+                .with_trust_domain_aliases(config.trust_domain_aliases.clone()),
+            ..Default::default()
+        }));
+        let (policy_tx, policy_updates) = watch::channel(());
+        let policy_tx = Arc::new(policy_tx);
         let xds_client = if config.xds_address.is_some() {
-            let updater = ProxyStateUpdater::new(state.clone(), cert_fetcher.clone());
-            Some(
-                xds::Config::new(config.clone())
-                    .with_address_handler(updater.clone())
-                    .with_authorization_handler(updater)
-                    .watch(xds::ADDRESS_TYPE.into())
-                    .watch(xds::AUTHORIZATION_TYPE.into())
-                    .build(metrics, awaiting_ready),
-            )
+            let updater = ProxyStateUpdater::new(state.clone(), cert_fetcher.clone())
+                .with_policy_notifier(policy_tx.clone());
+            let mut xds_config = xds::Config::new(config.clone())
+                .with_address_handler(updater.clone())
+                .with_authorization_handler(updater.clone())
+                .watch(xds::ADDRESS_TYPE.into())
+                .watch(xds::AUTHORIZATION_TYPE.into());
+            if config.xds_peer_authentication {
+                xds_config = xds_config
+                    .with_peer_authentication_handler(updater.clone())
+                    .watch(xds::PEER_AUTHENTICATION_TYPE.into());
+            }
+            if config.xds_trust_domain_aliases {
+                xds_config = xds_config
+                    .with_trust_domain_aliases_handler(updater)
+                    .watch(xds::TRUST_DOMAIN_ALIASES_TYPE.into());
+            }
+            if config.xds_trust_bundles {
+                xds_config = xds_config
+                    .with_trust_bundle_handler(trust_bundles.clone())
+                    .watch(xds::TRUST_BUNDLE_TYPE.into());
+            }
+            Some(xds_config.build(metrics, awaiting_ready))
         } else {
             None
         };
@@ -388,16 +650,27 @@ impl ProxyStateManager {
                 cfg,
                 state: state.clone(),
                 cert_fetcher,
+                policy_notifier: Some(policy_tx),
             };
             local_client.run().await?;
         }
         let demand = xds_client.as_ref().and_then(AdsClient::demander);
         Ok(ProxyStateManager {
             xds_client,
-            state: DemandProxyState { state, demand },
+            state: DemandProxyState::new(state, demand)
+                .with_ext_authz(ext_authz)
+                .with_authz_log(authz_log)
+                .with_trust_bundles(trust_bundles)
+                .with_crls(crls),
+            policy_updates,
         })
     }
 
+    /// policy_updates returns a receiver that is notified whenever authorization policies change.
+    pub fn policy_updates(&self) -> watch::Receiver<()> {
+        self.policy_updates.clone()
+    }
+
     pub async fn run(self) -> anyhow::Result<()> {
         match self.xds_client {
             Some(xds) => xds.run().await.map_err(|e| anyhow::anyhow!(e)),
@@ -405,3 +678,231 @@ impl ProxyStateManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::new_proxy_state;
+    use crate::xds::istio::security::Action as XdsAction;
+    use crate::xds::istio::security::Address as XdsAddress;
+    use crate::xds::istio::security::Authorization as XdsAuthorization;
+    use crate::xds::istio::security::Clause as XdsClause;
+    use crate::xds::istio::security::Match as XdsMatch;
+    use crate::xds::istio::security::Rule as XdsRule;
+    use crate::xds::istio::security::Scope as XdsScope;
+    use crate::xds::istio::workload::Workload as XdsWorkload;
+    use bytes::Bytes;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+
+    fn source_policy(name: &str, action: XdsAction, src: [u8; 4]) -> XdsAuthorization {
+        XdsAuthorization {
+            name: name.to_string(),
+            namespace: "ns".to_string(),
+            scope: XdsScope::Global as i32,
+            action: action as i32,
+            rules: vec![XdsRule {
+                clauses: vec![XdsClause {
+                    matches: vec![XdsMatch {
+                        source_ips: vec![XdsAddress {
+                            address: Bytes::copy_from_slice(&src),
+                            length: 32,
+                        }],
+                        ..Default::default()
+                    }],
+                }],
+            }],
+            provider: "".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn audit_policies_are_not_enforced() {
+        let workloads = vec![XdsWorkload {
+            uid: "cluster1//v1/Pod/ns/dst".to_string(),
+            name: "dst".to_string(),
+            namespace: "ns".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+            ..Default::default()
+        }];
+        // One policy matches the connection and one does not; neither may act as ALLOW or DENY.
+        let state = new_proxy_state(
+            workloads,
+            vec![],
+            vec![
+                source_policy("matched", XdsAction::Audit, [127, 0, 0, 1]),
+                source_policy("unmatched", XdsAction::Audit, [127, 0, 0, 3]),
+            ],
+        )
+        .unwrap();
+        let mut registry = Registry::default();
+        let metrics = Metrics::from(&mut registry);
+        let conn = rbac::Connection {
+            src_identity: None,
+            src_ip: "127.0.0.1".parse().unwrap(),
+            dst_network: "".to_string(),
+            dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
+        };
+        // Only assert_rbac records audit results.
+        assert!(state.evaluate_rbac(&conn).is_ok());
+        assert!(state.assert_rbac(&conn, &metrics).await.is_ok());
+
+        let mut buf = String::new();
+        encode(&mut buf, &registry).unwrap();
+        assert!(
+            buf.contains(r#"istio_policy_audits_total{policy="ns/matched",result="matched"} 1"#)
+        );
+        assert!(buf
+            .contains(r#"istio_policy_audits_total{policy="ns/unmatched",result="not_matched"} 1"#));
+    }
+
+    #[test]
+    fn destination_service() {
+        use crate::xds::istio::workload::NetworkAddress as XdsNetworkAddress;
+        use crate::xds::istio::workload::Port as XdsPort;
+        use crate::xds::istio::workload::PortList as XdsPortList;
+        use crate::xds::istio::workload::Service as XdsService;
+        use std::collections::HashMap;
+
+        let port = |service_port, target_port| XdsPort {
+            service_port,
+            target_port,
+        };
+        let service = |name: &str, vip: [u8; 4], ports: Vec<XdsPort>| XdsService {
+            name: name.to_string(),
+            namespace: "ns".to_string(),
+            hostname: format!("{name}.ns.svc.cluster.local"),
+            addresses: vec![XdsNetworkAddress {
+                network: "".to_string(),
+                address: vip.to_vec(),
+            }],
+            ports,
+            subject_alt_names: vec![],
+        };
+        let workloads = vec![XdsWorkload {
+            uid: "cluster1//v1/Pod/ns/dst".to_string(),
+            name: "dst".to_string(),
+            namespace: "ns".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+            virtual_ips: HashMap::from([
+                (
+                    "/10.0.0.1".to_string(),
+                    XdsPortList {
+                        ports: vec![port(80, 8080)],
+                    },
+                ),
+                (
+                    "/10.0.0.2".to_string(),
+                    XdsPortList {
+                        ports: vec![port(80, 9090)],
+                    },
+                ),
+            ]),
+            ..Default::default()
+        }];
+        let state = new_proxy_state(
+            workloads,
+            vec![
+                service("a", [10, 0, 0, 1], vec![port(80, 8080)]),
+                service("b", [10, 0, 0, 2], vec![port(80, 9090)]),
+            ],
+            vec![],
+        )
+        .unwrap();
+        let addr = network_addr("", "127.0.0.2".parse().unwrap());
+
+        // Without a claim, the service is only known if a single one routes to the port.
+        assert_eq!(
+            state.destination_service(&addr, 8080, None).as_deref(),
+            Some("a.ns.svc.cluster.local")
+        );
+        assert_eq!(state.destination_service(&addr, 7070, None), None);
+        // A claimed service must route to the destination port.
+        assert_eq!(
+            state
+                .destination_service(&addr, 9090, Some("b.ns.svc.cluster.local"))
+                .as_deref(),
+            Some("b.ns.svc.cluster.local")
+        );
+        assert_eq!(
+            state.destination_service(&addr, 9090, Some("a.ns.svc.cluster.local")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn explain_policies() {
+        let workloads = vec![XdsWorkload {
+            uid: "cluster1//v1/Pod/ns/dst".to_string(),
+            name: "dst".to_string(),
+            namespace: "ns".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+            ..Default::default()
+        }];
+        let state = new_proxy_state(
+            workloads,
+            vec![],
+            vec![
+                source_policy("allow", XdsAction::Allow, [127, 0, 0, 1]),
+                source_policy("deny", XdsAction::Deny, [127, 0, 0, 3]),
+            ],
+        )
+        .unwrap();
+        let metrics = Metrics::from(&mut Registry::default());
+        let conn = |src: &str| rbac::Connection {
+            src_identity: None,
+            src_ip: src.parse().unwrap(),
+            dst_network: "".to_string(),
+            dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
+        };
+
+        let explanation = state.explain_rbac(&conn("127.0.0.1")).await.unwrap();
+        assert_eq!(explanation.workload, "ns/dst");
+        assert!(explanation.allowed);
+        assert_eq!(explanation.policy.as_deref(), Some("ns/allow"));
+        assert_eq!(explanation.policies.len(), 2);
+        let deny = explanation
+            .policies
+            .iter()
+            .find(|p| p.policy == "ns/deny")
+            .unwrap();
+        assert!(!deny.matched);
+        assert_eq!(deny.rules[0].clauses[0].matches[0].source_ips, Some(false));
+        assert_eq!(deny.rules[0].clauses[0].matches[0].principals, None);
+        assert!(state
+            .assert_rbac(&conn("127.0.0.1"), &metrics)
+            .await
+            .is_ok());
+
+        let explanation = state.explain_rbac(&conn("127.0.0.3")).await.unwrap();
+        assert!(!explanation.allowed);
+        assert_eq!(explanation.policy.as_deref(), Some("ns/deny"));
+        assert_eq!(
+            state.assert_rbac(&conn("127.0.0.3"), &metrics).await,
+            Err(Denial::Policy("ns/deny".to_string()))
+        );
+        assert_eq!(
+            state.assert_rbac(&conn("127.0.0.4"), &metrics).await,
+            Err(Denial::NotAllowed)
+        );
+        assert_eq!(
+            state.evaluate_rbac(&conn("127.0.0.3")),
+            Err(Denial::Policy("ns/deny".to_string()))
+        );
+        assert_eq!(
+            state.evaluate_rbac(&conn("127.0.0.4")),
+            Err(Denial::NotAllowed)
+        );
+        assert_eq!(
+            state.evaluate_rbac(&rbac::Connection {
+                dst: "127.0.0.9:80".parse().unwrap(),
+                ..conn("127.0.0.1")
+            }),
+            Err(Denial::UnknownWorkload)
+        );
+    }
+}
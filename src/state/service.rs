@@ -114,6 +114,29 @@ impl ServiceStore {
         self.by_vip.get(vip).map(|s| s.deref().clone())
     }
 
+    /// Returns the hostnames of the [Service]s that route to the workload address on the given
+    /// target port.
+    pub fn hostnames_for_endpoint(
+        &self,
+        addr: &NetworkAddress,
+        target_port: u16,
+    ) -> HashSet<&str> {
+        let Some(vips) = self.workload_to_vips.get(addr) else {
+            return HashSet::new();
+        };
+        vips.iter()
+            .filter_map(|vip| self.by_vip.get(vip))
+            .filter(|svc| {
+                let overrides = svc.endpoints.get(addr).map(|ep| &ep.port);
+                svc.ports.iter().any(|(port, default)| {
+                    let target = overrides.and_then(|o| o.get(port)).unwrap_or(default);
+                    *target == target_port
+                })
+            })
+            .map(|svc| svc.hostname.as_str())
+            .collect()
+    }
+
     /// Returns the list of [Service]s matching the given hostname. Istio `ServiceEntry`
     /// affords the ability to define the same hostname (e.g. `www.google.com`) in different
     /// namespaces. In most cases, only a single [Service] will be returned.
@@ -12,8 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::identity::Identity;
-use crate::rbac::{Authorization, RbacScope};
+use crate::identity::{Identity, TrustDomainAliases};
+use crate::rbac::{Authorization, PolicySet, RbacScope};
 use crate::state::workload::WorkloadError::EnumParse;
 use crate::xds;
 use crate::xds::istio::workload::{Port, PortList};
@@ -77,6 +77,38 @@ impl TryFrom<Option<xds::istio::workload::WorkloadStatus>> for HealthStatus {
     }
 }
 
+/// MtlsMode controls whether a workload accepts plaintext traffic, following PeerAuthentication.
+#[derive(
+    Default, Debug, Hash, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MtlsMode {
+    /// Inherit the namespace or mesh-wide mode.
+    #[default]
+    Unset,
+    /// Accept both mTLS and plaintext traffic.
+    Permissive,
+    /// Reject plaintext traffic to workloads that can receive HBONE.
+    Strict,
+    /// Send plaintext traffic to the workload instead of using HBONE, unless it goes through a
+    /// waypoint.
+    Disable,
+}
+
+impl TryFrom<Option<xds::istio::workload::MtlsMode>> for MtlsMode {
+    type Error = WorkloadError;
+
+    fn try_from(value: Option<xds::istio::workload::MtlsMode>) -> Result<Self, Self::Error> {
+        match value {
+            Some(xds::istio::workload::MtlsMode::Unset) => Ok(MtlsMode::Unset),
+            Some(xds::istio::workload::MtlsMode::Permissive) => Ok(MtlsMode::Permissive),
+            Some(xds::istio::workload::MtlsMode::Strict) => Ok(MtlsMode::Strict),
+            Some(xds::istio::workload::MtlsMode::Disable) => Ok(MtlsMode::Disable),
+            None => Err(EnumParse("unknown type".into())),
+        }
+    }
+}
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct GatewayAddress {
@@ -156,6 +188,9 @@ pub struct Workload {
 
     #[serde(default)]
     pub cluster_id: String,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub mtls_mode: MtlsMode,
 }
 
 fn is_default<T: Default + PartialEq>(t: &T) -> bool {
@@ -340,6 +375,10 @@ impl TryFrom<&XdsWorkload> for Workload {
                     result
                 }
             },
+
+            mtls_mode: MtlsMode::try_from(xds::istio::workload::MtlsMode::from_i32(
+                resource.mtls_mode,
+            ))?,
         })
     }
 }
@@ -483,11 +522,82 @@ pub struct WorkloadStore {
     pub(super) policies: HashMap<String, Authorization>,
     // policies_by_namespace maintains a mapping of namespace (or "" for global) to policy names
     pub(super) policies_by_namespace: HashMap<String, HashSet<String>>,
+    /// mtls_modes maintains a mapping of namespace (or "" for mesh-wide) to the default mTLS mode
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    mtls_modes: HashMap<String, MtlsMode>,
+    /// rbac maintains a mapping of workload UID to the compiled policies that apply to it.
+    #[serde(skip_serializing)]
+    rbac: HashMap<String, Arc<PolicySet>>,
+    /// dirty holds the UIDs of workloads whose compiled policies are stale. They are compiled
+    /// together by compile_dirty_policies, once per update, rather than on every change.
+    #[serde(skip_serializing)]
+    dirty: HashSet<String>,
+    /// trust_domain_aliases are the trust domains that policies treat as equivalent.
+    #[serde(skip_serializing)]
+    trust_domain_aliases: TrustDomainAliases,
+    /// configured_trust_domain_aliases are the aliases from our configuration, which apply
+    /// unless aliases are received over XDS.
+    #[serde(skip_serializing)]
+    configured_trust_domain_aliases: TrustDomainAliases,
+    /// xds_trust_domain_aliases maintains a mapping of XDS resource name to the aliases it
+    /// declares. Their union is used while there are any.
+    #[serde(skip_serializing)]
+    xds_trust_domain_aliases: HashMap<String, TrustDomainAliases>,
 }
 
 impl WorkloadStore {
+    /// with_trust_domain_aliases sets the configured trust domains that policies treat as
+    /// equivalent. It must be set before policies are inserted.
+    pub fn with_trust_domain_aliases(mut self, aliases: TrustDomainAliases) -> Self {
+        self.trust_domain_aliases = aliases.clone();
+        self.configured_trust_domain_aliases = aliases;
+        self
+    }
+
+    /// insert_trust_domain_aliases sets the aliases declared by the named XDS resource. It returns
+    /// whether the aliases in effect changed, in which case every workload's policies must be
+    /// recompiled.
+    pub fn insert_trust_domain_aliases(
+        &mut self,
+        name: String,
+        aliases: TrustDomainAliases,
+    ) -> bool {
+        self.xds_trust_domain_aliases.insert(name, aliases);
+        self.update_trust_domain_aliases()
+    }
+
+    /// remove_trust_domain_aliases removes the aliases declared by the named XDS resource,
+    /// restoring the configured aliases once none are left. It returns whether the aliases in
+    /// effect changed.
+    pub fn remove_trust_domain_aliases(&mut self, name: &str) -> bool {
+        if self.xds_trust_domain_aliases.remove(name).is_none() {
+            return false;
+        }
+        self.update_trust_domain_aliases()
+    }
+
+    fn update_trust_domain_aliases(&mut self) -> bool {
+        let aliases = if self.xds_trust_domain_aliases.is_empty() {
+            self.configured_trust_domain_aliases.clone()
+        } else {
+            TrustDomainAliases::union(self.xds_trust_domain_aliases.values())
+        };
+        if aliases == self.trust_domain_aliases {
+            return false;
+        }
+        self.trust_domain_aliases = aliases;
+        self.mark_dirty(|_| true);
+        true
+    }
+
+    pub fn trust_domain_aliases(&self) -> &TrustDomainAliases {
+        &self.trust_domain_aliases
+    }
+
     pub fn insert_authorization(&mut self, rbac: Authorization) {
         let key = rbac.to_key();
+        // The previous version may have had a different scope.
+        let prev = self.unindex_authorization(&key);
         match rbac.scope {
             RbacScope::Global => {
                 self.policies_by_namespace
@@ -503,25 +613,130 @@ impl WorkloadStore {
             }
             RbacScope::WorkloadSelector => {}
         }
-        self.policies.insert(key, rbac);
+        let (scope, namespace) = (rbac.scope, rbac.namespace.clone());
+        self.policies.insert(key.clone(), rbac);
+        self.mark_dirty(|w| {
+            applies_to(scope, &namespace, &key, w)
+                || prev
+                    .as_ref()
+                    .map_or(false, |p| applies_to(p.scope, &p.namespace, &key, w))
+        });
     }
 
     pub fn remove_rbac(&mut self, name: String) {
-        let Some(rbac) = self.policies.remove(&name) else {
+        let Some(rbac) = self.unindex_authorization(&name) else {
             return;
         };
+        self.mark_dirty(|w| applies_to(rbac.scope, &rbac.namespace, &name, w));
+    }
+
+    /// unindex_authorization removes a policy, returning it if it was present. The compiled
+    /// policies of affected workloads are not updated.
+    fn unindex_authorization(&mut self, name: &str) -> Option<Authorization> {
+        let rbac = self.policies.remove(name)?;
         if let Some(key) = match rbac.scope {
-            RbacScope::Global => Some("".to_string()),
-            RbacScope::Namespace => Some(rbac.namespace),
+            RbacScope::Global => Some(""),
+            RbacScope::Namespace => Some(rbac.namespace.as_str()),
             RbacScope::WorkloadSelector => None,
         } {
-            if let Some(pl) = self.policies_by_namespace.get_mut(&key) {
-                pl.remove(&name);
+            if let Some(pl) = self.policies_by_namespace.get_mut(key) {
+                pl.remove(name);
                 if pl.is_empty() {
-                    self.policies_by_namespace.remove(&key);
+                    self.policies_by_namespace.remove(key);
                 }
             }
         }
+        Some(rbac)
+    }
+
+    /// policies_for returns the compiled policies that apply to a workload.
+    pub fn policies_for(&self, w: &Workload) -> Arc<PolicySet> {
+        match self.rbac.get(&w.uid) {
+            Some(set) if !self.dirty.contains(&w.uid) => set.clone(),
+            // The workload is not in the store, or its policies changed and have not been
+            // compiled yet, so compile its policies on demand.
+            _ => Arc::new(self.compile_policies(w)),
+        }
+    }
+
+    /// policies_applying returns the namespace, global and workload policies for a workload.
+    pub fn policies_applying<'a>(
+        &'a self,
+        w: &'a Workload,
+    ) -> impl Iterator<Item = &'a Authorization> {
+        let mut seen = HashSet::new();
+        [
+            self.policies_by_namespace.get(&w.namespace),
+            self.policies_by_namespace.get(""),
+        ]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .chain(w.authorization_policies.iter())
+        .filter(move |k| seen.insert(*k))
+        .filter_map(move |k| self.policies.get(k))
+    }
+
+    fn compile_policies(&self, w: &Workload) -> PolicySet {
+        PolicySet::new(self.policies_applying(w))
+            .with_trust_domain_aliases(self.trust_domain_aliases.clone())
+    }
+
+    /// mark_dirty marks the compiled policies of the workloads selected by filter as stale.
+    fn mark_dirty(&mut self, filter: impl Fn(&Workload) -> bool) {
+        let dirty = self
+            .workloads_by_uid
+            .values()
+            .filter(|w| filter(w))
+            .map(|w| w.uid.clone());
+        self.dirty.extend(dirty);
+    }
+
+    /// compile_dirty_policies compiles the policies of every workload marked as stale. It should
+    /// be called once a batch of updates has been applied. Workloads in the same namespace with
+    /// the same policies share the compiled result.
+    pub fn compile_dirty_policies(&mut self) {
+        if self.dirty.is_empty() {
+            return;
+        }
+        let dirty = std::mem::take(&mut self.dirty);
+        let mut compiled: HashMap<(&str, &[String]), Arc<PolicySet>> = HashMap::new();
+        let updates: Vec<_> = dirty
+            .iter()
+            .filter_map(|uid| self.workloads_by_uid.get(uid))
+            .map(|w| {
+                let set = compiled
+                    .entry((w.namespace.as_str(), w.authorization_policies.as_slice()))
+                    .or_insert_with(|| Arc::new(self.compile_policies(w)));
+                (w.uid.clone(), set.clone())
+            })
+            .collect();
+        self.rbac.extend(updates);
+    }
+
+    /// set_mtls_mode sets the default mTLS mode for a namespace, or mesh-wide if namespace is empty.
+    pub fn set_mtls_mode(&mut self, namespace: String, mode: MtlsMode) {
+        if mode == MtlsMode::Unset {
+            self.mtls_modes.remove(&namespace);
+        } else {
+            self.mtls_modes.insert(namespace, mode);
+        }
+    }
+
+    /// mtls_mode returns the effective mTLS mode for a workload. The workload's own mode takes
+    /// precedence over its namespace, which takes precedence over the mesh-wide mode.
+    /// Workloads default to PERMISSIVE.
+    pub fn mtls_mode(&self, w: &Workload) -> MtlsMode {
+        [
+            Some(&w.mtls_mode),
+            self.mtls_modes.get(&w.namespace),
+            self.mtls_modes.get(""),
+        ]
+        .into_iter()
+        .flatten()
+        .copied()
+        .find(|m| *m != MtlsMode::Unset)
+        .unwrap_or(MtlsMode::Permissive)
     }
 
     pub fn insert_workload(&mut self, w: Workload) -> anyhow::Result<()> {
@@ -534,10 +749,13 @@ impl WorkloadStore {
                 .insert(network_addr(&w.network, *ip), w.clone());
         }
         self.workloads_by_uid.insert(w.uid.clone(), w.clone());
+        self.dirty.insert(w.uid.clone());
         Ok(())
     }
 
     pub fn remove_workload(&mut self, uid: &str) -> Option<Workload> {
+        self.rbac.remove(uid);
+        self.dirty.remove(uid);
         match self.workloads_by_uid.remove(uid) {
             None => {
                 trace!("tried to remove workload keyed by {} but it was not found; presumably it was a service", uid);
@@ -555,6 +773,27 @@ impl WorkloadStore {
     pub fn find_workload(&self, addr: &NetworkAddress) -> Option<Workload> {
         self.workloads.get(addr).map(|wl| wl.deref().clone())
     }
+
+    pub fn find_uid(&self, uid: &str) -> Option<Workload> {
+        self.workloads_by_uid.get(uid).map(|wl| wl.deref().clone())
+    }
+
+    /// node_has_identity returns true if a workload on the node runs as the identity.
+    pub fn node_has_identity(&self, node: &str, id: &Identity) -> bool {
+        self.workloads_by_uid
+            .values()
+            .any(|w| w.node == node && w.identity() == *id)
+    }
+}
+
+/// applies_to returns whether a policy with the given scope and key applies to a workload.
+fn applies_to(scope: RbacScope, namespace: &str, key: &str, w: &Workload) -> bool {
+    let by_scope = match scope {
+        RbacScope::Global => true,
+        RbacScope::Namespace => w.namespace == namespace,
+        RbacScope::WorkloadSelector => false,
+    };
+    by_scope || w.authorization_policies.iter().any(|k| k == key)
 }
 
 #[allow(clippy::enum_variant_names)]
@@ -570,6 +809,10 @@ pub enum WorkloadError {
     EnumParse(String),
     #[error("nonempty gateway address is missing address")]
     MissingGatewayAddress,
+    #[error("CUSTOM policy {0} is missing a provider")]
+    MissingProvider(String),
+    #[error("invalid pattern {0}: {1}")]
+    InvalidPattern(String, String),
 }
 
 #[cfg(test)]
@@ -1069,6 +1312,300 @@ mod tests {
         }
     }
 
+    #[test]
+    fn mtls_mode() {
+        let mut store = WorkloadStore::default();
+        let wl = |namespace: &str, mtls_mode: MtlsMode| Workload {
+            namespace: namespace.to_string(),
+            mtls_mode,
+            ..test_helpers::test_default_workload()
+        };
+
+        assert_eq!(
+            store.mtls_mode(&wl("ns", MtlsMode::Unset)),
+            MtlsMode::Permissive
+        );
+
+        store.set_mtls_mode("".to_string(), MtlsMode::Strict);
+        assert_eq!(
+            store.mtls_mode(&wl("ns", MtlsMode::Unset)),
+            MtlsMode::Strict
+        );
+
+        store.set_mtls_mode("ns".to_string(), MtlsMode::Permissive);
+        assert_eq!(
+            store.mtls_mode(&wl("ns", MtlsMode::Unset)),
+            MtlsMode::Permissive
+        );
+        assert_eq!(
+            store.mtls_mode(&wl("other", MtlsMode::Unset)),
+            MtlsMode::Strict
+        );
+        assert_eq!(
+            store.mtls_mode(&wl("ns", MtlsMode::Disable)),
+            MtlsMode::Disable
+        );
+
+        store.set_mtls_mode("".to_string(), MtlsMode::Unset);
+        assert_eq!(
+            store.mtls_mode(&wl("other", MtlsMode::Unset)),
+            MtlsMode::Permissive
+        );
+
+        let xds = XdsWorkload {
+            uid: "uid".to_string(),
+            mtls_mode: xds::istio::workload::MtlsMode::Strict as i32,
+            ..Default::default()
+        };
+        assert_eq!(
+            Workload::try_from(&xds).unwrap().mtls_mode,
+            MtlsMode::Strict
+        );
+
+        // Namespace and mesh-wide defaults can also be set over XDS.
+        let state = Arc::new(RwLock::new(ProxyState::default()));
+        let updater = ProxyStateUpdater::new_no_fetch(state.clone());
+        let peer_authn = |namespace: &str, mode: xds::istio::workload::MtlsMode| {
+            xds::istio::workload::PeerAuthentication {
+                namespace: namespace.to_string(),
+                mtls_mode: mode as i32,
+            }
+        };
+        let mode = |namespace: &str| {
+            state
+                .read()
+                .unwrap()
+                .workloads
+                .mtls_mode(&wl(namespace, MtlsMode::Unset))
+        };
+        updater
+            .insert_peer_authentication("*", peer_authn("", xds::istio::workload::MtlsMode::Strict))
+            .unwrap();
+        updater
+            .insert_peer_authentication(
+                "ns",
+                peer_authn("ns", xds::istio::workload::MtlsMode::Disable),
+            )
+            .unwrap();
+        assert!(updater
+            .insert_peer_authentication(
+                "other",
+                peer_authn("ns", xds::istio::workload::MtlsMode::Permissive)
+            )
+            .is_err());
+        assert_eq!(mode("ns"), MtlsMode::Disable);
+        assert_eq!(mode("other"), MtlsMode::Strict);
+        updater.remove_peer_authentication("ns");
+        assert_eq!(mode("ns"), MtlsMode::Strict);
+        updater.remove_peer_authentication("*");
+        assert_eq!(mode("ns"), MtlsMode::Permissive);
+    }
+
+    #[test]
+    fn compiled_policies() {
+        use crate::rbac::{Decision, RbacAction};
+
+        let mut store = WorkloadStore::default();
+        let wl = |uid: &str, namespace: &str, ip: [u8; 4]| Workload {
+            uid: uid.to_string(),
+            namespace: namespace.to_string(),
+            workload_ips: vec![IpAddr::from(ip)],
+            ..test_helpers::test_default_workload()
+        };
+        let deny = |scope: RbacScope| Authorization {
+            name: "deny".to_string(),
+            namespace: "ns".to_string(),
+            scope,
+            action: RbacAction::Deny,
+            rules: vec![vec![]],
+            provider: None,
+        };
+        let conn = crate::rbac::Connection {
+            src_identity: None,
+            src_ip: "127.0.0.1".parse().unwrap(),
+            dst_network: "".to_string(),
+            dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
+        };
+        let decide = |store: &WorkloadStore, uid: &str| {
+            let w = store.find_uid(uid).unwrap();
+            store.policies_for(&w).decide(&conn) == Decision::Denied("ns/deny")
+        };
+
+        store
+            .insert_workload(wl("a", "ns", [127, 0, 0, 2]))
+            .unwrap();
+        store
+            .insert_workload(wl("b", "other", [127, 0, 0, 3]))
+            .unwrap();
+        assert!(!decide(&store, "a"));
+
+        store.insert_authorization(deny(RbacScope::Namespace));
+        assert!(decide(&store, "a"));
+        assert!(!decide(&store, "b"));
+
+        // Changing the scope recompiles both the old and new workloads.
+        store.insert_authorization(deny(RbacScope::Global));
+        assert!(decide(&store, "a"));
+        assert!(decide(&store, "b"));
+
+        // Workloads added later pick up existing policies.
+        store
+            .insert_workload(wl("c", "other", [127, 0, 0, 4]))
+            .unwrap();
+        assert!(decide(&store, "c"));
+
+        // Stale workloads are compiled together, sharing the result within a namespace.
+        store
+            .insert_workload(wl("d", "other", [127, 0, 0, 5]))
+            .unwrap();
+        assert_eq!(store.dirty.len(), 4);
+        store.compile_dirty_policies();
+        assert!(store.dirty.is_empty());
+        assert!(Arc::ptr_eq(&store.rbac["c"], &store.rbac["d"]));
+        assert!(decide(&store, "d"));
+
+        store.remove_rbac("ns/deny".to_string());
+        assert_eq!(store.dirty.len(), 4);
+        assert!(!decide(&store, "a"));
+        assert!(!decide(&store, "b"));
+        assert!(!decide(&store, "c"));
+        store.compile_dirty_policies();
+        assert!(!decide(&store, "d"));
+    }
+
+    #[test]
+    fn policy_change_notified_after_compile() {
+        use crate::xds::istio::security::Authorization as XdsAuthorization;
+        use crate::xds::{Handler, XdsResource, XdsUpdate};
+
+        let state = Arc::new(RwLock::new(ProxyState::default()));
+        let (tx, mut rx) = tokio::sync::watch::channel(());
+        let updater =
+            ProxyStateUpdater::new_no_fetch(state.clone()).with_policy_notifier(Arc::new(tx));
+        state
+            .write()
+            .unwrap()
+            .workloads
+            .insert_workload(test_helpers::test_default_workload())
+            .unwrap();
+        let policy = |name: &str| {
+            XdsUpdate::Update(XdsResource {
+                name: format!("ns/{name}"),
+                resource: XdsAuthorization {
+                    name: name.to_string(),
+                    namespace: "ns".to_string(),
+                    ..Default::default()
+                },
+            })
+        };
+
+        // A batch notifies once, after its policies are compiled.
+        rx.borrow_and_update();
+        updater.handle(vec![policy("a"), policy("b")]).unwrap();
+        assert!(rx.has_changed().unwrap());
+        rx.borrow_and_update();
+        assert!(state.read().unwrap().workloads.dirty.is_empty());
+
+        // Changes outside of a handled batch wait for the next compile.
+        updater.remove_authorization("ns/a".to_string());
+        assert!(!rx.has_changed().unwrap());
+        updater
+            .handle(vec![XdsUpdate::<XdsAuthorization>::Remove("ns/b".to_string())])
+            .unwrap();
+        assert!(rx.has_changed().unwrap());
+    }
+
+    #[test]
+    fn trust_domain_aliases_update() {
+        use crate::rbac::{Decision, RbacAction, RbacMatch, StringMatch};
+        use crate::xds::istio::security::TrustDomainAliases as XdsTrustDomainAliases;
+
+        let state = Arc::new(RwLock::new(ProxyState::default()));
+        let updater = ProxyStateUpdater::new_no_fetch(state.clone());
+        let w = Workload {
+            uid: "a".to_string(),
+            namespace: "ns".to_string(),
+            workload_ips: vec![IpAddr::from([127, 0, 0, 2])],
+            ..test_helpers::test_default_workload()
+        };
+        {
+            let mut state = state.write().unwrap();
+            state.workloads.insert_workload(w.clone()).unwrap();
+            state.workloads.insert_authorization(Authorization {
+                name: "allow".to_string(),
+                namespace: "ns".to_string(),
+                scope: RbacScope::Namespace,
+                action: RbacAction::Allow,
+                rules: vec![vec![vec![RbacMatch {
+                    principals: vec![StringMatch::Exact("old.example/ns/ns/sa/sa".to_string())],
+                    ..Default::default()
+                }]]],
+                provider: None,
+            });
+            state.workloads.compile_dirty_policies();
+        }
+        let conn = crate::rbac::Connection {
+            src_identity: Some(Identity::Spiffe {
+                trust_domain: "new.example".to_string(),
+                namespace: "ns".to_string(),
+                service_account: "sa".to_string(),
+            }),
+            src_ip: "127.0.0.1".parse().unwrap(),
+            dst_network: "".to_string(),
+            dst: "127.0.0.2:80".parse().unwrap(),
+            src_workload: None,
+            dst_service: None,
+        };
+        let allowed = || {
+            let state = state.read().unwrap();
+            state.workloads.policies_for(&w).decide(&conn) == Decision::Allowed("ns/allow")
+        };
+        let aliases = |trust_domain: &str| XdsTrustDomainAliases {
+            trust_domain: trust_domain.to_string(),
+            aliases: vec!["old.example".to_string()],
+        };
+
+        assert!(!allowed());
+        updater
+            .insert_trust_domain_aliases("new.example", aliases("new.example"))
+            .unwrap();
+        assert!(allowed());
+        assert!(updater
+            .insert_trust_domain_aliases("other.example", aliases("new.example"))
+            .is_err());
+        updater.remove_trust_domain_aliases("new.example");
+        assert!(!allowed());
+
+        // Aliases from several resources apply together, and removing one keeps the others.
+        updater
+            .insert_trust_domain_aliases("new.example", aliases("new.example"))
+            .unwrap();
+        updater
+            .insert_trust_domain_aliases("other.example", aliases("other.example"))
+            .unwrap();
+        assert!(allowed());
+        let other_allowed = || {
+            let conn = crate::rbac::Connection {
+                src_identity: Some(Identity::Spiffe {
+                    trust_domain: "other.example".to_string(),
+                    namespace: "ns".to_string(),
+                    service_account: "sa".to_string(),
+                }),
+                ..conn.clone()
+            };
+            let state = state.read().unwrap();
+            state.workloads.policies_for(&w).decide(&conn) == Decision::Allowed("ns/allow")
+        };
+        assert!(other_allowed());
+        updater.remove_trust_domain_aliases("other.example");
+        assert!(allowed());
+        assert!(!other_allowed());
+        updater.remove_trust_domain_aliases("new.example");
+        assert!(!allowed());
+    }
+
     #[tokio::test]
     async fn local_client() {
         let cfg = ConfigSource::File(
@@ -1082,6 +1619,7 @@ mod tests {
             cfg,
             state: state.clone(),
             cert_fetcher: Arc::new(cert_fetcher::NoCertFetcher()),
+            policy_notifier: None,
         };
         local_client.run().await.expect("client should run");
         let wl = demand.find_workload(&network_addr("", "127.0.0.1".parse().unwrap()));
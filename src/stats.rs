@@ -24,6 +24,7 @@ use prometheus_client::encoding::text::encode;
 use prometheus_client::registry::Registry;
 
 use crate::config::Config;
+use crate::hot_restart::Listeners;
 use crate::hyper_util::{empty_response, Server};
 
 pub struct Service {
@@ -31,10 +32,21 @@ pub struct Service {
 }
 
 impl Service {
-    pub async fn new(config: Config, registry: Registry, drain_rx: Watch) -> anyhow::Result<Self> {
-        Server::<Mutex<Registry>>::bind("stats", config.stats_addr, drain_rx, Mutex::new(registry))
-            .await
-            .map(|s| Service { s })
+    pub async fn new(
+        config: Config,
+        registry: Registry,
+        listeners: &Listeners,
+        drain_rx: Watch,
+    ) -> anyhow::Result<Self> {
+        Server::<Mutex<Registry>>::bind(
+            "stats",
+            config.stats_addr,
+            listeners,
+            drain_rx,
+            Mutex::new(registry),
+        )
+        .await
+        .map(|s| Service { s })
     }
 
     pub fn address(&self) -> SocketAddr {
@@ -14,6 +14,7 @@
 
 use crate::config::ConfigSource;
 use crate::config::{self, RootCert};
+use crate::identity::Identity;
 use crate::state::service::{Endpoint, Service};
 use crate::state::workload::Protocol::{HBONE, TCP};
 use crate::state::workload::{gatewayaddress, GatewayAddress, NetworkAddress, Workload};
@@ -70,13 +71,13 @@ pub fn test_config_with_port_xds_addr_and_root_cert(
         // Switch all addressed to localhost (so we don't make a bunch of ports expose on public internet when someone runs a test),
         // and port 0 (to avoid port conflicts)
         // inbound_addr cannot do localhost since we abuse that its listening on all of 127.0.0.0/8 range.
-        inbound_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
-        socks5_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+        inbound_addr: Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)),
+        socks5_addr: Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)),
         admin_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
         readiness_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
         stats_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
-        outbound_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
-        inbound_plaintext_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+        outbound_addr: Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)),
+        inbound_plaintext_addr: Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)),
         ..config::parse_config().unwrap()
     }
 }
@@ -112,6 +113,21 @@ pub fn localhost_error_message() -> String {
     )
 }
 
+/// test_identity returns the identity of a service account in the "ns" namespace of cluster.local.
+pub fn test_identity(service_account: &str) -> Identity {
+    Identity::Spiffe {
+        trust_domain: "cluster.local".to_string(),
+        namespace: "ns".to_string(),
+        service_account: service_account.to_string(),
+    }
+}
+
+/// temp_dir creates an empty directory, which is removed when the returned TempDir is dropped.
+#[cfg(test)]
+pub fn temp_dir() -> tempfile::TempDir {
+    tempfile::tempdir().unwrap()
+}
+
 pub fn test_default_workload() -> Workload {
     Workload {
         workload_ips: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
@@ -135,6 +151,7 @@ pub fn test_default_workload() -> Workload {
 
         authorization_policies: Vec::new(),
         native_tunnel: false,
+        mtls_mode: Default::default(),
     }
 }
 
@@ -233,6 +250,8 @@ fn local_xds_config(echo_port: u16, waypoint_ip: Option<IpAddr>) -> anyhow::Resu
         workloads: res,
         policies: vec![],
         services: svcs,
+        faults: vec![],
+        mtls_modes: Default::default(),
     };
     let mut b = bytes::BytesMut::new().writer();
     serde_yaml::to_writer(&mut b, &lc)?;
@@ -81,6 +81,8 @@ impl CaServer {
                 "Kubernetes".to_string(),
             ),
             true,
+            tls::KeyAlgorithm::default(),
+            Duration::from_secs(60 * 60 * 24),
         )
         .unwrap();
         (tx, client)
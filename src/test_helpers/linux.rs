@@ -70,6 +70,8 @@ impl WorkloadManager {
             workloads: self.workloads.clone(),
             policies: vec![],
             services: self.services.values().cloned().collect_vec(),
+            faults: vec![],
+            mtls_modes: Default::default(),
         };
         let mut b = bytes::BytesMut::new().writer();
         serde_yaml::to_writer(&mut b, &lc)?;
@@ -95,9 +97,12 @@ impl WorkloadManager {
                 admin_address: helpers::with_ip(app.admin_address, ip),
                 stats_address: helpers::with_ip(app.stats_address, ip),
                 proxy_addresses: proxy::Addresses {
-                    outbound: helpers::with_ip(app.proxy_addresses.outbound, ip),
-                    inbound: helpers::with_ip(app.proxy_addresses.inbound, ip),
-                    socks5: helpers::with_ip(app.proxy_addresses.socks5, ip),
+                    outbound: app
+                        .proxy_addresses
+                        .outbound
+                        .map(|a| helpers::with_ip(a, ip)),
+                    inbound: app.proxy_addresses.inbound.map(|a| helpers::with_ip(a, ip)),
+                    socks5: app.proxy_addresses.socks5.map(|a| helpers::with_ip(a, ip)),
                 },
                 readiness_address: helpers::with_ip(app.readiness_address, ip),
                 cert_manager,
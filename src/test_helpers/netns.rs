@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::future::Future;
 use std::net::IpAddr;
+use std::os::fd::OwnedFd;
 use std::sync::mpsc::SyncSender;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
@@ -95,6 +96,11 @@ impl Namespace {
         format!("veth{}", self.id)
     }
 
+    /// netns_fd opens the network namespace, such as to hand it to ztunnel as a node agent would.
+    pub fn netns_fd(&self) -> anyhow::Result<OwnedFd> {
+        Ok(std::fs::File::open(self.netns.path())?.into())
+    }
+
     // A small helper around run_ready that marks as "ready" immediately.
     pub fn run<F, Fut>(self, f: F) -> anyhow::Result<JoinHandle<anyhow::Result<()>>>
     where
@@ -13,10 +13,14 @@
 // limitations under the License.
 
 pub mod boring;
+mod crl;
+mod trust_bundle;
 
 use std::sync::Arc;
 
 pub use crate::tls::boring::*;
+pub use crate::tls::crl::*;
+pub use crate::tls::trust_bundle::*;
 use ::boring::error::ErrorStack;
 use hyper::http::uri::InvalidUri;
 
@@ -28,6 +32,9 @@ pub enum Error {
     #[error("invalid root certificate: {0}")]
     InvalidRootCert(ErrorStack),
 
+    #[error("invalid trust bundle: {0}")]
+    InvalidTrustBundle(String),
+
     #[error("invalid uri: {0}")]
     InvalidUri(#[from] Arc<InvalidUri>),
 }
@@ -15,9 +15,9 @@ use std::pin::Pin;
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use super::Error;
+use super::{Crls, Error, TrustBundles};
 use crate::config::RootCert;
-use crate::identity::{self, Identity};
+use crate::identity::{self, Identity, TrustDomainAliases};
 use crate::state::workload::NetworkAddress;
 use boring::asn1::{Asn1Time, Asn1TimeRef};
 use boring::bn::BigNum;
@@ -26,6 +26,7 @@ use boring::hash::MessageDigest;
 use boring::nid::Nid;
 use boring::pkey;
 use boring::pkey::{PKey, Private};
+use boring::rsa::Rsa;
 use boring::ssl::{self, SslContextBuilder};
 use boring::stack::Stack;
 use boring::x509::extension::{
@@ -77,15 +78,55 @@ pub struct CertSign {
     pub pkey: Vec<u8>,
 }
 
+/// KeyAlgorithm is the type of private key generated for workload certificates.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    #[default]
+    EcdsaP256,
+    EcdsaP384,
+    Rsa2048,
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    /// is_allowed returns false for algorithms that FIPS mode does not allow, when built with the
+    /// fips feature.
+    pub fn is_allowed(&self) -> bool {
+        !(cfg!(feature = "fips") && *self == KeyAlgorithm::Ed25519)
+    }
+
+    pub fn generate(&self) -> Result<PKey<Private>, Error> {
+        let ec = |nid| -> Result<PKey<Private>, Error> {
+            Ok(PKey::from_ec_key(EcKey::generate(
+                &EcGroup::from_curve_name(nid)?,
+            )?)?)
+        };
+        match self {
+            KeyAlgorithm::EcdsaP256 => ec(Nid::X9_62_PRIME256V1),
+            KeyAlgorithm::EcdsaP384 => ec(Nid::SECP384R1),
+            KeyAlgorithm::Rsa2048 => Ok(PKey::from_rsa(Rsa::generate(2048)?)?),
+            KeyAlgorithm::Ed25519 => Ok(PKey::generate_ed25519()?),
+        }
+    }
+
+    /// digest returns the digest to sign with. Ed25519 hashes as part of signing.
+    fn digest(&self) -> MessageDigest {
+        match self {
+            KeyAlgorithm::EcdsaP384 => MessageDigest::sha384(),
+            KeyAlgorithm::Ed25519 => MessageDigest::null(),
+            KeyAlgorithm::EcdsaP256 | KeyAlgorithm::Rsa2048 => MessageDigest::sha256(),
+        }
+    }
+}
+
 pub struct CsrOptions {
     pub san: String,
+    pub key_algorithm: KeyAlgorithm,
 }
 
 impl CsrOptions {
     pub fn generate(&self) -> Result<CertSign, Error> {
-        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
-        let ec_key = EcKey::generate(&group)?;
-        let pkey = PKey::from_ec_key(ec_key)?;
+        let pkey = self.key_algorithm.generate()?;
 
         let mut csr = x509::X509ReqBuilder::new()?;
         csr.set_pubkey(&pkey)?;
@@ -97,7 +138,7 @@ impl CsrOptions {
             .unwrap();
         extensions.push(subject_alternative_name)?;
         csr.add_extensions(&extensions)?;
-        csr.sign(&pkey, MessageDigest::sha256())?;
+        csr.sign(&pkey, self.key_algorithm.digest())?;
 
         let csr = csr.build();
         let pkey_pem = pkey.private_key_to_pem_pkcs8()?;
@@ -155,6 +196,16 @@ impl PartialEq for Certs {
 }
 
 impl Certs {
+    /// new builds certs from a private key, its leaf cert, and the remainder of the chain, ending
+    /// with the root.
+    pub fn new(key: PKey<Private>, cert: x509::X509, chain: Vec<x509::X509>) -> Certs {
+        Certs {
+            cert: ZtunnelCert::new(cert),
+            chain: chain.into_iter().map(ZtunnelCert::new).collect(),
+            key,
+        }
+    }
+
     pub fn chain(&self) -> Result<Bytes, Error> {
         Ok(self.chain[0].x509.to_pem()?.into())
     }
@@ -165,6 +216,14 @@ impl Certs {
         self.chain.iter().map(|zcert| &zcert.x509)
     }
 
+    pub fn not_before(&self) -> SystemTime {
+        self.cert.not_before
+    }
+
+    pub fn not_after(&self) -> SystemTime {
+        self.cert.not_after
+    }
+
     pub fn is_expired(&self) -> bool {
         SystemTime::now() > self.cert.not_after
     }
@@ -195,6 +254,15 @@ impl Certs {
     pub fn x509(&self) -> &x509::X509 {
         &self.cert.x509
     }
+
+    /// to_pem encodes the private key, followed by the leaf cert and the remainder of the chain.
+    pub fn to_pem(&self) -> Result<Vec<u8>, Error> {
+        let mut pem = self.key.private_key_to_pem_pkcs8()?;
+        for cert in std::iter::once(&self.cert).chain(&self.chain) {
+            pem.extend(cert.x509.to_pem()?);
+        }
+        Ok(pem)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -314,17 +382,25 @@ impl Certs {
         ssl::SslVerifyMode::PEER | ssl::SslVerifyMode::FAIL_IF_NO_PEER_CERT
     }
 
-    pub fn mtls_acceptor(&self, dest_id: Option<&Identity>) -> Result<ssl::SslAcceptor, Error> {
+    pub fn mtls_acceptor(
+        &self,
+        dest_id: Option<&Identity>,
+        aliases: &TrustDomainAliases,
+        bundles: &TrustBundles,
+        crls: &Crls,
+    ) -> Result<ssl::SslAcceptor, Error> {
         let _ctx = ssl::SslContext::builder(ssl::SslMethod::tls_server())?;
         // mozilla_intermediate_v5 is the only variant that enables TLSv1.3, so we use that.
         let mut conn = ssl::SslAcceptor::mozilla_intermediate_v5(ssl::SslMethod::tls_server())?;
         self.setup_ctx(&mut conn)?;
 
         if let Some(dest_id) = dest_id {
-            // Validate that the source cert shares the same trust domain
+            // Validate that the source cert shares the same trust domain, or an alias of it, or
+            // is from a federated trust domain
             conn.set_verify_callback(
                 Self::verify_mode(),
-                Verifier::SanTrustDomain(dest_id.clone()).callback(),
+                Verifier::SanTrustDomain(dest_id.clone(), aliases.clone())
+                    .callback(bundles.clone(), crls.clone()),
             );
         }
 
@@ -337,28 +413,56 @@ impl Certs {
         let mut conn = ssl::SslAcceptor::mozilla_intermediate_v5(ssl::SslMethod::tls_server())?;
         self.setup_ctx(&mut conn)?;
 
-        conn.set_verify_callback(ssl::SslVerifyMode::NONE, Verifier::None.callback());
+        conn.set_verify_callback(
+            ssl::SslVerifyMode::NONE,
+            Verifier::None.callback(TrustBundles::default(), Crls::default()),
+        );
         Ok(conn.build())
     }
 
-    pub fn connector(&self, dest_id: &Identity) -> Result<ssl::SslConnector, Error> {
+    pub fn connector(
+        &self,
+        dest_id: &Identity,
+        aliases: &TrustDomainAliases,
+        bundles: &TrustBundles,
+        crls: &Crls,
+    ) -> Result<ssl::SslConnector, Error> {
         let mut conn = ssl::SslConnector::builder(ssl::SslMethod::tls_client())?;
         self.setup_ctx(&mut conn)?;
 
         // client verifies SAN
         conn.set_verify_callback(
             Self::verify_mode(),
-            Verifier::San(dest_id.clone()).callback(),
+            Verifier::San(dest_id.clone(), aliases.clone()).callback(bundles.clone(), crls.clone()),
         );
 
         Ok(conn.build())
     }
 
     fn setup_ctx(&self, conn: &mut SslContextBuilder) -> Result<(), Error> {
+        use ssl::SslSignatureAlgorithm as Alg;
+        const VERIFY_ALGORITHMS: &[Alg] = &[
+            Alg::ECDSA_SECP256R1_SHA256,
+            Alg::RSA_PSS_RSAE_SHA256,
+            Alg::RSA_PKCS1_SHA256,
+            Alg::ECDSA_SECP384R1_SHA384,
+            Alg::RSA_PSS_RSAE_SHA384,
+            Alg::RSA_PKCS1_SHA384,
+            Alg::RSA_PSS_RSAE_SHA512,
+            Alg::RSA_PKCS1_SHA512,
+        ];
+
         // general TLS options
         conn.set_alpn_protos(Alpn::H2.encode())?;
         conn.set_min_proto_version(Some(ssl::SslVersion::TLS1_3))?;
         conn.set_max_proto_version(Some(ssl::SslVersion::TLS1_3))?;
+        // BoringSSL does not accept Ed25519 signatures by default; only opt in when the build
+        // permits Ed25519, as it is not a FIPS-approved algorithm.
+        let mut verify_algorithms = VERIFY_ALGORITHMS.to_vec();
+        if KeyAlgorithm::Ed25519.is_allowed() {
+            verify_algorithms.push(Alg::ED25519);
+        }
+        conn.set_verify_algorithm_prefs(&verify_algorithms)?;
 
         // key and certs
         conn.set_private_key(&self.key)?;
@@ -375,7 +479,10 @@ impl Certs {
         conn.check_private_key()?;
 
         // by default, allow boringssl to do standard validation
-        conn.set_verify_callback(Self::verify_mode(), Verifier::None.callback());
+        conn.set_verify_callback(
+            Self::verify_mode(),
+            Verifier::None.callback(TrustBundles::default(), Crls::default()),
+        );
 
         Ok(())
     }
@@ -385,22 +492,75 @@ enum Verifier {
     // Does not verify an individual identity.
     None,
 
-    // Allows exactly one identity, making sure at least one of the presented certs matches that identity
-    San(Identity),
+    // Allows exactly one identity, making sure at least one of the presented certs matches that identity.
+    // The trust domain may be an alias of the identity's.
+    San(Identity, TrustDomainAliases),
 
-    // Allows all identities that share the same trust domain
-    SanTrustDomain(Identity),
+    // Allows all identities that share the same trust domain, or an alias of it.
+    // Identities of federated trust domains are allowed as well.
+    SanTrustDomain(Identity, TrustDomainAliases),
 }
 
 impl Verifier {
-    fn base_verifier(verified: bool, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
-        if !verified {
-            return Err(TlsError::Verification(ctx.error()));
+    fn base_verifier(
+        verified: bool,
+        bundles: &TrustBundles,
+        crls: &Crls,
+        ctx: &mut X509StoreContextRef,
+    ) -> Result<(), TlsError> {
+        if verified {
+            return Ok(());
+        };
+        // The peer does not chain to our roots, but may chain to those of its own trust domain.
+        let err = ctx.error();
+        let ssl_idx = X509StoreContext::ssl_idx().map_err(Error::SslError)?;
+        let ssl = ctx.ex_data(ssl_idx).ok_or(TlsError::ExDataError)?;
+        let cert = ssl.peer_certificate().ok_or(TlsError::PeerCertError)?;
+        let Ok(Some((_, chain))) = bundles.verify_chain(&cert, ssl.peer_cert_chain()) else {
+            return Err(TlsError::Verification(err));
         };
+        // The chain was not built by this context, so is checked for revocation here.
+        if !crls.is_empty() {
+            for certs in chain.windows(2) {
+                crls.check(&certs[0], &certs[1])?;
+            }
+        }
         Ok(())
     }
 
-    fn verifiy_san(identity: &Identity, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
+    /// federated_trust_domain returns the trust domain whose bundle the peer's certificate
+    /// chains to, if any.
+    fn federated_trust_domain(
+        bundles: &TrustBundles,
+        ctx: &X509StoreContextRef,
+    ) -> Result<Option<String>, TlsError> {
+        let ssl_idx = X509StoreContext::ssl_idx().map_err(Error::SslError)?;
+        let ssl = ctx.ex_data(ssl_idx).ok_or(TlsError::ExDataError)?;
+        let cert = ssl.peer_certificate().ok_or(TlsError::PeerCertError)?;
+        Ok(bundles.verify(&cert, ssl.peer_cert_chain())?)
+    }
+
+    fn verify_not_revoked(crls: &Crls, ctx: &X509StoreContextRef) -> Result<(), TlsError> {
+        if crls.is_empty() {
+            return Ok(());
+        }
+        // Each certificate of the chain is checked against the CRLs of its issuer as it is
+        // verified. The root has no issuer in the chain, and cannot be revoked.
+        let depth = ctx.error_depth() as usize;
+        let Some(chain) = ctx.chain() else {
+            return Ok(());
+        };
+        let (Some(cert), Some(issuer)) = (chain.get(depth), chain.get(depth + 1)) else {
+            return Ok(());
+        };
+        crls.check(cert, issuer)
+    }
+
+    fn verifiy_san(
+        identity: &Identity,
+        aliases: &TrustDomainAliases,
+        ctx: &mut X509StoreContextRef,
+    ) -> Result<(), TlsError> {
         // internally, openssl tends to .expect the results of these methods.
         // TODO bubble up better error message
         let ssl_idx = X509StoreContext::ssl_idx().map_err(Error::SslError)?;
@@ -410,11 +570,13 @@ impl Verifier {
             .peer_certificate()
             .ok_or(TlsError::PeerCertError)?;
 
-        cert.verify_san(identity)
+        cert.verify_san(identity, aliases)
     }
 
     fn verifiy_san_trust_domain(
         identity: &Identity,
+        aliases: &TrustDomainAliases,
+        bundles: &TrustBundles,
         ctx: &mut X509StoreContextRef,
     ) -> Result<(), TlsError> {
         // internally, openssl tends to .expect the results of these methods.
@@ -426,21 +588,50 @@ impl Verifier {
             .peer_certificate()
             .ok_or(TlsError::PeerCertError)?;
 
-        cert.verify_san_trust_domain(identity)
+        let err = match cert.verify_san_trust_domain(identity, aliases) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        // Peers of federated trust domains are allowed if they chain to the bundle of their own
+        // trust domain, and only hold identities in it.
+        let Some(td) = Self::federated_trust_domain(bundles, ctx)? else {
+            return Err(err);
+        };
+        let sans = extract_sans(&cert);
+        let in_bundle = sans.iter().all(|id| match id {
+            Identity::Spiffe { trust_domain, .. } => *trust_domain == td,
+        });
+        if sans.is_empty() || !in_bundle {
+            return Err(TlsError::SanTrustDomainError(td, sans));
+        }
+        Ok(())
     }
 
-    fn verify(&self, verified: bool, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
-        Self::base_verifier(verified, ctx)?;
+    fn verify(
+        &self,
+        verified: bool,
+        bundles: &TrustBundles,
+        crls: &Crls,
+        ctx: &mut X509StoreContextRef,
+    ) -> Result<(), TlsError> {
+        Self::base_verifier(verified, bundles, crls, ctx)?;
+        Self::verify_not_revoked(crls, ctx)?;
         match self {
-            Self::San(identity) => Verifier::verifiy_san(identity, ctx)?,
-            Self::SanTrustDomain(identity) => Verifier::verifiy_san_trust_domain(identity, ctx)?,
+            Self::San(identity, aliases) => Verifier::verifiy_san(identity, aliases, ctx)?,
+            Self::SanTrustDomain(identity, aliases) => {
+                Verifier::verifiy_san_trust_domain(identity, aliases, bundles, ctx)?
+            }
             Self::None => (),
         };
         Ok(())
     }
 
-    fn callback(self) -> impl Fn(bool, &mut X509StoreContextRef) -> bool {
-        move |verified, ctx| match self.verify(verified, ctx) {
+    fn callback(
+        self,
+        bundles: TrustBundles,
+        crls: Crls,
+    ) -> impl Fn(bool, &mut X509StoreContextRef) -> bool {
+        move |verified, ctx| match self.verify(verified, &bundles, &crls, ctx) {
             Ok(_) => true,
             Err(e) => {
                 // TODO metrics/counters; info would be too noisy
@@ -452,17 +643,30 @@ impl Verifier {
 }
 
 pub trait SanChecker {
-    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError>;
-    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError>;
+    fn verify_san(&self, identity: &Identity, aliases: &TrustDomainAliases)
+        -> Result<(), TlsError>;
+    fn verify_san_trust_domain(
+        &self,
+        identity: &Identity,
+        aliases: &TrustDomainAliases,
+    ) -> Result<(), TlsError>;
 }
 
 impl SanChecker for Certs {
-    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError> {
-        self.cert.x509.verify_san(identity)
+    fn verify_san(
+        &self,
+        identity: &Identity,
+        aliases: &TrustDomainAliases,
+    ) -> Result<(), TlsError> {
+        self.cert.x509.verify_san(identity, aliases)
     }
 
-    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError> {
-        self.cert.x509.verify_san_trust_domain(identity)
+    fn verify_san_trust_domain(
+        &self,
+        identity: &Identity,
+        aliases: &TrustDomainAliases,
+    ) -> Result<(), TlsError> {
+        self.cert.x509.verify_san_trust_domain(identity, aliases)
     }
 }
 
@@ -477,22 +681,32 @@ pub fn extract_sans(cert: &x509::X509) -> Vec<Identity> {
 }
 
 impl SanChecker for x509::X509 {
-    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError> {
+    fn verify_san(
+        &self,
+        identity: &Identity,
+        aliases: &TrustDomainAliases,
+    ) -> Result<(), TlsError> {
         let sans = extract_sans(self);
         sans.iter()
-            .find(|id| id == &identity)
+            .find(|id| aliases.identities_match(id, identity))
             .ok_or_else(|| TlsError::SanError(identity.to_owned(), sans.clone()))
             .map(|_| ())
     }
 
-    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError> {
+    fn verify_san_trust_domain(
+        &self,
+        identity: &Identity,
+        aliases: &TrustDomainAliases,
+    ) -> Result<(), TlsError> {
         let source_trust_domain = match identity {
             Identity::Spiffe { trust_domain, .. } => trust_domain,
         };
         let sans = extract_sans(self);
         sans.iter()
             .find(|id| match id {
-                Identity::Spiffe { trust_domain, .. } => trust_domain == source_trust_domain,
+                Identity::Spiffe { trust_domain, .. } => {
+                    aliases.equivalent(trust_domain, source_trust_domain)
+                }
             })
             .ok_or_else(|| {
                 TlsError::SanTrustDomainError(source_trust_domain.to_string(), sans.clone())
@@ -544,6 +758,8 @@ pub enum TlsError {
     Verification(X509VerifyResult),
     #[error("certificate lookup error: {0} is not a known destination")]
     CertificateLookup(NetworkAddress),
+    #[error("certificate lookup error: pod {0} is not a known workload")]
+    PodLookup(String),
     #[error("signing error: {0}")]
     SigningError(#[from] identity::Error),
     #[error("san verification error: remote did not present the expected SAN ({0}), got {1:?}")]
@@ -556,6 +772,10 @@ pub enum TlsError {
     ExDataError,
     #[error("failed getting peer cert")]
     PeerCertError,
+    #[error("peer certificate was revoked")]
+    Revoked,
+    #[error("certificate revocation lists are unavailable or past their next update")]
+    CrlUnavailable,
     #[error("ssl error: {0}")]
     SslError(#[from] Error),
 }
@@ -623,8 +843,9 @@ fn generate_test_certs_at(
     not_before: SystemTime,
     not_after: SystemTime,
     rng: Option<&mut dyn rand::RngCore>,
+    key: Option<PKey<Private>>,
 ) -> Certs {
-    let key = pkey::PKey::private_key_from_pem(TEST_PKEY).unwrap();
+    let key = key.unwrap_or_else(|| pkey::PKey::private_key_from_pem(TEST_PKEY).unwrap());
     let (ca_cert, ca_key) = test_ca().unwrap();
     let mut builder = x509::X509::builder().unwrap();
     let not_before_asn = system_time_to_asn1_time(not_before).unwrap();
@@ -704,7 +925,13 @@ pub fn generate_test_certs(
     duration_until_expiry: Duration,
 ) -> Certs {
     let not_before = SystemTime::now() + duration_until_valid;
-    generate_test_certs_at(id, not_before, not_before + duration_until_expiry, None)
+    generate_test_certs_at(
+        id,
+        not_before,
+        not_before + duration_until_expiry,
+        None,
+        None,
+    )
 }
 
 fn test_ca() -> Result<(x509::X509, PKey<Private>), Error> {
@@ -724,6 +951,8 @@ pub mod mock {
     use rand::{rngs::SmallRng, SeedableRng};
     use std::time::SystemTime;
 
+    use boring::pkey::{PKey, Private};
+
     use super::{generate_test_certs_at, Certs, TestIdentity};
 
     /// Allows generating test certificates in a deterministic manner.
@@ -747,7 +976,18 @@ pub mod mock {
             not_before: SystemTime,
             not_after: SystemTime,
         ) -> Certs {
-            generate_test_certs_at(id, not_before, not_after, Some(&mut self.rng))
+            generate_test_certs_at(id, not_before, not_after, Some(&mut self.rng), None)
+        }
+
+        /// new_certs_with_key is like new_certs, but for the given key rather than the test key.
+        pub fn new_certs_with_key(
+            &mut self,
+            id: &TestIdentity,
+            not_before: SystemTime,
+            not_after: SystemTime,
+            key: PKey<Private>,
+        ) -> Certs {
+            generate_test_certs_at(id, not_before, not_after, Some(&mut self.rng), Some(key))
         }
     }
 
@@ -763,10 +1003,10 @@ pub mod mock {
 pub mod tests {
     use std::time::Duration;
 
-    use crate::identity::Identity;
+    use crate::identity::{Identity, TrustDomainAliases};
     use crate::tls::TestIdentity;
 
-    use super::generate_test_certs;
+    use super::{generate_test_certs, x509, CsrOptions, KeyAlgorithm, PKey, SanChecker};
 
     #[test]
     #[cfg(feature = "fips")]
@@ -780,6 +1020,38 @@ pub mod tests {
         assert!(!boring::fips::enabled());
     }
 
+    #[test]
+    fn csr_key_algorithms() {
+        use boring::pkey::Id;
+
+        for (alg, id) in [
+            (KeyAlgorithm::EcdsaP256, Id::EC),
+            (KeyAlgorithm::EcdsaP384, Id::EC),
+            (KeyAlgorithm::Rsa2048, Id::RSA),
+            (KeyAlgorithm::Ed25519, Id::ED25519),
+        ] {
+            if !alg.is_allowed() {
+                continue;
+            }
+            let cs = CsrOptions {
+                san: Identity::default().to_string(),
+                key_algorithm: alg,
+            }
+            .generate()
+            .unwrap();
+            let csr = x509::X509Req::from_pem(&cs.csr).unwrap();
+            let key = csr.public_key().unwrap();
+            assert_eq!(key.id(), id, "{alg:?}");
+            assert!(csr.verify(&key).unwrap(), "{alg:?}");
+            let pkey = PKey::private_key_from_pem(&cs.pkey).unwrap();
+            assert!(pkey.public_eq(&key), "{alg:?}");
+        }
+        assert_eq!(
+            KeyAlgorithm::Ed25519.is_allowed(),
+            cfg!(not(feature = "fips"))
+        );
+    }
+
     #[test]
     fn cert_expiration() {
         let expiry_seconds = 1000;
@@ -810,4 +1082,22 @@ pub mod tests {
         assert!(!future_certs.is_expired());
         assert_eq!(future_certs.get_duration_until_refresh(), zero_dur);
     }
+
+    #[test]
+    fn san_trust_domain_aliases() {
+        let id: TestIdentity = Identity::default().into();
+        let certs = generate_test_certs(&id, Duration::from_secs(0), Duration::from_secs(1000));
+        let aliased = Identity::Spiffe {
+            trust_domain: "old.example".to_string(),
+            namespace: "istio-system".to_string(),
+            service_account: "ztunnel".to_string(),
+        };
+        let none = TrustDomainAliases::default();
+        assert!(certs.verify_san(&aliased, &none).is_err());
+        assert!(certs.verify_san_trust_domain(&aliased, &none).is_err());
+
+        let aliases = TrustDomainAliases::new("cluster.local", ["old.example".to_string()]);
+        assert!(certs.verify_san(&aliased, &aliases).is_ok());
+        assert!(certs.verify_san_trust_domain(&aliased, &aliases).is_ok());
+    }
 }
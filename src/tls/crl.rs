@@ -0,0 +1,374 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail};
+use boring::hash::MessageDigest;
+use boring::ssl;
+use boring::x509::{CrlStatus, X509Crl, X509Ref};
+use bytes::Bytes;
+use drain::Watch;
+use http_body_util::{BodyExt, Empty};
+use tracing::{debug, info, warn};
+
+use super::{asn1_time_to_system_time, TlsError};
+use crate::metrics::crl::{Crl, CrlAge, CrlRejection};
+use crate::metrics::{IncrementRecorder, Metrics, Recorder};
+
+const PEM_BEGIN: &str = "-----BEGIN X509 CRL-----";
+const PEM_END: &str = "-----END X509 CRL-----";
+
+/// How long fetching a CRL from a source may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Crls holds the certificate revocation lists used to reject peers with revoked certificates.
+/// Each list is loaded from a file or URL, and refreshed periodically.
+/// Clones share the same lists, so refreshes are seen by every connector and acceptor.
+#[derive(Clone, Default)]
+pub struct Crls {
+    sources: Arc<Vec<String>>,
+    lists: Arc<RwLock<HashMap<String, Vec<Arc<RevocationList>>>>>,
+    /// If true, every peer is rejected while a source has not been loaded, or has a list past its
+    /// next update.
+    fail_closed: bool,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl fmt::Debug for Crls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lists = self.lists.read().unwrap();
+        f.debug_map()
+            .entries(lists.iter().map(|(source, lists)| (source, lists.len())))
+            .finish()
+    }
+}
+
+impl Crls {
+    /// load returns Crls that also fetch the CRLs of each source in the background, and keep them
+    /// up to date until drained. Sources are http(s) URLs or file paths, holding PEM or DER
+    /// encoded CRLs. The lists are shared with self, so CRLs set on it are checked as well.
+    pub fn load(
+        &self,
+        sources: &[String],
+        refresh_interval: Duration,
+        fail_closed: bool,
+        metrics: Arc<Metrics>,
+        drain: Watch,
+    ) -> Crls {
+        let crls = Crls {
+            sources: Arc::new(sources.to_vec()),
+            lists: self.lists.clone(),
+            fail_closed,
+            metrics: Some(metrics),
+        };
+        if !sources.is_empty() {
+            tokio::spawn(crls.clone().watch(refresh_interval, drain));
+        }
+        crls
+    }
+
+    /// watch periodically refreshes the CRLs until drained. A source that fails to load keeps its
+    /// previous lists.
+    async fn watch(self, refresh_interval: Duration, drain: Watch) {
+        let refresh = async {
+            let mut interval = tokio::time::interval(refresh_interval);
+            loop {
+                // The first tick completes immediately, loading the sources at startup.
+                interval.tick().await;
+                for source in self.sources.iter() {
+                    if let Err(e) = self.refresh(source).await {
+                        warn!(source, "failed to refresh certificate revocation list: {e}");
+                    }
+                }
+                self.record_age();
+            }
+        };
+        tokio::select! {
+            _ = refresh => {}
+            _ = drain.signaled() => debug!("stopped refreshing certificate revocation lists"),
+        }
+    }
+
+    async fn refresh(&self, source: &str) -> anyhow::Result<()> {
+        let data = tokio::time::timeout(FETCH_TIMEOUT, fetch(source))
+            .await
+            .map_err(|_| anyhow!("timed out after {FETCH_TIMEOUT:?}"))??;
+        let lists = RevocationList::parse_all(source, &data)
+            .map_err(|e| anyhow!("certificate revocation list {source}: {e}"))?;
+        self.insert(source, lists);
+        Ok(())
+    }
+
+    /// set replaces the lists of a source with DER encoded CRLs, such as those pushed alongside
+    /// certificates.
+    pub fn set(&self, source: &str, crls: &[Vec<u8>]) -> anyhow::Result<()> {
+        let lists = crls
+            .iter()
+            .map(|der| RevocationList::parse(der))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| anyhow!("certificate revocation list {source}: {e}"))?;
+        self.insert(source, lists);
+        Ok(())
+    }
+
+    fn insert(&self, source: &str, lists: Vec<RevocationList>) {
+        for list in &lists {
+            if list.is_stale(SystemTime::now()) {
+                warn!(
+                    source,
+                    "certificate revocation list is past its next update"
+                );
+            }
+        }
+        let loaded = self.lists.write().unwrap().insert(
+            source.to_string(),
+            lists.into_iter().map(Arc::new).collect(),
+        );
+        if loaded.is_none() {
+            info!(source, "loaded certificate revocation list");
+        } else {
+            debug!(source, "refreshed certificate revocation list");
+        }
+        self.record_age();
+    }
+
+    fn record_age(&self) {
+        let Some(metrics) = &self.metrics else {
+            return;
+        };
+        let now = SystemTime::now();
+        for (source, lists) in self.lists.read().unwrap().iter() {
+            // The age of a source is that of its oldest list.
+            if let Some(this_update) = lists.iter().map(|l| l.this_update).min() {
+                let age = now.duration_since(this_update).unwrap_or_default();
+                metrics.record(
+                    &CrlAge(Crl {
+                        source: source.clone(),
+                    }),
+                    age.as_secs(),
+                );
+            }
+        }
+    }
+
+    /// is_empty returns true if no CRL sources are configured, and no lists were set.
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty() && self.lists.read().unwrap().is_empty()
+    }
+
+    /// stale returns true if a source has not been loaded yet, or has a list past its next
+    /// update.
+    fn stale(&self) -> bool {
+        let now = SystemTime::now();
+        let lists = self.lists.read().unwrap();
+        self.sources.iter().any(|source| match lists.get(source) {
+            Some(lists) => lists.iter().any(|l| l.is_stale(now)),
+            None => true,
+        })
+    }
+
+    /// check returns an error if a CRL issued by the issuer lists the certificate. When failing
+    /// closed, every certificate is rejected while the lists are stale.
+    pub fn check(&self, cert: &X509Ref, issuer: &X509Ref) -> Result<(), TlsError> {
+        if self.fail_closed && self.stale() {
+            return Err(TlsError::CrlUnavailable);
+        }
+        let lists = self.lists.read().unwrap();
+        let Some(source) = lists.iter().find_map(|(source, source_lists)| {
+            source_lists
+                .iter()
+                .any(|l| l.revoked(cert) && l.issued_by(issuer))
+                .then_some(source)
+        }) else {
+            return Ok(());
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.increment(&CrlRejection(Crl {
+                source: source.clone(),
+            }));
+        }
+        Err(TlsError::Revoked)
+    }
+}
+
+async fn fetch(source: &str) -> anyhow::Result<Vec<u8>> {
+    if !source.starts_with("http://") && !source.starts_with("https://") {
+        return Ok(tokio::fs::read(source).await?);
+    }
+    let mut http = hyper_util::client::connect::HttpConnector::new();
+    http.enforce_http(false);
+    let https = hyper_boring::HttpsConnector::with_connector(
+        http,
+        ssl::SslConnector::builder(ssl::SslMethod::tls_client())?,
+    )?;
+    let client = hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
+        .build::<_, Empty<Bytes>>(https);
+    let resp = client.get(source.parse()?).await?;
+    if !resp.status().is_success() {
+        bail!("unexpected status {}", resp.status());
+    }
+    Ok(resp.into_body().collect().await?.to_bytes().to_vec())
+}
+
+/// RevocationList is a parsed CRL.
+struct RevocationList {
+    crl: X509Crl,
+    this_update: SystemTime,
+    next_update: Option<SystemTime>,
+    /// Whether the CRL was signed by an issuer, keyed by the SHA-256 digest of the issuer's
+    /// certificate.
+    issuers: Mutex<HashMap<Vec<u8>, bool>>,
+}
+
+impl RevocationList {
+    /// parse_all parses the PEM encoded CRLs in data, or data as a single DER encoded CRL.
+    fn parse_all(source: &str, data: &[u8]) -> anyhow::Result<Vec<RevocationList>> {
+        let Ok(pem) = std::str::from_utf8(data) else {
+            return Ok(vec![RevocationList::parse(data)?]);
+        };
+        if !pem.contains(PEM_BEGIN) {
+            return Ok(vec![RevocationList::parse(data)?]);
+        }
+        let mut lists = Vec::new();
+        for block in pem.split(PEM_BEGIN).skip(1) {
+            let Some((body, _)) = block.split_once(PEM_END) else {
+                bail!("unterminated PEM block in {source}");
+            };
+            let body: String = body.split_whitespace().collect();
+            lists.push(RevocationList::parse(&boring::base64::decode_block(
+                &body,
+            )?)?);
+        }
+        Ok(lists)
+    }
+
+    /// parse parses a DER encoded CRL.
+    fn parse(der: &[u8]) -> anyhow::Result<RevocationList> {
+        let crl = X509Crl::from_der(der)?;
+        let this_update = asn1_time_to_system_time(crl.last_update());
+        let next_update = crl.next_update().map(asn1_time_to_system_time);
+        Ok(RevocationList {
+            crl,
+            this_update,
+            next_update,
+            issuers: Default::default(),
+        })
+    }
+
+    fn is_stale(&self, now: SystemTime) -> bool {
+        self.next_update.map_or(false, |t| t < now)
+    }
+
+    /// revoked returns true if the CRL lists the certificate's serial number.
+    fn revoked(&self, cert: &X509Ref) -> bool {
+        matches!(
+            self.crl.get_by_serial(cert.serial_number()),
+            CrlStatus::Revoked(_)
+        )
+    }
+
+    /// issued_by returns true if the CRL is signed by the issuer's key.
+    fn issued_by(&self, issuer: &X509Ref) -> bool {
+        let Ok(key) = issuer.digest(MessageDigest::sha256()) else {
+            return false;
+        };
+        let mut issuers = self.issuers.lock().unwrap();
+        *issuers.entry(key.to_vec()).or_insert_with(|| {
+            issuer
+                .public_key()
+                .and_then(|key| self.crl.verify(&key))
+                .unwrap_or(false)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use boring::x509::X509;
+
+    use super::*;
+    use crate::identity::Identity;
+    use crate::tls::{generate_test_certs, TestIdentity};
+
+    const TEST_CRL: &[u8] = include_bytes!("crl.pem");
+    const TEST_ROOT: &[u8] = include_bytes!("root-cert.pem");
+    const TEST_CERT: &[u8] = include_bytes!("cert-chain.pem");
+
+    #[test]
+    fn parse() {
+        let lists = RevocationList::parse_all("crl.pem", TEST_CRL).unwrap();
+        assert_eq!(lists.len(), 1);
+        let list = &lists[0];
+        assert_eq!(list.crl.get_revoked().map_or(0, |r| r.len()), 1);
+        assert!(list.this_update < SystemTime::now());
+        assert!(list.next_update.unwrap() > SystemTime::now());
+
+        assert!(RevocationList::parse(b"not a crl").is_err());
+    }
+
+    #[test]
+    fn revoked() {
+        let crls = Crls {
+            sources: Arc::new(vec!["crl.pem".to_string()]),
+            ..Default::default()
+        };
+        let lists = RevocationList::parse_all("crl.pem", TEST_CRL).unwrap();
+        crls.lists.write().unwrap().insert(
+            "crl.pem".to_string(),
+            lists.into_iter().map(Arc::new).collect(),
+        );
+
+        let root = X509::from_pem(TEST_ROOT).unwrap();
+        let revoked = X509::from_pem(TEST_CERT).unwrap();
+        assert!(matches!(
+            crls.check(&revoked, &root),
+            Err(TlsError::Revoked)
+        ));
+
+        let id: TestIdentity = Identity::default().into();
+        let certs = generate_test_certs(&id, Duration::from_secs(0), Duration::from_secs(1000));
+        assert!(crls.check(certs.x509(), &root).is_ok());
+
+        // The CRL only applies to certificates of its issuer.
+        assert!(crls.check(&revoked, &revoked).is_ok());
+    }
+
+    #[test]
+    fn fail_closed() {
+        let crls = Crls {
+            sources: Arc::new(vec!["crl.pem".to_string()]),
+            fail_closed: true,
+            ..Default::default()
+        };
+        let root = X509::from_pem(TEST_ROOT).unwrap();
+        let id: TestIdentity = Identity::default().into();
+        let certs = generate_test_certs(&id, Duration::from_secs(0), Duration::from_secs(1000));
+        // Until the source is loaded, every peer is rejected.
+        assert!(matches!(
+            crls.check(certs.x509(), &root),
+            Err(TlsError::CrlUnavailable)
+        ));
+
+        let lists = RevocationList::parse_all("crl.pem", TEST_CRL).unwrap();
+        crls.lists.write().unwrap().insert(
+            "crl.pem".to_string(),
+            lists.into_iter().map(Arc::new).collect(),
+        );
+        assert!(crls.check(certs.x509(), &root).is_ok());
+    }
+}
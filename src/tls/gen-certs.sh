@@ -21,4 +21,19 @@ URI = spiffe://cluster.local/ns/default/sa/default
 EOF
 openssl req -new -sha256 -key "key.pem" -out "client.csr" -subj "/CN=default.default.svc.cluster.local" -config "client.conf"
 openssl x509 -req -in "client.csr" -CA "root-cert.pem" -CAkey "ca-key.pem" -CAcreateserial -out "cert-chain.pem" -days 100000 -extensions v3_req -extfile "client.conf"
-rm client.conf client.csr root-cert.srl
+
+# Revoke the client cert, for tests of revocation checking.
+cat > "ca.conf" <<EOF
+[ca]
+default_ca = ca_default
+[ca_default]
+database = index.txt
+crlnumber = crlnumber
+default_md = sha256
+default_crl_days = 36500
+EOF
+touch index.txt
+echo 01 > crlnumber
+openssl ca -config "ca.conf" -keyfile "ca-key.pem" -cert "root-cert.pem" -revoke "cert-chain.pem"
+openssl ca -config "ca.conf" -keyfile "ca-key.pem" -cert "root-cert.pem" -gencrl -out "crl.pem"
+rm client.conf client.csr root-cert.srl ca.conf index.txt* crlnumber*
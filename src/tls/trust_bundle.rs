@@ -0,0 +1,364 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use boring::stack::{Stack, StackRef};
+use boring::x509::store::{X509Store, X509StoreBuilder};
+use boring::x509::{self, X509StoreContext};
+use drain::Watch;
+use tracing::{debug, info, warn};
+
+use super::{extract_sans, Error};
+use crate::identity::Identity;
+
+/// How often bundles are reloaded from the trust bundle directory.
+const FILE_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// BundleSource is where a trust bundle came from. When several have a bundle for a trust domain,
+/// the one from XDS is used, then the one from the Workload API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BundleSource {
+    Xds,
+    WorkloadApi,
+    File,
+}
+
+/// The order in which sources are consulted for a trust domain's bundle.
+const SOURCE_PRECEDENCE: [BundleSource; 3] = [
+    BundleSource::Xds,
+    BundleSource::WorkloadApi,
+    BundleSource::File,
+];
+
+/// Bundle is the parsed root certificates of a trust domain.
+struct Bundle {
+    roots: usize,
+    store: X509Store,
+}
+
+impl Bundle {
+    fn from_pem(pem: &[u8]) -> Result<Bundle, Error> {
+        Bundle::from_certs(x509::X509::stack_from_pem(pem)?)
+    }
+
+    fn from_certs(roots: Vec<x509::X509>) -> Result<Bundle, Error> {
+        if roots.is_empty() {
+            return Err(Error::InvalidTrustBundle(
+                "no root certificates found".to_string(),
+            ));
+        }
+        let mut store = X509StoreBuilder::new()?;
+        for root in &roots {
+            store.add_cert(root.clone())?;
+        }
+        Ok(Bundle {
+            roots: roots.len(),
+            store: store.build(),
+        })
+    }
+}
+
+/// TrustBundles holds the root certificates of federated trust domains, keyed by trust domain.
+/// Peers whose certificate does not chain to our own roots are verified against the bundle of
+/// the trust domain of their SPIFFE identity instead. Certificates with more than one URI SAN are
+/// never verified this way, as their trust domain is ambiguous.
+/// Clones share the same bundles, so updates are seen by every connector and acceptor.
+#[derive(Clone, Default)]
+pub struct TrustBundles(Arc<RwLock<HashMap<BundleSource, HashMap<String, Arc<Bundle>>>>>);
+
+impl fmt::Debug for TrustBundles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bundles = self.0.read().unwrap();
+        let mut m = f.debug_map();
+        for (source, bundles) in bundles.iter() {
+            for (td, bundle) in bundles {
+                m.entry(&(source, td), &bundle.roots);
+            }
+        }
+        m.finish()
+    }
+}
+
+impl TrustBundles {
+    /// insert sets the bundle of the trust domain, from PEM encoded root certificates.
+    pub fn insert(
+        &self,
+        source: BundleSource,
+        trust_domain: &str,
+        pem: &[u8],
+    ) -> Result<(), Error> {
+        let bundle = Arc::new(Bundle::from_pem(pem)?);
+        self.0
+            .write()
+            .unwrap()
+            .entry(source)
+            .or_default()
+            .insert(trust_domain.to_string(), bundle);
+        Ok(())
+    }
+
+    /// replace sets the bundles of a source, removing those of trust domains not in bundles.
+    pub fn replace(
+        &self,
+        source: BundleSource,
+        bundles: HashMap<String, Vec<x509::X509>>,
+    ) -> Result<(), Error> {
+        let bundles = bundles
+            .into_iter()
+            .map(|(td, roots)| Ok((td, Arc::new(Bundle::from_certs(roots)?))))
+            .collect::<Result<HashMap<_, _>, Error>>()?;
+        self.0.write().unwrap().insert(source, bundles);
+        Ok(())
+    }
+
+    pub fn remove(&self, source: BundleSource, trust_domain: &str) {
+        if let Some(bundles) = self.0.write().unwrap().get_mut(&source) {
+            bundles.remove(trust_domain);
+        }
+    }
+
+    /// trust_domains returns the number of root certificates of each trust domain with a bundle.
+    pub fn trust_domains(&self) -> BTreeMap<String, usize> {
+        let bundles = self.0.read().unwrap();
+        let mut out = BTreeMap::new();
+        for source in SOURCE_PRECEDENCE.iter().rev() {
+            for (td, bundle) in bundles.get(source).into_iter().flatten() {
+                out.insert(td.clone(), bundle.roots);
+            }
+        }
+        out
+    }
+
+    fn get(&self, trust_domain: &str) -> Option<Arc<Bundle>> {
+        let bundles = self.0.read().unwrap();
+        SOURCE_PRECEDENCE
+            .iter()
+            .find_map(|source| bundles.get(source)?.get(trust_domain).cloned())
+    }
+
+    /// verify checks whether the certificate, along with the untrusted chain presented with it,
+    /// chains to the roots of the trust domain of its identity. If so, that trust domain is
+    /// returned. Certificates in trust domains without a bundle are never verified.
+    pub fn verify(
+        &self,
+        cert: &x509::X509,
+        chain: Option<&StackRef<x509::X509>>,
+    ) -> Result<Option<String>, Error> {
+        Ok(self.verify_chain(cert, chain)?.map(|(td, _)| td))
+    }
+
+    /// verify_chain is like verify, but also returns the verified chain, from the certificate up
+    /// to the root of the bundle.
+    pub fn verify_chain(
+        &self,
+        cert: &x509::X509,
+        chain: Option<&StackRef<x509::X509>>,
+    ) -> Result<Option<(String, Vec<x509::X509>)>, Error> {
+        let Some(td) = trust_domain(cert) else {
+            return Ok(None);
+        };
+        let Some(bundle) = self.get(&td) else {
+            return Ok(None);
+        };
+        let empty = Stack::new()?;
+        let chain = chain.unwrap_or(&empty);
+        let mut ctx = X509StoreContext::new()?;
+        let verified = ctx.init(&bundle.store, cert, chain, |ctx| {
+            if !ctx.verify_cert()? {
+                return Ok(None);
+            }
+            Ok(ctx
+                .chain()
+                .map(|chain| chain.iter().map(|c| c.to_owned()).collect::<Vec<_>>()))
+        })?;
+        debug!(%td, verified = verified.is_some(), "verified peer against trust bundle");
+        Ok(verified.map(|chain| (td, chain)))
+    }
+
+    /// load_dir replaces the bundles from files with those in dir. Each `<trust domain>.pem` file
+    /// holds the root certificates of that trust domain.
+    pub fn load_dir(&self, dir: &Path) -> anyhow::Result<()> {
+        let mut loaded = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "pem") {
+                continue;
+            }
+            let Some(td) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let bundle = Bundle::from_pem(&std::fs::read(&path)?)
+                .map_err(|e| anyhow::anyhow!("trust bundle {}: {e}", path.display()))?;
+            loaded.insert(td.to_string(), Arc::new(bundle));
+        }
+        self.0.write().unwrap().insert(BundleSource::File, loaded);
+        Ok(())
+    }
+
+    /// watch_dir periodically reloads the bundles from files until drained. A directory that
+    /// fails to load leaves the previous bundles in place.
+    pub async fn watch_dir(self, dir: PathBuf, drain: Watch) {
+        let reload = async {
+            let mut interval = tokio::time::interval(FILE_REFRESH_INTERVAL);
+            // The first tick completes immediately; the directory was loaded at startup.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match self.load_dir(&dir) {
+                    Ok(()) => debug!(dir=%dir.display(), "reloaded trust bundles"),
+                    Err(e) => warn!(dir=%dir.display(), "failed to reload trust bundles: {e}"),
+                }
+            }
+        };
+        tokio::select! {
+            _ = reload => {}
+            _ = drain.signaled() => debug!(dir=%dir.display(), "stopped reloading trust bundles"),
+        }
+    }
+
+    /// load_and_watch_dir loads the bundles in dir, and keeps them up to date in the background
+    /// until drained.
+    pub fn load_and_watch_dir(&self, dir: &Path, drain: Watch) -> anyhow::Result<()> {
+        self.load_dir(dir)?;
+        info!(dir=%dir.display(), trust_domains=?self.trust_domains(), "loaded trust bundles");
+        tokio::spawn(self.clone().watch_dir(dir.to_path_buf(), drain));
+        Ok(())
+    }
+}
+
+/// trust_domain returns the trust domain of the certificate's SPIFFE identity. A certificate
+/// must have exactly one URI SAN, so the bundle it is verified against covers its identity.
+fn trust_domain(cert: &x509::X509) -> Option<String> {
+    let uri_sans = cert
+        .subject_alt_names()
+        .iter()
+        .flat_map(|sans| sans.iter())
+        .filter(|san| san.uri().is_some())
+        .count();
+    if uri_sans != 1 {
+        return None;
+    }
+    extract_sans(cert).into_iter().next().map(|id| match id {
+        Identity::Spiffe { trust_domain, .. } => trust_domain,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::tls::{generate_test_certs, TestIdentity};
+
+    const TEST_ROOT: &[u8] = include_bytes!("root-cert.pem");
+    const TEST_CERT: &[u8] = include_bytes!("cert-chain.pem");
+
+    fn peer_cert(trust_domain: &str) -> x509::X509 {
+        let id: TestIdentity = Identity::Spiffe {
+            trust_domain: trust_domain.to_string(),
+            namespace: "ns".to_string(),
+            service_account: "sa".to_string(),
+        }
+        .into();
+        generate_test_certs(&id, Duration::from_secs(0), Duration::from_secs(1000))
+            .x509()
+            .clone()
+    }
+
+    #[test]
+    fn verify() {
+        let bundles = TrustBundles::default();
+        let cert = peer_cert("partner.example");
+        let partner = Some("partner.example".to_string());
+        assert_eq!(bundles.verify(&cert, None).unwrap(), None);
+
+        bundles
+            .insert(BundleSource::File, "partner.example", TEST_ROOT)
+            .unwrap();
+        assert_eq!(bundles.verify(&cert, None).unwrap(), partner);
+        // The bundle is only used for identities in its trust domain.
+        assert_eq!(
+            bundles.verify(&peer_cert("other.example"), None).unwrap(),
+            None
+        );
+
+        // A bundle from XDS takes precedence over one from a file.
+        bundles
+            .insert(BundleSource::Xds, "partner.example", TEST_CERT)
+            .unwrap();
+        assert_eq!(bundles.verify(&cert, None).unwrap(), None);
+        bundles.remove(BundleSource::Xds, "partner.example");
+        assert_eq!(bundles.verify(&cert, None).unwrap(), partner);
+
+        assert!(bundles
+            .insert(BundleSource::Xds, "partner.example", b"not a cert")
+            .is_err());
+    }
+
+    #[test]
+    fn verify_multiple_uri_sans() {
+        use boring::hash::MessageDigest;
+        use boring::pkey::PKey;
+        use boring::x509::extension::SubjectAlternativeName;
+
+        let root = x509::X509::from_pem(TEST_ROOT).unwrap();
+        let root_key = PKey::private_key_from_pem(include_bytes!("ca-key.pem")).unwrap();
+        let peer = peer_cert("partner.example");
+        let mut builder = x509::X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(&peer.public_key().unwrap()).unwrap();
+        builder.set_issuer_name(root.subject_name()).unwrap();
+        builder.set_not_before(peer.not_before()).unwrap();
+        builder.set_not_after(peer.not_after()).unwrap();
+        let san = SubjectAlternativeName::new()
+            .uri("spiffe://partner.example/ns/ns/sa/sa")
+            .uri("spiffe://cluster.local/ns/ns/sa/sa")
+            .build(&builder.x509v3_context(Some(&root), None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&root_key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        let bundles = TrustBundles::default();
+        bundles
+            .insert(BundleSource::File, "partner.example", TEST_ROOT)
+            .unwrap();
+        // The certificate chains to the bundle, but also claims an identity outside of it.
+        assert_eq!(bundles.verify(&cert, None).unwrap(), None);
+    }
+
+    #[test]
+    fn load_dir() {
+        let dir = crate::test_helpers::temp_dir();
+        let dir = dir.path();
+        std::fs::write(dir.join("partner.example.pem"), TEST_ROOT).unwrap();
+        std::fs::write(dir.join("README"), "ignored").unwrap();
+
+        let bundles = TrustBundles::default();
+        bundles.load_dir(dir).unwrap();
+        assert_eq!(
+            bundles.trust_domains(),
+            BTreeMap::from([("partner.example".to_string(), 1)])
+        );
+
+        std::fs::remove_file(dir.join("partner.example.pem")).unwrap();
+        bundles.load_dir(dir).unwrap();
+        assert!(bundles.trust_domains().is_empty());
+    }
+}
@@ -18,21 +18,28 @@ mod types;
 use self::service::discovery::v3::DeltaDiscoveryRequest;
 use crate::cert_fetcher::{CertFetcher, NoCertFetcher};
 use crate::config::ConfigSource;
+use crate::fault::Fault;
+use crate::identity::TrustDomainAliases;
 use crate::rbac;
 use crate::rbac::Authorization;
 use crate::state::service::{Endpoint, Service};
-use crate::state::workload::{network_addr, HealthStatus, NamespacedHostname, Workload};
+use crate::state::workload::{network_addr, HealthStatus, MtlsMode, NamespacedHostname, Workload};
 use crate::state::ProxyState;
+use crate::tls::{BundleSource, TrustBundles};
 use crate::xds;
 pub use client::*;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tracing::{debug, info, instrument, trace, warn};
 pub use types::*;
 use xds::istio::security::Authorization as XdsAuthorization;
+use xds::istio::security::TrustBundle as XdsTrustBundle;
+use xds::istio::security::TrustDomainAliases as XdsTrustDomainAliases;
 use xds::istio::workload::address::Type as XdsType;
 use xds::istio::workload::Address as XdsAddress;
+use xds::istio::workload::PeerAuthentication as XdsPeerAuthentication;
 use xds::istio::workload::PortList;
 use xds::istio::workload::Service as XdsService;
 use xds::istio::workload::Workload as XdsWorkload;
@@ -55,6 +62,10 @@ pub enum Error {
 pub struct ProxyStateUpdater {
     state: Arc<RwLock<ProxyState>>,
     cert_fetcher: Arc<dyn CertFetcher>,
+    policy_notifier: Option<Arc<watch::Sender<()>>>,
+    // Set when the batch being handled changed policies, so the notification is sent once they
+    // are compiled.
+    policy_changed: Arc<AtomicBool>,
 }
 
 impl ProxyStateUpdater {
@@ -63,6 +74,8 @@ impl ProxyStateUpdater {
         Self {
             state,
             cert_fetcher,
+            policy_notifier: None,
+            policy_changed: Default::default(),
         }
     }
 
@@ -71,10 +84,44 @@ impl ProxyStateUpdater {
         Self::new(state, Arc::new(NoCertFetcher()))
     }
 
+    /// Notify on the given channel whenever authorization policies, or the policies selected by a
+    /// workload, change.
+    pub fn with_policy_notifier(mut self, tx: Arc<watch::Sender<()>>) -> Self {
+        self.policy_notifier = Some(tx);
+        self
+    }
+
+    /// compile_dirty_policies compiles the policies of workloads affected by a batch of updates,
+    /// then notifies of the policy change, if the batch made one.
+    fn compile_dirty_policies(&self) {
+        self.state
+            .write()
+            .unwrap()
+            .workloads
+            .compile_dirty_policies();
+        if self.policy_changed.swap(false, Ordering::SeqCst) {
+            notify_policy_change(&self.policy_notifier);
+        }
+    }
+
+    // Policies are only re-evaluated once the batch is compiled; see compile_dirty_policies.
+    fn mark_policy_change(&self) {
+        self.policy_changed.store(true, Ordering::SeqCst);
+    }
+
     pub fn insert_workload(&self, w: XdsWorkload) -> anyhow::Result<()> {
         // Convert the workload.
         let workload = Workload::try_from(&w)?;
 
+        // Existing connections only need to be re-authorized if the workload was already known.
+        let prev_policies = self
+            .state
+            .read()
+            .unwrap()
+            .workloads
+            .find_uid(&w.uid)
+            .map(|prev| prev.authorization_policies);
+
         // First, remove the entry entirely to make sure things are cleaned up properly.
         self.remove(&w.uid);
 
@@ -91,11 +138,18 @@ impl ProxyStateUpdater {
 
         // Lock and upstate the stores.
         let mut state = self.state.write().unwrap();
+        let policies_changed = prev_policies
+            .map(|p| p != workload.authorization_policies)
+            .unwrap_or(false);
         state.workloads.insert_workload(workload)?;
         while let Some(endpoint) = endpoints.pop() {
             state.services.insert_endpoint(endpoint);
         }
+        drop(state);
 
+        if policies_changed {
+            self.mark_policy_change();
+        }
         Ok(())
     }
 
@@ -177,15 +231,110 @@ impl ProxyStateUpdater {
 
         let rbac = rbac::Authorization::try_from(&r)?;
         trace!("insert policy {}", serde_json::to_string(&rbac)?);
-        let mut state = self.state.write().unwrap();
-        state.workloads.insert_authorization(rbac);
+        self.state
+            .write()
+            .unwrap()
+            .workloads
+            .insert_authorization(rbac);
+        self.mark_policy_change();
         Ok(())
     }
 
     pub fn remove_authorization(&self, name: String) {
         info!("handling RBAC delete {}", name);
-        let mut state = self.state.write().unwrap();
-        state.workloads.remove_rbac(name);
+        self.state.write().unwrap().workloads.remove_rbac(name);
+        self.mark_policy_change();
+    }
+
+    pub fn insert_peer_authentication(
+        &self,
+        name: &str,
+        r: XdsPeerAuthentication,
+    ) -> anyhow::Result<()> {
+        if r.namespace != peer_authentication_namespace(name) {
+            anyhow::bail!(
+                "peer authentication for {:?} must be named after it",
+                r.namespace
+            );
+        }
+        let mode = MtlsMode::try_from(xds::istio::workload::MtlsMode::from_i32(r.mtls_mode))?;
+        info!(
+            namespace = r.namespace,
+            ?mode,
+            "handling peer authentication update"
+        );
+        self.state
+            .write()
+            .unwrap()
+            .workloads
+            .set_mtls_mode(r.namespace, mode);
+        Ok(())
+    }
+
+    pub fn remove_peer_authentication(&self, name: &str) {
+        info!(name, "handling peer authentication delete");
+        self.state.write().unwrap().workloads.set_mtls_mode(
+            peer_authentication_namespace(name).to_string(),
+            MtlsMode::Unset,
+        );
+    }
+
+    pub fn insert_trust_domain_aliases(
+        &self,
+        name: &str,
+        r: XdsTrustDomainAliases,
+    ) -> anyhow::Result<()> {
+        if r.trust_domain != name {
+            anyhow::bail!(
+                "trust domain aliases for {:?} must be named after it",
+                r.trust_domain
+            );
+        }
+        info!(trust_domain = name, aliases = ?r.aliases, "handling trust domain aliases update");
+        let aliases = TrustDomainAliases::new(&r.trust_domain, r.aliases);
+        let changed = self
+            .state
+            .write()
+            .unwrap()
+            .workloads
+            .insert_trust_domain_aliases(name.to_string(), aliases);
+        self.trust_domain_aliases_updated(changed);
+        Ok(())
+    }
+
+    pub fn remove_trust_domain_aliases(&self, name: &str) {
+        info!(trust_domain = name, "handling trust domain aliases delete");
+        let changed = self
+            .state
+            .write()
+            .unwrap()
+            .workloads
+            .remove_trust_domain_aliases(name);
+        self.trust_domain_aliases_updated(changed);
+    }
+
+    /// trust_domain_aliases_updated recompiles every policy if the aliases in effect changed.
+    fn trust_domain_aliases_updated(&self, changed: bool) {
+        if changed {
+            self.mark_policy_change();
+        }
+        self.compile_dirty_policies();
+    }
+}
+
+fn notify_policy_change(notifier: &Option<Arc<watch::Sender<()>>>) {
+    if let Some(tx) = notifier {
+        // An error just means nobody is listening, which is fine.
+        let _ = tx.send(());
+    }
+}
+
+/// PeerAuthentication resources are named after their namespace, except the mesh-wide default,
+/// which is named "*" as it has no namespace.
+fn peer_authentication_namespace(name: &str) -> &str {
+    match name {
+        "*" => "",
+        ns => ns,
     }
 }
 
@@ -198,7 +347,9 @@ impl Handler<XdsWorkload> for ProxyStateUpdater {
             }
             Ok(())
         };
-        handle_single_resource(updates, handle)
+        let res = handle_single_resource(updates, handle);
+        self.compile_dirty_policies();
+        res
     }
 }
 
@@ -211,7 +362,9 @@ impl Handler<XdsAddress> for ProxyStateUpdater {
             }
             Ok(())
         };
-        handle_single_resource(updates, handle)
+        let res = handle_single_resource(updates, handle);
+        self.compile_dirty_policies();
+        res
     }
 }
 
@@ -252,6 +405,68 @@ impl Handler<XdsAuthorization> for ProxyStateUpdater {
             }
             Ok(())
         };
+        let res = handle_single_resource(updates, handle);
+        self.compile_dirty_policies();
+        res
+    }
+}
+
+impl Handler<XdsPeerAuthentication> for ProxyStateUpdater {
+    fn handle(
+        &self,
+        updates: Vec<XdsUpdate<XdsPeerAuthentication>>,
+    ) -> Result<(), Vec<RejectedConfig>> {
+        let handle = |res: XdsUpdate<XdsPeerAuthentication>| {
+            match res {
+                XdsUpdate::Update(p) => self.insert_peer_authentication(&p.name, p.resource)?,
+                XdsUpdate::Remove(name) => self.remove_peer_authentication(&name),
+            }
+            Ok(())
+        };
+        handle_single_resource(updates, handle)
+    }
+}
+
+impl Handler<XdsTrustDomainAliases> for ProxyStateUpdater {
+    fn handle(
+        &self,
+        updates: Vec<XdsUpdate<XdsTrustDomainAliases>>,
+    ) -> Result<(), Vec<RejectedConfig>> {
+        let handle = |res: XdsUpdate<XdsTrustDomainAliases>| {
+            match res {
+                XdsUpdate::Update(a) => self.insert_trust_domain_aliases(&a.name, a.resource)?,
+                XdsUpdate::Remove(name) => self.remove_trust_domain_aliases(&name),
+            }
+            Ok(())
+        };
+        handle_single_resource(updates, handle)
+    }
+}
+
+/// TrustBundle resources are named after their trust domain, so they can be removed by name.
+impl Handler<XdsTrustBundle> for TrustBundles {
+    fn handle(&self, updates: Vec<XdsUpdate<XdsTrustBundle>>) -> Result<(), Vec<RejectedConfig>> {
+        let handle = |res: XdsUpdate<XdsTrustBundle>| {
+            match res {
+                XdsUpdate::Update(b) => {
+                    let bundle = b.resource;
+                    if !bundle.trust_domain.is_empty() && bundle.trust_domain != b.name {
+                        anyhow::bail!(
+                            "trust bundle for {} must be named after it",
+                            bundle.trust_domain
+                        );
+                    }
+                    let pem = bundle.root_certificates.join("\n");
+                    self.insert(BundleSource::Xds, &b.name, pem.as_bytes())?;
+                    info!(trust_domain = b.name, "trust bundle updated");
+                }
+                XdsUpdate::Remove(name) => {
+                    self.remove(BundleSource::Xds, &name);
+                    info!(trust_domain = name, "trust bundle removed");
+                }
+            }
+            Ok(())
+        };
         handle_single_resource(updates, handle)
     }
 }
@@ -261,6 +476,8 @@ pub struct LocalClient {
     pub cfg: ConfigSource,
     pub state: Arc<RwLock<ProxyState>>,
     pub cert_fetcher: Arc<dyn CertFetcher>,
+    /// Notified once the local policies are loaded.
+    pub policy_notifier: Option<Arc<watch::Sender<()>>>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
@@ -280,6 +497,11 @@ pub struct LocalConfig {
     pub policies: Vec<Authorization>,
     #[serde(default)]
     pub services: Vec<Service>,
+    #[serde(default)]
+    pub faults: Vec<Fault>,
+    /// Default mTLS modes by namespace. The empty namespace sets the mesh-wide default.
+    #[serde(default)]
+    pub mtls_modes: HashMap<String, MtlsMode>,
 }
 
 impl LocalClient {
@@ -314,6 +536,15 @@ impl LocalClient {
         for svc in r.services {
             state.services.insert(svc);
         }
+        for fault in r.faults {
+            state.faults.insert(fault)?;
+        }
+        for (namespace, mode) in r.mtls_modes {
+            state.workloads.set_mtls_mode(namespace, mode);
+        }
+        state.workloads.compile_dirty_policies();
+        drop(state);
+        notify_policy_change(&self.policy_notifier);
         info!(%num_workloads, %num_policies, "local config initialized");
         Ok(())
     }
@@ -30,7 +30,10 @@ use crate::config::RootCert;
 use crate::metrics::xds::*;
 use crate::metrics::{IncrementRecorder, Metrics};
 use crate::xds::istio::security::Authorization;
+use crate::xds::istio::security::TrustBundle;
+use crate::xds::istio::security::TrustDomainAliases;
 use crate::xds::istio::workload::Address;
+use crate::xds::istio::workload::PeerAuthentication;
 use crate::xds::service::discovery::v3::aggregated_discovery_service_client::AggregatedDiscoveryServiceClient;
 use crate::xds::service::discovery::v3::Resource as ProtoResource;
 use crate::xds::service::discovery::v3::*;
@@ -121,6 +124,9 @@ pub struct Config {
 
     address_handler: Box<dyn Handler<Address>>,
     authorization_handler: Box<dyn Handler<Authorization>>,
+    trust_bundle_handler: Box<dyn Handler<TrustBundle>>,
+    peer_authentication_handler: Box<dyn Handler<PeerAuthentication>>,
+    trust_domain_aliases_handler: Box<dyn Handler<TrustDomainAliases>>,
     initial_watches: Vec<String>,
     on_demand: bool,
 }
@@ -133,6 +139,9 @@ impl Config {
             auth: config.auth,
             address_handler: Box::new(NopHandler {}),
             authorization_handler: Box::new(NopHandler {}),
+            trust_bundle_handler: Box::new(NopHandler {}),
+            peer_authentication_handler: Box::new(NopHandler {}),
+            trust_domain_aliases_handler: Box::new(NopHandler {}),
             initial_watches: Vec::new(),
             on_demand: config.xds_on_demand,
             proxy_metadata: config.proxy_metadata,
@@ -149,6 +158,27 @@ impl Config {
         self
     }
 
+    pub fn with_trust_bundle_handler(mut self, f: impl Handler<TrustBundle>) -> Config {
+        self.trust_bundle_handler = Box::new(f);
+        self
+    }
+
+    pub fn with_peer_authentication_handler(
+        mut self,
+        f: impl Handler<PeerAuthentication>,
+    ) -> Config {
+        self.peer_authentication_handler = Box::new(f);
+        self
+    }
+
+    pub fn with_trust_domain_aliases_handler(
+        mut self,
+        f: impl Handler<TrustDomainAliases>,
+    ) -> Config {
+        self.trust_domain_aliases_handler = Box::new(f);
+        self
+    }
+
     pub fn watch(mut self, type_url: String) -> Config {
         self.initial_watches.push(type_url);
         self
@@ -487,6 +517,16 @@ impl AdsClient {
                 |a| &a.config.authorization_handler,
                 response,
             ),
+            xds::TRUST_BUNDLE_TYPE => self
+                .decode_and_handle::<TrustBundle, _>(|a| &a.config.trust_bundle_handler, response),
+            xds::PEER_AUTHENTICATION_TYPE => self.decode_and_handle::<PeerAuthentication, _>(
+                |a| &a.config.peer_authentication_handler,
+                response,
+            ),
+            xds::TRUST_DOMAIN_ALIASES_TYPE => self.decode_and_handle::<TrustDomainAliases, _>(
+                |a| &a.config.trust_domain_aliases_handler,
+                response,
+            ),
             _ => {
                 error!("unknown type");
                 Ok(())
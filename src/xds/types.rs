@@ -22,6 +22,11 @@ pub mod service {
             tonic::include_proto!("envoy.service.discovery.v3");
         }
     }
+    pub mod auth {
+        pub mod v3 {
+            tonic::include_proto!("envoy.service.auth.v3");
+        }
+    }
 }
 
 #[allow(warnings)]
@@ -38,8 +43,19 @@ pub mod istio {
     }
 }
 
+/// The SPIFFE Workload API. Its protos have no package, which prost generates as `_`.
+#[allow(warnings)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub mod spiffe {
+    include!(concat!(env!("OUT_DIR"), "/_.rs"));
+}
+
 pub const WORKLOAD_TYPE: &str = "type.googleapis.com/istio.workload.Workload";
 pub const SERVICE_TYPE: &str = "type.googleapis.com/istio.workload.Service";
 pub const GATEWAY_ADDRESS_TYPE: &str = "type.googleapis.com/istio.workload.GatewayAddress";
 pub const ADDRESS_TYPE: &str = "type.googleapis.com/istio.workload.Address";
 pub const AUTHORIZATION_TYPE: &str = "type.googleapis.com/istio.security.Authorization";
+pub const PEER_AUTHENTICATION_TYPE: &str = "type.googleapis.com/istio.workload.PeerAuthentication";
+pub const TRUST_BUNDLE_TYPE: &str = "type.googleapis.com/istio.security.TrustBundle";
+pub const TRUST_DOMAIN_ALIASES_TYPE: &str =
+    "type.googleapis.com/istio.security.TrustDomainAliases";
@@ -60,22 +60,22 @@ async fn test_bind_conflict<F: FnOnce(&mut ztunnel::config::Config) -> &mut Sock
 
 #[tokio::test]
 async fn test_conflicting_bind_error_inbound() {
-    test_bind_conflict(|c| &mut c.inbound_addr).await;
+    test_bind_conflict(|c| c.inbound_addr.as_mut().unwrap()).await;
 }
 
 #[tokio::test]
 async fn test_conflicting_bind_error_inbound_plaintext() {
-    test_bind_conflict(|c| &mut c.inbound_plaintext_addr).await;
+    test_bind_conflict(|c| c.inbound_plaintext_addr.as_mut().unwrap()).await;
 }
 
 #[tokio::test]
 async fn test_conflicting_bind_error_outbound() {
-    test_bind_conflict(|c| &mut c.outbound_addr).await;
+    test_bind_conflict(|c| c.outbound_addr.as_mut().unwrap()).await;
 }
 
 #[tokio::test]
 async fn test_conflicting_bind_error_socks5() {
-    test_bind_conflict(|c| &mut c.socks5_addr).await;
+    test_bind_conflict(|c| c.socks5_addr.as_mut().unwrap()).await;
 }
 
 #[tokio::test]
@@ -28,7 +28,7 @@ mod namespaced {
     use tokio::time::timeout;
     use tracing::{error, info};
 
-    use ztunnel::identity;
+    use ztunnel::proxy::inpod::PodNetns;
     use ztunnel::state::workload::NetworkAddress;
     use ztunnel::test_helpers::app::ParsedMetrics;
     use ztunnel::test_helpers::app::TestApp;
@@ -36,6 +36,7 @@ mod namespaced {
     use ztunnel::test_helpers::linux::WorkloadManager;
     use ztunnel::test_helpers::netns::{Namespace, Resolver};
     use ztunnel::test_helpers::*;
+    use ztunnel::{identity, proxy, tls};
 
     macro_rules! function {
         () => {{
@@ -163,6 +164,21 @@ mod namespaced {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_pod_netns_connect() -> anyhow::Result<()> {
+        let mut manager = setup_netns_test!();
+        let pod = manager.workload_builder("pod", DEFAULT_NODE).register()?;
+        let netns = PodNetns::new(pod.netns_fd()?);
+        run_tcp_server(pod)?;
+
+        // The server is only reachable on loopback from within the pod's network namespace.
+        let addr = SocketAddr::from(([127, 0, 0, 1], SERVER_PORT));
+        assert!(TcpStream::connect(addr).await.is_err());
+        let mut stream = proxy::freebind_connect(Some(&netns), None, addr).await?;
+        double_read_write_stream(&mut stream).await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_tcp_request() -> anyhow::Result<()> {
         let mut manager = setup_netns_test!();
@@ -417,7 +433,12 @@ mod namespaced {
                         .unwrap();
                 let cert = app.cert_manager.fetch_certificate(id).await?;
                 let mut connector = cert
-                    .connector(&dst_id)
+                    .connector(
+                        &dst_id,
+                        &identity::TrustDomainAliases::default(),
+                        &tls::TrustBundles::default(),
+                        &tls::Crls::default(),
+                    )
                     .unwrap()
                     .configure()
                     .expect("configure");
@@ -475,13 +496,18 @@ mod namespaced {
                         .unwrap();
                 let cert = app.cert_manager.fetch_certificate(id).await?;
                 let mut connector = cert
-                    .connector(&dst_id)
+                    .connector(
+                        &dst_id,
+                        &identity::TrustDomainAliases::default(),
+                        &tls::TrustBundles::default(),
+                        &tls::Crls::default(),
+                    )
                     .unwrap()
                     .configure()
                     .expect("configure");
                 connector.set_verify_hostname(false);
                 connector.set_use_server_name_indication(false);
-                let tcp_stream = TcpStream::connect(app.proxy_addresses.inbound)
+                let tcp_stream = TcpStream::connect(app.proxy_addresses.inbound.unwrap())
                     .await
                     .unwrap();
                 let tls_stream = tokio_boring::connect(connector, "", tcp_stream)